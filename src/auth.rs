@@ -0,0 +1,91 @@
+//! Authentication of clients before their requests are forwarded.
+//!
+//! How clients prove who they are differs from one deployment to the next (JWTs, API
+//! keys, the subject of a client certificate put in a header by the TLS terminator), so
+//! the proxy leaves the check to a [ClientAuthenticator]. Clients it turns away are
+//! answered with `401 Unauthorized` without anything being forwarded. The identity it
+//! finds for the others can be passed on to the proxied server in a header, which the
+//! client itself is never allowed to set. The proxy can also log in to the proxied
+//! server with credentials of its own, in place of whatever the client sent.
+
+use async_trait::async_trait;
+use poem::{ Error, Request, http::{ HeaderMap, HeaderName, HeaderValue, StatusCode }, web::headers::{ Authorization, authorization::Credentials } };
+
+/// The outcome of authenticating a client with a [ClientAuthenticator].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Authentication {
+
+    /// Forward the request, optionally naming the client. The identity is sent to the
+    /// proxied server in the [identity header](crate::ProxyConfig::identity_header),
+    /// if one is set.
+    Allow( Option<String> ),
+
+    /// Answer the request with `401 Unauthorized`.
+    Deny,
+}
+
+/// A check that every client has to pass before its requests (and websockets) are
+/// forwarded. Implement this to authenticate clients in whatever way the proxied
+/// server needs.
+///
+/// ```
+/// use std::{ collections::HashMap, sync::Arc };
+/// use poem::{ Request, http::{ HeaderName, header } };
+/// use poem_proxy::{ Authentication, ClientAuthenticator, ProxyConfig };
+///
+/// /// Lets in clients with a known API key, naming them by its owner.
+/// struct ApiKeys( HashMap<String, String> );
+///
+/// #[poem_proxy::async_trait]
+/// impl ClientAuthenticator for ApiKeys {
+///     async fn authenticate( &self, req: &Request ) -> Authentication {
+///         let owner = req.header( header::AUTHORIZATION )
+///             .and_then( |value| value.strip_prefix( "Bearer " ) )
+///             .and_then( |key| self.0.get( key ) );
+///         match owner {
+///             Some( owner ) => Authentication::Allow( Some( owner.clone() ) ),
+///             None => Authentication::Deny,
+///         }
+///     }
+/// }
+///
+/// let keys = HashMap::from( [ ( "s3cr3t".to_owned(), "alice".to_owned() ) ] );
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .client_authenticator( Arc::new( ApiKeys( keys ) ) )
+///     .identity_header( HeaderName::from_static( "x-authenticated-user" ) )
+///     .finish();
+/// ```
+#[async_trait]
+pub trait ClientAuthenticator: Send + Sync {
+
+    /// Decides whether the client that sent `req` may have it forwarded.
+    async fn authenticate( &self, req: &Request ) -> Authentication;
+}
+
+/// Runs `authenticator` on a request, returning the identity of the client it allowed,
+/// or the error to answer the request with if it was denied.
+pub(crate) async fn authenticate( authenticator: &dyn ClientAuthenticator, req: &Request ) -> Result<Option<String>, Error> {
+    match authenticator.authenticate( req ).await {
+        Authentication::Allow( identity ) => Ok( identity ),
+        Authentication::Deny => Err( Error::from_string( "The client could not be authenticated!", StatusCode::UNAUTHORIZED ) ),
+    }
+}
+
+/// Sets the identity header of a request about to be forwarded to the identity the
+/// authenticator found, replacing whatever the client sent in it. Identities that
+/// can't be sent in a header are left out.
+pub(crate) fn set_identity( headers: &mut HeaderMap, name: &HeaderName, identity: Option<&str> ) {
+    headers.remove( name );
+    if let Some( value ) = identity.and_then( |identity| HeaderValue::from_str( identity ).ok() ) {
+        headers.insert( name.clone(), value );
+    }
+}
+
+/// Encodes the credentials the proxy logs in to the proxied server with, keeping them
+/// out of logs.
+pub(crate) fn credentials<C: Credentials>( authorization: Authorization<C> ) -> HeaderValue {
+    let mut value = authorization.0.encode();
+    value.set_sensitive( true );
+    value
+}
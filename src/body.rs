@@ -0,0 +1,260 @@
+//! Reading of request bodies from the client, and limiting the size of response bodies
+//! from the proxied server.
+//!
+//! Bodies are usually read in full before being forwarded, so the proxy enforces a size
+//! limit while reading them: a client that keeps sending is cut off as soon as it goes
+//! over, rather than once the whole body has been buffered.
+//!
+//! Large uploads can instead be streamed to the proxied server as they arrive. Their
+//! bytes are passed on untouched, so multipart bodies keep their boundaries, and the
+//! limit is enforced on the fly by failing the upload once it goes over.
+//!
+//! A client that stops sending part way, such as by resetting its connection, fails the
+//! upload as well, which aborts the request to the proxied server rather than leaving
+//! it waiting on the rest of the body.
+//!
+//! Either way, a body has to be exactly as long as its `Content-Length` says. Forwarding
+//! one that isn't would leave the proxied server to work out where the request ends,
+//! which is how requests get smuggled past a proxy.
+//!
+//! Responses can be limited as well, so a runaway backend can't stream without end
+//! through the proxy. One that is read in full is answered with `502 Bad Gateway` once
+//! it goes over, while one being streamed is cut off, since its status is already sent.
+
+use std::{ io, sync::{ Arc, Mutex, atomic::{ AtomicU64, Ordering } } };
+use bytes::{ Bytes, BytesMut };
+use futures_util::{ StreamExt, TryStreamExt, future, stream::{ self, BoxStream } };
+use poem::{ Body, Error, http::{ HeaderMap, StatusCode, header } };
+
+/// Returns the length of the body according to the `Content-Length` header, if it has
+/// a valid one.
+pub(crate) fn declared_length( headers: &HeaderMap ) -> Option<u64> {
+    headers.get( header::CONTENT_LENGTH )
+        .and_then( |v| v.to_str().ok() )
+        .and_then( |v| v.parse::<u64>().ok() )
+}
+
+/// Whether the body has to be streamed to find out its length, which is the case for
+/// chunked bodies. Without either header, a request has no body at all.
+pub(crate) fn is_unbounded( headers: &HeaderMap ) -> bool {
+    declared_length( headers ).is_none() && headers.contains_key( header::TRANSFER_ENCODING )
+}
+
+/// Whether the request has no body, which is when it neither declares a length above
+/// zero nor is chunked.
+pub(crate) fn is_empty( headers: &HeaderMap ) -> bool {
+    !is_unbounded( headers ) && declared_length( headers ).unwrap_or( 0 ) == 0
+}
+
+/// Reads the whole body. Fails with `413 Payload Too Large` if the body is longer than
+/// `limit`, which is checked against the `Content-Length` header before anything is read.
+pub(crate) async fn read( body: Body, headers: &HeaderMap, limit: Option<usize> ) -> poem::Result<Bytes> {
+    check_declared( headers, limit )?;
+
+    let mut stream = body.into_bytes_stream();
+    let mut buffer = BytesMut::new();
+    while let Some( chunk ) = stream.try_next().await
+        .map_err( |e| Error::from_string( e.to_string(), StatusCode::BAD_REQUEST ) )? {
+
+        // Chunked bodies don't say how long they are up front
+        if let Some( limit ) = limit {
+            if buffer.len() + chunk.len() > limit {
+                return Err( too_large( limit ) );
+            }
+        }
+
+        buffer.extend_from_slice( &chunk );
+    }
+
+    match declared_length( headers ) {
+        Some( declared ) if declared != buffer.len() as u64 => Err( length_mismatch( declared ) ),
+        _ => Ok( buffer.freeze() ),
+    }
+}
+
+/// How many uploads streamed to the proxied server made it through whole, and how many
+/// the client cut off part way, such as by resetting its connection. Uploads the proxy
+/// cut off itself, for going over the size limit or their `Content-Length`, count as
+/// neither.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UploadStats {
+
+    /// The number of uploads sent to the end
+    pub completed: u64,
+
+    /// The number of uploads the client stopped sending part way
+    pub aborted: u64,
+}
+
+/// The counters behind [UploadStats], shared by every clone of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct UploadCounters {
+    completed: AtomicU64,
+    aborted: AtomicU64,
+}
+
+impl UploadCounters {
+    pub(crate) fn stats( &self ) -> UploadStats {
+        UploadStats {
+            completed: self.completed.load( Ordering::Relaxed ),
+            aborted: self.aborted.load( Ordering::Relaxed ),
+        }
+    }
+
+    /// Records an upload the client cut off.
+    fn record_abort( &self, error: &dyn std::fmt::Display ) {
+        tracing::info!( %error, "the client aborted its upload" );
+        self.aborted.fetch_add( 1, Ordering::Relaxed );
+    }
+}
+
+/// Why a streamed upload was cut off, which is only known once the request to the
+/// proxied server has failed because of it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UploadFailure( Arc<Mutex<Option<Error>>> );
+
+impl UploadFailure {
+
+    /// Records why the upload failed, returning the error to end the body with.
+    fn record( &self, error: Error ) -> std::io::Error {
+        let failure = std::io::Error::new( std::io::ErrorKind::InvalidData, error.to_string() );
+        self.0.lock().unwrap_or_else( |e| e.into_inner() ).get_or_insert( error );
+        failure
+    }
+
+    /// Returns the error to answer the client with if the upload failed because of
+    /// something the client did, rather than the proxied server.
+    pub(crate) fn take( &self ) -> Option<Error> {
+        self.0.lock().unwrap_or_else( |e| e.into_inner() ).take()
+    }
+}
+
+/// Turns the body into one the http client sends on as it arrives, without reading it
+/// first. Bodies that say they are longer than `limit` are refused with `413 Payload Too
+/// Large` right away, while the upload of a body that goes over without saying so fails
+/// as soon as it does. So does the upload of a body that turns out longer or shorter
+/// than its `Content-Length`, or that the client stops sending part way. The returned
+/// [UploadFailure] tells which of these happened, while `counters` keeps track of the
+/// uploads that completed and those the client aborted.
+pub(crate) fn stream( body: Body, headers: &HeaderMap, limit: Option<usize>, counters: Arc<UploadCounters> ) -> poem::Result<( reqwest::Body, UploadFailure )> {
+    check_declared( headers, limit )?;
+
+    let failure = UploadFailure::default();
+    let recorder = failure.clone();
+    let declared = declared_length( headers );
+    let mut sent = 0;
+
+    // The end of the body is marked with `None`, to check that nothing is missing
+    let chunks = body.into_bytes_stream()
+        .map( Some )
+        .chain( stream::once( future::ready( None ) ) )
+        .filter_map( move |chunk| future::ready( match chunk {
+            Some( Ok( chunk ) ) => {
+                sent += chunk.len();
+                match ( limit, declared ) {
+                    ( Some( limit ), _ ) if sent > limit => Some( Err( recorder.record( too_large( limit ) ) ) ),
+                    ( _, Some( declared ) ) if sent as u64 > declared => Some( Err( recorder.record( length_mismatch( declared ) ) ) ),
+                    ( _, declared ) => {
+
+                        // The http client stops reading once it has sent as much as was
+                        // declared, so the end of the body may never be reached
+                        if declared == Some( sent as u64 ) {
+                            counters.completed.fetch_add( 1, Ordering::Relaxed );
+                        }
+                        Some( Ok( chunk ) )
+                    },
+                }
+            },
+            Some( Err( e ) ) => {
+                counters.record_abort( &e );
+                Some( Err( recorder.record( match declared {
+                    Some( declared ) => length_mismatch( declared ),
+                    None => Error::from_string( e.to_string(), StatusCode::BAD_REQUEST ),
+                } ) ) )
+            },
+            None => match declared {
+                Some( declared ) if ( sent as u64 ) < declared => {
+                    let error = length_mismatch( declared );
+                    counters.record_abort( &error );
+                    Some( Err( recorder.record( error ) ) )
+                },
+                Some( _ ) => None,
+                None => {
+                    counters.completed.fetch_add( 1, Ordering::Relaxed );
+                    None
+                },
+            },
+        }));
+
+    Ok( ( reqwest::Body::wrap_stream( chunks ), failure ) )
+}
+
+/// Fails with `413 Payload Too Large` if the `Content-Length` header says the body is
+/// longer than `limit`.
+fn check_declared( headers: &HeaderMap, limit: Option<usize> ) -> poem::Result<()> {
+    match ( limit, declared_length( headers ) ) {
+        ( Some( limit ), Some( declared ) ) if declared > limit as u64 => Err( too_large( limit ) ),
+        _ => Ok( () ),
+    }
+}
+
+/// Fails with `502 Bad Gateway` if a response from the proxied server says up front that
+/// its body is longer than `limit`.
+pub(crate) fn check_response_length( length: Option<u64>, limit: Option<usize> ) -> poem::Result<()> {
+    match ( length, limit ) {
+        ( Some( length ), Some( limit ) ) if length > limit as u64 => Err( response_too_large( limit ) ),
+        _ => Ok( () ),
+    }
+}
+
+/// Reads the whole body of a response from the proxied server. Failing to read it is
+/// left to the caller, while a body longer than `limit` fails with `502 Bad Gateway`.
+pub(crate) async fn read_response( response: reqwest::Response, limit: Option<usize> ) -> Result<poem::Result<Bytes>, reqwest::Error> {
+    let Some( limit ) = limit else {
+        return response.bytes().await.map( Ok );
+    };
+
+    let mut stream = response.bytes_stream();
+    let mut buffer = BytesMut::new();
+    while let Some( chunk ) = stream.try_next().await? {
+        if buffer.len() + chunk.len() > limit {
+            return Ok( Err( response_too_large( limit ) ) );
+        }
+        buffer.extend_from_slice( &chunk );
+    }
+    Ok( Ok( buffer.freeze() ) )
+}
+
+/// Ends a streamed response body with an error once it goes over `limit`, which aborts
+/// the response part way.
+pub(crate) fn limit_response( body: BoxStream<'static, io::Result<Bytes>>, limit: usize ) -> BoxStream<'static, io::Result<Bytes>> {
+    body.scan( Some( 0 ), move |sent: &mut Option<usize>, chunk| {
+        let Some( total ) = sent else {
+            return future::ready( None );
+        };
+        let chunk = match chunk {
+            Ok( chunk ) if *total + chunk.len() > limit => {
+                *sent = None;
+                Err( io::Error::new( io::ErrorKind::Other, format!( "the response body is larger than the limit of {} bytes", limit ) ) )
+            },
+            Ok( chunk ) => {
+                *total += chunk.len();
+                Ok( chunk )
+            },
+            Err( error ) => Err( error ),
+        };
+        future::ready( Some( chunk ) )
+    }).boxed()
+}
+
+fn length_mismatch( declared: u64 ) -> Error {
+    Error::from_string( format!( "The request body does not match its Content-Length of {} bytes!", declared ), StatusCode::BAD_REQUEST )
+}
+
+fn too_large( limit: usize ) -> Error {
+    Error::from_string( format!( "The request body is larger than the limit of {} bytes!", limit ), StatusCode::PAYLOAD_TOO_LARGE )
+}
+
+pub(crate) fn response_too_large( limit: usize ) -> Error {
+    Error::from_string( format!( "The response body is larger than the limit of {} bytes!", limit ), StatusCode::BAD_GATEWAY )
+}
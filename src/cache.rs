@@ -0,0 +1,595 @@
+//! Caching of the responses sent back by the proxied server.
+//!
+//! Only successful responses to `GET` requests are cached (along with `404` and `410`
+//! responses, if negative caching is enabled), and only when the proxied server says
+//! they may be stored by a shared cache. Where the responses are kept is up
+//! to the [CacheStore] the proxy is configured with, which is an in-memory
+//! [MemoryCache] by default.
+//!
+//! Responses that vary by request headers (through the `Vary` response header) are stored
+//! once per variant, under a key that includes the values of those headers. The url
+//! itself then holds a bodiless entry naming the headers, so the right variant can be
+//! found for the next request.
+//!
+//! Stale responses are kept around to be revalidated, which also lets them stand in for
+//! the proxied server when it fails, as far as their `stale-if-error` directive allows.
+//!
+//! Each of these defaults can be changed for the requests under a path with a
+//! [CachePolicy].
+
+use std::{
+    collections::{ HashMap, VecDeque },
+    sync::{ Mutex, atomic::{ AtomicU64, Ordering } },
+    time::{ Duration, SystemTime },
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use poem::http::{ HeaderMap, HeaderName, Method, StatusCode, header };
+use crate::rewrite;
+
+/// A response from the proxied server, as kept in a [CacheStore].
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+
+    /// The status of the response
+    pub status: StatusCode,
+
+    /// The headers of the response
+    pub headers: HeaderMap,
+
+    /// The body of the response
+    pub body: Bytes,
+
+    /// The point in time after which the response should no longer be served
+    /// without checking back with the proxied server
+    pub expires_at: SystemTime,
+}
+
+impl CachedResponse {
+
+    /// Whether the response can still be served without checking back with the
+    /// proxied server.
+    pub fn is_fresh( &self ) -> bool {
+        SystemTime::now() < self.expires_at
+    }
+}
+
+/// Storage for cached responses. Implement this to keep responses somewhere other than
+/// in memory, such as on disk or in Redis.
+///
+/// ```
+/// use std::{ collections::HashMap, sync::{ Arc, Mutex } };
+/// use poem_proxy::{ CachedResponse, CacheStore, ProxyConfig };
+///
+/// #[derive(Default)]
+/// struct MapStore( Mutex<HashMap<String, CachedResponse>> );
+///
+/// #[poem_proxy::async_trait]
+/// impl CacheStore for MapStore {
+///     async fn get( &self, key: &str ) -> Option<CachedResponse> {
+///         self.0.lock().unwrap().get( key ).cloned()
+///     }
+///
+///     async fn put( &self, key: &str, response: CachedResponse ) {
+///         self.0.lock().unwrap().insert( key.into(), response );
+///     }
+///
+///     async fn invalidate( &self, key: &str ) {
+///         self.0.lock().unwrap().remove( key );
+///     }
+///
+///     async fn invalidate_prefix( &self, prefix: &str ) -> usize {
+///         let mut responses = self.0.lock().unwrap();
+///         let before = responses.len();
+///         responses.retain( |key, _| !key.starts_with( prefix ) );
+///         before - responses.len()
+///     }
+/// }
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .cache_store( Arc::new( MapStore::default() ) )
+///     .finish();
+/// ```
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+
+    /// Returns the response stored under `key`, if there is one. Stale responses may
+    /// be returned, the proxy checks their freshness itself.
+    async fn get( &self, key: &str ) -> Option<CachedResponse>;
+
+    /// Stores a response under `key`, replacing any response already there.
+    async fn put( &self, key: &str, response: CachedResponse );
+
+    /// Removes the response stored under `key`, if there is one.
+    async fn invalidate( &self, key: &str );
+
+    /// Removes every response stored under a key that starts with `prefix`, returning
+    /// how many there were. Stores that can't find their keys by prefix may leave this
+    /// out, in which case nothing is removed.
+    async fn invalidate_prefix( &self, _prefix: &str ) -> usize {
+        0
+    }
+}
+
+/// A [CacheStore] that keeps responses in memory, evicting the least recently used
+/// response once it is full.
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    entries: Mutex<LruEntries>,
+}
+
+/// The responses held by a [MemoryCache], along with the order they were used in.
+#[derive(Debug, Default)]
+struct LruEntries {
+    responses: HashMap<String, CachedResponse>,
+
+    /// Keys from least to most recently used
+    order: VecDeque<String>,
+}
+
+impl LruEntries {
+
+    /// Marks `key` as the most recently used.
+    fn touch( &mut self, key: &str ) {
+        if let Some( index ) = self.order.iter().position( |k| k == key ) {
+            self.order.remove( index );
+        }
+        self.order.push_back( key.to_owned() );
+    }
+}
+
+impl MemoryCache {
+
+    /// Creates an empty cache that holds up to `capacity` responses.
+    pub fn new( capacity: usize ) -> MemoryCache {
+        MemoryCache { capacity, entries: Mutex::default() }
+    }
+}
+
+impl Default for MemoryCache {
+
+    /// Returns an empty cache that holds up to 1024 responses.
+    fn default() -> Self {
+        MemoryCache::new( 1024 )
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCache {
+    async fn get( &self, key: &str ) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap_or_else( |e| e.into_inner() );
+        let response = entries.responses.get( key ).cloned()?;
+        entries.touch( key );
+        Some( response )
+    }
+
+    async fn put( &self, key: &str, response: CachedResponse ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else( |e| e.into_inner() );
+        entries.responses.insert( key.to_owned(), response );
+        entries.touch( key );
+
+        while entries.responses.len() > self.capacity {
+            let Some( oldest ) = entries.order.pop_front() else {
+                break;
+            };
+            entries.responses.remove( &oldest );
+        }
+    }
+
+    async fn invalidate( &self, key: &str ) {
+        let mut entries = self.entries.lock().unwrap_or_else( |e| e.into_inner() );
+        entries.responses.remove( key );
+        if let Some( index ) = entries.order.iter().position( |k| k == key ) {
+            entries.order.remove( index );
+        }
+    }
+
+    async fn invalidate_prefix( &self, prefix: &str ) -> usize {
+        let mut entries = self.entries.lock().unwrap_or_else( |e| e.into_inner() );
+        let before = entries.responses.len();
+        entries.responses.retain( |key, _| !key.starts_with( prefix ) );
+        entries.order.retain( |key| !key.starts_with( prefix ) );
+        before - entries.responses.len()
+    }
+}
+
+/// How the responses to requests under a path are cached, in place of the defaults. Set
+/// through [ProxyConfig::cache_policy](crate::ProxyConfig::cache_policy).
+///
+/// ```
+/// use std::time::Duration;
+/// use poem::http::header;
+/// use poem_proxy::{ CachePolicy, ProxyConfig };
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .enable_nesting()
+///     .enable_cache()
+///     .cache_policy( "/static", CachePolicy::new()
+///         .ttl( Duration::from_secs( 24 * 60 * 60 ) ) ) // Assets are fingerprinted
+///     .cache_policy( "/images", CachePolicy::new()
+///         .ignore_query() // Tracking parameters don't change the image
+///         .key_header( header::ACCEPT ) ) // Nor does anything but the formats the client takes
+///     .cache_policy( "/api", &CachePolicy::disabled() )
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct CachePolicy {
+    enabled: bool,
+
+    /// How long responses are fresh for, whatever the proxied server says
+    ttl: Option<Duration>,
+
+    /// Whether responses are stored regardless of the query string
+    ignore_query: bool,
+
+    /// The request headers whose values are part of the key responses are stored under
+    key_headers: Vec<HeaderName>,
+}
+
+impl CachePolicy {
+
+    /// Creates a policy that caches responses as usual.
+    pub fn new() -> CachePolicy {
+        CachePolicy { enabled: true, ttl: None, ignore_query: false, key_headers: Vec::new() }
+    }
+
+    /// Creates a policy that never caches responses, nor serves them from the cache.
+    pub fn disabled() -> CachePolicy {
+        CachePolicy { enabled: false, ..CachePolicy::new() }
+    }
+
+    /// Keeps responses fresh for `ttl`, in place of the lifetime the proxied server
+    /// gives them. Responses it gives no lifetime are cached as well, though those it
+    /// forbids shared caches to store still aren't.
+    pub fn ttl( &mut self, ttl: Duration ) -> &mut CachePolicy {
+        self.ttl = Some( ttl );
+        self
+    }
+
+    /// Stores responses under their path alone, so requests that differ only by their
+    /// query string share them.
+    pub fn ignore_query( &mut self ) -> &mut CachePolicy {
+        self.ignore_query = true;
+        self
+    }
+
+    /// Stores a response separately for each value of the given request header, as
+    /// though the proxied server had named it in the `Vary` header.
+    pub fn key_header( &mut self, name: HeaderName ) -> &mut CachePolicy {
+        self.key_headers.push( name );
+        self
+    }
+
+    /// Whether responses are cached under this policy.
+    pub(crate) fn is_enabled( &self ) -> bool {
+        self.enabled
+    }
+
+    /// Returns the lifetime that replaces the one the proxied server gives, if any.
+    pub(crate) fn ttl_override( &self ) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Returns the key responses to a request for `url` are stored under.
+    pub(crate) fn key( &self, url: &str, request: &HeaderMap ) -> String {
+        let mut key = match self.ignore_query {
+            true => url.split( '?' ).next().unwrap_or( url ).to_owned(),
+            false => url.to_owned(),
+        };
+        for name in &self.key_headers {
+            let values = request.get_all( name )
+                .iter()
+                .filter_map( |value| value.to_str().ok() )
+                .collect::<Vec<_>>();
+            key.push_str( &format!( "\n{}: {}", name, values.join( ", " ) ) );
+        }
+        key
+    }
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy::new()
+    }
+}
+
+/// Returns the policy for requests to `path`, which is the one set for the longest path
+/// that covers it. Paths are matched by whole segments, so a policy for `/api` covers
+/// `/api/users` but not `/apis`.
+pub(crate) fn policy_for<'a>( policies: &'a [( String, CachePolicy )], path: &str ) -> Option<&'a CachePolicy> {
+    policies.iter()
+        .filter( |( prefix, _ )| rewrite::covers( path, prefix ) )
+        .max_by_key( |( prefix, _ )| prefix.len() )
+        .map( |( _, policy )| policy )
+}
+
+/// How often requests were answered without the proxied server having to send a response
+/// body. Obtained through [ProxyHandle::cache_stats](crate::ProxyHandle::cache_stats).
+///
+/// Only requests that could be answered from the cache count as hits or misses. A stale
+/// response the proxied server confirms is still current counts as a hit.
+///
+/// ```
+/// use poem_proxy::CacheStats;
+///
+/// let stats = CacheStats { hits: 3, misses: 1, ..CacheStats::default() };
+/// assert_eq!( stats.hit_ratio(), Some( 0.75 ) );
+/// assert_eq!( CacheStats::default().hit_ratio(), None );
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+
+    /// The number of requests answered from the cache
+    pub hits: u64,
+
+    /// The number of requests that could have been answered from the cache, but had to
+    /// be forwarded to the proxied server
+    pub misses: u64,
+
+    /// The number of hits that served a cached `404 Not Found` or `410 Gone` response
+    pub negative_hits: u64,
+
+    /// The number of retried requests answered with the response to the original
+    /// request, instead of being forwarded again
+    pub collapsed: u64,
+}
+
+impl CacheStats {
+
+    /// Returns the fraction of requests answered from the cache, out of those that could
+    /// have been, or `None` if there weren't any yet.
+    pub fn hit_ratio( &self ) -> Option<f64> {
+        let total = self.hits + self.misses;
+        ( total > 0 ).then( || self.hits as f64 / total as f64 )
+    }
+}
+
+/// The counters behind [CacheStats], shared by every clone of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    negative_hits: AtomicU64,
+    collapsed: AtomicU64,
+}
+
+impl CacheCounters {
+
+    /// Records a request answered with a cached response with the given status.
+    pub(crate) fn record_hit( &self, status: StatusCode ) {
+        self.hits.fetch_add( 1, Ordering::Relaxed );
+        if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+            self.negative_hits.fetch_add( 1, Ordering::Relaxed );
+        }
+    }
+
+    /// Records a request that could have been answered from the cache, but wasn't.
+    pub(crate) fn record_miss( &self ) {
+        self.misses.fetch_add( 1, Ordering::Relaxed );
+    }
+
+    /// Records a retried request answered with the response to the original one.
+    pub(crate) fn record_collapsed( &self ) {
+        self.collapsed.fetch_add( 1, Ordering::Relaxed );
+    }
+
+    pub(crate) fn stats( &self ) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load( Ordering::Relaxed ),
+            misses: self.misses.load( Ordering::Relaxed ),
+            negative_hits: self.negative_hits.load( Ordering::Relaxed ),
+            collapsed: self.collapsed.load( Ordering::Relaxed ),
+        }
+    }
+}
+
+/// Returns the directives of the `Cache-Control` headers in `headers`, lowercased.
+fn cache_control( headers: &HeaderMap ) -> Vec<String> {
+    headers.get_all( header::CACHE_CONTROL )
+        .iter()
+        .filter_map( |value| value.to_str().ok() )
+        .flat_map( |value| value.split( ',' ) )
+        .map( |directive| directive.trim().to_ascii_lowercase() )
+        .collect()
+}
+
+/// Returns the number of seconds given by the first directive called `name`, if any.
+fn seconds( directives: &[String], name: &str ) -> Option<u64> {
+    directives.iter()
+        .filter_map( |d| d.strip_prefix( name )?.strip_prefix( '=' )?.trim_matches( '"' ).parse().ok() )
+        .next()
+}
+
+/// Whether a response to this request may be looked up in or stored into the cache.
+pub(crate) fn is_cacheable_request( method: &Method, headers: &HeaderMap ) -> bool {
+    *method == Method::GET
+        && !headers.contains_key( header::AUTHORIZATION )
+        && !cache_control( headers ).iter().any( |d| d == "no-store" || d == "no-cache" )
+}
+
+/// Returns how long a response may be served from the cache, or `None` if it must not
+/// be stored at all. Responses without an explicit lifetime are still stored, already
+/// stale, if they can be revalidated with the proxied server later on.
+///
+/// `404 Not Found` and `410 Gone` responses are only stored when `negative_ttl` is
+/// given, which is their lifetime unless the server sets one. A `ttl` from a
+/// [CachePolicy] replaces any lifetime the server sets.
+pub(crate) fn freshness_lifetime( status: StatusCode, headers: &HeaderMap, negative_ttl: Option<Duration>, ttl: Option<Duration> ) -> Option<Duration> {
+    let default_lifetime = match status {
+        StatusCode::OK => None,
+        StatusCode::NOT_FOUND | StatusCode::GONE => Some( negative_ttl? ),
+        _ => return None,
+    };
+
+    if headers.contains_key( header::SET_COOKIE ) {
+        return None;
+    }
+
+    // A response that varies by something other than headers can never be reused
+    if vary( headers ).iter().any( |name| name == "*" ) {
+        return None;
+    }
+
+    let directives = cache_control( headers );
+    if directives.iter().any( |d| d == "no-store" || d == "private" ) {
+        return None;
+    }
+
+    // Responses marked `no-cache` may be stored, but must be revalidated every time
+    if directives.iter().any( |d| d == "no-cache" ) {
+        return Some( Duration::ZERO );
+    }
+    if ttl.is_some() {
+        return ttl;
+    }
+
+    // The shared cache lifetime takes precedence over the general one
+    let max_age = |name: &str| seconds( &directives, name ).map( Duration::from_secs );
+
+    let has_validators = headers.contains_key( header::ETAG ) || headers.contains_key( header::LAST_MODIFIED );
+    max_age( "s-maxage" )
+        .or_else( || max_age( "max-age" ) )
+        .or( default_lifetime )
+        .or_else( || has_validators.then_some( Duration::ZERO ) )
+}
+
+/// Returns the names listed by the `Vary` headers in `headers`, lowercased and sorted.
+fn vary( headers: &HeaderMap ) -> Vec<String> {
+    let mut names = headers.get_all( header::VARY )
+        .iter()
+        .filter_map( |value| value.to_str().ok() )
+        .flat_map( |value| value.split( ',' ) )
+        .map( |name| name.trim().to_ascii_lowercase() )
+        .filter( |name| !name.is_empty() )
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Returns the key under which the variant of a response for `request` is stored, given
+/// the headers of a response stored for `url`. Responses that don't vary are stored
+/// under the url itself.
+pub(crate) fn variant_key( url: &str, response: &HeaderMap, request: &HeaderMap ) -> String {
+    let mut key = url.to_owned();
+    for name in vary( response ) {
+        let values = request.get_all( name.as_str() )
+            .iter()
+            .filter_map( |value| value.to_str().ok() )
+            .collect::<Vec<_>>();
+        key.push_str( &format!( "\n{}: {}", name, values.join( ", " ) ) );
+    }
+    key
+}
+
+/// Returns the entry stored under the url of a response that varies, which only names
+/// the headers it varies by, or `None` if the response doesn't vary.
+pub(crate) fn variants_entry( response: &CachedResponse ) -> Option<CachedResponse> {
+    let mut headers = HeaderMap::new();
+    for value in response.headers.get_all( header::VARY ) {
+        headers.append( header::VARY, value.clone() );
+    }
+
+    ( !vary( &headers ).is_empty() ).then( || CachedResponse {
+        status: response.status,
+        headers,
+        body: Bytes::new(),
+        expires_at: response.expires_at,
+    })
+}
+
+/// Whether the client already has the cached response, according to the conditional
+/// headers of its request. `If-None-Match` takes precedence over `If-Modified-Since`.
+pub(crate) fn is_not_modified( request: &HeaderMap, cached: &CachedResponse ) -> bool {
+    if let Some( if_none_match ) = request.get( header::IF_NONE_MATCH ).and_then( |v| v.to_str().ok() ) {
+        let Some( etag ) = cached.headers.get( header::ETAG ).and_then( |v| v.to_str().ok() ) else {
+            return false;
+        };
+
+        // If-None-Match uses the weak comparison, so `W/` prefixes are ignored
+        let opaque = |tag: &str| tag.trim().trim_start_matches( "W/" ).to_owned();
+        return if_none_match.trim() == "*"
+            || if_none_match.split( ',' ).any( |tag| opaque( tag ) == opaque( etag ) );
+    }
+
+    let date = |headers: &HeaderMap, name| headers.get( name )
+        .and_then( |v| v.to_str().ok() )
+        .and_then( |v| httpdate::parse_http_date( v ).ok() );
+
+    match ( date( request, header::IF_MODIFIED_SINCE ), date( &cached.headers, header::LAST_MODIFIED ) ) {
+        ( Some( since ), Some( modified ) ) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Replaces any conditional headers of a request with the validators of a stale cached
+/// response, so the proxied server can confirm that the response is still current.
+pub(crate) fn add_validators( request: &mut HeaderMap, cached: &CachedResponse ) {
+    crate::headers::remove_in_order( request, header::IF_NONE_MATCH.as_str() );
+    crate::headers::remove_in_order( request, header::IF_MODIFIED_SINCE.as_str() );
+
+    if let Some( etag ) = cached.headers.get( header::ETAG ) {
+        request.insert( header::IF_NONE_MATCH, etag.clone() );
+    }
+    if let Some( modified ) = cached.headers.get( header::LAST_MODIFIED ) {
+        request.insert( header::IF_MODIFIED_SINCE, modified.clone() );
+    }
+}
+
+/// Whether a stale cached response may be served in place of an error from the proxied
+/// server. The `stale-if-error` directive of either the response or the request allows
+/// this for the given number of seconds after the response went stale, unless the
+/// response must always be revalidated.
+pub(crate) fn is_usable_on_error( cached: &CachedResponse, request: &HeaderMap ) -> bool {
+    let directives = cache_control( &cached.headers );
+    if directives.iter().any( |d| d == "must-revalidate" || d == "proxy-revalidate" ) {
+        return false;
+    }
+
+    let window = seconds( &directives, "stale-if-error" ).into_iter()
+        .chain( seconds( &cache_control( request ), "stale-if-error" ) )
+        .max();
+    match window {
+        Some( window ) => SystemTime::now() < cached.expires_at + Duration::from_secs( window ),
+        None => false,
+    }
+}
+
+/// Updates a stale cached response with the headers of a `304 Not Modified` response
+/// from the proxied server, making it fresh again. See [freshness_lifetime] for
+/// `negative_ttl` and `ttl`.
+pub(crate) fn refresh( cached: &mut CachedResponse, not_modified: &HeaderMap, negative_ttl: Option<Duration>, ttl: Option<Duration> ) {
+    for name in not_modified.keys() {
+        cached.headers.remove( name );
+        for value in not_modified.get_all( name ) {
+            cached.headers.append( name, value.clone() );
+        }
+    }
+
+    let lifetime = freshness_lifetime( cached.status, &cached.headers, negative_ttl, ttl ).unwrap_or_default();
+    cached.expires_at = SystemTime::now() + lifetime;
+}
+
+/// The headers of a cached response that are repeated in a `304 Not Modified` response
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 6] = [
+    header::CACHE_CONTROL, header::CONTENT_LOCATION, header::DATE,
+    header::ETAG, header::EXPIRES, header::VARY,
+];
+
+/// Returns the headers to send with a `304 Not Modified` response for a cached response.
+pub(crate) fn not_modified_headers( cached: &CachedResponse ) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for name in NOT_MODIFIED_HEADERS {
+        for value in cached.headers.get_all( &name ) {
+            headers.append( name.clone(), value.clone() );
+        }
+    }
+    headers
+}
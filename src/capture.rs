@@ -0,0 +1,184 @@
+//! Capturing of complete request/response pairs for a sample of the traffic.
+//!
+//! Captures are handed to a user-provided [CaptureSink] on a separate task, so a slow
+//! sink never holds up the response. Bodies are cut off at a configurable size, and
+//! sensitive headers are masked before they leave the proxy.
+
+use std::{ net::SocketAddr, sync::Arc };
+use async_trait::async_trait;
+use bytes::Bytes;
+use poem::http::{ HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header };
+use crate::Opaque;
+
+/// A request that was forwarded to the proxied server, along with its response.
+#[derive(Clone, Debug)]
+pub struct CapturedExchange {
+
+    /// The method of the request
+    pub method: Method,
+
+    /// The url the request was forwarded to
+    pub uri: String,
+
+    /// The headers of the request, as forwarded, with sensitive values masked
+    pub request_headers: HeaderMap,
+
+    /// The body of the request, cut off at the size limit
+    pub request_body: Bytes,
+
+    /// The status of the response
+    pub status: StatusCode,
+
+    /// The headers of the response, with sensitive values masked
+    pub response_headers: HeaderMap,
+
+    /// The body of the response, cut off at the size limit
+    pub response_body: Bytes,
+
+    /// The address of the proxied server the response came from, if known
+    pub upstream_addr: Option<SocketAddr>,
+}
+
+/// Somewhere to send captured traffic, such as a log file or a debugging service.
+#[async_trait]
+pub trait CaptureSink: Send + Sync {
+
+    /// Records a captured request/response pair.
+    async fn capture( &self, exchange: CapturedExchange );
+}
+
+/// Settings for capturing a sample of the traffic going through the proxy.
+///
+/// ```
+/// use std::sync::Arc;
+/// use poem::http::HeaderName;
+/// use poem_proxy::{ CapturedExchange, CaptureSink, ProxyConfig, TrafficCapture };
+///
+/// struct PrintSink;
+///
+/// #[poem_proxy::async_trait]
+/// impl CaptureSink for PrintSink {
+///     async fn capture( &self, exchange: CapturedExchange ) {
+///         println!( "{} {} -> {}", exchange.method, exchange.uri, exchange.status );
+///     }
+/// }
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .capture_traffic( TrafficCapture::new( Arc::new( PrintSink ) )
+///         .sample_rate( 0.01 )                           // 1% of requests
+///         .max_body_size( 16 * 1024 )                    // Keep at most 16KiB of each body
+///         .redact( HeaderName::from_static( "x-api-key" ) ) )
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct TrafficCapture {
+    sink: Opaque<dyn CaptureSink>,
+
+    /// The fraction of requests to capture, between 0 and 1
+    sample_rate: f64,
+
+    /// The most bytes of each body to keep
+    max_body_size: usize,
+
+    /// The headers whose values are masked
+    redacted: Vec<HeaderName>,
+}
+
+/// What is used in place of the value of a redacted header
+const REDACTED: HeaderValue = HeaderValue::from_static( "[REDACTED]" );
+
+impl TrafficCapture {
+
+    /// Creates new capture settings sending captures to `sink`. By default, every
+    /// request is captured, bodies are cut off after 64KiB, and the `Authorization`,
+    /// `Proxy-Authorization`, `Cookie` and `Set-Cookie` headers are redacted.
+    pub fn new( sink: Arc<dyn CaptureSink> ) -> TrafficCapture {
+        TrafficCapture {
+            sink: Opaque( sink ),
+            sample_rate: 1.0,
+            max_body_size: 64 * 1024,
+            redacted: vec![ header::AUTHORIZATION, header::PROXY_AUTHORIZATION, header::COOKIE, header::SET_COOKIE ],
+        }
+    }
+
+    /// Sets the fraction of requests to capture, between `0.0` (none) and `1.0` (all).
+    pub fn sample_rate( &mut self, rate: f64 ) -> &mut TrafficCapture {
+        self.sample_rate = rate;
+        self
+    }
+
+    /// Sets the most bytes of each request and response body to keep.
+    pub fn max_body_size( &mut self, size: usize ) -> &mut TrafficCapture {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Masks the value of another header in captures.
+    pub fn redact( &mut self, name: HeaderName ) -> &mut TrafficCapture {
+        self.redacted.push( name );
+        self
+    }
+
+    /// Decides whether to capture a request, and if so, keeps hold of it until its
+    /// response arrives.
+    pub(crate) fn begin( &self, method: &Method, uri: &str, headers: &HeaderMap, body: &Bytes ) -> Option<PendingCapture> {
+        if rand::random::<f64>() >= self.sample_rate {
+            return None;
+        }
+
+        Some( PendingCapture {
+            settings: self.clone(),
+            method: method.clone(),
+            uri: uri.to_owned(),
+            request_headers: self.redact_headers( headers ),
+            request_body: self.truncate( body ),
+        })
+    }
+
+    fn redact_headers( &self, headers: &HeaderMap ) -> HeaderMap {
+        let mut headers = headers.clone();
+        for name in &self.redacted {
+            if headers.contains_key( name ) {
+                headers.insert( name.clone(), REDACTED );
+            }
+        }
+        headers
+    }
+
+    fn truncate( &self, body: &Bytes ) -> Bytes {
+        body.slice( ..body.len().min( self.max_body_size ) )
+    }
+}
+
+/// A captured request that is waiting for its response.
+pub(crate) struct PendingCapture {
+    settings: TrafficCapture,
+    method: Method,
+    uri: String,
+    request_headers: HeaderMap,
+    request_body: Bytes,
+}
+
+impl PendingCapture {
+
+    /// Completes the capture with the response, and sends it to the sink in the
+    /// background.
+    pub(crate) fn finish( self, status: StatusCode, headers: &HeaderMap, body: &Bytes, upstream_addr: Option<SocketAddr> ) {
+        let exchange = CapturedExchange {
+            method: self.method,
+            uri: self.uri,
+            request_headers: self.request_headers,
+            request_body: self.request_body,
+            status,
+            response_headers: self.settings.redact_headers( headers ),
+            response_body: self.settings.truncate( body ),
+            upstream_addr,
+        };
+
+        let sink = self.settings.sink;
+        tokio::spawn( async move {
+            sink.capture( exchange ).await;
+        });
+    }
+}
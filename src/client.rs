@@ -0,0 +1,82 @@
+//! Limits on how many requests a single client can have in flight at once.
+//!
+//! Clients are told apart by their IP address. When the proxy sits behind other proxies
+//! (such as a load balancer), every request appears to come from one of them, so the
+//! addresses of those proxies can be marked as trusted. For requests from a trusted
+//! proxy, the client is instead the last address in `X-Forwarded-For` that isn't one of
+//! the trusted proxies itself.
+
+use std::{ collections::HashMap, net::IpAddr, sync::{ Arc, Mutex } };
+use poem::Request;
+
+/// The number of requests each client currently has in flight, shared by every clone
+/// of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct ClientLimiter {
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Counts a request as in flight for its client for as long as it is alive.
+#[derive(Debug)]
+pub(crate) struct ClientGuard {
+    limiter: Arc<ClientLimiter>,
+    client: IpAddr,
+}
+
+impl ClientLimiter {
+
+    /// Counts a request from `client` as in flight until the returned guard is dropped,
+    /// or returns `None` if the client already has `max` requests in flight.
+    pub(crate) fn acquire( self: &Arc<Self>, client: IpAddr, max: usize ) -> Option<ClientGuard> {
+        let mut active = self.active.lock().unwrap_or_else( |e| e.into_inner() );
+        let count = active.entry( client ).or_default();
+        if *count >= max {
+            return None;
+        }
+
+        *count += 1;
+        Some( ClientGuard { limiter: self.clone(), client } )
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop( &mut self ) {
+        let mut active = self.limiter.active.lock().unwrap_or_else( |e| e.into_inner() );
+        if let Some( count ) = active.get_mut( &self.client ) {
+            *count -= 1;
+
+            // Forget clients once they are done, so the map doesn't grow forever
+            if *count == 0 {
+                active.remove( &self.client );
+            }
+        }
+    }
+}
+
+/// Returns the address of the client that sent a request, looking through
+/// `X-Forwarded-For` for requests that came through one of the `trusted` proxies.
+/// Returns `None` if the request didn't come over IP.
+pub(crate) fn client_ip( req: &Request, trusted: &[IpAddr] ) -> Option<IpAddr> {
+    let peer = req.remote_addr().as_socket_addr()?.ip();
+    if !trusted.contains( &peer ) {
+        return Some( peer );
+    }
+
+    // Each proxy appends the address it received the request from, so the client is
+    // the last one added by a proxy we trust. Anything we can't read can't be trusted
+    let forwarded = req.headers().get_all( "x-forwarded-for" )
+        .iter()
+        .map( |value| value.to_str().unwrap_or_default() )
+        .flat_map( |value| value.split( ',' ) )
+        .map( |address| address.trim().parse::<IpAddr>().ok() )
+        .collect::<Vec<_>>();
+
+    for address in forwarded.into_iter().rev() {
+        match address {
+            Some( address ) if trusted.contains( &address ) => continue,
+            Some( address ) => return Some( address ),
+            None => break,
+        }
+    }
+    Some( peer )
+}
@@ -0,0 +1,107 @@
+//! Validation of the content types of request bodies, and defaults for those of
+//! response bodies.
+//!
+//! Some servers fail in unhelpful ways when sent a body they don't expect. The proxy can
+//! check the `Content-Type` of each request against the types allowed for its path, and
+//! answer with `415 Unsupported Media Type` instead of forwarding anything else.
+//!
+//! Others leave the `Content-Type` out of their responses, which leaves browsers to guess
+//! it from the body. The proxy can fill in a type for the responses under a path, and
+//! tell browsers not to second-guess it.
+
+use poem::{ Error, http::{ HeaderMap, HeaderName, HeaderValue, StatusCode, header } };
+use crate::{ body, rewrite };
+
+const X_CONTENT_TYPE_OPTIONS: HeaderName = HeaderName::from_static( "x-content-type-options" );
+
+/// The content types allowed for the requests under a path.
+#[derive(Clone, Debug)]
+pub(crate) struct ContentTypeRule {
+
+    /// The path the rule applies to, along with everything below it
+    path: String,
+
+    /// The allowed media types, lowercase and without parameters. A type may end in
+    /// `/*` to allow every subtype.
+    types: Vec<String>,
+}
+
+impl ContentTypeRule {
+
+    pub(crate) fn new( path: &str, types: impl IntoIterator<Item = impl Into<String>> ) -> ContentTypeRule {
+        ContentTypeRule {
+            path: path.trim_end_matches( '/' ).to_owned(),
+            types: types.into_iter().map( |t| media_type( &t.into() ) ).collect(),
+        }
+    }
+
+    /// Whether both rules are for the same path, so that one replaces the other.
+    pub(crate) fn same_path( &self, other: &ContentTypeRule ) -> bool {
+        self.path == other.path
+    }
+
+    /// Whether the rule applies to a request for `path`.
+    fn covers( &self, path: &str ) -> bool {
+        rewrite::covers( path, &self.path )
+    }
+
+    fn allows( &self, media_type: &str ) -> bool {
+        self.types.iter().any( |allowed| matches( allowed, media_type ) )
+    }
+}
+
+/// Returns the media type of a `Content-Type` value, lowercase and without parameters.
+pub(crate) fn media_type( content_type: &str ) -> String {
+    content_type.split( ';' ).next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// Whether `media_type` is the `allowed` type, or one of its subtypes if it ends in `/*`.
+pub(crate) fn matches( allowed: &str, media_type: &str ) -> bool {
+    match allowed.strip_suffix( "/*" ) {
+        Some( main ) => media_type.split_once( '/' ).map_or( false, |( m, _ )| m == main ),
+        None => allowed == media_type,
+    }
+}
+
+/// Checks the `Content-Type` of a request for `path` against the most specific rule
+/// that covers it. Requests without a body don't need a content type, but any that
+/// have one must declare an allowed type, or are answered with
+/// `415 Unsupported Media Type`.
+pub(crate) fn check( rules: &[ContentTypeRule], path: &str, headers: &HeaderMap ) -> Result<(), Error> {
+    let Some( rule ) = rules.iter()
+        .filter( |rule| rule.covers( path ) )
+        .max_by_key( |rule| rule.path.len() ) else {
+        return Ok( () );
+    };
+
+    let allowed = match headers.get( header::CONTENT_TYPE ) {
+        Some( value ) => value.to_str().map_or( false, |v| rule.allows( &media_type( v ) ) ),
+        None => body::is_empty( headers ),
+    };
+
+    match allowed {
+        true => Ok( () ),
+        false => Err( Error::from_string( "The request body has an unsupported content type!", StatusCode::UNSUPPORTED_MEDIA_TYPE ) ),
+    }
+}
+
+/// Gives a response to a request for `path` the default content type for the most
+/// specific path in `defaults` that covers it, if the proxied server sent none. Browsers
+/// are then told not to sniff the type of the body. Responses without a body are left
+/// as they are.
+pub(crate) fn apply_default( defaults: &[( String, HeaderValue )], path: &str, status: StatusCode, headers: &mut HeaderMap ) {
+    if headers.contains_key( header::CONTENT_TYPE )
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || body::declared_length( headers ) == Some( 0 ) {
+        return;
+    }
+
+    let default = defaults.iter()
+        .filter( |( prefix, _ )| rewrite::covers( path, prefix ) )
+        .max_by_key( |( prefix, _ )| prefix.len() );
+    if let Some( ( _, content_type ) ) = default {
+        headers.insert( header::CONTENT_TYPE, content_type.clone() );
+        headers.insert( X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static( "nosniff" ) );
+    }
+}
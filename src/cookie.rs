@@ -0,0 +1,242 @@
+//! Normalization of the attributes of the cookies set by the proxied server.
+//!
+//! When the proxy terminates TLS, the proxied server often has no idea that its cookies
+//! are being delivered over https, and so sets them without the attributes they should
+//! have. A [CookiePolicy] fills those attributes in on every forwarded `Set-Cookie` header,
+//! leaving the name and value of each cookie untouched.
+//!
+//! The proxied server also scopes its cookies to its own domain and paths, which the
+//! browser never sees. A [CookieRewrite] moves them over to the proxy's.
+
+use poem::http::HeaderValue;
+use crate::rewrite;
+
+/// The possible values of a cookie's `SameSite` attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str( &self ) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// A policy describing which attributes every cookie forwarded to the client must have.
+///
+/// ```
+/// use poem_proxy::{ CookiePolicy, ProxyConfig, SameSite };
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .cookie_policy( CookiePolicy::new()
+///         .secure()                     // Adds `Secure` to every cookie
+///         .http_only()                  // Adds `HttpOnly` to every cookie
+///         .same_site( SameSite::Lax ) ) // Sets `SameSite=Lax`, replacing any other value
+///     .finish();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CookiePolicy {
+
+    /// Whether the `Secure` attribute must be present
+    secure: bool,
+
+    /// Whether the `HttpOnly` attribute must be present
+    http_only: bool,
+
+    /// The value the `SameSite` attribute must have, if any
+    same_site: Option<SameSite>,
+}
+
+impl CookiePolicy {
+
+    /// Creates a new policy that leaves cookies as they are.
+    pub fn new() -> CookiePolicy {
+        CookiePolicy::default()
+    }
+
+    /// Requires every cookie to have the `Secure` attribute.
+    pub fn secure( &mut self ) -> &mut CookiePolicy {
+        self.secure = true;
+        self
+    }
+
+    /// Requires every cookie to have the `HttpOnly` attribute.
+    pub fn http_only( &mut self ) -> &mut CookiePolicy {
+        self.http_only = true;
+        self
+    }
+
+    /// Requires every cookie to have the given `SameSite` attribute. Since browsers
+    /// reject `SameSite=None` cookies that are not also `Secure`, choosing
+    /// [SameSite::None] adds the `Secure` attribute as well.
+    pub fn same_site( &mut self, same_site: SameSite ) -> &mut CookiePolicy {
+        self.same_site = Some( same_site );
+        self
+    }
+
+    /// Applies the policy to the value of a single `Set-Cookie` header. Values that
+    /// are not valid text are returned unchanged.
+    pub(crate) fn apply( &self, value: &HeaderValue ) -> HeaderValue {
+        let Ok( cookie ) = value.to_str() else {
+            return value.clone();
+        };
+
+        // The first segment is the cookie's name and value, which are kept verbatim
+        let mut segments = cookie.split( ';' );
+        let mut normalized = segments.next().unwrap_or_default().trim().to_owned();
+
+        let mut has_secure = false;
+        let mut has_http_only = false;
+        for attribute in segments.map( str::trim ).filter( |a| !a.is_empty() ) {
+            let name = attribute.split( '=' ).next().unwrap_or_default().trim();
+
+            if name.eq_ignore_ascii_case( "samesite" ) && self.same_site.is_some() {
+                continue;
+            }
+            has_secure |= name.eq_ignore_ascii_case( "secure" );
+            has_http_only |= name.eq_ignore_ascii_case( "httponly" );
+
+            normalized.push_str( "; " );
+            normalized.push_str( attribute );
+        }
+
+        if ( self.secure || self.same_site == Some( SameSite::None ) ) && !has_secure {
+            normalized.push_str( "; Secure" );
+        }
+        if self.http_only && !has_http_only {
+            normalized.push_str( "; HttpOnly" );
+        }
+        if let Some( same_site ) = self.same_site {
+            normalized.push_str( "; SameSite=" );
+            normalized.push_str( same_site.as_str() );
+        }
+
+        HeaderValue::from_str( &normalized ).unwrap_or_else( |_| value.clone() )
+    }
+}
+
+/// What to do with the `Domain` attribute of cookies.
+#[derive(Clone, Debug, Default)]
+enum DomainRewrite {
+    #[default]
+    Keep,
+    Replace( String ),
+    Remove,
+}
+
+/// A rewrite of the `Domain` and `Path` attributes of the cookies set by the proxied
+/// server, so that they are scoped to where the proxy serves it instead.
+///
+/// ```
+/// use poem_proxy::{ CookieRewrite, ProxyConfig };
+///
+/// // The backend serves `/` of `backend.internal`, which the proxy serves under
+/// // `/app` of `www.example.com`
+/// let config = ProxyConfig::new( "backend.internal:8080" )
+///     .web_insecure()
+///     .enable_nesting()
+///     .rewrite_cookies( CookieRewrite::new()
+///         .domain( "www.example.com" )   // `Domain=backend.internal` becomes `Domain=www.example.com`
+///         .path( "/", "/app" ) )         // `Path=/account` becomes `Path=/app/account`
+///     .finish();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CookieRewrite {
+
+    /// What to do with the `Domain` attribute
+    domain: DomainRewrite,
+
+    /// The path prefixes to rewrite, and what to rewrite them to
+    paths: Vec<( String, String )>,
+}
+
+impl CookieRewrite {
+
+    /// Creates a new rewrite that leaves cookies as they are.
+    pub fn new() -> CookieRewrite {
+        CookieRewrite::default()
+    }
+
+    /// Replaces the `Domain` attribute of every cookie that has one with `domain`.
+    /// Cookies without one are already scoped to the host the browser asked, which is
+    /// the proxy's.
+    pub fn domain( &mut self, domain: impl Into<String> ) -> &mut CookieRewrite {
+        self.domain = DomainRewrite::Replace( domain.into() );
+        self
+    }
+
+    /// Removes the `Domain` attribute from every cookie, scoping each to exactly the
+    /// host the browser asked, whatever it was.
+    pub fn host_only( &mut self ) -> &mut CookieRewrite {
+        self.domain = DomainRewrite::Remove;
+        self
+    }
+
+    /// Rewrites the `Path` attributes that are `from` or lie below it to start with
+    /// `to` instead. Paths are matched by whole segments, and the most specific
+    /// prefix wins. This can be called once per prefix.
+    pub fn path( &mut self, from: &str, to: &str ) -> &mut CookieRewrite {
+        let from = from.trim_end_matches( '/' ).to_owned();
+        self.paths.retain( |( existing, _ )| *existing != from );
+        self.paths.push( ( from, to.trim_end_matches( '/' ).to_owned() ) );
+        self
+    }
+
+    /// Rewrites a cookie path, if one of the prefixes covers it.
+    fn rewrite_path( &self, path: &str ) -> Option<String> {
+        let ( from, to ) = self.paths.iter()
+            .filter( |( from, _ )| rewrite::covers( path, from ) )
+            .max_by_key( |( from, _ )| from.len() )?;
+        let rest = match &path[from.len()..] {
+            "/" => "",
+            rest => rest,
+        };
+        let rewritten = format!( "{}{}", to, rest );
+        Some( match rewritten.is_empty() {
+            true => "/".to_owned(),
+            false => rewritten,
+        })
+    }
+
+    /// Applies the rewrite to the value of a single `Set-Cookie` header. Values that
+    /// are not valid text are returned unchanged.
+    pub(crate) fn apply( &self, value: &HeaderValue ) -> HeaderValue {
+        let Ok( cookie ) = value.to_str() else {
+            return value.clone();
+        };
+
+        // The first segment is the cookie's name and value, which are kept verbatim
+        let mut segments = cookie.split( ';' );
+        let mut rewritten = segments.next().unwrap_or_default().trim().to_owned();
+
+        for attribute in segments.map( str::trim ).filter( |a| !a.is_empty() ) {
+            let ( name, attribute_value ) = attribute.split_once( '=' )
+                .map_or( ( attribute, "" ), |( name, value )| ( name.trim(), value.trim() ) );
+
+            let replacement = if name.eq_ignore_ascii_case( "domain" ) {
+                match &self.domain {
+                    DomainRewrite::Keep => None,
+                    DomainRewrite::Replace( domain ) => Some( format!( "Domain={}", domain ) ),
+                    DomainRewrite::Remove => continue,
+                }
+            } else if name.eq_ignore_ascii_case( "path" ) {
+                self.rewrite_path( attribute_value ).map( |path| format!( "Path={}", path ) )
+            } else {
+                None
+            };
+
+            rewritten.push_str( "; " );
+            rewritten.push_str( replacement.as_deref().unwrap_or( attribute ) );
+        }
+
+        HeaderValue::from_str( &rewritten ).unwrap_or_else( |_| value.clone() )
+    }
+}
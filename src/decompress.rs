@@ -0,0 +1,421 @@
+//! Decoding of compressed response bodies, for proxies that should hand clients bodies
+//! as they are rather than as the proxied server compressed them.
+//!
+//! Only the codings that can be decoded without extra dependencies are handled: `gzip`
+//! ([RFC 1952](https://www.rfc-editor.org/rfc/rfc1952)) and `deflate`
+//! ([RFC 1950](https://www.rfc-editor.org/rfc/rfc1950), also accepted without its zlib
+//! wrapper, as some servers send it), both built on the DEFLATE format
+//! ([RFC 1951](https://www.rfc-editor.org/rfc/rfc1951)). Bodies in any other coding, or
+//! in more than one, are forwarded as they are, still labeled with their coding.
+//!
+//! The decoded body is held to the same size limit as any other response body, so a
+//! small compressed body can't be used to exhaust the proxy's memory.
+
+use bytes::Bytes;
+use poem::{ Error, http::{ HeaderMap, HeaderValue, StatusCode, header } };
+use crate::body;
+
+/// A content coding the proxy knows how to decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Coding {
+    Gzip,
+    Deflate,
+}
+
+/// Returns the coding of a response body, if the proxy can decode it. Partial content
+/// is left alone, since a range of a compressed body can't be decoded on its own.
+pub(crate) fn coding( status: StatusCode, headers: &HeaderMap ) -> Option<Coding> {
+    if status == StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+
+    let mut codings = headers.get_all( header::CONTENT_ENCODING ).iter()
+        .flat_map( |value| value.to_str().unwrap_or( "?" ).split( ',' ) )
+        .map( str::trim )
+        .filter( |coding| !coding.is_empty() && !coding.eq_ignore_ascii_case( "identity" ) );
+    let coding = match codings.next()? {
+        coding if coding.eq_ignore_ascii_case( "gzip" ) || coding.eq_ignore_ascii_case( "x-gzip" ) => Coding::Gzip,
+        coding if coding.eq_ignore_ascii_case( "deflate" ) => Coding::Deflate,
+        _ => return None,
+    };
+    codings.next().is_none().then_some( coding )
+}
+
+/// Updates the headers of a response whose body is being decoded, so they describe the
+/// decoded body. Its entity tag is weakened, since the decoded body is no longer the
+/// exact bytes the tag was given to.
+pub(crate) fn strip( headers: &mut HeaderMap ) {
+    headers.remove( header::CONTENT_ENCODING );
+    headers.remove( header::CONTENT_LENGTH );
+    if let Some( etag ) = headers.get( header::ETAG ) {
+        if !etag.as_bytes().starts_with( b"W/" ) {
+            let weak = [ b"W/", etag.as_bytes() ].concat();
+            if let Ok( weak ) = HeaderValue::from_bytes( &weak ) {
+                headers.insert( header::ETAG, weak );
+            }
+        }
+    }
+}
+
+/// Decodes a whole response body. A body that isn't valid in its coding fails with
+/// `502 Bad Gateway`, as does one that decodes to more than `limit` bytes.
+pub(crate) fn decode( coding: Coding, body: &[u8], limit: Option<usize> ) -> poem::Result<Bytes> {
+    let limit = limit.unwrap_or( usize::MAX );
+    let decoded = match coding {
+        _ if body.is_empty() => Ok( Vec::new() ),
+        Coding::Gzip => gunzip( body, limit ),
+        Coding::Deflate => match zlib( body, limit ) {
+            Err( Failure::Corrupt ) => inflate( &mut BitReader::new( body ), limit ),
+            decoded => decoded,
+        },
+    };
+    match decoded {
+        Ok( decoded ) => Ok( decoded.into() ),
+        Err( Failure::TooLarge ) => Err( body::response_too_large( limit ) ),
+        Err( Failure::Corrupt ) => Err( Error::from_string( "The proxied server sent a compressed body that could not be decoded!", StatusCode::BAD_GATEWAY ) ),
+    }
+}
+
+/// Why a body couldn't be decoded.
+#[derive(Debug)]
+enum Failure {
+    Corrupt,
+    TooLarge,
+}
+
+/// Decodes a gzip body, which may hold several members one after the other. Like the
+/// gzip tool, anything after the last member that doesn't start another one, such as
+/// padding, is ignored.
+fn gunzip( body: &[u8], limit: usize ) -> Result<Vec<u8>, Failure> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    while !rest.is_empty() {
+        if rest.len() < 10 || rest[..3] != [ 0x1f, 0x8b, 8 ] {
+            match rest.len() == body.len() {
+                true => return Err( Failure::Corrupt ),
+                false => break,
+            }
+        }
+        let flags = rest[3];
+        let mut at = 10;
+        if flags & FEXTRA != 0 {
+            let length = u16::from_le_bytes( [ *rest.get( at ).ok_or( Failure::Corrupt )?, *rest.get( at + 1 ).ok_or( Failure::Corrupt )? ] );
+            at += 2 + length as usize;
+        }
+        for flag in [ FNAME, FCOMMENT ] {
+            if flags & flag != 0 {
+                at += rest.get( at.. ).and_then( |field| field.iter().position( |&byte| byte == 0 ) ).ok_or( Failure::Corrupt )? + 1;
+            }
+        }
+        if flags & FHCRC != 0 {
+            at += 2;
+        }
+
+        let mut bits = BitReader::new( rest.get( at.. ).ok_or( Failure::Corrupt )? );
+        let member = inflate( &mut bits, limit.saturating_sub( decoded.len() ) )?;
+        let trailer = bits.rest().get( ..8 ).ok_or( Failure::Corrupt )?;
+        let crc = u32::from_le_bytes( [ trailer[0], trailer[1], trailer[2], trailer[3] ] );
+        let size = u32::from_le_bytes( [ trailer[4], trailer[5], trailer[6], trailer[7] ] );
+        if crc != crc32( &member ) || size != member.len() as u32 {
+            return Err( Failure::Corrupt );
+        }
+
+        rest = &bits.rest()[8..];
+        decoded.extend( member );
+    }
+    Ok( decoded )
+}
+
+/// Decodes a body in the zlib format.
+fn zlib( body: &[u8], limit: usize ) -> Result<Vec<u8>, Failure> {
+    let &[ method, flags, .. ] = body else {
+        return Err( Failure::Corrupt );
+    };
+    let preset_dictionary = flags & 0x20 != 0;
+    if method & 0x0f != 8 || ( u16::from( method ) << 8 | u16::from( flags ) ) % 31 != 0 || preset_dictionary {
+        return Err( Failure::Corrupt );
+    }
+
+    let mut bits = BitReader::new( &body[2..] );
+    let decoded = inflate( &mut bits, limit )?;
+    let checksum = bits.rest().get( ..4 ).ok_or( Failure::Corrupt )?;
+    if u32::from_be_bytes( [ checksum[0], checksum[1], checksum[2], checksum[3] ] ) != adler32( &decoded ) {
+        return Err( Failure::Corrupt );
+    }
+    Ok( decoded )
+}
+
+/// Decodes DEFLATE data up to the end of its last block, leaving `bits` at the first
+/// whole byte after it.
+fn inflate( bits: &mut BitReader, limit: usize ) -> Result<Vec<u8>, Failure> {
+    let mut out = Vec::new();
+    loop {
+        let last = bits.read( 1 )? == 1;
+        match bits.read( 2 )? {
+            0 => {
+                bits.align();
+                let header = bits.take( 4 )?;
+                let length = u16::from_le_bytes( [ header[0], header[1] ] );
+                if length != !u16::from_le_bytes( [ header[2], header[3] ] ) {
+                    return Err( Failure::Corrupt );
+                }
+                if out.len() + length as usize > limit {
+                    return Err( Failure::TooLarge );
+                }
+                out.extend_from_slice( bits.take( length as usize )? );
+            },
+            1 => {
+                let ( lengths, distances ) = fixed_codes();
+                codes( bits, &mut out, &lengths, &distances, limit )?;
+            },
+            2 => {
+                let ( lengths, distances ) = dynamic_codes( bits )?;
+                codes( bits, &mut out, &lengths, &distances, limit )?;
+            },
+            _ => return Err( Failure::Corrupt ),
+        }
+        if last {
+            bits.align();
+            return Ok( out );
+        }
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [ 3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258 ];
+const LENGTH_EXTRA: [u8; 29] = [ 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0 ];
+const DISTANCE_BASE: [u16; 30] = [ 1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577 ];
+const DISTANCE_EXTRA: [u8; 30] = [ 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13 ];
+
+/// The order in which the code lengths of the code length code are sent
+const CODE_LENGTH_ORDER: [usize; 19] = [ 16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15 ];
+
+/// Decodes the symbols of a compressed block into `out`, up to its end of block.
+fn codes( bits: &mut BitReader, out: &mut Vec<u8>, lengths: &Huffman, distances: &Huffman, limit: usize ) -> Result<(), Failure> {
+    loop {
+        let symbol = lengths.decode( bits )? as usize;
+        match symbol {
+            0..=255 => out.push( symbol as u8 ),
+            256 => return Ok( () ),
+            _ => {
+                let index = symbol - 257;
+                let length = *LENGTH_BASE.get( index ).ok_or( Failure::Corrupt )? as usize
+                    + bits.read( LENGTH_EXTRA[index].into() )? as usize;
+                let index = distances.decode( bits )? as usize;
+                let distance = *DISTANCE_BASE.get( index ).ok_or( Failure::Corrupt )? as usize
+                    + bits.read( DISTANCE_EXTRA[index].into() )? as usize;
+                if distance > out.len() {
+                    return Err( Failure::Corrupt );
+                }
+                if out.len() + length > limit {
+                    return Err( Failure::TooLarge );
+                }
+
+                // The copy may overlap the bytes it produces, so it goes a byte at a time
+                let start = out.len() - distance;
+                for at in start..start + length {
+                    out.push( out[at] );
+                }
+            },
+        }
+        if out.len() > limit {
+            return Err( Failure::TooLarge );
+        }
+    }
+}
+
+/// Returns the codes of blocks compressed with the fixed codes.
+fn fixed_codes() -> ( Huffman, Huffman ) {
+    let mut lengths = [ 8; 288 ];
+    lengths[144..256].fill( 9 );
+    lengths[256..280].fill( 7 );
+    let lengths = Huffman::new( &lengths ).expect( "the fixed literal/length code is complete" );
+    let distances = Huffman::new( &[ 5; 30 ] ).expect( "the fixed distance code is complete" );
+    ( lengths, distances )
+}
+
+/// Reads the codes of a block compressed with dynamic codes from its header.
+fn dynamic_codes( bits: &mut BitReader ) -> Result<( Huffman, Huffman ), Failure> {
+    let literals = bits.read( 5 )? as usize + 257;
+    let distances = bits.read( 5 )? as usize + 1;
+    let code_lengths = bits.read( 4 )? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err( Failure::Corrupt );
+    }
+
+    let mut lengths = [ 0u8; 19 ];
+    for &index in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[index] = bits.read( 3 )? as u8;
+    }
+    let code_length_code = Huffman::new( &lengths ).ok_or( Failure::Corrupt )?;
+
+    // The lengths of both codes are sent as one sequence, and runs may cross over
+    let mut lengths = Vec::with_capacity( literals + distances );
+    while lengths.len() < literals + distances {
+        let ( length, repeat ) = match code_length_code.decode( bits )? {
+            symbol @ 0..=15 => ( symbol as u8, 1 ),
+            16 => ( *lengths.last().ok_or( Failure::Corrupt )?, 3 + bits.read( 2 )? ),
+            17 => ( 0, 3 + bits.read( 3 )? ),
+            _ => ( 0, 11 + bits.read( 7 )? ),
+        };
+        if lengths.len() + repeat as usize > literals + distances {
+            return Err( Failure::Corrupt );
+        }
+        lengths.extend( std::iter::repeat( length ).take( repeat as usize ) );
+    }
+
+    // A block without an end can't be decoded
+    if lengths[256] == 0 {
+        return Err( Failure::Corrupt );
+    }
+    let literal_code = Huffman::new( &lengths[..literals] ).ok_or( Failure::Corrupt )?;
+    let distance_code = Huffman::new( &lengths[literals..] ).ok_or( Failure::Corrupt )?;
+    Ok( ( literal_code, distance_code ) )
+}
+
+/// A canonical Huffman code, given by the number of codes of each length and the
+/// symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+
+    /// Builds the code with the given code length for each symbol, where a length of
+    /// zero leaves the symbol out. Codes with more lengths than fit are refused, while
+    /// incomplete ones are allowed, since a code of a single symbol has to be.
+    fn new( lengths: &[u8] ) -> Option<Huffman> {
+        let mut counts = [ 0u16; 16 ];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = ( left << 1 ) - i32::from( count );
+            if left < 0 {
+                return None;
+            }
+        }
+
+        let mut offsets = [ 0u16; 16 ];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![ 0; lengths.len() ];
+        for ( symbol, &length ) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Some( Huffman { counts, symbols } )
+    }
+
+    /// Reads the next symbol, whose code is sent starting from its first bit.
+    fn decode( &self, bits: &mut BitReader ) -> Result<u16, Failure> {
+        let ( mut code, mut first, mut index ) = ( 0i32, 0i32, 0i32 );
+        for &count in &self.counts[1..] {
+            code |= bits.read( 1 )? as i32;
+            let count = i32::from( count );
+            if code - count < first {
+                return Ok( self.symbols[( index + code - first ) as usize] );
+            }
+            index += count;
+            first = ( first + count ) << 1;
+            code <<= 1;
+        }
+        Err( Failure::Corrupt )
+    }
+}
+
+/// Reads DEFLATE data a few bits at a time, from the least significant bit of each byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    at: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+
+    fn new( data: &'a [u8] ) -> BitReader<'a> {
+        BitReader { data, at: 0, buffer: 0, count: 0 }
+    }
+
+    fn read( &mut self, bits: u32 ) -> Result<u32, Failure> {
+        while self.count < bits {
+            let byte = *self.data.get( self.at ).ok_or( Failure::Corrupt )?;
+            self.at += 1;
+            self.buffer |= u64::from( byte ) << self.count;
+            self.count += 8;
+        }
+        let value = ( self.buffer & ( ( 1 << bits ) - 1 ) ) as u32;
+        self.buffer >>= bits;
+        self.count -= bits;
+        Ok( value )
+    }
+
+    /// Skips to the start of the next byte. Bytes are only loaded as their bits are
+    /// needed, so this only ever drops part of one.
+    fn align( &mut self ) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    /// Takes the next `length` whole bytes, once aligned.
+    fn take( &mut self, length: usize ) -> Result<&'a [u8], Failure> {
+        let bytes = self.data.get( self.at..self.at + length ).ok_or( Failure::Corrupt )?;
+        self.at += length;
+        Ok( bytes )
+    }
+
+    /// The bytes that haven't been read yet, once aligned.
+    fn rest( &self ) -> &'a [u8] {
+        &self.data[self.at..]
+    }
+}
+
+/// The CRC-32 that gzip members end with.
+fn crc32( data: &[u8] ) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [ 0u32; 256 ];
+        let mut n = 0;
+        while n < 256 {
+            let mut crc = n as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 { 0xedb8_8320 ^ ( crc >> 1 ) } else { crc >> 1 };
+                bit += 1;
+            }
+            table[n] = crc;
+            n += 1;
+        }
+        table
+    };
+
+    !data.iter().fold( !0u32, |crc, &byte| TABLE[( ( crc ^ u32::from( byte ) ) & 0xff ) as usize] ^ ( crc >> 8 ) )
+}
+
+/// The Adler-32 checksum that zlib data ends with.
+fn adler32( data: &[u8] ) -> u32 {
+    const MOD: u32 = 65521;
+    let ( mut a, mut b ) = ( 1u32, 0u32 );
+
+    // Sums can go this many bytes before they have to be reduced
+    for chunk in data.chunks( 5552 ) {
+        for &byte in chunk {
+            a += u32::from( byte );
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
@@ -0,0 +1,92 @@
+//! Resolution of the names of the proxied servers.
+//!
+//! Servers behind DNS-based load balancers often hand out short-lived records. The proxy
+//! can keep resolved addresses for a fixed time of its own choosing, so that it neither
+//! holds on to an address for too long nor looks the name up for every request. The
+//! cache is shared by every client the proxy creates, and sits in front of either the
+//! system resolver or a user-provided [Resolve] implementation.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{ Arc, Mutex },
+    time::{ Duration, Instant },
+};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{ Addrs, Resolve, Resolving };
+
+/// The addresses resolved for a name, along with when they were resolved.
+type Entry = ( Vec<SocketAddr>, Instant );
+
+/// A resolver that remembers the addresses it resolved for a while.
+pub(crate) struct CachingResolver {
+
+    /// The resolver that names are looked up with, or `None` for the system resolver
+    inner: Option<Arc<dyn Resolve>>,
+
+    /// How long resolved addresses are kept, or `None` to always look names up
+    ttl: Option<Duration>,
+
+    /// The addresses of every name resolved
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl CachingResolver {
+
+    pub(crate) fn new( inner: Option<Arc<dyn Resolve>>, ttl: Option<Duration> ) -> CachingResolver {
+        CachingResolver { inner, ttl, entries: Arc::default() }
+    }
+
+    /// Returns a resolver that looks names up with `inner`, keeping the same TTL.
+    pub(crate) fn with_inner( &self, inner: Arc<dyn Resolve> ) -> CachingResolver {
+        CachingResolver::new( Some( inner ), self.ttl )
+    }
+
+    /// Returns a resolver that keeps addresses for `ttl`, using the same inner resolver.
+    pub(crate) fn with_ttl( &self, ttl: Duration ) -> CachingResolver {
+        CachingResolver::new( self.inner.clone(), Some( ttl ) )
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve( &self, name: Name ) -> Resolving {
+        let host = name.as_str().to_owned();
+
+        if let Some( ttl ) = self.ttl {
+            let entries = self.entries.lock().unwrap_or_else( |e| e.into_inner() );
+            if let Some( ( addrs, resolved ) ) = entries.get( &host ) {
+                if resolved.elapsed() < ttl {
+                    let addrs: Addrs = Box::new( addrs.clone().into_iter() );
+                    return Box::pin( async move { Ok( addrs ) } );
+                }
+            }
+        }
+
+        let inner = self.inner.clone();
+        let entries = self.entries.clone();
+        let cache = self.ttl.is_some();
+        Box::pin( async move {
+            let addrs = match inner {
+                Some( inner ) => inner.resolve( name ).await?.collect::<Vec<_>>(),
+
+                // The port is filled in by the http client
+                None => tokio::net::lookup_host( ( host.as_str(), 0 ) ).await?.collect::<Vec<_>>(),
+            };
+
+            if cache {
+                entries.lock().unwrap_or_else( |e| e.into_inner() )
+                    .insert( host, ( addrs.clone(), Instant::now() ) );
+            }
+            Ok( Box::new( addrs.into_iter() ) as Addrs )
+        })
+    }
+}
+
+impl fmt::Debug for CachingResolver {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        f.debug_struct( "CachingResolver" )
+            .field( "ttl", &self.ttl )
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,53 @@
+//! Handling of requests sent as TLS 1.3 early data (0-RTT).
+//!
+//! Early data can be replayed by an attacker, so requests sent that way are only safe
+//! to act on when repeating them does no harm. The server terminating TLS marks such
+//! requests with the `Early-Data: 1` header
+//! ([RFC 8470](https://www.rfc-editor.org/rfc/rfc8470)), which the proxy uses to decide
+//! what to do with them.
+
+use poem::{ Error, http::{ HeaderMap, Method, StatusCode } };
+
+/// The ways in which the proxy can handle requests sent as TLS early data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EarlyDataPolicy {
+
+    /// Forward every request, leaving the `Early-Data` header in place so that the
+    /// proxied server can decide for itself.
+    #[default]
+    Forward,
+
+    /// Answer requests with non-idempotent methods (such as `POST` and `PATCH`) with
+    /// `425 Too Early`, so the client retries them after the handshake completes.
+    RejectUnsafe,
+
+    /// Answer every early data request with `425 Too Early`.
+    RejectAll,
+}
+
+impl EarlyDataPolicy {
+
+    /// Makes sure a request may be forwarded, returning a `425 Too Early` error if it
+    /// was sent as early data that the policy doesn't allow.
+    pub(crate) fn check( &self, method: &Method, headers: &HeaderMap ) -> poem::Result<()> {
+        let early = headers.get( "early-data" ).map( |v| v.as_bytes() == b"1" ).unwrap_or( false );
+
+        let reject = early && match self {
+            EarlyDataPolicy::Forward => false,
+            EarlyDataPolicy::RejectUnsafe => !is_idempotent( method ),
+            EarlyDataPolicy::RejectAll => true,
+        };
+
+        if reject {
+            let too_early = StatusCode::from_u16( 425 ).expect( "425 is a valid status code" );
+            return Err( Error::from_string( "This request can't be sent as TLS early data!", too_early ) );
+        }
+
+        Ok( () )
+    }
+}
+
+/// Whether repeating a request with this method has the same effect as sending it once.
+fn is_idempotent( method: &Method ) -> bool {
+    matches!( *method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE )
+}
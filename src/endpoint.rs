@@ -0,0 +1,160 @@
+//! The proxy as an endpoint of its own.
+
+use std::{ ops::Deref, sync::Arc };
+use async_trait::async_trait;
+use poem::{ Endpoint, Request, Response, Result, http::Uri };
+use crate::ProxyConfig;
+
+/// A proxy [Endpoint] that holds its own configuration. Unlike the [proxy](crate::proxy)
+/// handler, it doesn't need the configuration passed in as data, so it can be mounted
+/// directly, and proxies with different configurations can't pick up each other's.
+///
+/// ```
+/// use poem::{ Route, Server, listener::TcpListener };
+/// use poem_proxy::{ ProxyConfig, ProxyEndpoint };
+///
+/// let api = ProxyConfig::new( "localhost:8080" )
+///     .web_insecure()
+///     .enable_nesting()
+///     .finish();
+/// let app = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .ws_insecure()
+///     .enable_nesting()
+///     .finish();
+///
+/// let routes = Route::new()
+///     .nest( "/api", ProxyEndpoint::new( api ) )
+///     .nest( "/", ProxyEndpoint::new( app ) );
+///
+/// Server::new( TcpListener::bind( "127.0.0.1:3000" ) ).run( routes );
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProxyEndpoint {
+    config: Arc<ProxyConfig>,
+
+    /// The path the endpoint is nested under, which is put back in front of the path
+    /// of each request. If not set, paths are forwarded as poem hands them over
+    prefix: Option<String>,
+}
+
+impl ProxyEndpoint {
+
+    /// Creates an endpoint that proxies requests with the given configuration. If
+    /// [health checks](ProxyConfig::health_check_path) are on and this is called
+    /// within a tokio runtime, they start right away, and otherwise with the first
+    /// request.
+    pub fn new( config: ProxyConfig ) -> ProxyEndpoint {
+        SharedProxyConfig::new( config ).endpoint()
+    }
+
+    /// Returns the configuration of the endpoint, such as to get a
+    /// [handle](ProxyConfig::handle) to it.
+    pub fn config( &self ) -> &ProxyConfig {
+        &self.config
+    }
+}
+
+#[async_trait]
+impl Endpoint for ProxyEndpoint {
+    type Output = Response;
+
+    async fn call( &self, mut req: Request ) -> Result<Response> {
+        if let Some( prefix ) = &self.prefix {
+            if let Some( uri ) = prefixed( req.uri(), prefix ) {
+                *req.uri_mut() = uri;
+            }
+        }
+        let body = req.take_body();
+        crate::serve( &req, &self.config, body ).await
+    }
+}
+
+/// A configuration shared by several endpoints, such as when the same proxied server
+/// is mounted under more than one path. Every endpoint made from it uses the one copy of
+/// the configuration, so they share their connections, caches, counters and
+/// [health checks](ProxyConfig::health_check_path), and one [handle](ProxyConfig::handle)
+/// controls them all. Obtained through [ProxyConfig::shared], and dereferences to the
+/// configuration.
+///
+/// Endpoints made by cloning a [ProxyConfig] share this state too, though each holds a
+/// copy of the rest of the configuration.
+///
+/// ```
+/// use poem::{ Endpoint, Request, Route, http::{ StatusCode, Uri } };
+/// use poem_proxy::ProxyConfig;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// // Nothing listens on the discard port, so every request fails the same way
+/// let shared = ProxyConfig::new( "127.0.0.1:9" )
+///     .web_insecure()
+///     .enable_nesting()
+///     .shared();
+///
+/// let app = Route::new()
+///     .nest( "/api", shared.endpoint_for( "/api" ) )
+///     .nest( "/auth", shared.endpoint_for( "/auth" ) )
+///     .nest( "/static", shared.endpoint_for( "/static" ) );
+///
+/// for path in [ "/api/users", "/auth/login", "/static/app.js" ] {
+///     let req = Request::builder().uri( Uri::from_static( path ) ).finish();
+///     let status = app.call( req ).await.map_or_else( |e| e.status(), |r| r.status() );
+///     assert_eq!( status, StatusCode::BAD_GATEWAY );
+/// }
+///
+/// let metrics = shared.handle().metrics_snapshot();
+/// assert_eq!( metrics.requests, 3 );
+/// assert_eq!( metrics.server_error, 3 );
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedProxyConfig( Arc<ProxyConfig> );
+
+impl SharedProxyConfig {
+
+    pub(crate) fn new( config: ProxyConfig ) -> SharedProxyConfig {
+        SharedProxyConfig( Arc::new( config ) )
+    }
+
+    /// Creates an endpoint that proxies requests with the shared configuration. Like
+    /// one made with [ProxyEndpoint::new], when nested under a path it forwards
+    /// requests without that path.
+    pub fn endpoint( &self ) -> ProxyEndpoint {
+        self.0.start_health_checks();
+        ProxyEndpoint { config: self.0.clone(), prefix: None }
+    }
+
+    /// Creates an endpoint to be nested under `prefix`, which forwards requests with
+    /// the prefix kept in front of their paths, so the proxied server sees the path the
+    /// client asked for. This only makes a difference when
+    /// [nesting is enabled](ProxyConfig::enable_nesting).
+    pub fn endpoint_for( &self, prefix: &str ) -> ProxyEndpoint {
+        let prefix = prefix.trim_end_matches( '/' );
+        ProxyEndpoint { prefix: ( !prefix.is_empty() ).then( || prefix.into() ), ..self.endpoint() }
+    }
+}
+
+impl Deref for SharedProxyConfig {
+    type Target = ProxyConfig;
+
+    fn deref( &self ) -> &ProxyConfig {
+        &self.0
+    }
+}
+
+/// Returns `uri` with `prefix` put in front of its path, or `None` if that doesn't make
+/// a valid uri.
+fn prefixed( uri: &Uri, prefix: &str ) -> Option<Uri> {
+    let path = uri.path_and_query().map_or( "/", |path| path.as_str() );
+    let path = match path.strip_prefix( '/' ) {
+        Some( "" ) => prefix.to_owned(),
+        Some( rest ) if rest.starts_with( '?' ) => format!( "{}{}", prefix, rest ),
+        Some( rest ) => format!( "{}/{}", prefix, rest ),
+        None => format!( "{}{}", prefix, path ),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some( path.parse().ok()? );
+    Uri::from_parts( parts ).ok()
+}
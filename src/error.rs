@@ -0,0 +1,273 @@
+//! The ways in which forwarding a request to the proxied server can fail, and how the
+//! errors the proxy generates itself are presented to the client.
+//!
+//! Each kind of failure can be given an [ErrorPage] of its own, so a backend that is
+//! down can be told apart from one that is slow without clients seeing the http
+//! client's error messages.
+
+use std::{ error, fmt };
+use poem::{ Response, http::{ HeaderValue, StatusCode, header } };
+
+/// A failure to get a response from the proxied server, sorted by what went wrong so that
+/// [error hooks](crate::ProxyConfig::on_upstream_error) can decide how to react. The
+/// underlying error from the http client is kept in every case. Error responses from the
+/// proxied server only count as failures if the endpoint is set to
+/// [handle them](crate::ProxyConfig::handle_error_statuses), or to
+/// [treat server errors as failures](crate::ProxyConfig::treat_upstream_errors_as_failures).
+///
+/// ```
+/// use poem_proxy::{ ProxyConfig, ProxyError };
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .on_upstream_error( |req, error| match error {
+///         ProxyError::Connect( _ ) => eprintln!( "backend is down! ({})", req.uri() ),
+///         ProxyError::Timeout( _ ) => eprintln!( "backend is slow: {}", error ),
+///         ProxyError::ClosedPrematurely( _ ) => eprintln!( "backend hung up on {}", req.uri() ),
+///         _ => {},
+///     })
+///     .finish();
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ProxyError {
+
+    /// No connection could be made to the proxied server
+    Connect( reqwest::Error ),
+
+    /// The proxied server didn't respond in time
+    Timeout( reqwest::Error ),
+
+    /// The proxied server closed the connection without answering, such as when it
+    /// crashed while handling the request
+    ClosedPrematurely( reqwest::Error ),
+
+    /// The proxied server redirected the request too many times
+    Redirect( reqwest::Error ),
+
+    /// The body of the response couldn't be read
+    Body( reqwest::Error ),
+
+    /// The request failed for any other reason
+    Request( reqwest::Error ),
+
+    /// The proxied server answered with an error status
+    Status( reqwest::Error ),
+}
+
+impl ProxyError {
+
+    /// Returns the error reported by the http client.
+    pub fn inner( &self ) -> &reqwest::Error {
+        match self {
+            ProxyError::Connect( error )
+            | ProxyError::Timeout( error )
+            | ProxyError::ClosedPrematurely( error )
+            | ProxyError::Redirect( error )
+            | ProxyError::Body( error )
+            | ProxyError::Request( error )
+            | ProxyError::Status( error ) => error,
+        }
+    }
+
+    /// Returns the status the client is answered with, unless an [ErrorPage] or
+    /// [error handler](crate::ProxyConfig::on_error) says otherwise. For an error
+    /// status, this is the status the proxied server answered with.
+    pub fn status( &self ) -> StatusCode {
+        self.inner().status().unwrap_or( StatusCode::BAD_GATEWAY )
+    }
+
+    /// Returns what kind of error this is, without the underlying error.
+    pub fn kind( &self ) -> ProxyErrorKind {
+        match self {
+            ProxyError::Connect( _ ) => ProxyErrorKind::Connect,
+            ProxyError::Timeout( _ ) => ProxyErrorKind::Timeout,
+            ProxyError::ClosedPrematurely( _ ) => ProxyErrorKind::ClosedPrematurely,
+            ProxyError::Redirect( _ ) => ProxyErrorKind::Redirect,
+            ProxyError::Body( _ ) => ProxyErrorKind::Body,
+            ProxyError::Request( _ ) => ProxyErrorKind::Request,
+            ProxyError::Status( _ ) => ProxyErrorKind::Status,
+        }
+    }
+}
+
+/// The kinds of [ProxyError], used to pick the [ErrorPage] for an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ProxyErrorKind {
+
+    /// See [ProxyError::Connect]
+    Connect,
+
+    /// See [ProxyError::Timeout]
+    Timeout,
+
+    /// See [ProxyError::ClosedPrematurely]
+    ClosedPrematurely,
+
+    /// See [ProxyError::Redirect]
+    Redirect,
+
+    /// See [ProxyError::Body]
+    Body,
+
+    /// See [ProxyError::Request]
+    Request,
+
+    /// See [ProxyError::Status]
+    Status,
+}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from( error: reqwest::Error ) -> Self {
+
+        // Timeouts are checked first, since a connect timeout is both
+        if error.is_timeout() {
+            ProxyError::Timeout( error )
+        } else if error.is_connect() {
+            ProxyError::Connect( error )
+        } else if !error.is_body() && closed_prematurely( &error ) {
+            ProxyError::ClosedPrematurely( error )
+        } else if error.is_redirect() {
+            ProxyError::Redirect( error )
+        } else if error.is_body() || error.is_decode() {
+            ProxyError::Body( error )
+        } else if error.is_status() {
+            ProxyError::Status( error )
+        } else {
+            ProxyError::Request( error )
+        }
+    }
+}
+
+/// Whether the proxied server closed the connection before its response was complete.
+/// Responses whose body was cut off are told apart by the caller.
+fn closed_prematurely( error: &reqwest::Error ) -> bool {
+    let mut source = error::Error::source( error );
+    while let Some( cause ) = source {
+        if let Some( error ) = cause.downcast_ref::<hyper::Error>() {
+            return error.is_incomplete_message();
+        }
+        source = cause.source();
+    }
+    false
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        fmt::Display::fmt( self.inner(), f )
+    }
+}
+
+impl error::Error for ProxyError {
+    fn source( &self ) -> Option<&( dyn error::Error + 'static )> {
+        Some( self.inner() )
+    }
+}
+
+/// The response sent to the client in place of a kind of [ProxyError].
+///
+/// The body is a template, in which `{status}` is replaced with the status code and
+/// `{error}` with the error reported by the http client. Keep in mind that the latter
+/// usually names the url of the proxied server.
+///
+/// ```
+/// use poem::http::{ HeaderValue, StatusCode };
+/// use poem_proxy::{ ErrorPage, ProxyConfig, ProxyErrorKind };
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .error_page( ProxyErrorKind::Connect, &ErrorPage::new( StatusCode::SERVICE_UNAVAILABLE,
+///         "<h1>We'll be right back</h1>" ) )
+///     .error_page( ProxyErrorKind::Timeout, ErrorPage::new( StatusCode::GATEWAY_TIMEOUT,
+///         r#"{"error": "upstream timed out", "code": {status}}"# )
+///         .content_type( HeaderValue::from_static( "application/json" ) ) )
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ErrorPage {
+    status: StatusCode,
+    body: String,
+    content_type: HeaderValue,
+}
+
+impl ErrorPage {
+
+    /// Creates a page answering with `status` and the `body` template, as HTML.
+    pub fn new( status: StatusCode, body: impl Into<String> ) -> ErrorPage {
+        ErrorPage {
+            status,
+            body: body.into(),
+            content_type: HeaderValue::from_static( "text/html; charset=utf-8" ),
+        }
+    }
+
+    /// Sets the `Content-Type` of the page.
+    pub fn content_type( &mut self, content_type: HeaderValue ) -> &mut ErrorPage {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Renders the page for `error`. The result is marked so that the
+    /// [error format](ErrorFormat) leaves it alone.
+    pub(crate) fn render( &self, error: &ProxyError ) -> poem::Error {
+        let body = self.body
+            .replace( "{status}", self.status.as_str() )
+            .replace( "{error}", &error.to_string() );
+        handled( Response::builder()
+            .status( self.status )
+            .header( header::CONTENT_TYPE, self.content_type.clone() )
+            .body( body ) )
+    }
+}
+
+/// Turns a response standing in for a [ProxyError] into an error that the
+/// [error format](ErrorFormat) leaves alone.
+pub(crate) fn handled( response: Response ) -> poem::Error {
+    let mut error = poem::Error::from_response( response );
+    error.set_data( RenderedPage );
+    error
+}
+
+/// Marks the errors rendered from an [ErrorPage] or built by an error handler.
+struct RenderedPage;
+
+/// The ways in which the proxy can format the bodies of the errors it generates itself,
+/// such as timeouts or rate limits. Error responses from the proxied server are always
+/// passed through untouched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+
+    /// Send the error message as plain text.
+    #[default]
+    Text,
+
+    /// Send the error as JSON, in the form
+    /// `{"error": {"code": 504, "message": "The request took too long to complete!"}}`,
+    /// where `code` is the status of the response.
+    Json,
+}
+
+impl ErrorFormat {
+
+    /// Turns an error generated by the proxy into the response sent to the client,
+    /// keeping its status and headers. Error pages and the responses of error handlers
+    /// are sent as they are.
+    pub(crate) async fn apply( &self, error: poem::Error ) -> Response {
+        let rendered = error.data::<RenderedPage>().is_some();
+        let response = error.into_response();
+        if *self == ErrorFormat::Text || rendered {
+            return response;
+        }
+
+        let ( mut parts, body ) = response.into_parts();
+        let message = body.into_string().await.unwrap_or_default();
+        let envelope = serde_json::json!( {
+            "error": { "code": parts.status.as_u16(), "message": message },
+        } );
+
+        parts.headers.insert( header::CONTENT_TYPE, HeaderValue::from_static( "application/json" ) );
+        parts.headers.remove( header::CONTENT_LENGTH );
+        Response::from_parts( parts, envelope.to_string().into() )
+    }
+}
@@ -0,0 +1,145 @@
+//! Synthetic latency and fault injection for chaos testing.
+//!
+//! This module is only compiled when the `fault-injection` feature is enabled, so that
+//! production builds cannot accidentally be configured to fail on purpose.
+
+use std::time::Duration;
+use poem::{ Body, Error, Response, http::StatusCode };
+
+/// A description of the faults the proxy should inject into the requests it forwards.
+/// Every fault is rolled for independently, with its own probability between `0.0`
+/// (never) and `1.0` (every request).
+///
+/// ```
+/// use std::time::Duration;
+/// use poem::http::StatusCode;
+/// use poem_proxy::{ FaultInjection, ProxyConfig };
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .fault_injection( FaultInjection::new()
+///         .delay( 0.25, Duration::from_millis( 500 ) ) // A quarter of requests are slow
+///         .error( 0.05, StatusCode::BAD_GATEWAY )      // 5% fail outright
+///         .drop_connection( 0.01 ) )                   // 1% lose their connection
+///     .finish();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjection {
+
+    /// The probability of delaying a request, and for how long
+    delay: Option<( f64, Duration )>,
+
+    /// The probability of answering a request with an error, and which status to use
+    error: Option<( f64, StatusCode )>,
+
+    /// The probability of dropping the connection instead of responding
+    drop_connection: Option<f64>,
+
+    /// The probability of failing to build an http client
+    client_failure: Option<f64>,
+}
+
+/// The fault, if any, that was chosen for a single request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+
+    /// Forward the request as usual
+    None,
+
+    /// Answer with an error instead of forwarding the request
+    Error( StatusCode ),
+
+    /// Drop the connection instead of forwarding the request
+    DropConnection,
+}
+
+impl FaultInjection {
+
+    /// Creates a new fault injection configuration that does not inject any faults.
+    pub fn new() -> FaultInjection {
+        FaultInjection::default()
+    }
+
+    /// Delays the given fraction of requests by `duration` before they are forwarded.
+    pub fn delay( &mut self, probability: f64, duration: Duration ) -> &mut FaultInjection {
+        self.delay = Some( ( probability, duration ) );
+        self
+    }
+
+    /// Answers the given fraction of requests with `status` instead of forwarding them.
+    pub fn error( &mut self, probability: f64, status: StatusCode ) -> &mut FaultInjection {
+        self.error = Some( ( probability, status ) );
+        self
+    }
+
+    /// Drops the connection for the given fraction of requests instead of forwarding them.
+    pub fn drop_connection( &mut self, probability: f64 ) -> &mut FaultInjection {
+        self.drop_connection = Some( probability );
+        self
+    }
+
+    /// Fails to build the given fraction of the http clients the proxy builds to reach
+    /// the proxied server, which it never runs into otherwise. This is how
+    /// [strict mode](crate::ProxyConfig::strict_mode) can be tried out: the request that
+    /// needed the client panics in strict mode, and is answered with
+    /// `500 Internal Server Error` otherwise.
+    pub fn client_failure( &mut self, probability: f64 ) -> &mut FaultInjection {
+        self.client_failure = Some( probability );
+        self
+    }
+
+    /// Rolls for whether building an http client should fail.
+    pub(crate) fn fails_client( &self ) -> bool {
+        self.client_failure.map_or( false, roll )
+    }
+
+    /// Rolls for each configured fault, sleeping if the request should be delayed,
+    /// and returns the fault (if any) the request should be answered with.
+    pub(crate) async fn inject( &self ) -> Fault {
+        if let Some( ( probability, duration ) ) = self.delay {
+            if roll( probability ) {
+                tokio::time::sleep( duration ).await;
+            }
+        }
+
+        if let Some( probability ) = self.drop_connection {
+            if roll( probability ) {
+                return Fault::DropConnection;
+            }
+        }
+
+        match self.error {
+            Some( ( probability, status ) ) if roll( probability ) => Fault::Error( status ),
+            _ => Fault::None,
+        }
+    }
+}
+
+impl Fault {
+
+    /// Turns the fault into the result the handler should return, or `None` if the
+    /// request should be forwarded as usual. Injected `503` and `429` errors carry a
+    /// `Retry-After` of `retry_after`, like the ones the proxy produces for real.
+    pub(crate) fn into_result( self, retry_after: Duration ) -> Option<poem::Result<Response>> {
+        match self {
+            Fault::None => None,
+            Fault::Error( status @ ( StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS ) ) => {
+                Some( Err( crate::throttled( status, "Injected fault", retry_after ) ) )
+            },
+            Fault::Error( status ) => Some( Err( Error::from_string( "Injected fault", status ) ) ),
+
+            // A body that fails as soon as it is read makes the server abort the
+            // connection without finishing the response
+            Fault::DropConnection => Some( Ok( Response::builder()
+                .body( Body::from_bytes_stream( futures_util::stream::once( async {
+                    Err::<Vec<u8>, _>( std::io::Error::from( std::io::ErrorKind::ConnectionAborted ) )
+                } ) ) )
+            ) ),
+        }
+    }
+}
+
+/// Returns true with the given probability.
+fn roll( probability: f64 ) -> bool {
+    rand::random::<f64>() < probability
+}
@@ -0,0 +1,51 @@
+//! The `X-Forwarded-*` headers that tell the proxied server about the original request.
+//!
+//! Behind a proxy, the server only sees the proxy's address, and the scheme and host the
+//! proxy used to reach it. The client's address is appended to `X-Forwarded-For`, so
+//! the header lists every hop a request went through. The scheme and host the client
+//! used are sent in `X-Forwarded-Proto` and `X-Forwarded-Host`, unless the request came
+//! from one of the trusted proxies, which already knows them better.
+
+use std::net::IpAddr;
+use poem::{ Request, http::{ HeaderMap, HeaderName, HeaderValue, header } };
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static( "x-forwarded-for" );
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static( "x-forwarded-proto" );
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static( "x-forwarded-host" );
+
+/// Adds the `X-Forwarded-*` headers for `req` to the headers it is forwarded with.
+pub(crate) fn add( req: &Request, headers: &mut HeaderMap, trusted: &[IpAddr] ) {
+    let peer = req.remote_addr().as_socket_addr().map( |addr| addr.ip() );
+
+    // Earlier hops may have sent the header more than once, so the chain is joined
+    if let Some( peer ) = peer {
+        let chain = headers.get_all( &X_FORWARDED_FOR )
+            .iter()
+            .filter_map( |value| value.to_str().ok() )
+            .map( str::trim )
+            .filter( |value| !value.is_empty() )
+            .chain( std::iter::once( peer.to_string().as_str() ) )
+            .collect::<Vec<_>>()
+            .join( ", " );
+        if let Ok( value ) = HeaderValue::from_str( &chain ) {
+            headers.insert( X_FORWARDED_FOR, value );
+        }
+    }
+
+    // Anyone else could claim to have been reached over https, or under another host
+    let from_trusted = peer.map_or( false, |peer| trusted.contains( &peer ) );
+    if !( from_trusted && headers.contains_key( &X_FORWARDED_PROTO ) ) {
+        headers.insert( X_FORWARDED_PROTO, HeaderValue::from_static( match req.scheme().as_str() {
+            "https" => "https",
+            _ => "http",
+        } ) );
+    }
+    if !( from_trusted && headers.contains_key( &X_FORWARDED_HOST ) ) {
+        let host = req.headers().get( header::HOST ).cloned()
+            .or_else( || req.uri().authority().and_then( |a| HeaderValue::from_str( a.as_str() ).ok() ) );
+        match host {
+            Some( host ) => { headers.insert( X_FORWARDED_HOST, host ); },
+            None => { headers.remove( X_FORWARDED_HOST ); },
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Translation of gRPC statuses into http statuses.
+//!
+//! gRPC always answers with `200 OK` at the http level and reports the outcome of a call
+//! in its `grpc-status` field, which clients that only speak http can't make sense of.
+//! Calls that fail before producing any messages carry that field in the response
+//! headers ("Trailers-Only" responses), which is where the proxy picks it up.
+
+use std::collections::HashMap;
+use poem::http::{ HeaderMap, StatusCode };
+
+/// Returns the default http status for each gRPC status code, following the mapping
+/// suggested by the gRPC project.
+pub(crate) fn default_mapping() -> HashMap<u32, StatusCode> {
+    let client_closed = StatusCode::from_u16( 499 ).expect( "499 is a valid status code" );
+
+    HashMap::from([
+        ( 0, StatusCode::OK ),                       // OK
+        ( 1, client_closed ),                        // CANCELLED
+        ( 2, StatusCode::INTERNAL_SERVER_ERROR ),    // UNKNOWN
+        ( 3, StatusCode::BAD_REQUEST ),              // INVALID_ARGUMENT
+        ( 4, StatusCode::GATEWAY_TIMEOUT ),          // DEADLINE_EXCEEDED
+        ( 5, StatusCode::NOT_FOUND ),                // NOT_FOUND
+        ( 6, StatusCode::CONFLICT ),                 // ALREADY_EXISTS
+        ( 7, StatusCode::FORBIDDEN ),                // PERMISSION_DENIED
+        ( 8, StatusCode::TOO_MANY_REQUESTS ),        // RESOURCE_EXHAUSTED
+        ( 9, StatusCode::BAD_REQUEST ),              // FAILED_PRECONDITION
+        ( 10, StatusCode::CONFLICT ),                // ABORTED
+        ( 11, StatusCode::BAD_REQUEST ),             // OUT_OF_RANGE
+        ( 12, StatusCode::NOT_IMPLEMENTED ),         // UNIMPLEMENTED
+        ( 13, StatusCode::INTERNAL_SERVER_ERROR ),   // INTERNAL
+        ( 14, StatusCode::SERVICE_UNAVAILABLE ),     // UNAVAILABLE
+        ( 15, StatusCode::INTERNAL_SERVER_ERROR ),   // DATA_LOSS
+        ( 16, StatusCode::UNAUTHORIZED ),            // UNAUTHENTICATED
+    ])
+}
+
+/// Returns the http status a response should be sent with according to its
+/// `grpc-status` header, or `None` if it has none or the code isn't mapped.
+pub(crate) fn mapped_status( mapping: &HashMap<u32, StatusCode>, headers: &HeaderMap ) -> Option<StatusCode> {
+    let code = headers.get( "grpc-status" )?.to_str().ok()?.trim().parse::<u32>().ok()?;
+    mapping.get( &code ).copied()
+}
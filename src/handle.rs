@@ -0,0 +1,305 @@
+//! Control over a running proxy endpoint.
+
+use std::{ collections::HashMap, sync::{ Arc, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use crate::{
+    Opaque, ProxyConfig,
+    body::{ UploadCounters, UploadStats },
+    meter::{ ByteCount, ByteLedger },
+    metrics::{ MetricCounters, ProxyMetrics },
+    cache::{ CacheCounters, CacheStats, CacheStore },
+    pool::{ ClientPool, ConnectionStats },
+    target::{ Target, TargetPool },
+    websocket::{ CloseCounters, Shutdown, WebSocketStats },
+};
+
+/// A handle for changing the behavior of a proxy endpoint while it is running. It is
+/// obtained through [ProxyConfig::handle](crate::ProxyConfig::handle), and affects every
+/// endpoint that was given that configuration (or a copy of it). Targets added or
+/// changed on the builder afterwards go into a separate pool, which the handle doesn't
+/// see.
+///
+/// Targets are referred to the same way they were configured, though the scheme, if
+/// any, is not taken into account.
+///
+/// ```
+/// use poem_proxy::ProxyConfig;
+///
+/// let config = ProxyConfig::new( "backend-1:8080" )
+///     .add_target( "backend-2:8080" )
+///     .web_insecure()
+///     .finish();
+/// let handle = config.handle();
+///
+/// // Bring up a new backend, which starts receiving requests right away
+/// handle.add_target( "backend-3:8080" );
+/// assert_eq!( handle.list_targets(), [ "backend-1:8080", "backend-2:8080", "backend-3:8080" ] );
+///
+/// // Stop sending new requests to the first backend, let the current ones finish
+/// handle.drain_target( "backend-1:8080" );
+///
+/// // Once it is idle, it can be taken out of the pool for good
+/// if handle.active_requests( "backend-1:8080" ) == Some( 0 ) {
+///     handle.remove_target( "backend-1:8080" );
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProxyHandle {
+    targets: Arc<TargetPool>,
+    clients: Arc<ClientPool>,
+    active: Arc<AtomicUsize>,
+    cache: Arc<CacheCounters>,
+    store: Option<Opaque<dyn CacheStore>>,
+    websockets: Arc<CloseCounters>,
+    buffered: Arc<AtomicUsize>,
+    shutdown: Arc<Shutdown>,
+    uploads: Arc<UploadCounters>,
+    bytes: Arc<ByteLedger>,
+    metrics: Arc<MetricCounters>,
+
+    /// What's needed to work out the urls responses are cached under
+    web_secure: Option<bool>,
+    port: Option<u16>,
+}
+
+impl ProxyHandle {
+    pub(crate) fn new( config: &ProxyConfig ) -> ProxyHandle {
+        ProxyHandle {
+            targets: config.targets.clone(),
+            clients: config.clients.clone(),
+            active: config.active_requests.clone(),
+            cache: config.cache_counters.clone(),
+            store: config.cache.clone(),
+            websockets: config.ws_closes.clone(),
+            buffered: config.ws_buffered.clone(),
+            shutdown: config.ws_shutdown.clone(),
+            uploads: config.upload_counters.clone(),
+            bytes: config.byte_ledger.clone(),
+            metrics: config.metrics.clone(),
+            web_secure: config.web_secure,
+            port: config.proxy_port,
+        }
+    }
+
+    /// Adds a target to the pool. It is included in the rotation starting with the
+    /// next request. Like when configuring the proxy, the target may start with a
+    /// scheme to pick the protocol it is reached over.
+    pub fn add_target( &self, target: &str ) {
+        self.targets.push( Target::parse( target ) );
+    }
+
+    /// Returns every target in the pool, including those being drained.
+    pub fn list_targets( &self ) -> Vec<String> {
+        self.targets.list().iter().map( Target::to_string ).collect()
+    }
+
+    /// Stops forwarding new requests and websockets to the given target, while letting
+    /// those already forwarded to it finish. Returns whether the target was found.
+    pub fn drain_target( &self, target: &str ) -> bool {
+        self.targets.drain( target )
+    }
+
+    /// Removes the given target from the pool. Requests already forwarded to it are
+    /// left to finish. Returns whether the target was found.
+    pub fn remove_target( &self, target: &str ) -> bool {
+        let removed = self.targets.remove( target );
+        if removed {
+            self.clients.forget( Target::parse( target ).address() );
+        }
+        removed
+    }
+
+    /// Returns the number of requests and websockets currently being forwarded to the
+    /// given target, or `None` if it isn't in the pool.
+    pub fn active_requests( &self, target: &str ) -> Option<usize> {
+        self.targets.active( target )
+    }
+
+    /// Returns whether the given target passed its last
+    /// [health check](crate::ProxyConfig::health_check_path), or `None` if it isn't in
+    /// the pool. Targets count as healthy until their first check, and always when
+    /// health checks are off.
+    pub fn is_healthy( &self, target: &str ) -> Option<bool> {
+        self.targets.is_up( target )
+    }
+
+    /// Returns the number of requests and websockets the endpoint is currently
+    /// handling, across every target.
+    pub fn total_active_requests( &self ) -> usize {
+        self.active.load( Ordering::Relaxed )
+    }
+
+    /// Returns how many forwarded requests reused an open connection to the proxied
+    /// servers, and how many connections had to be opened. See [ConnectionStats] for how
+    /// these are counted.
+    pub fn connection_stats( &self ) -> ConnectionStats {
+        self.clients.stats()
+    }
+
+    /// Returns how often requests were answered from the cache rather than by the
+    /// proxied server, including cached `404` and `410` responses, and how many retried
+    /// requests were answered with the response to the original one. See [CacheStats]
+    /// for how these are counted.
+    pub fn cache_stats( &self ) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Removes the cached responses for the given path, along with every variant of
+    /// them, for each target. The path is the one the proxied server sees, including
+    /// the query if there is one. Returns how many responses were removed.
+    pub async fn invalidate_cache( &self, path: &str ) -> usize {
+        let Some( store ) = &self.store else {
+            return 0;
+        };
+
+        let mut removed = 0;
+        for url in self.cached_urls( path ) {
+            if store.get( &url ).await.is_some() {
+                store.invalidate( &url ).await;
+                removed += 1;
+            }
+            removed += store.invalidate_prefix( &format!( "{}\n", url ) ).await;
+        }
+        removed
+    }
+
+    /// Removes the cached responses for every path that starts with `prefix`, for each
+    /// target. As with [invalidate_cache](ProxyHandle::invalidate_cache), the paths are
+    /// the ones the proxied server sees. Returns how many responses were removed, which
+    /// is always none if the [CacheStore] can't
+    /// [invalidate by prefix](CacheStore::invalidate_prefix).
+    ///
+    /// ```
+    /// use std::{ sync::Arc, time::SystemTime };
+    /// use poem::http::{ HeaderMap, StatusCode };
+    /// use poem_proxy::{ CachedResponse, CacheStore, MemoryCache, ProxyConfig };
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let store = Arc::new( MemoryCache::default() );
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .cache_store( store.clone() )
+    ///     .finish();
+    ///
+    /// let response = CachedResponse {
+    ///     status: StatusCode::OK,
+    ///     headers: HeaderMap::new(),
+    ///     body: "{}".into(),
+    ///     expires_at: SystemTime::now(),
+    /// };
+    /// for path in [ "/products/1", "/products/2?color=red", "/productsale", "/about" ] {
+    ///     store.put( &format!( "http://localhost:5173{}", path ), response.clone() ).await;
+    /// }
+    ///
+    /// // After a deploy, purge every product
+    /// assert_eq!( config.handle().invalidate_cache_prefix( "/products/" ).await, 2 );
+    ///
+    /// assert!( store.get( "http://localhost:5173/products/1" ).await.is_none() );
+    /// assert!( store.get( "http://localhost:5173/products/2?color=red" ).await.is_none() );
+    /// assert!( store.get( "http://localhost:5173/productsale" ).await.is_some() );
+    /// assert!( store.get( "http://localhost:5173/about" ).await.is_some() );
+    /// # }
+    /// ```
+    pub async fn invalidate_cache_prefix( &self, prefix: &str ) -> usize {
+        let Some( store ) = &self.store else {
+            return 0;
+        };
+
+        let mut removed = 0;
+        for url in self.cached_urls( prefix ) {
+            removed += store.invalidate_prefix( &url ).await;
+        }
+        removed
+    }
+
+    /// Returns the urls that responses for `path` are cached under, one for each target.
+    fn cached_urls( &self, path: &str ) -> Vec<String> {
+        self.targets.list()
+            .iter()
+            .filter_map( |target| target.web_base( self.web_secure, self.port ) )
+            .map( |base| base+path )
+            .collect()
+    }
+
+    /// Returns how many websockets were closed cleanly, and how many weren't. See
+    /// [WebSocketClose::is_abnormal](crate::WebSocketClose::is_abnormal) for which
+    /// closes count as abnormal.
+    pub fn websocket_stats( &self ) -> WebSocketStats {
+        self.websockets.stats()
+    }
+
+    /// Returns how many websocket messages are currently
+    /// [read ahead](crate::ProxyConfig::ws_max_inflight_frames) of being sent on, across
+    /// every websocket and in both directions.
+    pub fn buffered_websocket_messages( &self ) -> usize {
+        self.buffered.load( Ordering::Relaxed )
+    }
+
+    /// Returns the number of websockets currently open, including those still being
+    /// connected to the proxied server.
+    pub fn open_websockets( &self ) -> usize {
+        self.shutdown.open_count()
+    }
+
+    /// Closes every websocket for the proxy to shut down, sending both peers a close
+    /// frame with code `1001 Going Away`, and waits up to `grace` for them to finish
+    /// closing. Returns how many websockets are still open after that, such as those
+    /// whose peers stopped reading. From then on, new websockets are answered with
+    /// `503 Service Unavailable`, while web requests are forwarded as usual.
+    ///
+    /// Call this when the server starts shutting down, since the server otherwise
+    /// waits on open websockets like any other connection.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use poem::{ Route, Server, listener::TcpListener };
+    /// use poem_proxy::{ ProxyConfig, ProxyEndpoint };
+    ///
+    /// # async fn run( stop: tokio::sync::oneshot::Receiver<()> ) -> std::io::Result<()> {
+    /// let config = ProxyConfig::new( "localhost:5173" ).ws_insecure().finish();
+    /// let handle = config.handle();
+    /// let app = Route::new().nest( "/", ProxyEndpoint::new( config ) );
+    ///
+    /// Server::new( TcpListener::bind( "127.0.0.1:3000" ) )
+    ///     .run_with_graceful_shutdown( app, async move {
+    ///         let _ = stop.await;
+    ///         let left = handle.shutdown_websockets( Duration::from_secs( 5 ) ).await;
+    ///         if left > 0 {
+    ///             eprintln!( "{} websockets didn't close in time", left );
+    ///         }
+    ///     }, Some( Duration::from_secs( 10 ) ) )
+    ///     .await
+    /// # }
+    /// ```
+    pub async fn shutdown_websockets( &self, grace: Duration ) -> usize {
+        self.shutdown.raise( grace ).await
+    }
+
+    /// Returns how many uploads streamed to the proxied servers completed, and how
+    /// many the client aborted part way. See [UploadStats] for how these are counted.
+    pub fn upload_stats( &self ) -> UploadStats {
+        self.uploads.stats()
+    }
+
+    /// Returns the bytes [accounted](crate::ProxyConfig::account_bytes) to each key
+    /// so far, for the requests that are done.
+    pub fn byte_totals( &self ) -> HashMap<String, ByteCount> {
+        self.bytes.totals()
+    }
+
+    /// Returns how many requests the endpoint answered with each class of status, how
+    /// many websockets are open and how many bytes went through it, for exporting to
+    /// a metrics system. See [ProxyMetrics] for how these are counted.
+    ///
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    ///
+    /// let config = ProxyConfig::new( "localhost:5173" ).web_insecure().finish();
+    /// let metrics = config.handle().metrics_snapshot();
+    /// assert_eq!( metrics.requests, 0 );
+    /// assert_eq!( metrics.open_websockets, 0 );
+    /// ```
+    pub fn metrics_snapshot( &self ) -> ProxyMetrics {
+        self.metrics.snapshot( self.shutdown.open_count() )
+    }
+}
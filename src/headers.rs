@@ -0,0 +1,216 @@
+//! Normalization of forwarded headers for bridging between versions of HTTP.
+//!
+//! HTTP/1.1 treats header names case-insensitively and lets clients manage their
+//! connection through headers, while HTTP/2 requires lowercase names and rejects any
+//! request carrying connection-specific headers. The names held by a [HeaderMap] are
+//! already lowercase, so normalizing mostly means dropping what HTTP/2 won't accept.
+//!
+//! The framing of request bodies is checked here as well, since it is the one
+//! connection-specific header the proxy has to understand before forwarding anything.
+//!
+//! Header values may hold bytes beyond ASCII, which HTTP only allows for compatibility
+//! ([RFC 9110](https://www.rfc-editor.org/rfc/rfc9110#section-5.5)). The server and the
+//! http client pass them on, but the websocket client refuses to send them, so they can
+//! be dealt with before either gets the request.
+//!
+//! Every forwarded request and response loses its hop-by-hop headers, which only apply to
+//! the connection it arrived on. Throughout, request headers keep the order the client
+//! sent them in, apart from the ones the proxy adds itself.
+
+use poem::{ Error, http::{ HeaderMap, HeaderName, HeaderValue, StatusCode, Version, header } };
+
+/// The connection-specific headers that HTTP/2 forbids
+/// ([RFC 9113, section 8.2.2](https://www.rfc-editor.org/rfc/rfc9113#section-8.2.2)).
+const CONNECTION_SPECIFIC: [&str; 5] = [ "connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade" ];
+
+/// The headers that describe the connection between two peers rather than the message,
+/// which a proxy removes before forwarding it
+/// ([RFC 9110, section 7.6.1](https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1)),
+/// along with the credentials and challenges meant for a proxy rather than the server.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection", "keep-alive", "te", "transfer-encoding", "upgrade",
+    "proxy-connection", "proxy-authenticate", "proxy-authorization",
+];
+
+/// The ways in which the proxy can handle request headers whose values aren't plain
+/// ASCII, such as text in Latin-1 (`caf\xe9`). These are forwarded to web servers as they
+/// are, but can't be sent in a websocket handshake, which then fails as though the
+/// proxied server couldn't be reached.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonAsciiHeaderPolicy {
+
+    /// Forward the header as it is.
+    #[default]
+    Forward,
+
+    /// Leave the header out, logging a warning.
+    Drop,
+
+    /// Replace every byte beyond ASCII with `?`, logging a warning.
+    Sanitize,
+
+    /// Answer the request with `400 Bad Request`.
+    Reject,
+}
+
+impl NonAsciiHeaderPolicy {
+
+    /// Deals with the values in `headers` that aren't plain ASCII, returning an error if
+    /// the request should be refused.
+    pub(crate) fn apply( &self, headers: &mut HeaderMap ) -> poem::Result<()> {
+        if *self == NonAsciiHeaderPolicy::Forward || headers.values().all( |value| value.to_str().is_ok() ) {
+            return Ok( () );
+        }
+
+        let mut checked = HeaderMap::with_capacity( headers.len() );
+        for ( name, value ) in headers.iter() {
+            if value.to_str().is_ok() {
+                checked.append( name, value.clone() );
+                continue;
+            }
+
+            match self {
+                NonAsciiHeaderPolicy::Forward => {
+                    checked.append( name, value.clone() );
+                },
+                NonAsciiHeaderPolicy::Drop => {
+                    tracing::warn!( header = %name, "dropped a header whose value isn't ASCII" );
+                },
+                NonAsciiHeaderPolicy::Sanitize => {
+                    tracing::warn!( header = %name, "replaced the bytes beyond ASCII in a header's value" );
+                    let sanitized = value.as_bytes().iter()
+                        .map( |&byte| if byte.is_ascii() { byte } else { b'?' } )
+                        .collect::<Vec<_>>();
+                    let mut sanitized = HeaderValue::from_bytes( &sanitized ).expect( "ASCII header values are valid" );
+                    sanitized.set_sensitive( value.is_sensitive() );
+                    checked.append( name, sanitized );
+                },
+                NonAsciiHeaderPolicy::Reject => {
+                    return Err( Error::from_string(
+                        format!( "The value of the `{}` header is not ASCII!", name ),
+                        StatusCode::BAD_REQUEST,
+                    ) );
+                },
+            }
+        }
+
+        *headers = checked;
+        Ok( () )
+    }
+}
+
+/// Returns a copy of `headers` that is safe to send over any version of HTTP.
+pub(crate) fn normalize( headers: HeaderMap ) -> HeaderMap {
+
+    // Headers named by the `Connection` header only apply to the current connection
+    let listed = headers.get_all( header::CONNECTION )
+        .iter()
+        .filter_map( |value| value.to_str().ok() )
+        .flat_map( |value| value.split( ',' ) )
+        .map( |name| name.trim().to_ascii_lowercase() )
+        .collect::<Vec<_>>();
+
+    let mut normalized = HeaderMap::with_capacity( headers.len() );
+    for ( name, value ) in headers.iter() {
+        let lowercase = name.as_str().to_ascii_lowercase();
+
+        if CONNECTION_SPECIFIC.contains( &lowercase.as_str() ) || listed.contains( &lowercase ) {
+            continue;
+        }
+
+        // `TE` is only allowed to say that trailers are accepted
+        if name == header::TE && value.as_bytes() != b"trailers" {
+            continue;
+        }
+
+        // Drop anything that doesn't survive being parsed as a lowercase name, such as
+        // pseudo-headers
+        let Ok( name ) = HeaderName::from_bytes( lowercase.as_bytes() ) else {
+            continue;
+        };
+
+        normalized.append( name, value.clone() );
+    }
+
+    normalized
+}
+
+/// Removes every value of the `name` header, leaving the other headers in the order they
+/// were in. [HeaderMap::remove] fills the gap with the last header instead, and some
+/// servers fingerprint their clients by the order of their headers, so requests should
+/// reach the proxied server with the client's order wherever possible.
+pub(crate) fn remove_in_order( headers: &mut HeaderMap, name: &str ) {
+    if headers.contains_key( name ) {
+        retain_in_order( headers, |key| key != name );
+    }
+}
+
+/// Removes the headers that only apply to the connection a request or response arrived
+/// on, before it is forwarded on another one
+/// ([RFC 9110, section 7.6.1](https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1)).
+/// That is `Connection` and every header it names, and unless `minimal` is set, the
+/// other [hop-by-hop](HOP_BY_HOP) headers as well. The protocol only requires the former.
+pub(crate) fn remove_hop_by_hop( headers: &mut HeaderMap, minimal: bool ) {
+    let listed = headers.get_all( header::CONNECTION )
+        .iter()
+        .filter_map( |value| value.to_str().ok() )
+        .flat_map( |value| value.split( ',' ) )
+        .map( |name| name.trim().to_ascii_lowercase() )
+        .filter( |name| !name.is_empty() )
+        .collect::<Vec<_>>();
+
+    let removed = |name: &HeaderName| {
+        name == header::CONNECTION
+            || listed.iter().any( |listed| listed == name.as_str() )
+            || ( !minimal && HOP_BY_HOP.contains( &name.as_str() ) )
+    };
+    if headers.keys().any( removed ) {
+        retain_in_order( headers, |name| !removed( name ) );
+    }
+}
+
+/// Keeps only the headers whose name passes `keep`, in the order they were in.
+fn retain_in_order( headers: &mut HeaderMap, keep: impl Fn( &HeaderName ) -> bool ) {
+
+    // Repeated values of a header come without a name, following the first one
+    let mut current = None;
+    for ( key, value ) in std::mem::take( headers ) {
+        if let Some( key ) = key {
+            current = Some( key );
+        }
+        if let Some( key ) = current.as_ref().filter( |key| keep( key ) ) {
+            headers.append( key.clone(), value );
+        }
+    }
+}
+
+/// Makes sure the client framed its request body in a way the proxy understands,
+/// returning a `501 Not Implemented` error for any transfer coding other than `chunked`
+/// and `identity`. Forwarding a body whose framing the proxy can't decode would leave the
+/// proxied server guessing where it ends.
+pub(crate) fn check_transfer_encoding( headers: &HeaderMap ) -> poem::Result<()> {
+    let unsupported = headers.get_all( header::TRANSFER_ENCODING )
+        .iter()
+        .flat_map( |value| value.to_str().unwrap_or( "?" ).split( ',' ) )
+        .map( str::trim )
+        .find( |coding| !coding.eq_ignore_ascii_case( "chunked" ) && !coding.eq_ignore_ascii_case( "identity" ) );
+
+    match unsupported {
+        Some( coding ) => Err( Error::from_string(
+            format!( "Unsupported transfer encoding `{}`! Only `chunked` is supported.", coding ),
+            StatusCode::NOT_IMPLEMENTED,
+        ) ),
+        None => Ok( () ),
+    }
+}
+
+/// Returns the name of the given version of HTTP, the way the specifications write it.
+pub(crate) fn http_version( version: Version ) -> HeaderValue {
+    HeaderValue::from_static( match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "HTTP/1.1",
+    })
+}
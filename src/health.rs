@@ -0,0 +1,70 @@
+//! Active health checks, which take targets that stop answering out of rotation until
+//! they recover.
+//!
+//! Every interval, each target in the pool, including those being drained, is sent a
+//! `GET` for the configured path. A target that answers with the expected status before
+//! the next check is due is up, and any other outcome takes it down. The checks run in
+//! a task of their own, which ends once every copy of the configuration is gone.
+
+use std::{ sync::{ Arc, Weak, atomic::{ AtomicBool, Ordering } }, time::Duration };
+use futures_util::future;
+use poem::http::StatusCode;
+use crate::target::{ Target, TargetPool };
+
+/// Whether the health checks of a configuration have been started, shared by every
+/// clone of it. The checks stop once the last clone is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct HealthChecker {
+    started: AtomicBool,
+}
+
+impl HealthChecker {
+
+    /// Marks the checks as started, returning whether they already were.
+    pub(crate) fn start( &self ) -> bool {
+        self.started.swap( true, Ordering::Relaxed )
+    }
+}
+
+/// What a health check asks of each target.
+#[derive(Debug)]
+pub(crate) struct Probe {
+    pub(crate) path: String,
+    pub(crate) interval: Duration,
+    pub(crate) status: StatusCode,
+
+    /// Whether targets without a scheme are reached securely
+    pub(crate) secure: bool,
+
+    /// The proxy's `proxy_port` setting, which replaces the port of every target
+    pub(crate) port: Option<u16>,
+}
+
+/// Checks every target in the pool each interval, for as long as `checker` is alive.
+pub(crate) async fn run( checker: Weak<HealthChecker>, targets: Arc<TargetPool>, client: reqwest::Client, probe: Probe ) {
+    while checker.strong_count() > 0 {
+        let results = future::join_all( targets.list().into_iter().map( |target| check( &client, &probe, target ) ) ).await;
+        for ( address, up ) in results {
+            if targets.set_up( &address, up ) {
+                match up {
+                    true => tracing::info!( target = %address, "target passed its health check and is back in rotation" ),
+                    false => tracing::warn!( target = %address, "target failed its health check and was taken out of rotation" ),
+                }
+            }
+        }
+        tokio::time::sleep( probe.interval ).await;
+    }
+}
+
+/// Sends a target the health check, returning its address and whether it passed.
+async fn check( client: &reqwest::Client, probe: &Probe, target: Target ) -> ( String, bool ) {
+    let url = target.web_base( Some( probe.secure ), probe.port ).unwrap_or_default() + &probe.path;
+    let up = match client.get( &url ).timeout( probe.interval ).send().await {
+        Ok( response ) => response.status() == probe.status,
+        Err( error ) => {
+            tracing::debug!( %url, %error, "health check failed" );
+            false
+        },
+    };
+    ( target.address().to_owned(), up )
+}
@@ -0,0 +1,43 @@
+//! Handling of requests that don't say which host they are for.
+//!
+//! HTTP/1.0 clients may leave out the `Host` header, which HTTP/1.1 requires. Without
+//! it, the proxied server can't route the request by host, and rewrites that match on
+//! the header have nothing to match against.
+
+use poem::{ Error, Request, http::{ HeaderMap, HeaderValue, StatusCode, header } };
+
+/// The ways in which the proxy can handle requests without a `Host` header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingHostPolicy {
+
+    /// Send the address of the target the request is forwarded to as the `Host`.
+    #[default]
+    UseTarget,
+
+    /// Answer the request with `400 Bad Request`.
+    Reject,
+}
+
+impl MissingHostPolicy {
+
+    /// Makes sure the headers of a request about to be forwarded to `address` name a
+    /// host, adding one or returning an error if the client didn't. HTTP/2 requests
+    /// carry the host in their url instead, which counts as well.
+    pub(crate) fn apply( &self, req: &Request, address: &str, headers: &mut HeaderMap ) -> poem::Result<()> {
+        if headers.contains_key( header::HOST ) || req.uri().authority().is_some() {
+            return Ok( () );
+        }
+
+        match self {
+            MissingHostPolicy::UseTarget => {
+                let host = HeaderValue::from_str( address )
+                    .map_err( |_| Error::from_string( "The proxied server's address is not a valid host!", StatusCode::BAD_GATEWAY ) )?;
+                headers.insert( header::HOST, host );
+                Ok( () )
+            },
+            MissingHostPolicy::Reject => {
+                Err( Error::from_string( "The request has no Host header!", StatusCode::BAD_REQUEST ) )
+            },
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Replaying responses to requests that carry an `Idempotency-Key` header.
+//!
+//! Clients retrying a write send the same key with every attempt, so that the write
+//! only happens once. The proxy keeps the response to the first request with a key for
+//! a while, answering any repeat of it with that response instead of forwarding it.
+//! Repeats that arrive while the first request is still being forwarded wait for it.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{ Duration, SystemTime },
+};
+use bytes::Bytes;
+use poem::http::{ HeaderMap, Method, StatusCode };
+use tokio::sync::watch;
+use crate::CachedResponse;
+
+/// The state of the request made with a single key.
+#[derive(Debug)]
+enum Entry {
+
+    /// The first request with the key is still being forwarded
+    Pending( watch::Receiver<Option<CachedResponse>> ),
+
+    /// The response to the first request with the key
+    Done( CachedResponse ),
+}
+
+/// The responses to requests with an idempotency key, kept for a fixed time.
+#[derive(Debug)]
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// What to do with a request that carries an idempotency key.
+pub(crate) enum Claim<'a> {
+
+    /// Answer the request with the response to the first one
+    Replay( CachedResponse ),
+
+    /// Forward the request, handing its response to the claim afterwards
+    Forward( Pending<'a> ),
+}
+
+/// A request being forwarded on behalf of everyone using its key. If it is dropped
+/// without a response, the key is released and waiting requests are forwarded too.
+pub(crate) struct Pending<'a> {
+    store: &'a IdempotencyStore,
+    key: String,
+    sender: watch::Sender<Option<CachedResponse>>,
+    finished: bool,
+}
+
+impl IdempotencyStore {
+
+    /// Creates a store that keeps responses for `ttl`.
+    pub(crate) fn new( ttl: Duration ) -> IdempotencyStore {
+        IdempotencyStore { ttl, entries: Mutex::default() }
+    }
+
+    /// Claims the idempotency key of a request, or returns `None` if it doesn't have
+    /// one. Keys are only shared by requests with the same method and url.
+    pub(crate) async fn claim( &self, method: &Method, uri: &str, headers: &HeaderMap ) -> Option<Claim<'_>> {
+        let key = headers.get( "idempotency-key" )?.to_str().ok()?;
+        let key = format!( "{} {}\n{}", method, uri, key );
+
+        loop {
+            let mut pending = {
+                let mut entries = self.entries.lock().unwrap_or_else( |e| e.into_inner() );
+                entries.retain( |_, entry| !matches!( entry, Entry::Done( response ) if !response.is_fresh() ) );
+
+                match entries.get( &key ) {
+                    Some( Entry::Done( response ) ) => return Some( Claim::Replay( response.clone() ) ),
+                    Some( Entry::Pending( receiver ) ) => receiver.clone(),
+                    None => {
+                        let ( sender, receiver ) = watch::channel( None );
+                        entries.insert( key.clone(), Entry::Pending( receiver ) );
+                        return Some( Claim::Forward( Pending { store: self, key, sender, finished: false } ) );
+                    },
+                }
+            };
+
+            // Wait for the first request to finish, then try again. If it failed, the
+            // key was released and this request is the next to claim it
+            let response = pending.wait_for( Option::is_some ).await
+                .ok()
+                .and_then( |response| response.clone() );
+            if let Some( response ) = response {
+                return Some( Claim::Replay( response ) );
+            }
+        }
+    }
+}
+
+impl Pending<'_> {
+
+    /// Keeps the response to the request, answering every other request with the same
+    /// key with it from now on.
+    pub(crate) fn finish( mut self, status: StatusCode, headers: &HeaderMap, body: &Bytes ) {
+        let response = CachedResponse {
+            status,
+            headers: headers.clone(),
+            body: body.clone(),
+            expires_at: SystemTime::now() + self.store.ttl,
+        };
+
+        let mut entries = self.store.entries.lock().unwrap_or_else( |e| e.into_inner() );
+        entries.insert( self.key.clone(), Entry::Done( response.clone() ) );
+        self.sender.send_replace( Some( response ) );
+        self.finished = true;
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop( &mut self ) {
+        if !self.finished {
+            self.store.entries.lock().unwrap_or_else( |e| e.into_inner() ).remove( &self.key );
+        }
+    }
+}
@@ -1,5 +1,5 @@
 //! Poem-proxy is a simple and easy-to-use proxy [Endpoint](poem::Endpoint) compatible with the
-//! [Poem Web Framework](poem). It supports the forwarding of http get and post requests
+//! [Poem Web Framework](poem). It supports the forwarding of http requests of any method
 //! as well as websockets right out of the box!
 //! 
 //! # Table of Contents
@@ -7,6 +7,7 @@
 //! - [Quickstart](#quickstart)
 //! - [Proxy Configuration](#proxy-configuration)
 //! - [Endpoint](#endpoint)
+//! - [Limitations](#limitations)
 //! 
 //! # Quickstart
 //! 
@@ -62,26 +63,248 @@
 //! This can be used with poem's built-in routing. You can apply specific request types, 
 //! or even use [at](poem::Route::at) and [nest](poem::Route::at).
 //! 
+//! Requests are forwarded with the method they were sent with, whatever it is. Besides
+//! the standard methods, that includes extension methods such as WebDAV's `PROPFIND`
+//! and `MKCOL` or a custom API's own, with their case left as it is. Only the standard
+//! methods that are idempotent are ever [retried](ProxyConfig::with_retries) or
+//! [failed over](ProxyConfig::failover).
+//! 
+//! `TRACE` and `OPTIONS` requests honor `Max-Forwards`: the proxy lowers it by one
+//! before forwarding them, and answers them itself once it reaches zero, so that chains
+//! of proxies can be probed one hop at a time.
+//! 
+//! Header values can't be used to smuggle extra headers through to the proxied server.
+//! Poem's http server answers requests with a carriage return, line feed or other control
+//! character in a header value with `400 Bad Request` before they reach the proxy, and
+//! the headers the proxy adds itself are held to the same rules.
+//! 
 //! The [Quickstart](#quickstart) section shows a working example, so this section doesn't.
+//! 
+//! The [proxy] handler takes its configuration from the endpoint's data. Alternatively,
+//! a [ProxyEndpoint] holds its own, so it can be mounted as it is:
+//! 
+//! ```
+//! use poem::Route;
+//! use poem_proxy::{ ProxyConfig, ProxyEndpoint };
+//! 
+//! let api = ProxyConfig::new( "localhost:8080" ).web_insecure().enable_nesting().finish();
+//! let app = Route::new().nest( "/api", ProxyEndpoint::new( api ) );
+//! ```
+//! 
+//! Each request is handled inside a [tracing] span named `proxy`, which records its
+//! method and path, the url it was forwarded to (`upstream`) and the status the proxied
+//! server answered with (`upstream_status`). An event with the final status and the time
+//! taken (`elapsed_ms`) is logged once the request is handled, and websockets log when
+//! they are opened and closed. Install a subscriber, such as `tracing-subscriber`, to
+//! see them.
+//! 
+//! # Limitations
+//! 
+//! Some things the proxy can't do, because the libraries it is built on don't allow it:
+//! 
+//! - Request bodies can't be hashed or signed into a header as they stream through to
+//!   the proxied server. The header has to be sent before the body, and the http client
+//!   can't send trailers, so a digest would mean holding every upload in memory first.
+//! - Websocket messages aren't compressed toward clients. `permessage-deflate` has to be
+//!   negotiated and marked on each frame, and neither poem's websockets nor tungstenite
+//!   0.20 support the extension, so frames are always relayed uncompressed.
+//! - The protocol a client negotiated through ALPN isn't known, as poem's TLS listeners
+//!   don't pass it on. The [HTTP version](ProxyConfig::forward_http_version) its
+//!   requests arrive over can be forwarded instead.
+//! - Proxied servers that only speak HTTP/3 can't be reached. The http client
+//!   connects over TCP, with HTTP/1.1 or [HTTP/2](ProxyConfig::upstream_http2), and has
+//!   no QUIC transport to fall back on.
+//! - TLS sessions with `https` servers aren't resumed. The http client's TLS backend,
+//!   native-tls, keeps no session cache and has no ticket options, so every new
+//!   connection makes a full handshake. Connections are reused while the server keeps
+//!   them open, which is what keeps handshakes down.
 
-use futures_util::{ SinkExt, StreamExt };
+// Poem's error type is large, but it is what every handler returns
+#![allow(clippy::result_large_err)]
+
+use futures_util::{ SinkExt, StreamExt, future::{ self, Either }, stream::{ self, BoxStream } };
 use poem::{
     Request, Result, Response, Error, handler, Body, FromRequest, IntoResponse, 
-    http::{ StatusCode, Method, HeaderMap },
-    web::{ Data, websocket::{ WebSocket } }
+    http::{ StatusCode, Method, HeaderMap, HeaderName, HeaderValue, header, header::InvalidHeaderValue },
+    web::{ Data, headers::Authorization, websocket::{ CloseCode, Message, WebSocket } }
+};
+use bytes::Bytes;
+use tokio::sync::{ RwLock, Semaphore };
+use tracing::{ Instrument, Span, field };
+use std::{
+    collections::HashMap, fmt, net::IpAddr, ops::Deref,
+    sync::{ Arc, atomic::{ AtomicUsize, Ordering } },
+    time::{ Duration, Instant, SystemTime },
 };
-use tokio_tungstenite::connect_async;
-use tokio::sync::RwLock;
-use std::sync::Arc;
+
+mod pool;
+use pool::ClientPool;
+pub use pool::ConnectionStats;
+
+mod dns;
+use dns::CachingResolver;
+pub use hyper::client::connect::dns::Name;
+pub use reqwest::dns::{ Addrs, Resolve, Resolving };
+pub use reqwest::{ Certificate, RequestBuilder };
+
+mod cookie;
+pub use cookie::{ CookiePolicy, CookieRewrite, SameSite };
+
+mod cache;
+use cache::CacheCounters;
+pub use cache::{ CachedResponse, CachePolicy, CacheStats, CacheStore, MemoryCache };
+pub use async_trait::async_trait;
+
+mod capture;
+pub use capture::{ CapturedExchange, CaptureSink, TrafficCapture };
+
+mod auth;
+pub use auth::{ Authentication, ClientAuthenticator };
+
+mod tap;
+pub use tap::{ FrameDirection, FrameSink, TappedFrame, WebSocketTap };
+
+mod transform;
+pub use transform::{ BodyTransform, ResponseTransform };
+
+mod body;
+use body::UploadCounters;
+pub use body::UploadStats;
+mod client;
+mod content_type;
+use content_type::ContentTypeRule;
+use client::ClientLimiter;
+mod decompress;
+mod forwarded;
+mod error;
+pub use error::{ ErrorFormat, ErrorPage, ProxyError, ProxyErrorKind };
+mod grpc;
+mod idempotency;
+use idempotency::{ Claim, IdempotencyStore };
+mod headers;
+pub use headers::NonAsciiHeaderPolicy;
+mod health;
+use health::HealthChecker;
+mod max_forwards;
+mod meter;
+use meter::{ ByteLedger, Meter };
+pub use meter::ByteCount;
+mod metrics;
+use metrics::MetricCounters;
+pub use metrics::ProxyMetrics;
+mod negotiation;
+mod queue;
+use queue::WaitingLine;
+mod status;
+use status::StatusFilter;
+mod timing;
+use timing::RequestTimer;
+mod websocket;
+use websocket::CloseCounters;
+pub use websocket::{ WebSocketClose, WebSocketStats };
+
+mod early_data;
+pub use early_data::EarlyDataPolicy;
+
+mod host;
+pub use host::MissingHostPolicy;
+
+mod upgrade;
+pub use upgrade::InsecureRequestPolicy;
+
+mod socks;
+pub use socks::Socks5Proxy;
+
+mod redirect;
+pub use redirect::RedirectMode;
+mod retry;
+
+mod target;
+use target::{ ActiveGuard, Target, TargetPool };
+pub use target::Cost;
+
+mod rewrite;
+pub use rewrite::{ HeaderRewrite, PathRewrite, TrailingSlashPolicy };
+
+mod handle;
+pub use handle::ProxyHandle;
+mod endpoint;
+pub use endpoint::{ ProxyEndpoint, SharedProxyConfig };
+mod trace;
+
+#[cfg(feature = "fault-injection")]
+mod fault;
+#[cfg(feature = "fault-injection")]
+pub use fault::FaultInjection;
+
+/// A shared value held by the [ProxyConfig] that has no meaningful debug output, such
+/// as a user-provided store or callback.
+pub(crate) struct Opaque<T: ?Sized>( Arc<T> );
+
+impl<T: ?Sized> Clone for Opaque<T> {
+    fn clone( &self ) -> Self {
+        Opaque( self.0.clone() )
+    }
+}
+
+impl<T: ?Sized> Deref for Opaque<T> {
+    type Target = T;
+
+    fn deref( &self ) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Opaque<T> {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        f.write_str( "<opaque>" )
+    }
+}
+
+/// A callback that picks the upstream timeout for a request.
+type TimeoutSelector = dyn Fn( &Request ) -> Duration + Send + Sync;
+
+/// A callback that picks the largest request body allowed for a request.
+type BodySizeSelector = dyn Fn( &Request ) -> Option<usize> + Send + Sync;
+
+/// A callback that decides whether the response to a request is streamed.
+type StreamSelector = dyn Fn( &Request ) -> Option<bool> + Send + Sync;
+
+/// A callback that works out how demanding a request is to serve.
+type CostSelector = dyn Fn( &Request ) -> Cost + Send + Sync;
+
+/// A callback that is told about requests the proxied server failed to answer.
+type ErrorHook = dyn Fn( &Request, &ProxyError ) + Send + Sync;
+
+/// A callback that makes the last changes to a request before it is sent upstream.
+type RequestHook = dyn Fn( reqwest::RequestBuilder, &Request ) -> reqwest::RequestBuilder + Send + Sync;
+
+/// A callback that builds the response sent in place of an upstream error.
+type ErrorHandler = dyn Fn( &ProxyError ) -> Response + Send + Sync;
+
+/// A callback that is told how each websocket was closed.
+type CloseHook = dyn Fn( &WebSocketClose ) + Send + Sync;
+
+/// A callback that picks the key the bytes of a request are accounted under.
+type ByteKeySelector = dyn Fn( &Request ) -> String + Send + Sync;
+
+/// A callback that is told how many bytes each request moved, along with its key.
+type ByteHook = dyn Fn( &str, ByteCount ) + Send + Sync;
 
 /// A configuration object that allows for fine-grained control over a proxy endpoint.
 #[derive(Clone, Debug)]
 pub struct ProxyConfig {
 
-    /// This is the url where requests and websocket connections are to be
-    /// forwarded to. Port numbers are supported here, though they may be
-    /// broken off into their own parameter in the future.
-    proxy_target: String,
+    /// These are the servers where requests and websocket connections are to be
+    /// forwarded to, in round-robin order. Port numbers are supported here, unless
+    /// `proxy_port` is set. The pool is shared with finished copies of the
+    /// configuration and their handles, see [ProxyConfig::targets_mut].
+    targets: Arc<TargetPool>,
+
+    /// The port to reach every target on, replacing any port given with the target
+    /// itself. If not set, targets are reached on the port they were given with, or
+    /// the default port for their protocol.
+    proxy_port: Option<u16>,
 
     /// Whether to use https (true) or http for requests to the proxied server. If not
     /// set, the proxy will not forward web requests.
@@ -94,23 +317,647 @@ pub struct ProxyConfig {
     /// Whether or not nesting should be supported when forwarding requests
     /// to the server.
     support_nesting: bool,
+
+    /// The prefix removed from the path of every request before it is forwarded, for
+    /// endpoints mounted under a path the proxied server doesn't know about. If not
+    /// set, paths are forwarded as they are.
+    strip_prefix: Option<String>,
+
+    /// Whether forwarded paths keep, gain or lose their trailing slash
+    trailing_slash: TrailingSlashPolicy,
+
+    /// Whether the `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// headers should be added to requests, telling the proxied server about the client.
+    add_forwarded_headers: bool,
+
+    /// Whether the `Keep-Alive` hints sent back by the proxied server should be used
+    /// to rotate connections before the server closes them.
+    honor_keep_alive: bool,
+
+    /// The maximum number of idle connections to keep open to each upstream host. If
+    /// not set, there is no limit.
+    max_connections_per_host: Option<usize>,
+
+    /// Works out the cost of each request, which picks the tier of targets it is
+    /// forwarded to. If not set, requests are spread across every target.
+    cost_selector: Option<Opaque<CostSelector>>,
+
+    /// The clients shared by every clone of this configuration, one per host when
+    /// keep-alive hints are being honored and a single one otherwise.
+    clients: Arc<ClientPool>,
+
+    /// How the names of the proxied servers are resolved, shared by every client. If
+    /// not set, the http client resolves them itself.
+    dns: Option<Arc<CachingResolver>>,
+
+    /// The number of requests and websockets being handled, shared by every clone of
+    /// this configuration.
+    active_requests: Arc<AtomicUsize>,
+
+    /// The number of requests and websockets above which new requests are turned away.
+    /// If not set, requests are never turned away for being too many.
+    overload_threshold: Option<usize>,
+
+    /// The clients turned away for overload that are waiting for room, if they are
+    /// kept in line at all.
+    overload_queue: Option<Arc<WaitingLine>>,
+
+    /// The number of requests each client has in flight, shared by every clone of this
+    /// configuration.
+    client_requests: Arc<ClientLimiter>,
+
+    /// The number of requests and websockets a single client may have in flight at
+    /// once. If not set, clients aren't limited individually.
+    max_connections_per_client: Option<usize>,
+
+    /// The addresses of the proxies in front of this one, whose `X-Forwarded-*`
+    /// headers are trusted to describe the client.
+    trusted_proxies: Vec<IpAddr>,
+
+    /// The check clients have to pass before their requests are forwarded. If not set,
+    /// every client is let through.
+    authenticator: Option<Opaque<dyn ClientAuthenticator>>,
+
+    /// The request header in which to send the identity the authenticator found for
+    /// the client. If not set, the identity isn't sent.
+    identity_header: Option<HeaderName>,
+
+    /// The `Authorization` header sent to the proxied server with every request in
+    /// place of the client's. If not set, the client's is forwarded.
+    upstream_authorization: Option<HeaderValue>,
+
+    /// The attributes that every cookie set by the proxied server must have. If not
+    /// set, cookies are forwarded as they are.
+    cookie_policy: Option<CookiePolicy>,
+
+    /// The rewrite of the domain and path of every cookie set by the proxied server, if
+    /// any.
+    rewrite_cookies: Option<CookieRewrite>,
+
+    /// The largest request body, in bytes, the proxy accepts. If not set, bodies of any
+    /// size are accepted.
+    max_body_size: Option<usize>,
+
+    /// The largest response body the proxy relays from the proxied server, in bytes. If
+    /// not set, response bodies can be of any size.
+    max_response_body_size: Option<usize>,
+
+    /// A callback that picks the largest request body allowed for each request,
+    /// overriding `max_body_size` whenever it returns a limit.
+    body_size_selector: Option<Opaque<BodySizeSelector>>,
+
+    /// The ports the proxy is allowed to connect to on the proxied server. If not set,
+    /// any port is allowed.
+    allowed_ports: Option<Vec<u16>>,
+
+    /// The request header naming the server each request is forwarded to. If not set,
+    /// or a request doesn't have it, the request goes to one of the targets.
+    upstream_header: Option<HeaderName>,
+
+    /// The only servers the upstream header may name.
+    allowed_upstreams: Vec<Target>,
+
+    /// The content types allowed for request bodies, by path. Paths without a rule
+    /// accept any content type.
+    content_types: Vec<ContentTypeRule>,
+
+    /// The content types given to responses the proxied server sent without one, by
+    /// path.
+    default_content_types: Vec<( String, HeaderValue )>,
+
+    /// The transform the bodies of responses are run through. If not set, bodies are
+    /// relayed as they are.
+    response_transform: Option<ResponseTransform>,
+
+    /// Whether `gzip` and `deflate` response bodies are decoded before they are
+    /// forwarded, rather than passed through compressed.
+    decompress_upstream: bool,
+
+    /// Whether a websocket close frame from one peer should only end that direction
+    /// of the relay, leaving the other open until it closes as well.
+    ws_half_close: bool,
+
+    /// How long to wait for the proxied server to respond to a request. If not set,
+    /// the proxy waits indefinitely.
+    upstream_timeout: Option<Duration>,
+
+    /// How much longer to wait for the proxied server for every megabyte in the body
+    /// of the request.
+    timeout_per_megabyte: Duration,
+
+    /// Whether the proxy should continue (or start) a W3C trace context for every
+    /// request it forwards.
+    propagate_trace_context: bool,
+
+    /// The header in which to tell the proxied server which version of HTTP the client
+    /// sent its request with. If not set, the version isn't sent.
+    http_version_header: Option<HeaderName>,
+
+    /// Whether to speak HTTP/2 to the proxied server without first negotiating it.
+    upstream_http2: bool,
+
+    /// Whether to accept any certificate from the proxied server, even an invalid or
+    /// self-signed one.
+    danger_accept_invalid_certs: bool,
+
+    /// The certificates trusted to sign the proxied server's certificate, besides the
+    /// system's.
+    root_certificates: Vec<Certificate>,
+
+    /// What to do with request headers whose values aren't plain ASCII
+    non_ascii_headers: NonAsciiHeaderPolicy,
+
+    /// Whether the names of forwarded request headers should be checked and normalized
+    /// so that they are valid for any version of HTTP.
+    normalize_headers: bool,
+
+    /// Where responses from the proxied server are cached. If not set, nothing is
+    /// cached.
+    cache: Option<Opaque<dyn CacheStore>>,
+
+    /// How responses are cached under particular paths, in place of the defaults
+    cache_policies: Vec<( String, CachePolicy )>,
+
+    /// How long `404 Not Found` and `410 Gone` responses are cached when the proxied
+    /// server doesn't say. If not set, they aren't cached.
+    negative_cache_ttl: Option<Duration>,
+
+    /// How often requests were answered from the cache, shared by every clone of this
+    /// configuration.
+    cache_counters: Arc<CacheCounters>,
+
+    /// Picks how long to wait for the proxied server based on the request itself.
+    /// If set, this takes precedence over the upstream timeout.
+    timeout_selector: Option<Opaque<TimeoutSelector>>,
+
+    /// A callback run whenever the proxied server can't be reached or fails to respond.
+    error_hook: Option<Opaque<ErrorHook>>,
+
+    /// A callback that makes the last changes to each web request sent to the proxied
+    /// server.
+    request_hook: Option<Opaque<RequestHook>>,
+
+    /// How redirects sent back by the proxied server are handled.
+    redirect_mode: RedirectMode,
+
+    /// The most redirects followed for a single request, unless its target has a limit
+    /// of its own.
+    max_redirects: usize,
+
+    /// How long the proxy may spend on a web request as a whole. If not set, there is
+    /// no limit.
+    request_timeout: Option<Duration>,
+
+    /// How long a web request may last as a whole, including sending a streamed response
+    /// body to the client. If not set, there is no limit.
+    max_request_duration: Option<Duration>,
+
+    /// The paths under which requests are long-polling, with the server holding them
+    /// open until it has something to say
+    long_poll_paths: Vec<String>,
+
+    /// A header whose presence marks a request as long-polling
+    long_poll_header: Option<HeaderName>,
+
+    /// How long a long-polling request may last, in place of every other timeout
+    long_poll_timeout: Duration,
+
+    /// The http status to send for each gRPC status code reported by the proxied
+    /// server. If not set, gRPC statuses are left alone.
+    grpc_status_mapping: Option<HashMap<u32, StatusCode>>,
+
+    /// The method to send to the proxied server in place of each method received from
+    /// the client. Methods without an entry are forwarded as they are.
+    method_rewrites: HashMap<Method, Method>,
+
+    /// What to do with requests that were sent as TLS early data.
+    early_data: EarlyDataPolicy,
+
+    /// What to do with plain http requests asking to be upgraded to https.
+    insecure_requests: InsecureRequestPolicy,
+
+    /// What to do with requests that don't have a `Host` header.
+    missing_host: MissingHostPolicy,
+
+    /// The responses kept for requests with an `Idempotency-Key` header, shared by
+    /// every clone of this configuration. If not set, the header is just forwarded.
+    idempotency: Option<Arc<IdempotencyStore>>,
+
+    /// Which requests and responses are captured for debugging. If not set, nothing
+    /// is captured.
+    capture: Option<TrafficCapture>,
+
+    /// How long clients are told to wait before retrying when the proxy itself turns
+    /// them away with a `503` or `429`.
+    retry_after: Duration,
+
+    /// The status to answer with when the proxied server responds with a content type
+    /// the client didn't ask for. If not set, responses aren't checked.
+    validate_content_negotiation: Option<StatusCode>,
+
+    /// The most websocket messages that may be buffered between receiving them from
+    /// one peer and sending them to the other, in each direction. If not set, messages
+    /// are only received as fast as they can be sent on.
+    ws_max_inflight_frames: Option<usize>,
+
+    /// The response header in which to tell the client the address of the proxied
+    /// server that answered. If not set, the address isn't sent.
+    upstream_address_header: Option<HeaderName>,
+
+    /// Whether responses tell the client how long each phase of the request took in a
+    /// `Server-Timing` header.
+    server_timing: bool,
+
+    /// The `Timing-Allow-Origin` header sent with every response, which lets pages on
+    /// other origins read its timings. If not set, the proxied server's is forwarded.
+    timing_allow_origin: Option<HeaderValue>,
+
+    /// The size, in bytes, from which responses are passed on to the client as they
+    /// arrive instead of being read in full first. Responses of unknown length are
+    /// always streamed.
+    stream_threshold: usize,
+
+    /// A callback that decides whether to stream the response to each request,
+    /// overriding `stream_threshold` whenever it returns a decision.
+    stream_selector: Option<Opaque<StreamSelector>>,
+
+    /// The size, in bytes, from which request bodies are sent on to the proxied server
+    /// as they arrive instead of being read in full first. Bodies of unknown length are
+    /// always streamed.
+    upload_stream_threshold: usize,
+
+    /// How many streamed uploads completed and how many the client aborted, shared by
+    /// every clone of this configuration.
+    upload_counters: Arc<UploadCounters>,
+
+    /// Picks the key the bytes of each request are accounted under. If not set, bytes
+    /// aren't accounted.
+    byte_key: Option<Opaque<ByteKeySelector>>,
+
+    /// A callback run with the bytes of each accounted request once it is done.
+    byte_hook: Option<Opaque<ByteHook>>,
+
+    /// The bytes accounted to each key, shared by every clone of this configuration.
+    byte_ledger: Arc<ByteLedger>,
+
+    /// How many requests were answered with each class of status and how many bytes
+    /// went through, shared by every clone of this configuration.
+    metrics: Arc<MetricCounters>,
+
+    /// How many other targets to try when the one chosen for an idempotent request
+    /// can't be reached
+    failover_attempts: u32,
+
+    /// How many times to resend an idempotent request to the same target when it fails
+    /// in a way that is likely to pass
+    retry_attempts: u32,
+
+    /// The path every target is sent a health check for. If not set, targets aren't
+    /// checked.
+    health_check_path: Option<String>,
+
+    /// How often targets are checked, which is also how long they have to answer
+    health_check_interval: Duration,
+
+    /// The status a target has to answer a health check with to be up
+    health_check_status: StatusCode,
+
+    /// Whether the health checks have been started, shared by every clone of this
+    /// configuration.
+    health_checker: Arc<HealthChecker>,
+
+    /// How many times to retry connecting to the proxied server when a websocket is
+    /// opened, if no connection could be made.
+    ws_connect_retries: u32,
+
+    /// The websocket handshakes with the proxied server that may be in progress at
+    /// once, shared by every clone of this configuration. If not set, there is no limit.
+    ws_handshakes: Option<Arc<Semaphore>>,
+
+    /// The only subprotocols clients may ask for when opening a websocket. If not set,
+    /// any subprotocol may be asked for.
+    ws_allowed_subprotocols: Option<Vec<String>>,
+
+    /// The largest message payload, in bytes, either peer of a websocket may send. If
+    /// not set, only the websocket library's own limits apply.
+    ws_max_frame_size: Option<usize>,
+
+    /// The largest total size, in bytes, of the headers a client may open a websocket
+    /// with. If not set, only the server's own limits apply.
+    ws_max_handshake_size: Option<usize>,
+
+    /// The SOCKS5 proxy through which websockets to the proxied server are opened. If
+    /// not set, they are opened directly.
+    ws_socks5_proxy: Option<Socks5Proxy>,
+
+    /// How long a websocket may go without a message in either direction before it is
+    /// closed. If not set, idle websockets are left open.
+    ws_idle_timeout: Option<Duration>,
+
+    /// How often to ping the client of a websocket, closing it if the client stops
+    /// answering. If not set, no pings are sent.
+    ws_keepalive: Option<Duration>,
+
+    /// Whether websockets are closed when the target they were forwarded to is removed
+    /// from the pool, rather than left open until either peer closes them.
+    ws_close_on_removal: bool,
+
+    /// How many websockets were closed cleanly and how many weren't, shared by every
+    /// clone of this configuration.
+    ws_closes: Arc<CloseCounters>,
+
+    /// How many websocket messages are [read ahead](ProxyConfig::ws_max_inflight_frames)
+    /// and waiting to be sent on, shared by every clone of this configuration.
+    ws_buffered: Arc<AtomicUsize>,
+
+    /// A callback that is told how each websocket was closed.
+    ws_close_hook: Option<Opaque<CloseHook>>,
+
+    /// The signal that closes every websocket when the proxy shuts down, shared by
+    /// every clone of this configuration.
+    ws_shutdown: Arc<websocket::Shutdown>,
+
+    /// Where copies of a sample of the messages relayed over websockets are sent
+    ws_tap: Option<WebSocketTap>,
+
+    /// Which statuses from the proxied server may be forwarded to the client. If not
+    /// set, every status is forwarded.
+    status_filter: Option<StatusFilter>,
+
+    /// The status sent to the client in place of one the filter blocks.
+    blocked_status: StatusCode,
+
+    /// How the bodies of the errors generated by the proxy itself are formatted.
+    error_format: ErrorFormat,
+
+    /// The responses sent in place of each kind of upstream error. Kinds without one
+    /// are answered with the error itself.
+    error_pages: HashMap<ProxyErrorKind, ErrorPage>,
+
+    /// Builds the response sent in place of the upstream errors without an error page.
+    /// If not set, they are answered with the error itself.
+    error_handler: Option<Opaque<ErrorHandler>>,
+
+    /// Whether error responses from the proxied server are treated as upstream errors,
+    /// rather than passed through.
+    handle_error_statuses: bool,
+
+    /// Whether server error responses (`5xx`) from the proxied server are treated as
+    /// upstream errors, rather than passed through.
+    treat_upstream_errors_as_failures: bool,
+
+    /// Whether requests and responses are forwarded with as few changes to their
+    /// headers as possible, overriding the options that would add or remove them.
+    transparent: bool,
+
+    /// Whether the proxy panics when it runs into a condition that points to a bug,
+    /// rather than reporting it and carrying on as best it can.
+    strict_mode: bool,
+
+    /// The faults to inject into forwarded requests, for chaos testing. Only available
+    /// with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    fault_injection: Option<FaultInjection>,
 }
 
 impl Default for ProxyConfig {
 
     /// Returns the default value for the [ProxyConfig], which corresponds
     /// to the following:
-    /// > `proxy_target: "http://localhost:3000"`
+    /// > `targets: ["http://localhost:3000"]`
+    /// 
+    /// > `proxy_port: None`
     /// 
     /// > `web_secure: None`
     /// 
     /// > `ws_secure: None`
     /// 
     /// > `support_nesting: false`
+    /// 
+    /// > `strip_prefix: None`
+    /// 
+    /// > `trailing_slash: TrailingSlashPolicy::Preserve`
+    /// 
+    /// > `add_forwarded_headers: true`
+    /// 
+    /// > `honor_keep_alive: false`
+    /// 
+    /// > `max_connections_per_host: None`
+    /// 
+    /// > `cost_selector: None`
+    /// 
+    /// > `overload_threshold: None`
+    /// 
+    /// > `overload_queue: None`
+    /// 
+    /// > `dns: None`
+    /// 
+    /// > `max_connections_per_client: None`
+    /// 
+    /// > `trusted_proxies: []`
+    /// 
+    /// > `authenticator: None`
+    /// 
+    /// > `identity_header: None`
+    /// 
+    /// > `upstream_authorization: None`
+    /// 
+    /// > `cookie_policy: None`
+    /// 
+    /// > `rewrite_cookies: None`
+    /// 
+    /// > `max_body_size: None`
+    /// 
+    /// > `max_response_body_size: None`
+    /// 
+    /// > `body_size_selector: None`
+    /// 
+    /// > `allowed_ports: None`
+    /// 
+    /// > `upstream_header: None`
+    /// 
+    /// > `allowed_upstreams: []`
+    /// 
+    /// > `content_types: []`
+    /// 
+    /// > `default_content_types: []`
+    /// 
+    /// > `response_transform: None`
+    /// 
+    /// > `decompress_upstream: false`
+    /// 
+    /// > `ws_half_close: false`
+    /// 
+    /// > `upstream_timeout: None`
+    /// 
+    /// > `timeout_per_megabyte: 0s`
+    /// 
+    /// > `propagate_trace_context: false`
+    /// 
+    /// > `http_version_header: None`
+    /// 
+    /// > `upstream_http2: false`
+    /// 
+    /// > `danger_accept_invalid_certs: false`
+    /// 
+    /// > `root_certificates: []`
+    /// 
+    /// > `non_ascii_headers: NonAsciiHeaderPolicy::Forward`
+    /// 
+    /// > `normalize_headers: false`
+    /// 
+    /// > `cache: None`
+    /// 
+    /// > `cache_policies: []`
+    /// 
+    /// > `negative_cache_ttl: None`
+    /// 
+    /// > `timeout_selector: None`
+    /// 
+    /// > `error_hook: None`
+    /// 
+    /// > `request_hook: None`
+    /// 
+    /// > `redirect_mode: RedirectMode::Follow`
+    /// 
+    /// > `max_redirects: 10`
+    /// 
+    /// > `request_timeout: None`
+    /// 
+    /// > `max_request_duration: None`
+    /// 
+    /// > `long_poll_paths: []`
+    /// 
+    /// > `long_poll_header: None`
+    /// 
+    /// > `long_poll_timeout: 120s`
+    /// 
+    /// > `grpc_status_mapping: None`
+    /// 
+    /// > `method_rewrites: {}`
+    /// 
+    /// > `early_data: EarlyDataPolicy::Forward`
+    /// 
+    /// > `insecure_requests: InsecureRequestPolicy::Forward`
+    /// 
+    /// > `missing_host: MissingHostPolicy::UseTarget`
+    /// 
+    /// > `idempotency: None`
+    /// 
+    /// > `capture: None`
+    /// 
+    /// > `retry_after: 5s`
+    /// 
+    /// > `validate_content_negotiation: None`
+    /// 
+    /// > `ws_max_inflight_frames: None`
+    /// 
+    /// > `upstream_address_header: None`
+    /// 
+    /// > `server_timing: false`
+    /// 
+    /// > `timing_allow_origin: None`
+    /// 
+    /// > `stream_threshold: 0`
+    /// 
+    /// > `stream_selector: None`
+    /// 
+    /// > `upload_stream_threshold: 0`
+    /// 
+    /// > `byte_key: None`
+    /// 
+    /// > `byte_hook: None`
+    /// 
+    /// > `failover_attempts: 0`
+    /// 
+    /// > `retry_attempts: 0`
+    /// 
+    /// > `health_check_path: None`
+    /// 
+    /// > `health_check_interval: 10s`
+    /// 
+    /// > `health_check_status: 200 OK`
+    /// 
+    /// > `ws_connect_retries: 0`
+    /// 
+    /// > `ws_handshakes: None`
+    /// 
+    /// > `ws_allowed_subprotocols: None`
+    /// 
+    /// > `ws_max_frame_size: None`
+    /// 
+    /// > `ws_max_handshake_size: None`
+    /// 
+    /// > `ws_socks5_proxy: None`
+    /// 
+    /// > `ws_idle_timeout: None`
+    /// 
+    /// > `ws_keepalive: None`
+    /// 
+    /// > `ws_close_on_removal: false`
+    /// 
+    /// > `ws_close_hook: None`
+    /// 
+    /// > `ws_tap: None`
+    /// 
+    /// > `status_filter: None`
+    /// 
+    /// > `blocked_status: 502 Bad Gateway`
+    /// 
+    /// > `error_format: ErrorFormat::Text`
+    /// 
+    /// > `error_pages: {}`
+    /// 
+    /// > `error_handler: None`
+    /// 
+    /// > `handle_error_statuses: false`
+    /// 
+    /// > `treat_upstream_errors_as_failures: false`
+    /// 
+    /// > `transparent: false`
+    /// 
+    /// > `strict_mode: false`
     fn default() -> Self {
         Self { 
-            proxy_target: "http://localhost:3000".into(),
-            web_secure: None, ws_secure: None, support_nesting: false
+            targets: Arc::new( TargetPool::new( Target::parse( "http://localhost:3000" ) ) ),
+            proxy_port: None,
+            web_secure: None, ws_secure: None, support_nesting: false, strip_prefix: None, trailing_slash: TrailingSlashPolicy::Preserve, add_forwarded_headers: true,
+            honor_keep_alive: false, max_connections_per_host: None, cost_selector: None,
+            clients: Arc::default(), active_requests: Arc::default(), overload_threshold: None, overload_queue: None,
+            client_requests: Arc::default(), max_connections_per_client: None, trusted_proxies: Vec::new(),
+            authenticator: None, identity_header: None, upstream_authorization: None,
+            dns: None,
+            cookie_policy: None, rewrite_cookies: None,
+            max_body_size: None, max_response_body_size: None, body_size_selector: None,
+            allowed_ports: None, upstream_header: None, allowed_upstreams: Vec::new(), content_types: Vec::new(), default_content_types: Vec::new(), response_transform: None, decompress_upstream: false, ws_half_close: false,
+            upstream_timeout: None, timeout_per_megabyte: Duration::ZERO,
+            propagate_trace_context: false, http_version_header: None,
+            upstream_http2: false, danger_accept_invalid_certs: false, root_certificates: Vec::new(),
+            non_ascii_headers: NonAsciiHeaderPolicy::Forward, normalize_headers: false,
+            cache: None, cache_policies: Vec::new(), negative_cache_ttl: None, cache_counters: Arc::default(), timeout_selector: None, error_hook: None, request_hook: None,
+            redirect_mode: RedirectMode::Follow, max_redirects: redirect::MAX_REDIRECTS,
+            request_timeout: None, max_request_duration: None,
+            long_poll_paths: Vec::new(), long_poll_header: None, long_poll_timeout: Duration::from_secs( 120 ), grpc_status_mapping: None, method_rewrites: HashMap::new(),
+            early_data: EarlyDataPolicy::Forward,
+            insecure_requests: InsecureRequestPolicy::Forward,
+            missing_host: MissingHostPolicy::UseTarget, idempotency: None, capture: None,
+            retry_after: Duration::from_secs( 5 ), validate_content_negotiation: None,
+            upstream_address_header: None, server_timing: false, timing_allow_origin: None, stream_threshold: 0, stream_selector: None, upload_stream_threshold: 0, upload_counters: Arc::default(),
+            byte_key: None, byte_hook: None, byte_ledger: Arc::default(), metrics: Arc::default(),
+            failover_attempts: 0, retry_attempts: 0,
+            health_check_path: None, health_check_interval: Duration::from_secs( 10 ), health_check_status: StatusCode::OK, health_checker: Arc::default(),
+            ws_max_inflight_frames: None, ws_connect_retries: 0, ws_handshakes: None, ws_allowed_subprotocols: None, ws_max_frame_size: None, ws_max_handshake_size: None, ws_socks5_proxy: None, ws_idle_timeout: None,
+            ws_keepalive: None,
+            ws_close_on_removal: false, ws_closes: Arc::default(), ws_buffered: Arc::default(), ws_close_hook: None, ws_shutdown: Arc::default(),
+            ws_tap: None,
+            status_filter: None,
+            blocked_status: StatusCode::BAD_GATEWAY,
+            error_format: ErrorFormat::Text, error_pages: HashMap::new(),
+            error_handler: None, handle_error_statuses: false, treat_upstream_errors_as_failures: false,
+            transparent: false,
+            strict_mode: false,
+            #[cfg(feature = "fault-injection")]
+            fault_injection: None,
         }
     }
 }
@@ -125,25 +972,195 @@ impl ProxyConfig {
     /// and sets all other parameters to their default values. See
     /// [the default implementation](ProxyConfig::default) for more
     /// information.
-    pub fn new<'a>( target: impl Into<String> ) -> ProxyConfig {
+    /// 
+    /// The target may start with a scheme (like `https://`), in which case
+    /// it is always reached over that protocol, regardless of the
+    /// `web_secure` and `ws_secure` settings.
+    pub fn new( target: impl Into<String> ) -> ProxyConfig {
         ProxyConfig { 
-            proxy_target: target.into(),
+            targets: Arc::new( TargetPool::new( Target::parse( &target.into() ) ) ),
             ..ProxyConfig::default()
         }
     }
 
-    /// This function sets the endpoint to forward websockets over
-    /// https instead of http. (This is WSS - WebSocket Secure)
-    pub fn ws_secure<'a>( &'a mut self ) -> &'a mut ProxyConfig {
-        self.ws_secure = Some( true );
-        self
+    /// This function adds another server to forward requests to. Requests
+    /// are spread across all of the targets in round-robin order. With
+    /// [failover](ProxyConfig::failover) enabled, a request whose target
+    /// can't be reached is sent to the next one instead of being answered
+    /// with `502 Bad Gateway`.
+    /// 
+    /// Like with [new](ProxyConfig::new), the target may start with a scheme
+    /// to pick its protocol, which makes it possible to mix secure and
+    /// insecure servers:
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "https://backend-1.internal" ) // Always https
+    ///     .add_target( "http://backend-2.internal" )                // Always http
+    ///     .add_target( "backend-3.internal" )                       // http, from web_insecure
+    ///     .web_insecure()
+    ///     .finish();
+    /// 
+    /// assert_eq!( config.get_web_request_uri( None ), Ok( "https://backend-1.internal".into() ) );
+    /// ```
+    pub fn add_target( &mut self, target: impl Into<String> ) -> &mut ProxyConfig {
+        let target = Target::parse( &target.into() );
+        self.note_port_conflict( &target );
+        self.targets_mut().push( target );
+        self
+    }
+
+    /// This function sets the port every target is reached on, so it doesn't
+    /// have to be written into each of them. A port given with a target is
+    /// overridden by this one.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// // The port can be part of the target,
+    /// let config = ProxyConfig::new( "localhost:5173" ).web_insecure().finish();
+    /// assert_eq!( config.get_web_request_uri( None ), Ok( "http://localhost:5173".into() ) );
+    /// 
+    /// // or set on its own,
+    /// let config = ProxyConfig::new( "localhost" ).with_port( 5173 ).web_insecure().finish();
+    /// assert_eq!( config.get_web_request_uri( None ), Ok( "http://localhost:5173".into() ) );
+    /// 
+    /// // in which case it wins over the one in the target
+    /// let config = ProxyConfig::new( "https://[::1]:8443" ).with_port( 9443 ).web_insecure().finish();
+    /// assert_eq!( config.get_web_request_uri( None ), Ok( "https://[::1]:9443".into() ) );
+    /// ```
+    pub fn with_port( &mut self, port: u16 ) -> &mut ProxyConfig {
+        self.proxy_port = Some( port );
+        for target in self.targets.list() {
+            self.note_port_conflict( &target );
+        }
+        self
+    }
+
+    /// Returns the pool of targets for a builder function to change. Once the pool is
+    /// shared, by a [finished](ProxyConfig::finish) configuration or a
+    /// [handle](ProxyConfig::handle), it is copied first, so that the builder never
+    /// changes the targets of an endpoint that may already be running. Only a
+    /// [ProxyHandle] does that.
+    fn targets_mut( &mut self ) -> &TargetPool {
+        if Arc::strong_count( &self.targets ) > 1 {
+            self.targets = Arc::new( self.targets.copy() );
+        }
+        &self.targets
+    }
+
+    /// Lets whoever is debugging the configuration know that the port given with a
+    /// target is overridden by [with_port](ProxyConfig::with_port).
+    fn note_port_conflict( &self, target: &Target ) {
+        if let ( Some( port ), Some( given ) ) = ( self.proxy_port, target.port() ) {
+            tracing::debug!( %target, port, given, "target port overridden" );
+        }
+    }
+
+    /// This function sets how the paths of requests are rewritten when they
+    /// are forwarded to the given target, which should be written the same
+    /// way it was added. Other targets are unaffected. Since the path is only
+    /// forwarded when [nesting is enabled](ProxyConfig::enable_nesting), that
+    /// is the only time this has an effect. See [PathRewrite] for more
+    /// information.
+    pub fn rewrite_path( &mut self, target: &str, rewrite: &PathRewrite ) -> &mut ProxyConfig {
+        self.targets_mut().set_path_rewrite( target, rewrite.clone() );
+        self
+    }
+
+    /// This function sets how the headers of requests and websockets are
+    /// rewritten when they are forwarded to the given target, which should
+    /// be written the same way it was added. Other targets are unaffected.
+    /// See [HeaderRewrite] for more information.
+    pub fn rewrite_headers( &mut self, target: &str, rewrite: &HeaderRewrite ) -> &mut ProxyConfig {
+        self.targets_mut().set_header_rewrite( target, rewrite.clone() );
+        self
+    }
+
+    /// This function reserves the given target, which should be written the
+    /// same way it was added, for requests of the given [Cost]. Targets that
+    /// aren't reserved take requests of any cost. This only has an effect
+    /// along with a [cost selector](ProxyConfig::cost_selector).
+    pub fn target_tier( &mut self, target: &str, tier: Cost ) -> &mut ProxyConfig {
+        self.targets_mut().set_tier( target, tier );
+        self
+    }
+
+    /// This function sets the most redirects the endpoint follows for a
+    /// request forwarded to the given target, which should be written the
+    /// same way it was added, in place of the
+    /// [limit for every target](ProxyConfig::max_redirects). Other targets are
+    /// unaffected.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "legacy.internal:8080" )
+    ///     .add_target( "api.internal:8080" )
+    ///     .web_insecure()
+    ///     .target_max_redirects( "legacy.internal:8080", 20 ) // Bounces through an old login flow
+    ///     .target_max_redirects( "api.internal:8080", 0 )     // Never redirects on purpose
+    ///     .finish();
+    /// ```
+    pub fn target_max_redirects( &mut self, target: &str, max: usize ) -> &mut ProxyConfig {
+        self.targets_mut().set_max_redirects( target, max );
+        self
+    }
+
+    /// This function sets a callback that works out the [Cost] of each
+    /// request, so that demanding requests can be forwarded to more capable
+    /// servers. Each request goes to one of the targets
+    /// [reserved](ProxyConfig::target_tier) for its cost, or to one that isn't
+    /// reserved at all. If no such target is left, any target will do.
+    /// 
+    /// ```
+    /// use poem::http::header;
+    /// use poem_proxy::{ Cost, ProxyConfig };
+    /// 
+    /// let config = ProxyConfig::new( "small-backend:8080" )
+    ///     .add_target( "big-backend:8080" )
+    ///     .target_tier( "small-backend:8080", Cost::Light )
+    ///     .target_tier( "big-backend:8080", Cost::Heavy )
+    ///     .web_insecure()
+    ///     .cost_selector( |req| {
+    ///         let length = req.header( header::CONTENT_LENGTH )
+    ///             .and_then( |length| length.parse::<u64>().ok() )
+    ///             .unwrap_or( 0 );
+    ///         match length > 1024 * 1024 || req.uri().path().starts_with( "/reports" ) {
+    ///             true => Cost::Heavy,
+    ///             false => Cost::Light,
+    ///         }
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn cost_selector( &mut self, selector: impl Fn( &Request ) -> Cost + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.cost_selector = Some( Opaque( Arc::new( selector ) ) );
+        self
+    }
+
+    /// This function sets the endpoint to forward websockets over
+    /// https instead of http. (This is WSS - WebSocket Secure)
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let secure = ProxyConfig::new( "localhost:5173" ).ws_secure().finish();
+    /// let insecure = ProxyConfig::new( "localhost:5173" ).ws_insecure().finish();
+    /// 
+    /// assert_eq!( secure.get_web_socket_uri(), Ok( "wss://localhost:5173".into() ) );
+    /// assert_eq!( insecure.get_web_socket_uri(), Ok( "ws://localhost:5173".into() ) );
+    /// ```
+    pub fn ws_secure( &mut self ) -> &mut ProxyConfig {
+        self.ws_secure = Some( true );
+        self
     }
 
     /// This function sets the endpoint to forward websockets over
     /// http instead of https. This means any information being sent
     /// through the websocket has the potential to be 
     /// [intercepted by malicious actors](https://brightsec.com/blog/websocket-security-top-vulnerabilities/#unencrypted-tcp-channel).
-    pub fn ws_insecure<'a>( &'a mut self ) -> &'a mut ProxyConfig {
+    pub fn ws_insecure( &mut self ) -> &mut ProxyConfig {
         self.ws_secure = Some( false );
         self
     }
@@ -151,7 +1168,17 @@ impl ProxyConfig {
     /// This function sets the endpoint to forward requests to the
     /// target over the https protocol. This is a secure and encrypted
     /// communication channel that should be utilized when possible.
-    pub fn web_secure<'a>( &'a mut self ) -> &'a mut ProxyConfig {
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let secure = ProxyConfig::new( "localhost:5173" ).web_secure().finish();
+    /// let insecure = ProxyConfig::new( "localhost:5173" ).web_insecure().finish();
+    /// 
+    /// assert_eq!( secure.get_web_request_uri( None ), Ok( "https://localhost:5173".into() ) );
+    /// assert_eq!( insecure.get_web_request_uri( None ), Ok( "http://localhost:5173".into() ) );
+    /// ```
+    pub fn web_secure( &mut self ) -> &mut ProxyConfig {
         self.web_secure = Some( true );
         self
     }
@@ -159,7 +1186,7 @@ impl ProxyConfig {
     /// This function sets the endpoint to forward requests to the
     /// target over the http protocol. This is an insecure and unencrypted
     /// communication channel that should be used very carefully.
-    pub fn web_insecure<'a>( &'a mut self ) -> &'a mut ProxyConfig {
+    pub fn web_insecure( &mut self ) -> &mut ProxyConfig {
         self.web_secure = Some( false );
         self
     }
@@ -170,7 +1197,20 @@ impl ProxyConfig {
     /// if `endpoint.target` is `https://google.com` and the proxy is reached
     /// at `https://proxy_address/favicon.png`, the proxy server will forward
     /// the request to `https://google.com/favicon.png`.
-    pub fn enable_nesting<'a>( &'a mut self ) -> &'a mut ProxyConfig {
+    /// 
+    /// ```
+    /// use poem::{ Request, http::Uri };
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "google.com" )
+    ///     .web_secure()
+    ///     .enable_nesting()
+    ///     .finish();
+    /// let req = Request::builder().uri( Uri::from_static( "/favicon.png" ) ).finish();
+    /// 
+    /// assert_eq!( config.get_request_uri( &req ), Ok( "https://google.com/favicon.png".into() ) );
+    /// ```
+    pub fn enable_nesting( &mut self ) -> &mut ProxyConfig {
         self.support_nesting = true;
         self
     }
@@ -180,207 +1220,3022 @@ impl ProxyConfig {
     /// For example,
     /// if `endpoint.target` is `https://google.com` and the proxy is reached
     /// at `https://proxy_address/favicon.png`, the proxy server will forward
-    /// the request to `https://google.com`.
-    pub fn disable_nesting<'a>( &'a mut self ) -> &'a mut ProxyConfig {
+    /// the request to `https://google.com`. This is the default.
+    /// 
+    /// ```
+    /// use poem::{ Request, http::Uri };
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "google.com" )
+    ///     .web_secure()
+    ///     .disable_nesting()
+    ///     .finish();
+    /// let req = Request::builder().uri( Uri::from_static( "/favicon.png" ) ).finish();
+    /// 
+    /// assert_eq!( config.get_request_uri( &req ), Ok( "https://google.com".into() ) );
+    /// ```
+    pub fn disable_nesting( &mut self ) -> &mut ProxyConfig {
         self.support_nesting = false;
         self
     }
 
-    /// Finishes off the building proccess by returning a new ProxyConfig object
-    /// (not reference) that contains all the settings that were previously
-    /// specified.
-    pub fn finish<'a>( &'a mut self ) -> ProxyConfig {
-        self.clone()
+    /// This function sets a prefix to remove from the path of every request
+    /// before it is forwarded, for when the endpoint is mounted under a path
+    /// that the proxied server doesn't expect, such as with
+    /// `Route::new().at( "/api/*", proxy )`. Only whole segments are removed,
+    /// and paths that don't start with the prefix are forwarded as they are.
+    /// Since the path is only forwarded when
+    /// [nesting is enabled](ProxyConfig::enable_nesting), that is the only
+    /// time this has an effect.
+    /// 
+    /// ```
+    /// use poem::{ Request, http::Uri };
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .strip_prefix( "/api" )
+    ///     .finish();
+    /// let uri = |path| config.get_request_uri( &Request::builder().uri( Uri::from_static( path ) ).finish() );
+    /// 
+    /// assert_eq!( uri( "/api/users?page=2" ), Ok( "http://localhost:5173/users?page=2".into() ) );
+    /// assert_eq!( uri( "/api" ), Ok( "http://localhost:5173/".into() ) );
+    /// assert_eq!( uri( "/assets/app.js" ), Ok( "http://localhost:5173/assets/app.js".into() ) );
+    /// ```
+    pub fn strip_prefix( &mut self, prefix: impl Into<String> ) -> &mut ProxyConfig {
+        self.strip_prefix = Some( prefix.into() );
+        self
+    }
+
+    /// This function sets whether forwarded paths get a trailing slash added
+    /// or removed, for proxied servers that treat `/docs` and `/docs/`
+    /// differently. The root path and the query string are never changed.
+    /// This applies after the [prefix is stripped](ProxyConfig::strip_prefix)
+    /// and the [paths are rewritten](ProxyConfig::rewrite_path), and like them
+    /// only when [nesting is enabled](ProxyConfig::enable_nesting). See
+    /// [TrailingSlashPolicy] for the available options.
+    /// 
+    /// ```
+    /// use poem::{ Request, http::Uri };
+    /// use poem_proxy::{ ProxyConfig, TrailingSlashPolicy };
+    /// 
+    /// let uri = |policy, path| ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .trailing_slash( policy )
+    ///     .finish()
+    ///     .get_request_uri( &Request::builder().uri( Uri::from_static( path ) ).finish() )
+    ///     .unwrap();
+    /// 
+    /// assert_eq!( uri( TrailingSlashPolicy::Preserve, "/docs?page=2" ), "http://localhost:5173/docs?page=2" );
+    /// assert_eq!( uri( TrailingSlashPolicy::Preserve, "/docs/?page=2" ), "http://localhost:5173/docs/?page=2" );
+    /// 
+    /// assert_eq!( uri( TrailingSlashPolicy::Add, "/docs?page=2" ), "http://localhost:5173/docs/?page=2" );
+    /// assert_eq!( uri( TrailingSlashPolicy::Add, "/docs/?page=2" ), "http://localhost:5173/docs/?page=2" );
+    /// 
+    /// assert_eq!( uri( TrailingSlashPolicy::Strip, "/docs?page=2" ), "http://localhost:5173/docs?page=2" );
+    /// assert_eq!( uri( TrailingSlashPolicy::Strip, "/docs//?page=2" ), "http://localhost:5173/docs?page=2" );
+    /// 
+    /// // The root is left alone either way
+    /// assert_eq!( uri( TrailingSlashPolicy::Add, "/?page=2" ), "http://localhost:5173/?page=2" );
+    /// assert_eq!( uri( TrailingSlashPolicy::Strip, "/" ), "http://localhost:5173/" );
+    /// ```
+    pub fn trailing_slash( &mut self, policy: TrailingSlashPolicy ) -> &mut ProxyConfig {
+        self.trailing_slash = policy;
+        self
+    }
+
+    /// This function sets the endpoint to tell the proxied server about the
+    /// client of each request, which it would otherwise not know. This is
+    /// enabled by default, and works as follows:
+    /// 
+    /// - the client's address is appended to `X-Forwarded-For`, keeping the
+    ///   addresses added by any proxies in front of this one
+    /// - `X-Forwarded-Proto` is set to the scheme the client used (`http` or
+    ///   `https`)
+    /// - `X-Forwarded-Host` is set to the `Host` the client asked for
+    /// 
+    /// Requests from one of the [trusted proxies](ProxyConfig::trusted_proxies)
+    /// keep the `X-Forwarded-Proto` and `X-Forwarded-Host` they were sent with,
+    /// since those proxies saw the original request.
+    pub fn enable_forwarded_headers( &mut self ) -> &mut ProxyConfig {
+        self.add_forwarded_headers = true;
+        self
+    }
+
+    /// This function sets the endpoint to forward requests without adding the
+    /// `X-Forwarded-*` headers, see [enable_forwarded_headers](ProxyConfig::enable_forwarded_headers).
+    /// Any the client sent are still forwarded as they are.
+    pub fn disable_forwarded_headers( &mut self ) -> &mut ProxyConfig {
+        self.add_forwarded_headers = false;
+        self
+    }
+
+    /// This function sets the endpoint to honor the `Keep-Alive` hints sent
+    /// back by the proxied server.
+    /// 
+    /// For example, if the server responds with `Keep-Alive: timeout=5, max=100`,
+    /// the proxy will open fresh connections shortly before 100 requests have
+    /// been sent or 5 seconds have gone by without a request, instead of
+    /// waiting for the server to drop a connection in the middle of a request.
+    ///
+    /// The hints are per connection, but the http client doesn't say which
+    /// connection a request went out on, so the proxy counts the requests sent
+    /// to each host instead. A host with several connections open at once has
+    /// all of them replaced once it was sent about `max` requests in total,
+    /// well before any single one of them served that many. This errs on the
+    /// side of opening connections more often than needed, never on the side
+    /// of using one the server is about to close.
+    pub fn honor_keep_alive( &mut self ) -> &mut ProxyConfig {
+        self.honor_keep_alive = true;
+        self
+    }
+
+    /// This function limits the number of idle connections the proxy will keep
+    /// open to each host it forwards requests to. This only has an effect when
+    /// [keep-alive hints are honored](ProxyConfig::honor_keep_alive).
+    pub fn max_connections_per_host( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.max_connections_per_host = Some( max );
+        self
+    }
+
+    /// This function sets how long the endpoint keeps the addresses it
+    /// resolved for the proxied servers, regardless of the TTL of their DNS
+    /// records. A short time spreads requests across servers behind a DNS
+    /// load balancer sooner, while a longer one saves on lookups.
+    pub fn dns_cache_ttl( &mut self, ttl: Duration ) -> &mut ProxyConfig {
+        let resolver = match &self.dns {
+            Some( resolver ) => resolver.with_ttl( ttl ),
+            None => CachingResolver::new( None, Some( ttl ) ),
+        };
+        self.dns = Some( Arc::new( resolver ) );
+        self
+    }
+
+    /// This function sets the resolver used to look up the addresses of the
+    /// proxied servers, instead of the system's. Addresses are still kept for
+    /// the [DNS cache TTL](ProxyConfig::dns_cache_ttl), if one is set.
+    /// 
+    /// ```
+    /// use std::{ net::SocketAddr, sync::Arc };
+    /// use poem_proxy::{ Addrs, Name, ProxyConfig, Resolve, Resolving };
+    /// 
+    /// // Sends every request to the same local address
+    /// struct Loopback;
+    /// 
+    /// impl Resolve for Loopback {
+    ///     fn resolve( &self, _name: Name ) -> Resolving {
+    ///         let addrs: Addrs = Box::new( std::iter::once( SocketAddr::from( ( [127, 0, 0, 1], 0 ) ) ) );
+    ///         Box::pin( async move { Ok( addrs ) } )
+    ///     }
+    /// }
+    /// 
+    /// let config = ProxyConfig::new( "backend:8080" )
+    ///     .web_insecure()
+    ///     .dns_resolver( Arc::new( Loopback ) )
+    ///     .finish();
+    /// ```
+    pub fn dns_resolver( &mut self, resolver: Arc<dyn Resolve> ) -> &mut ProxyConfig {
+        let resolver = match &self.dns {
+            Some( caching ) => caching.with_inner( resolver ),
+            None => CachingResolver::new( Some( resolver ), None ),
+        };
+        self.dns = Some( Arc::new( resolver ) );
+        self
+    }
+
+    /// This function sets how many requests and websockets the endpoint will
+    /// handle at once. Past that, new requests are answered right away with
+    /// `503 Service Unavailable` and a `Retry-After` header, before any work is
+    /// done for them. The current count is available through
+    /// [ProxyHandle::total_active_requests].
+    pub fn overload_threshold( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.overload_threshold = Some( max );
+        self
+    }
+
+    /// This function sets the endpoint to keep the clients it turns away for
+    /// [overload](ProxyConfig::overload_threshold) in line, so they can back
+    /// off for as long as they actually have to. Each one is told its place
+    /// in line through the `X-Queue-Position` header, starting at 1, and to
+    /// retry after the [retry_after](ProxyConfig::retry_after) delay times its
+    /// position. As room frees up it goes to the clients at the front of the
+    /// line, so newcomers are turned away until those waiting have had their
+    /// turn. A client that doesn't come back within one more delay past the
+    /// one it was given loses its place.
+    /// 
+    /// Clients are told apart by their IP address, as with
+    /// [max_connections_per_client](ProxyConfig::max_connections_per_client).
+    pub fn overload_queue( &mut self ) -> &mut ProxyConfig {
+        self.overload_queue = Some( Arc::default() );
+        self
+    }
+
+    /// This function sets how many requests and websockets a single client
+    /// may have in flight at once, so that no one client can monopolize the
+    /// endpoint. Past that, the client's new requests are answered with
+    /// `429 Too Many Requests` and a `Retry-After` header, while other clients
+    /// are unaffected.
+    /// 
+    /// Clients are told apart by their IP address. If the endpoint sits behind
+    /// other proxies, see [trusted_proxies](ProxyConfig::trusted_proxies).
+    pub fn max_connections_per_client( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.max_connections_per_client = Some( max );
+        self
+    }
+
+    /// This function sets the addresses of the proxies in front of the
+    /// endpoint, such as a load balancer. Requests from them are attributed to
+    /// the client named by their `X-Forwarded-For` header (the last address in
+    /// it that isn't one of these proxies) rather than to the proxy itself.
+    /// The header is ignored on requests from anyone else, since clients can
+    /// put whatever they like in it. Likewise, only these proxies get to pass
+    /// on their [`X-Forwarded-Proto` and `X-Forwarded-Host`](ProxyConfig::enable_forwarded_headers).
+    pub fn trusted_proxies( &mut self, proxies: impl IntoIterator<Item = IpAddr> ) -> &mut ProxyConfig {
+        self.trusted_proxies = proxies.into_iter().collect();
+        self
+    }
+
+    /// This function sets the check every client has to pass before its
+    /// requests and websockets are forwarded, such as validating a JWT or
+    /// looking up an API key. Clients that are turned away are answered with
+    /// `401 Unauthorized`. See [ClientAuthenticator] for an example.
+    pub fn client_authenticator( &mut self, authenticator: Arc<dyn ClientAuthenticator> ) -> &mut ProxyConfig {
+        self.authenticator = Some( Opaque( authenticator ) );
+        self
+    }
+
+    /// This function sets the request header in which the identity the
+    /// [client authenticator](ProxyConfig::client_authenticator) found for the
+    /// client is sent to the proxied server. Whatever the client itself sent in
+    /// this header is always removed, so the proxied server can trust it.
+    pub fn identity_header( &mut self, header: HeaderName ) -> &mut ProxyConfig {
+        self.identity_header = Some( header );
+        self
+    }
+
+    /// This function sets the endpoint to log in to the proxied server with
+    /// HTTP basic authentication, sending the given user and password in the
+    /// `Authorization` header of every request and websocket handshake. The
+    /// header the client sent, if any, is replaced. The user can't contain a
+    /// colon.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .upstream_basic_auth( "proxy", "s3cr3t" )
+    ///     .finish();
+    /// ```
+    pub fn upstream_basic_auth( &mut self, user: &str, password: &str ) -> &mut ProxyConfig {
+        self.upstream_authorization = Some( auth::credentials( Authorization::basic( user, password ) ) );
+        self
+    }
+
+    /// This function sets the endpoint to send the given bearer token to the
+    /// proxied server in the `Authorization` header of every request and
+    /// websocket handshake, replacing the header the client sent, if any.
+    /// This fails if the token has characters that can't be sent in a
+    /// header, leaving the configuration as it was.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// # fn main() -> Result<(), poem::http::header::InvalidHeaderValue> {
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .ws_insecure()
+    ///     .upstream_bearer_token( "eyJhbGciOiJIUzI1NiJ9.e30.c2lnbmF0dXJl" )?
+    ///     .finish();
+    /// # Ok( () )
+    /// # }
+    /// ```
+    pub fn upstream_bearer_token( &mut self, token: &str ) -> Result<&mut ProxyConfig, InvalidHeaderValue> {
+        let mut authorization = HeaderValue::try_from( format!( "Bearer {}", token ) )?;
+        authorization.set_sensitive( true );
+        self.upstream_authorization = Some( authorization );
+        Ok( self )
+    }
+
+    /// This function sets the endpoint to normalize the attributes of every
+    /// cookie set by the proxied server according to the given policy. This
+    /// is useful when the proxy terminates TLS, since the proxied server
+    /// usually can't tell that its cookies should be `Secure`. See
+    /// [CookiePolicy] for the available options.
+    pub fn cookie_policy( &mut self, policy: &CookiePolicy ) -> &mut ProxyConfig {
+        self.cookie_policy = Some( policy.clone() );
+        self
+    }
+
+    /// This function sets the endpoint to rewrite the `Domain` and `Path` of
+    /// every cookie set by the proxied server, so that the browser accepts
+    /// them for the host and path the proxy serves it under. Each `Set-Cookie`
+    /// header of a response is rewritten separately, before the
+    /// [cookie policy](ProxyConfig::cookie_policy) is applied. See
+    /// [CookieRewrite] for the available options.
+    pub fn rewrite_cookies( &mut self, rewrite: &CookieRewrite ) -> &mut ProxyConfig {
+        self.rewrite_cookies = Some( rewrite.clone() );
+        self
+    }
+
+    /// This function limits the size of the request bodies the proxy accepts.
+    /// Requests with a larger body are answered with `413 Payload Too Large`,
+    /// and are cut off as soon as they go over the limit.
+    pub fn max_body_size( &mut self, bytes: usize ) -> &mut ProxyConfig {
+        self.max_body_size = Some( bytes );
+        self
+    }
+
+    /// This function limits the size of the response bodies the proxy relays
+    /// from the proxied server. Responses that say up front that their body
+    /// is larger, and those read in full that turn out to be, are answered
+    /// with `502 Bad Gateway`. A [streamed](ProxyConfig::stream_threshold)
+    /// response has already been started by the time it goes over, so it is
+    /// cut off there instead, which the client sees as an incomplete body.
+    pub fn max_response_body_size( &mut self, bytes: usize ) -> &mut ProxyConfig {
+        self.max_response_body_size = Some( bytes );
+        self
+    }
+
+    /// This function sets a callback that picks the largest request body
+    /// allowed for each request, such as by its path. Whenever the callback
+    /// returns `None`, the [overall limit](ProxyConfig::max_body_size) applies.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .max_body_size( 1024 * 1024 )
+    ///     .body_size_selector( |req| match req.uri().path() {
+    ///         "/avatar" => Some( 64 * 1024 ),
+    ///         "/documents" => Some( 100 * 1024 * 1024 ),
+    ///         _ => None,
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn body_size_selector( &mut self, selector: impl Fn( &Request ) -> Option<usize> + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.body_size_selector = Some( Opaque( Arc::new( selector ) ) );
+        self
+    }
+
+    /// This function restricts the ports the proxy is allowed to connect to.
+    /// Requests that would be forwarded to any other port are rejected with
+    /// `403 Forbidden`, which guards against the proxy being used to reach
+    /// services it was never meant to expose.
+    /// 
+    /// For example, `.allowed_ports( [80, 443, 8080] )` allows http and https
+    /// traffic to the standard ports and 8080, but not ssh (22) or smtp (25).
+    pub fn allowed_ports( &mut self, ports: impl IntoIterator<Item = u16> ) -> &mut ProxyConfig {
+        self.allowed_ports = Some( ports.into_iter().collect() );
+        self
+    }
+
+    /// This function sets the endpoint to forward each request to the server
+    /// named by the given request header, for gateways where whatever sits
+    /// in front of the proxy decides where requests go. The header names a
+    /// server the way targets are given (`backend:8080`), and only the
+    /// `upstreams` listed here can be named: requests naming any other
+    /// server, or more than one, are rejected with `403 Forbidden`, so that
+    /// clients can't use the proxy to reach services it was never meant to
+    /// expose. Whether a server is reached securely is taken from the list
+    /// rather than the header, and the [allowed ports](ProxyConfig::allowed_ports)
+    /// still apply.
+    /// 
+    /// The header is never forwarded. Requests without it go to the targets
+    /// as usual, while requests routed by it are never
+    /// [failed over](ProxyConfig::failover) to the targets.
+    /// 
+    /// ```
+    /// use poem::http::HeaderName;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .route_by_header( HeaderName::from_static( "x-upstream" ), [ "orders:8080", "https://billing" ] )
+    ///     .finish();
+    /// ```
+    pub fn route_by_header( &mut self, header: HeaderName, upstreams: impl IntoIterator<Item = impl Into<String>> ) -> &mut ProxyConfig {
+        self.upstream_header = Some( header );
+        self.allowed_upstreams = upstreams.into_iter().map( |upstream| Target::parse( &upstream.into() ) ).collect();
+        self
+    }
+
+    /// This function restricts the content types of request bodies sent to
+    /// the given path, and every path below it. Requests with a body of any
+    /// other type, or with a body but no `Content-Type`, are answered with
+    /// `415 Unsupported Media Type` without being forwarded. Types are
+    /// matched without their parameters, and `type/*` allows every subtype.
+    /// 
+    /// This can be called once per path, and the rule for the most specific
+    /// path that matches a request is the one checked. Paths are matched
+    /// against the path the client requested.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .allow_content_types( "/api", [ "application/json" ] )
+    ///     .allow_content_types( "/api/uploads", [ "multipart/form-data", "image/*" ] )
+    ///     .finish();
+    /// ```
+    pub fn allow_content_types( &mut self, path: &str, types: impl IntoIterator<Item = impl Into<String>> ) -> &mut ProxyConfig {
+        let rule = ContentTypeRule::new( path, types );
+        self.content_types.retain( |existing| !existing.same_path( &rule ) );
+        self.content_types.push( rule );
+        self
+    }
+
+    /// This function sets the content type given to responses to requests under
+    /// `path` that the proxied server sent without a `Content-Type`. Those
+    /// responses also get `X-Content-Type-Options: nosniff`, so browsers use
+    /// the type instead of guessing one from the body. Responses without a
+    /// body are left as they are.
+    /// 
+    /// This can be called once per path, and the default for the most specific
+    /// path that matches a request is the one used. Paths are matched against
+    /// the path the client requested.
+    /// 
+    /// ```
+    /// use poem::http::HeaderValue;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .default_content_type( "/", HeaderValue::from_static( "text/plain; charset=utf-8" ) )
+    ///     .default_content_type( "/api", HeaderValue::from_static( "application/json" ) )
+    ///     .finish();
+    /// ```
+    pub fn default_content_type( &mut self, path: &str, content_type: HeaderValue ) -> &mut ProxyConfig {
+        let path = path.trim_end_matches( '/' ).to_owned();
+        self.default_content_types.retain( |( existing, _ )| *existing != path );
+        self.default_content_types.push( ( path, content_type ) );
+        self
+    }
+
+    /// This function sets the endpoint to run the bodies of responses through
+    /// a transform, such as a minifier, before they reach the client. Streamed
+    /// responses are transformed chunk by chunk as they arrive, so they are
+    /// never buffered for it. See [ResponseTransform] for an example.
+    /// 
+    /// Transformed responses are sent without a `Content-Length`, since the
+    /// transform may change it. The transformed body is what gets cached.
+    pub fn transform_responses( &mut self, transform: &ResponseTransform ) -> &mut ProxyConfig {
+        self.response_transform = Some( transform.clone() );
+        self
+    }
+
+    /// This function sets the endpoint to rewrite the bodies of HTML, CSS and
+    /// JavaScript responses as a whole, such as to point the absolute links
+    /// in a page at the proxy rather than the proxied server. These responses
+    /// are read in full before they are rewritten, even if they would
+    /// otherwise be streamed, while all others are relayed untouched. This
+    /// replaces any [transform](ProxyConfig::transform_responses) set before;
+    /// for other content types, see [ResponseTransform::whole_body].
+    /// 
+    /// Compressed bodies are never rewritten, so either have them
+    /// [decompressed](ProxyConfig::decompress_upstream) first or keep the
+    /// proxied server from sending them, such as by removing `Accept-Encoding`
+    /// with a [header rewrite](ProxyConfig::rewrite_headers).
+    /// 
+    /// ```
+    /// use bytes::Bytes;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "backend.internal" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .rewrite_body( |body| {
+    ///         let text = String::from_utf8_lossy( body );
+    ///         Bytes::from( text.replace( "http://backend.internal", "https://proxy.example" ) )
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn rewrite_body( &mut self, rewrite: impl Fn( &Bytes ) -> Bytes + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.transform_responses( ResponseTransform::whole_body( rewrite )
+            .content_type( "text/html" )
+            .content_type( "text/css" )
+            .content_type( "application/javascript" )
+            .content_type( "text/javascript" ) )
+    }
+
+    /// This function sets the endpoint to decode response bodies the proxied
+    /// server compressed with `gzip` or `deflate`, forwarding them without a
+    /// `Content-Encoding` and with their entity tag made weak. Decoded bodies
+    /// are read in full rather than streamed, can be
+    /// [transformed](ProxyConfig::transform_responses), and are held to the
+    /// [response size limit](ProxyConfig::max_response_body_size) once
+    /// decoded. A body that can't be decoded is answered with `502 Bad
+    /// Gateway`.
+    /// 
+    /// Bodies in other codings, such as `br`, and partial content are
+    /// forwarded as they are, still labeled with their coding. Without this,
+    /// every body is forwarded exactly as the proxied server sent it, along
+    /// with its `Content-Encoding`: the http client never decodes bodies on
+    /// its own.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .decompress_upstream()
+    ///     .max_response_body_size( 16 * 1024 * 1024 )
+    ///     .finish();
+    /// ```
+    pub fn decompress_upstream( &mut self ) -> &mut ProxyConfig {
+        self.decompress_upstream = true;
+        self
+    }
+
+    /// This function sets the endpoint to support half-closed websockets.
+    /// 
+    /// Normally, the proxy tears down both directions of a websocket as soon
+    /// as either peer stops sending. With half-close enabled, a close frame
+    /// from one peer is forwarded and ends only that direction, while the
+    /// other peer can keep sending until it answers with its own close frame.
+    /// This lets both peers complete the closing handshake.
+    pub fn ws_half_close( &mut self ) -> &mut ProxyConfig {
+        self.ws_half_close = true;
+        self
+    }
+
+    /// This function sets how long the proxy will wait for the proxied server
+    /// to respond before giving up on a request.
+    pub fn upstream_timeout( &mut self, timeout: Duration ) -> &mut ProxyConfig {
+        self.upstream_timeout = Some( timeout );
+        self
+    }
+
+    /// This function gives requests with large bodies more time to complete.
+    /// For every megabyte (1,048,576 bytes) in the body of a request, the
+    /// [upstream timeout](ProxyConfig::upstream_timeout) is extended by the
+    /// given amount, so large uploads aren't cut off while small requests
+    /// still fail fast.
+    /// 
+    /// For example, with a timeout of 5 seconds and 2 seconds per megabyte,
+    /// a 10 megabyte upload is given 25 seconds.
+    pub fn timeout_per_megabyte( &mut self, extra: Duration ) -> &mut ProxyConfig {
+        self.timeout_per_megabyte = extra;
+        self
+    }
+
+    /// This function sets the endpoint to take part in distributed traces by
+    /// propagating [W3C trace context](https://www.w3.org/TR/trace-context/)
+    /// headers. Incoming traces are continued with a new span for the proxy,
+    /// and requests without a `traceparent` header start a new trace.
+    pub fn propagate_trace_context( &mut self ) -> &mut ProxyConfig {
+        self.propagate_trace_context = true;
+        self
+    }
+
+    /// This function sets the endpoint to tell the proxied server which
+    /// version of HTTP the client sent its request with (`HTTP/1.0`,
+    /// `HTTP/1.1` or `HTTP/2`), in the given request header. Any value the
+    /// client sent for the header is replaced.
+    /// 
+    /// For example, `.forward_http_version( HeaderName::from_static( "x-forwarded-http-version" ) )`
+    /// sends `x-forwarded-http-version: HTTP/2` for clients that talk HTTP/2
+    /// to the endpoint. This is the version the request arrived over, not the
+    /// protocol negotiated through ALPN while connecting over TLS, which the
+    /// endpoint isn't told about. The two only differ for clients that
+    /// negotiated one protocol and then spoke another.
+    pub fn forward_http_version( &mut self, header: HeaderName ) -> &mut ProxyConfig {
+        self.http_version_header = Some( header );
+        self
+    }
+
+    /// This function sets the endpoint to speak HTTP/2 to the proxied server
+    /// right away, instead of starting with HTTP/1.1. Since HTTP/2 is much
+    /// stricter about headers than HTTP/1.1, this also enables
+    /// [header normalization](ProxyConfig::normalize_headers).
+    pub fn upstream_http2( &mut self ) -> &mut ProxyConfig {
+        self.upstream_http2 = true;
+        self.normalize_headers = true;
+        self
+    }
+
+    /// This function sets the endpoint to trust the given certificate when
+    /// checking the certificate of the proxied server, on top of the system's
+    /// root certificates. This is the safe way to reach a server whose
+    /// certificate is signed by a private authority, or is self-signed.
+    /// 
+    /// This can be called more than once to trust several certificates. It
+    /// applies to web requests, and has to be set before the first request is
+    /// forwarded.
+    /// 
+    /// ```no_run
+    /// use poem_proxy::{ Certificate, ProxyConfig };
+    /// 
+    /// let pem = std::fs::read( "internal-ca.pem" ).unwrap();
+    /// let config = ProxyConfig::new( "backend.internal:8443" )
+    ///     .web_secure()
+    ///     .add_root_certificate( Certificate::from_pem( &pem ).unwrap() )
+    ///     .finish();
+    /// ```
+    pub fn add_root_certificate( &mut self, certificate: Certificate ) -> &mut ProxyConfig {
+        self.root_certificates.push( certificate );
+        self
+    }
+
+    /// This function sets the endpoint to accept any certificate from the
+    /// proxied server, including expired, self-signed and mismatched ones.
+    /// 
+    /// **This is dangerous.** Anyone able to intercept the connection can
+    /// then read and change the forwarded traffic. Only use it for servers in
+    /// development; [add_root_certificate](ProxyConfig::add_root_certificate)
+    /// trusts a particular certificate without giving up on checking the
+    /// others. It applies to web requests, and has to be set before the first
+    /// request is forwarded.
+    pub fn danger_accept_invalid_certs( &mut self ) -> &mut ProxyConfig {
+        self.danger_accept_invalid_certs = true;
+        self
+    }
+
+    /// This function sets the endpoint to normalize the headers it forwards
+    /// so that they are valid under both HTTP/1.1 and HTTP/2. Header names
+    /// are always sent in lowercase, connection-specific headers (such as
+    /// `Connection` and `Keep-Alive`, which HTTP/2 forbids) are removed, and
+    /// headers that don't form a valid name are dropped.
+    /// 
+    /// This is needed when bridging HTTP/1.1 clients to HTTP/2 servers.
+    pub fn normalize_headers( &mut self ) -> &mut ProxyConfig {
+        self.normalize_headers = true;
+        self
+    }
+
+    /// This function sets what the endpoint does with request headers whose
+    /// values aren't plain ASCII. Web servers get them as they are by default,
+    /// but the websocket client can't send them, so a websocket carrying one
+    /// fails to connect. They can instead be left out or sanitized, which lets
+    /// the request through, or have the request refused with `400 Bad Request`.
+    /// See [NonAsciiHeaderPolicy] for details. The headers are checked after
+    /// the proxy has added its own.
+    /// 
+    /// ```
+    /// use poem::{ Endpoint, Request, http::{ HeaderValue, StatusCode } };
+    /// use poem_proxy::{ NonAsciiHeaderPolicy, ProxyConfig, ProxyEndpoint };
+    /// 
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let call = |policy| async move {
+    ///     let config = ProxyConfig::new( "127.0.0.1:9" )
+    ///         .web_insecure()
+    ///         .non_ascii_headers( policy )
+    ///         .finish();
+    ///     let req = Request::builder()
+    ///         .header( "x-city", HeaderValue::from_bytes( b"Montr\xe9al" ).unwrap() )
+    ///         .finish();
+    ///     ProxyEndpoint::new( config ).call( req ).await.map_or_else( |e| e.status(), |r| r.status() )
+    /// };
+    /// 
+    /// // Refused before anything is sent on
+    /// assert_eq!( call( NonAsciiHeaderPolicy::Reject ).await, StatusCode::BAD_REQUEST );
+    /// 
+    /// // Let through, though nothing is listening on the discard port
+    /// assert_eq!( call( NonAsciiHeaderPolicy::Drop ).await, StatusCode::BAD_GATEWAY );
+    /// # }
+    /// ```
+    pub fn non_ascii_headers( &mut self, policy: NonAsciiHeaderPolicy ) -> &mut ProxyConfig {
+        self.non_ascii_headers = policy;
+        self
+    }
+
+    /// This function sets a callback that picks how long the proxy will wait
+    /// for the proxied server, based on the request being forwarded. This
+    /// takes precedence over the [upstream timeout](ProxyConfig::upstream_timeout).
+    /// 
+    /// ```
+    /// use std::time::Duration;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .timeout_selector( |req| match req.uri().path() {
+    ///         "/health" => Duration::from_secs( 1 ),
+    ///         _ => Duration::from_secs( 30 ),
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn timeout_selector( &mut self, selector: impl Fn( &Request ) -> Duration + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.timeout_selector = Some( Opaque( Arc::new( selector ) ) );
+        self
+    }
+
+    /// This function sets a callback that is run whenever a request can't be
+    /// forwarded because the proxied server couldn't be reached or failed to
+    /// respond. The callback is given the request and the reason it failed,
+    /// see [ProxyError] for an example. It can't change the response, which
+    /// is still an error with the status given by [ProxyError::status], the
+    /// [error page](ProxyConfig::error_page) set for its kind, or whatever the
+    /// [error handler](ProxyConfig::on_error) builds.
+    pub fn on_upstream_error( &mut self, hook: impl Fn( &Request, &ProxyError ) + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.error_hook = Some( Opaque( Arc::new( hook ) ) );
+        self
+    }
+
+    /// This function sets a callback that is given the request to the
+    /// proxied server just before it is sent, along with the client's request,
+    /// and returns the one to send instead. It runs after everything else the
+    /// proxy does to the request, so it sees the final url, headers and body,
+    /// and nothing changes the request after it. This makes it the place to
+    /// sign requests or set options of the http client the proxy has no
+    /// setting for. It runs again for every retry, failover and redirect
+    /// the proxy sends itself, but not for websockets. Bodies that are
+    /// [streamed](ProxyConfig::upload_stream_threshold) haven't been read
+    /// yet, so only buffered ones can be looked at.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .before_send( |builder, req| {
+    ///         let signature = format!( "{} {}", req.method(), req.uri().path() ).len();
+    ///         builder.header( "x-signature", signature )
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn before_send( &mut self, hook: impl Fn( reqwest::RequestBuilder, &Request ) -> reqwest::RequestBuilder + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.request_hook = Some( Opaque( Arc::new( hook ) ) );
+        self
+    }
+
+    /// This function sets a deadline for handling each web request as a
+    /// whole. Unlike the [upstream timeout](ProxyConfig::upstream_timeout),
+    /// which only covers waiting on the proxied server, this also includes
+    /// reading the request body, consulting the cache, and running any
+    /// callbacks the endpoint was configured with. Requests that run past
+    /// the deadline are answered with `504 Gateway Timeout`.
+    /// 
+    /// Note that callbacks are only interrupted when they yield, so a
+    /// callback that blocks the thread can still hold a request up.
+    pub fn request_timeout( &mut self, timeout: Duration ) -> &mut ProxyConfig {
+        self.request_timeout = Some( timeout );
+        self
+    }
+
+    /// This function sets the longest a web request may last from start to
+    /// finish. Unlike the [request timeout](ProxyConfig::request_timeout),
+    /// which ends once the response starts, this also covers
+    /// [streaming the response body](ProxyConfig::stream_threshold) to the
+    /// client, so a download that keeps going for too long is aborted part
+    /// way even if data is still flowing. Requests that run past it before
+    /// the response starts are answered with `504 Gateway Timeout`.
+    /// 
+    /// Websockets are not affected, see [ws_idle_timeout](ProxyConfig::ws_idle_timeout)
+    /// for closing those.
+    pub fn max_request_duration( &mut self, duration: Duration ) -> &mut ProxyConfig {
+        self.max_request_duration = Some( duration );
+        self
+    }
+
+    /// This function marks the requests under the given path as long-polling,
+    /// which the proxied server holds open until it has something to send
+    /// back. Paths are matched by whole segments against the path the client
+    /// requested, so `/events` covers `/events/orders` but not `/eventsource`.
+    /// 
+    /// Long-polling requests may wait as long as the
+    /// [long-poll timeout](ProxyConfig::long_poll_timeout), in place of the
+    /// [upstream timeout](ProxyConfig::upstream_timeout), the
+    /// [request timeout](ProxyConfig::request_timeout) and the
+    /// [longest request duration](ProxyConfig::max_request_duration), which are
+    /// usually much shorter. Their responses are streamed to the client as
+    /// soon as they start, whatever the [size threshold](ProxyConfig::stream_threshold)
+    /// or [stream selector](ProxyConfig::stream_selector) say, unless they
+    /// have to be kept whole, such as for the [cache](ProxyConfig::enable_cache).
+    /// 
+    /// ```
+    /// use std::time::Duration;
+    /// use poem::http::HeaderName;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .upstream_timeout( Duration::from_secs( 5 ) )
+    ///     .long_poll_path( "/events" )
+    ///     .long_poll_header( HeaderName::from_static( "x-long-poll" ) )
+    ///     .long_poll_timeout( Duration::from_secs( 60 ) )
+    ///     .finish();
+    /// ```
+    pub fn long_poll_path( &mut self, path: &str ) -> &mut ProxyConfig {
+        self.long_poll_paths.push( path.trim_end_matches( '/' ).to_owned() );
+        self
+    }
+
+    /// This function marks the requests carrying the given header as
+    /// long-polling, whatever their path, for clients that say so themselves.
+    /// The header is forwarded along with the others. See
+    /// [long_poll_path](ProxyConfig::long_poll_path) for how long-polling
+    /// requests are handled.
+    pub fn long_poll_header( &mut self, name: HeaderName ) -> &mut ProxyConfig {
+        self.long_poll_header = Some( name );
+        self
+    }
+
+    /// This function sets how long a [long-polling](ProxyConfig::long_poll_path)
+    /// request may last as a whole, including waiting for the proxied server
+    /// and streaming its response. Requests that run past it are answered
+    /// with `504 Gateway Timeout`. Defaults to 2 minutes.
+    pub fn long_poll_timeout( &mut self, timeout: Duration ) -> &mut ProxyConfig {
+        self.long_poll_timeout = timeout;
+        self
+    }
+
+    /// This function sets how the endpoint handles redirects sent back by
+    /// the proxied server. By default, redirects are followed the way a
+    /// browser would, which turns a `POST` into a `GET` on a `301` or `302`.
+    /// See [RedirectMode] for the alternatives.
+    pub fn redirect_mode( &mut self, mode: RedirectMode ) -> &mut ProxyConfig {
+        self.redirect_mode = mode;
+        self
+    }
+
+    /// This function sets the most redirects the endpoint follows for a
+    /// single request, 10 by default. A request redirected more times than
+    /// that is answered with `502 Bad Gateway`. Targets can have a limit of
+    /// their own, see [target_max_redirects](ProxyConfig::target_max_redirects).
+    /// Redirects are never followed in [RedirectMode::PassThrough].
+    pub fn max_redirects( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.max_redirects = max;
+        self
+    }
+
+    /// This function sets the endpoint to translate the `grpc-status` of
+    /// responses from a gRPC server into a matching http status, for clients
+    /// that only speak http. For example, gRPC's `NOT_FOUND` (5) becomes
+    /// `404 Not Found`. The mapping suggested by the gRPC project is used,
+    /// and can be adjusted with [grpc_status](ProxyConfig::grpc_status).
+    /// 
+    /// Since the http client can't read trailers, only statuses sent in the
+    /// response headers are translated. gRPC servers do this whenever a call
+    /// fails before any messages were sent.
+    pub fn map_grpc_status( &mut self ) -> &mut ProxyConfig {
+        self.grpc_status_mapping.get_or_insert_with( grpc::default_mapping );
+        self
+    }
+
+    /// This function sets the http status to send when the proxied server
+    /// reports the given gRPC status code, enabling
+    /// [gRPC status mapping](ProxyConfig::map_grpc_status) if needed.
+    pub fn grpc_status( &mut self, code: u32, status: StatusCode ) -> &mut ProxyConfig {
+        self.grpc_status_mapping.get_or_insert_with( grpc::default_mapping ).insert( code, status );
+        self
+    }
+
+    /// This function sets the endpoint to forward web requests made with the
+    /// `from` method to the proxied server with the `to` method instead, such
+    /// as to turn a browser's `GET` into the `POST` a backend expects. Since
+    /// each route has its own configuration, this applies per route.
+    /// 
+    /// The body is forwarded as it is. When a request without a body is sent
+    /// on with a method that usually has one, it is given a `Content-Length`
+    /// of `0` so the proxied server doesn't wait for one. Websockets are never
+    /// rewritten, and the cache and idempotency keys still go by the method
+    /// the client used.
+    /// 
+    /// ```
+    /// use poem::http::Method;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .method_rewrite( Method::GET, Method::POST )
+    ///     .finish();
+    /// ```
+    pub fn method_rewrite( &mut self, from: Method, to: Method ) -> &mut ProxyConfig {
+        self.method_rewrites.insert( from, to );
+        self
+    }
+
+    /// This function sets what the endpoint does with requests that were sent
+    /// as TLS early data (0-RTT), which an attacker could replay. The server
+    /// terminating TLS must mark these with the `Early-Data: 1` header. See
+    /// [EarlyDataPolicy] for the available options.
+    /// 
+    /// The header is all the proxy goes by, since poem doesn't say whether a
+    /// request arrived as early data. Poem's own TLS listeners don't accept
+    /// early data at all, so this is for when TLS is terminated in front of
+    /// the proxy, by a server that sets the header.
+    pub fn early_data( &mut self, policy: EarlyDataPolicy ) -> &mut ProxyConfig {
+        self.early_data = policy;
+        self
+    }
+
+    /// This function sets what the endpoint does with plain http requests
+    /// carrying `Upgrade-Insecure-Requests: 1`, which browsers send to ask
+    /// for https. They can either be forwarded as they are, or redirected to
+    /// the endpoint's https url. See [InsecureRequestPolicy] for details.
+    pub fn upgrade_insecure_requests( &mut self, policy: InsecureRequestPolicy ) -> &mut ProxyConfig {
+        self.insecure_requests = policy;
+        self
+    }
+
+    /// This function sets what the endpoint does with requests that don't
+    /// have a `Host` header, which HTTP/1.0 clients may leave out. By default
+    /// the address of the target is sent as the host. See [MissingHostPolicy]
+    /// for the available options.
+    pub fn missing_host( &mut self, policy: MissingHostPolicy ) -> &mut ProxyConfig {
+        self.missing_host = policy;
+        self
+    }
+
+    /// This function sets the endpoint to honor `Idempotency-Key` headers,
+    /// which clients send to make retrying a write safe. The response to the
+    /// first request with a key is kept for `ttl`, and any other request with
+    /// the same key, method and url is answered with it instead of being
+    /// forwarded. Requests that arrive while the first one is still being
+    /// forwarded wait for its response. If the first request fails before the
+    /// proxied server responds, the next one is forwarded in its place.
+    pub fn idempotency_keys( &mut self, ttl: Duration ) -> &mut ProxyConfig {
+        self.idempotency = Some( Arc::new( IdempotencyStore::new( ttl ) ) );
+        self
+    }
+
+    /// This function sets the endpoint to capture complete requests and
+    /// responses, headers and bodies, for a sample of the traffic it forwards.
+    /// This is meant for debugging issues that only show up in production.
+    /// See [TrafficCapture] for the available options.
+    pub fn capture_traffic( &mut self, capture: &TrafficCapture ) -> &mut ProxyConfig {
+        self.capture = Some( capture.clone() );
+        self
+    }
+
+    /// This function sets the endpoint to account the bytes each request
+    /// moves, such as for billing or quotas, under the key the callback picks
+    /// for it, like its route or its client. The bodies of requests are
+    /// counted as they are read from the client, and those of responses as
+    /// they are sent back, so streamed bodies are counted by what was
+    /// actually sent. Headers and websocket messages aren't counted.
+    /// 
+    /// A request is accounted once its response has been sent in full, or the
+    /// client has gone away. The totals of each key are available through
+    /// [ProxyHandle::byte_totals], and each request can be reported as it
+    /// completes with [on_bytes_accounted](ProxyConfig::on_bytes_accounted).
+    /// The totals are kept for every key ever picked, so keys should come from
+    /// a bounded set. Errors the proxy answers with itself are turned into
+    /// their responses so that they can be counted.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .account_bytes( |req| match req.original_uri().path() {
+    ///         path if path.starts_with( "/api/" ) => "api".into(),
+    ///         _ => "static".into(),
+    ///     })
+    ///     .finish();
+    /// 
+    /// let totals = config.handle().byte_totals();
+    /// ```
+    pub fn account_bytes( &mut self, key: impl Fn( &Request ) -> String + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.byte_key = Some( Opaque( Arc::new( key ) ) );
+        self
+    }
+
+    /// This function sets a callback that is run with the key and the bytes
+    /// of each request once it has been [accounted](ProxyConfig::account_bytes),
+    /// such as to pass them on to a quota system. It is only run while bytes
+    /// are being accounted.
+    pub fn on_bytes_accounted( &mut self, hook: impl Fn( &str, ByteCount ) + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.byte_hook = Some( Opaque( Arc::new( hook ) ) );
+        self
+    }
+
+    /// This function sets how long clients are told to wait (through the
+    /// `Retry-After` header) before trying again when the proxy itself answers
+    /// with `503 Service Unavailable` or `429 Too Many Requests`, such as when
+    /// every target is being drained. Responses from the proxied server are
+    /// left as they are.
+    pub fn retry_after( &mut self, delay: Duration ) -> &mut ProxyConfig {
+        self.retry_after = delay;
+        self
+    }
+
+    /// This function sets the endpoint to make sure the proxied server
+    /// honored the client's `Accept` header. When a successful response has
+    /// a `Content-Type` that doesn't match any of the accepted types, the
+    /// client is answered with the given status instead, such as
+    /// `406 Not Acceptable` or `502 Bad Gateway`.
+    pub fn validate_content_negotiation( &mut self, status: StatusCode ) -> &mut ProxyConfig {
+        self.validate_content_negotiation = Some( status );
+        self
+    }
+
+    /// This function sets the endpoint to cache responses from the proxied
+    /// server in memory. Up to 1024 responses are kept, evicting the least
+    /// recently used ones first. Only successful responses to `GET` requests
+    /// that the server allows shared caches to store (through `Cache-Control`)
+    /// are cached.
+    /// 
+    /// Conditional requests (`If-None-Match` and `If-Modified-Since`) are
+    /// answered with `304 Not Modified` straight from the cache when they
+    /// match, and stale responses are revalidated with the proxied server
+    /// instead of being fetched again in full.
+    /// 
+    /// When the proxied server fails to answer, or answers with a `5xx`
+    /// error, a stale response is served in its place for as long as the
+    /// `stale-if-error` directive of the response or request allows. These
+    /// responses carry a `Warning: 111 - "Revalidation Failed"` header.
+    /// 
+    /// Cached responses can be purged ahead of time, such as after a deploy,
+    /// through the [handle](ProxyConfig::handle) of the endpoint. See
+    /// [ProxyHandle::invalidate_cache_prefix] for more information.
+    pub fn enable_cache( &mut self ) -> &mut ProxyConfig {
+        self.cache_store( Arc::new( MemoryCache::default() ) )
+    }
+
+    /// This function sets the endpoint to cache responses from the proxied
+    /// server in the given store, which can keep them anywhere, such as on
+    /// disk or in Redis. See [CacheStore] for more information.
+    pub fn cache_store( &mut self, store: Arc<dyn CacheStore> ) -> &mut ProxyConfig {
+        self.cache = Some( Opaque( store ) );
+        self
+    }
+
+    /// This function sets the endpoint to cache `404 Not Found` and `410 Gone`
+    /// responses as well, so that requests for missing content don't all
+    /// reach the proxied server. They are kept for `ttl`, unless the server
+    /// gives them a lifetime of its own or forbids caching them through
+    /// `Cache-Control`. This only has an effect when
+    /// [caching is enabled](ProxyConfig::enable_cache).
+    pub fn cache_negative_responses( &mut self, ttl: Duration ) -> &mut ProxyConfig {
+        self.negative_cache_ttl = Some( ttl );
+        self
+    }
+
+    /// This function sets how the responses to requests under the given path
+    /// are cached, replacing any policy set for the same path before. Only
+    /// the policy for the most specific path that matches a request applies,
+    /// and paths are matched against the path the client requested. See
+    /// [CachePolicy] for what a policy can change. This only has an effect
+    /// when [caching is enabled](ProxyConfig::enable_cache).
+    pub fn cache_policy( &mut self, path: &str, policy: &CachePolicy ) -> &mut ProxyConfig {
+        let path = path.trim_end_matches( '/' ).to_owned();
+        self.cache_policies.retain( |( existing, _ )| *existing != path );
+        self.cache_policies.push( ( path, policy.clone() ) );
+        self
+    }
+
+    /// This function restricts the response statuses forwarded to the client
+    /// to the given ones. A response with any other status is replaced with a
+    /// generic error, see [ProxyConfig::blocked_status]. This replaces any
+    /// statuses set with [ProxyConfig::deny_statuses].
+    pub fn allow_statuses( &mut self, statuses: impl IntoIterator<Item = StatusCode> ) -> &mut ProxyConfig {
+        self.status_filter = Some( StatusFilter::Allow( statuses.into_iter().collect() ) );
+        self
+    }
+
+    /// This function keeps the given response statuses from reaching the
+    /// client, replacing responses that have them with a generic error, see
+    /// [ProxyConfig::blocked_status]. This replaces any statuses set with
+    /// [ProxyConfig::allow_statuses].
+    /// 
+    /// For example, `.deny_statuses( [StatusCode::INTERNAL_SERVER_ERROR] )`
+    /// answers the client with `502 Bad Gateway` whenever the proxied server
+    /// fails, instead of passing along its error page.
+    pub fn deny_statuses( &mut self, statuses: impl IntoIterator<Item = StatusCode> ) -> &mut ProxyConfig {
+        self.status_filter = Some( StatusFilter::Deny( statuses.into_iter().collect() ) );
+        self
+    }
+
+    /// This function sets the status sent to the client in place of a response
+    /// status that isn't allowed to be forwarded. The default is
+    /// `502 Bad Gateway`.
+    pub fn blocked_status( &mut self, status: StatusCode ) -> &mut ProxyConfig {
+        self.blocked_status = status;
+        self
+    }
+
+    /// This function sets the endpoint to send the address (ip and port) of
+    /// the proxied server that answered each request in the given response
+    /// header, which helps track down a misbehaving server when the targets
+    /// are load balanced through DNS. The address is also recorded with
+    /// [captured traffic](CapturedExchange::upstream_addr) either way.
+    pub fn upstream_address_header( &mut self, header: HeaderName ) -> &mut ProxyConfig {
+        self.upstream_address_header = Some( header );
+        self
+    }
+
+    /// This function sets the endpoint to add a `Server-Timing` header to
+    /// forwarded responses, so that the browser's developer tools and
+    /// performance APIs show how long the proxied server took to answer.
+    /// Three durations are given, in milliseconds: `proxy` for the time spent
+    /// before the request was sent on, `ttfb` for the wait for the proxied
+    /// server's response headers, and `total` for the time until the
+    /// response headers were sent. Connecting to the proxied server counts
+    /// towards `ttfb`, since the http client doesn't say how long it took.
+    /// Any `Server-Timing` the proxied server sent is kept alongside.
+    /// 
+    /// Browsers only show the timings to pages on other origins if they are
+    /// allowed to by the [`Timing-Allow-Origin`](ProxyConfig::timing_allow_origin)
+    /// header.
+    /// 
+    /// ```
+    /// use poem::http::HeaderValue;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .server_timing()
+    ///     .timing_allow_origin( HeaderValue::from_static( "https://app.example.com" ) )
+    ///     .finish();
+    /// ```
+    pub fn server_timing( &mut self ) -> &mut ProxyConfig {
+        self.server_timing = true;
+        self
+    }
+
+    /// This function sets the `Timing-Allow-Origin` header sent with every
+    /// forwarded response, replacing the one the proxied server sent, if any.
+    /// It names the origins (or `*` for any) whose pages may read the
+    /// [server timings](ProxyConfig::server_timing) and detailed resource
+    /// timings of the response.
+    pub fn timing_allow_origin( &mut self, origin: HeaderValue ) -> &mut ProxyConfig {
+        self.timing_allow_origin = Some( origin );
+        self
+    }
+
+    /// This function sets the size from which responses are streamed to the
+    /// client as they arrive, rather than read in full before being sent on.
+    /// By default every response is streamed, keeping the memory used per
+    /// request flat; raising the threshold reads smaller responses whole.
+    /// Responses of unknown size are always streamed, and the `Content-Length`
+    /// of the others is passed on.
+    /// 
+    /// Responses that have to be kept whole, for the [cache](ProxyConfig::enable_cache),
+    /// for [idempotency keys](ProxyConfig::idempotency_keys) or to be
+    /// [captured](ProxyConfig::capture_traffic), are still read in full.
+    pub fn stream_threshold( &mut self, bytes: usize ) -> &mut ProxyConfig {
+        self.stream_threshold = bytes;
+        self
+    }
+
+    /// This function sets a callback that decides for each request whether
+    /// its response is streamed (`true`) or read in full (`false`), whatever
+    /// its size, such as by its path. Whenever the callback returns `None`,
+    /// the [size threshold](ProxyConfig::stream_threshold) decides. Responses
+    /// that have to be kept whole are still read in full.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .stream_threshold( 1024 * 1024 )
+    ///     .stream_selector( |req| match req.uri().path() {
+    ///         path if path.starts_with( "/download/" ) => Some( true ),
+    ///         path if path.starts_with( "/api/" ) => Some( false ),
+    ///         _ => None,
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn stream_selector( &mut self, selector: impl Fn( &Request ) -> Option<bool> + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.stream_selector = Some( Opaque( Arc::new( selector ) ) );
+        self
+    }
+
+    /// This function sets the size from which request bodies are streamed to
+    /// the proxied server as they arrive, rather than read in full before
+    /// being forwarded. By default every body is streamed; raising the
+    /// threshold reads smaller bodies whole. Bodies of unknown size are always
+    /// streamed. The bytes are passed on verbatim along with the body's
+    /// `Content-Length` or `Transfer-Encoding`, so large multipart uploads go
+    /// through without being buffered or parsed. If the client aborts an
+    /// upload, the request to the proxied server is aborted with it.
+    /// 
+    /// Bodies are still read in full when they are needed whole: to
+    /// [capture traffic](ProxyConfig::capture_traffic), or to resend them
+    /// when [following redirects](RedirectMode::PreserveMethod). An upload
+    /// that goes over the [maximum body size](ProxyConfig::max_body_size)
+    /// without declaring its length is cut off as soon as it does, and one that
+    /// doesn't match its `Content-Length` once it is all sent. Both abort the
+    /// request to the proxied server, and the client is answered with
+    /// `413 Payload Too Large` or `400 Bad Request` respectively.
+    pub fn upload_stream_threshold( &mut self, bytes: usize ) -> &mut ProxyConfig {
+        self.upload_stream_threshold = bytes;
+        self
+    }
+
+    /// This function sets how many other targets an idempotent request (`GET`,
+    /// `HEAD`, `OPTIONS`, `TRACE`, `PUT` or `DELETE`) is sent to when no
+    /// connection can be made to the target it was meant for. Each target is
+    /// tried at most once, in the same order requests are spread across them,
+    /// skipping those being drained. The [error hook](ProxyConfig::on_upstream_error)
+    /// still hears about every target that couldn't be reached.
+    /// 
+    /// Only connection failures fail over: a target that was reached but
+    /// failed to answer may have acted on the request already. Neither do
+    /// [streamed uploads](ProxyConfig::upload_stream_threshold), which can't
+    /// be sent twice.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "backend-1:8080" )
+    ///     .add_target( "backend-2:8080" )
+    ///     .add_target( "backend-3:8080" )
+    ///     .web_insecure()
+    ///     .failover( 2 )
+    ///     .finish();
+    /// ```
+    pub fn failover( &mut self, attempts: u32 ) -> &mut ProxyConfig {
+        self.failover_attempts = attempts;
+        self
+    }
+
+    /// This function sets how many times an idempotent request (`GET`, `HEAD`,
+    /// `OPTIONS`, `TRACE`, `PUT` or `DELETE`) is sent again to the same target
+    /// when it fails in a way that is usually transient: no connection could
+    /// be made, the connection was reset, or the target answered with
+    /// `502 Bad Gateway` or `503 Service Unavailable`. Retries back off
+    /// exponentially, starting at 100ms, and the client gets the outcome of
+    /// the last attempt. The [error hook](ProxyConfig::on_upstream_error)
+    /// still hears about every failed attempt.
+    /// 
+    /// A retry after a connection failed or was reset goes out on a new
+    /// connection rather than another one from the pool, which may have been
+    /// dropped by the target just the same, such as when it restarted. The
+    /// pool's idle connections are closed along with it, which, unless
+    /// [keep-alive hints are honored](ProxyConfig::honor_keep_alive), are
+    /// those to every target. Retries after a `502` or `503` reuse
+    /// connections as usual.
+    /// 
+    /// Other methods, such as `POST`, are never retried, since the target may
+    /// have acted on the request before failing. Neither are
+    /// [streamed uploads](ProxyConfig::upload_stream_threshold), which can't
+    /// be sent twice, or requests that [timed out](ProxyConfig::upstream_timeout).
+    /// Along with [failover](ProxyConfig::failover), the retries go to the
+    /// last target tried.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .with_retries( 2 )
+    ///     .finish();
+    /// ```
+    pub fn with_retries( &mut self, attempts: u32 ) -> &mut ProxyConfig {
+        self.retry_attempts = attempts;
+        self
+    }
+
+    /// This function turns on health checks, which send every target a `GET`
+    /// for `path` each [interval](ProxyConfig::health_check_interval). A target
+    /// that doesn't answer with the [expected status](ProxyConfig::health_check_status)
+    /// before the next check is due is taken out of rotation, the way a
+    /// [drained](ProxyHandle::drain_target) one is, and put back once it passes
+    /// a check again. Targets count as up until their first check. When every
+    /// target is down, requests are answered with `503 Service Unavailable`.
+    /// The path is sent as it is, without the target's path rewrites.
+    /// 
+    /// The checks start along with the [ProxyEndpoint], or with the first
+    /// request when the [proxy] handler is used instead, and stop once every
+    /// copy of the configuration has been dropped, such as when the server
+    /// shuts down. [ProxyHandle::is_healthy] tells how a target did on its
+    /// last check.
+    /// 
+    /// ```
+    /// use std::time::Duration;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "backend-1:8080" )
+    ///     .add_target( "backend-2:8080" )
+    ///     .web_insecure()
+    ///     .health_check_path( "/healthz" )
+    ///     .health_check_interval( Duration::from_secs( 5 ) )
+    ///     .finish();
+    /// ```
+    pub fn health_check_path( &mut self, path: &str ) -> &mut ProxyConfig {
+        self.health_check_path = Some( path.into() );
+        self
+    }
+
+    /// This function sets how often targets are sent a
+    /// [health check](ProxyConfig::health_check_path), which is also how long
+    /// they have to answer it. Defaults to 10 seconds.
+    pub fn health_check_interval( &mut self, interval: Duration ) -> &mut ProxyConfig {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// This function sets the status a target has to answer a
+    /// [health check](ProxyConfig::health_check_path) with to be up. Defaults
+    /// to `200 OK`.
+    pub fn health_check_status( &mut self, status: StatusCode ) -> &mut ProxyConfig {
+        self.health_check_status = status;
+        self
+    }
+
+    /// This function sets how many times the endpoint retries connecting to
+    /// the proxied server when a client opens a websocket, in case the server
+    /// is briefly unreachable (a DNS blip, or a restart refusing connections).
+    /// Retries back off exponentially, starting at 100ms. Only the initial
+    /// connection is retried: a rejected handshake or a websocket that drops
+    /// later on is not.
+    pub fn ws_connect_retries( &mut self, retries: u32 ) -> &mut ProxyConfig {
+        self.ws_connect_retries = retries;
+        self
+    }
+
+    /// This function sets how many websocket handshakes with the proxied
+    /// server may be in progress at once, so that a burst of clients
+    /// connecting together doesn't overwhelm it. Past that, new websockets
+    /// are answered with `503 Service Unavailable` and a `Retry-After` header
+    /// instead of being upgraded. Websockets that are already open don't
+    /// count towards the limit; see
+    /// [overload_threshold](ProxyConfig::overload_threshold) for that.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .ws_insecure()
+    ///     .ws_max_concurrent_handshakes( 16 )
+    ///     .finish();
+    /// ```
+    pub fn ws_max_concurrent_handshakes( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.ws_handshakes = Some( Arc::new( Semaphore::new( max ) ) );
+        self
+    }
+
+    /// This function restricts the subprotocols clients may ask for in the
+    /// `Sec-WebSocket-Protocol` header when opening a websocket. A client
+    /// asking for any other, even alongside allowed ones, is answered with
+    /// `403 Forbidden` before anything is sent to the proxied server.
+    /// Clients that don't ask for a subprotocol are let through. Names are
+    /// matched case sensitively.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .ws_insecure()
+    ///     .ws_allowed_subprotocols( [ "graphql-transport-ws", "mqtt" ] )
+    ///     .finish();
+    /// ```
+    pub fn ws_allowed_subprotocols( &mut self, protocols: impl IntoIterator<Item = impl Into<String>> ) -> &mut ProxyConfig {
+        self.ws_allowed_subprotocols = Some( protocols.into_iter().map( Into::into ).collect() );
+        self
+    }
+
+    /// This function sets the largest payload, in bytes, that either peer of a
+    /// websocket may send in a message. A peer that sends a bigger one has the
+    /// websocket closed on both sides with code `1009 Message Too Big`, and
+    /// the message is not relayed. Since messages are relayed whole, a
+    /// message sent in fragments counts as a single frame.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .ws_insecure()
+    ///     .ws_max_frame_size( 64 * 1024 )
+    ///     .finish();
+    /// ```
+    pub fn ws_max_frame_size( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.ws_max_frame_size = Some( max );
+        self
+    }
+
+    /// This function sets the largest total size, in bytes, of the headers a
+    /// client may send when opening a websocket, counted the way they are
+    /// sent over HTTP/1.1: each name and value, plus four bytes for the
+    /// separator and line break. A client sending more, such as an oversized
+    /// `Cookie`, is answered with `431 Request Header Fields Too Large` before
+    /// anything is sent to the proxied server. The headers the proxy adds
+    /// itself don't count.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .ws_insecure()
+    ///     .ws_max_handshake_size( 8 * 1024 )
+    ///     .finish();
+    /// ```
+    pub fn ws_max_handshake_size( &mut self, max: usize ) -> &mut ProxyConfig {
+        self.ws_max_handshake_size = Some( max );
+        self
+    }
+
+    /// This function sets the endpoint to open websockets to the proxied
+    /// server through the given SOCKS5 proxy, for networks where outbound
+    /// connections have to go through one. The proxy resolves the server's
+    /// name itself. See [Socks5Proxy] for an example.
+    /// 
+    /// Only websockets go through the proxy, web requests are still sent to
+    /// the proxied server directly.
+    pub fn ws_socks5_proxy( &mut self, socks: &Socks5Proxy ) -> &mut ProxyConfig {
+        self.ws_socks5_proxy = Some( socks.clone() );
+        self
+    }
+
+    /// This function sets the endpoint to close websockets that neither peer
+    /// has sent a message on for the given time. A message in either
+    /// direction keeps the websocket open, so a server pushing updates to a
+    /// quiet client isn't cut off. Both peers are sent a close frame with code
+    /// `1001 Going Away`.
+    pub fn ws_idle_timeout( &mut self, timeout: Duration ) -> &mut ProxyConfig {
+        self.ws_idle_timeout = Some( timeout );
+        self
+    }
+
+    /// This function sets the endpoint to ping the client of every websocket
+    /// once per `interval`, so that NATs and load balancers in between don't
+    /// drop the connection for being idle. A client that leaves the pings
+    /// unanswered for two intervals is taken to be gone, and the websocket
+    /// is closed on both sides.
+    /// 
+    /// The pongs answering these pings are not relayed to the proxied
+    /// server, and neither they nor the pings count as activity for the
+    /// [idle timeout](ProxyConfig::ws_idle_timeout). Pings and pongs sent by
+    /// either peer are relayed as usual.
+    pub fn ws_keepalive( &mut self, interval: Duration ) -> &mut ProxyConfig {
+        self.ws_keepalive = Some( interval );
+        self
+    }
+
+    /// This function sets the endpoint to close websockets when the target
+    /// they were forwarded to is removed through [ProxyHandle::remove_target].
+    /// Both peers are sent a close frame with code `1001 Going Away`, so
+    /// clients can reconnect and land on one of the remaining targets.
+    /// 
+    /// Draining a target leaves its websockets open, so a rolling deployment
+    /// can drain a target, give its websockets a grace period, and then remove
+    /// it to close the rest.
+    pub fn ws_close_on_removal( &mut self ) -> &mut ProxyConfig {
+        self.ws_close_on_removal = true;
+        self
+    }
+
+    /// This function sets a callback that is run whenever a websocket closes,
+    /// once both directions are done. It is told the code of the first close
+    /// frame sent by either peer, or `1006` if the websocket ended without one,
+    /// which makes it a good place to log closes that
+    /// [weren't clean](WebSocketClose::is_abnormal). Totals are also available
+    /// through [ProxyHandle::websocket_stats].
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .ws_insecure()
+    ///     .on_ws_close( |close| if close.is_abnormal() {
+    ///         eprintln!( "websocket closed abnormally: {} {}", close.code, close.reason );
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn on_ws_close( &mut self, hook: impl Fn( &WebSocketClose ) + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.ws_close_hook = Some( Opaque( Arc::new( hook ) ) );
+        self
+    }
+
+    /// This function sets the endpoint to copy a sample of the messages it
+    /// relays over websockets, in both directions, to a sink. This is meant
+    /// for debugging without logging every message. See [WebSocketTap] for the
+    /// available options.
+    pub fn tap_websockets( &mut self, tap: &WebSocketTap ) -> &mut ProxyConfig {
+        self.ws_tap = Some( tap.clone() );
+        self
+    }
+
+    /// This function lets the endpoint read up to the given number of
+    /// websocket messages ahead of sending them on, in each direction. This
+    /// smooths out bursts from a fast peer to a slow one, while still
+    /// bounding how much each connection can buffer: once that many messages
+    /// are waiting, the proxy stops reading until the slow peer catches up.
+    /// How many are waiting is available through
+    /// [ProxyHandle::buffered_websocket_messages].
+    pub fn ws_max_inflight_frames( &mut self, frames: usize ) -> &mut ProxyConfig {
+        self.ws_max_inflight_frames = Some( frames );
+        self
+    }
+
+    /// This function sets how the endpoint formats the bodies of the errors it
+    /// generates itself, like `504 Gateway Timeout` when a request takes too
+    /// long or `429 Too Many Requests` when a client is rate limited. Error
+    /// responses sent by the proxied server are passed through as they are,
+    /// so with [ErrorFormat::Json] clients can tell the two apart. The status
+    /// and headers of the error are kept either way.
+    pub fn error_format( &mut self, format: ErrorFormat ) -> &mut ProxyConfig {
+        self.error_format = format;
+        self
+    }
+
+    /// This function sets the response the endpoint sends when forwarding a
+    /// request fails with the given kind of [ProxyError], such as a branded
+    /// page for when the proxied server is down. See [ErrorPage] for an
+    /// example. The page replaces the error entirely, so it isn't affected by
+    /// the [error format](ProxyConfig::error_format). The
+    /// [error hook](ProxyConfig::on_upstream_error) still runs first.
+    pub fn error_page( &mut self, kind: ProxyErrorKind, page: &ErrorPage ) -> &mut ProxyConfig {
+        self.error_pages.insert( kind, page.clone() );
+        self
+    }
+
+    /// This function sets a callback that builds the response the endpoint
+    /// sends when forwarding a request fails, for when the shape of error
+    /// responses depends on what went wrong in ways an
+    /// [error page](ProxyConfig::error_page) can't express. Kinds of
+    /// [ProxyError] with an error page of their own are still answered with
+    /// it. Like error pages, the response isn't affected by the
+    /// [error format](ProxyConfig::error_format), and the
+    /// [error hook](ProxyConfig::on_upstream_error) still runs first.
+    /// 
+    /// ```
+    /// use poem::{ Response, http::StatusCode };
+    /// use poem_proxy::{ ProxyConfig, ProxyError };
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .on_error( |error| {
+    ///         let ( status, reason ) = match error {
+    ///             ProxyError::Connect( _ ) => ( StatusCode::SERVICE_UNAVAILABLE, "unavailable" ),
+    ///             ProxyError::Timeout( _ ) => ( StatusCode::GATEWAY_TIMEOUT, "timeout" ),
+    ///             _ => ( error.status(), "upstream" ),
+    ///         };
+    ///         Response::builder()
+    ///             .status( status )
+    ///             .content_type( "application/json" )
+    ///             .body( format!( r#"{{"error": "{}"}}"#, reason ) )
+    ///     })
+    ///     .finish();
+    /// ```
+    pub fn on_error( &mut self, handler: impl Fn( &ProxyError ) -> Response + Send + Sync + 'static ) -> &mut ProxyConfig {
+        self.error_handler = Some( Opaque( Arc::new( handler ) ) );
+        self
+    }
+
+    /// This function sets the endpoint to treat error responses (`4xx` and
+    /// `5xx`) from the proxied server as failures, given to the
+    /// [error hook](ProxyConfig::on_upstream_error) as [ProxyError::Status]
+    /// and answered with the [error page](ProxyConfig::error_page) for
+    /// [ProxyErrorKind::Status] or by the [error handler](ProxyConfig::on_error).
+    /// Without either, the client gets the status with the error message of
+    /// the http client rather than the body the proxied server sent. A stale
+    /// [cached](ProxyConfig::cache) response that may be served on errors is
+    /// still preferred.
+    pub fn handle_error_statuses( &mut self ) -> &mut ProxyConfig {
+        self.handle_error_statuses = true;
+        self
+    }
+
+    /// This function sets the endpoint to treat server error responses
+    /// (`5xx`) from the proxied server as failures, the way
+    /// [handle_error_statuses](ProxyConfig::handle_error_statuses) does for
+    /// every error response, so that the [error hook](ProxyConfig::on_upstream_error)
+    /// and [error handler](ProxyConfig::on_error) see the backend failing.
+    /// Client errors (`4xx`) are still forwarded with the status and body the
+    /// proxied server sent.
+    /// 
+    /// ```
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .treat_upstream_errors_as_failures()
+    ///     .on_upstream_error( |req, error| eprintln!( "backend failed on {}: {}", req.uri(), error ) )
+    ///     .finish();
+    /// ```
+    pub fn treat_upstream_errors_as_failures( &mut self ) -> &mut ProxyConfig {
+        self.treat_upstream_errors_as_failures = true;
+        self
+    }
+
+    /// This function sets the endpoint to forward requests and responses with
+    /// their headers as untouched as possible, for backends that verify
+    /// signatures over them. It takes precedence over the options that would
+    /// otherwise change headers, so in this mode the endpoint does not:
+    /// 
+    /// - add a `Host` header to requests without one, or reject them
+    ///   (see [missing_host](ProxyConfig::missing_host))
+    /// - add the [`X-Forwarded-*`](ProxyConfig::enable_forwarded_headers)
+    ///   headers to requests
+    /// - [normalize](ProxyConfig::normalize_headers) header names
+    /// - add a [`traceparent`](ProxyConfig::propagate_trace_context) or
+    ///   [HTTP version](ProxyConfig::forward_http_version) header to requests
+    /// - replace the conditional headers of requests to revalidate stale
+    ///   [cached](ProxyConfig::enable_cache) responses
+    /// - apply the [cookie policy](ProxyConfig::cookie_policy) or
+    ///   [cookie rewrite](ProxyConfig::rewrite_cookies) to responses
+    /// - add a [default content type](ProxyConfig::default_content_type) to
+    ///   responses without one
+    /// - add the [upstream address](ProxyConfig::upstream_address_header),
+    ///   [`Server-Timing`](ProxyConfig::server_timing) or
+    ///   [`Timing-Allow-Origin`](ProxyConfig::timing_allow_origin) headers to
+    ///   responses
+    /// 
+    /// Outside of this mode, the hop-by-hop headers (`Connection`, `Keep-Alive`,
+    /// `TE`, `Transfer-Encoding`, `Upgrade` and the `Proxy-*` headers) are
+    /// removed from requests and responses alike, along with any header named
+    /// in `Connection`. In this mode only `Connection` and the headers it names
+    /// are removed, as the protocol requires.
+    /// 
+    /// [Header rewrites](ProxyConfig::rewrite_headers) are still applied, since
+    /// they are configured per target on purpose. The `Content-Length` and
+    /// `Transfer-Encoding` headers still describe the body as it is actually
+    /// sent, and the http client still sends `Accept: */*` on requests that
+    /// have no `Accept` header.
+    pub fn transparent( &mut self ) -> &mut ProxyConfig {
+        self.transparent = true;
+        self
+    }
+
+    /// This function makes the endpoint panic when it runs into a condition
+    /// that should never happen, such as failing to build its http client,
+    /// so that bugs surface loudly during development. By default these
+    /// conditions are logged as errors and handled as gracefully as
+    /// possible, which usually means answering with
+    /// `500 Internal Server Error`.
+    pub fn strict_mode( &mut self ) -> &mut ProxyConfig {
+        self.strict_mode = true;
+        self
+    }
+
+    /// This function sets the endpoint to inject artificial latency and faults
+    /// into the requests it forwards, which is useful for testing how the
+    /// systems behind the proxy cope with an unreliable network. See
+    /// [FaultInjection] for the available faults.
+    /// 
+    /// This is only available with the `fault-injection` feature, which should
+    /// never be enabled for production builds.
+    #[cfg(feature = "fault-injection")]
+    pub fn fault_injection( &mut self, faults: &FaultInjection ) -> &mut ProxyConfig {
+        self.fault_injection = Some( faults.clone() );
+        self
+    }
+
+    /// Finishes off the building proccess by returning a new ProxyConfig object
+    /// (not reference) that contains all the settings that were previously
+    /// specified.
+    pub fn finish( &mut self ) -> ProxyConfig {
+        self.clone()
+    }
+
+    /// Finishes off the building process like [finish](ProxyConfig::finish),
+    /// but returns the configuration ready to be shared by several endpoints,
+    /// such as to mount the same proxied server under more than one path. See
+    /// [SharedProxyConfig] for more information.
+    pub fn shared( &self ) -> SharedProxyConfig {
+        SharedProxyConfig::new( self.clone() )
+    }
+
+}
+
+/// # Convenience Functions
+/// 
+/// These functions make it possible to get information from the ProxyConfig struct.
+impl ProxyConfig {
+
+    /// Returns a handle for controlling the endpoint while it is running, such as
+    /// adding targets or draining them for a rolling deployment. See [ProxyHandle] for more
+    /// information.
+    pub fn handle( &self ) -> ProxyHandle {
+        ProxyHandle::new( self )
+    }
+
+    /// Returns the target url of the request, including the proper protocol information
+    /// and the correct pathing if nesting is enabled. When there are multiple targets,
+    /// this is the url for the first one.
+    /// 
+    /// An example output would be
+    /// 
+    /// > `"https://proxy.domain.com"`
+    #[allow(clippy::result_unit_err)]
+    pub fn get_web_request_uri( &self, subpath: Option<String> ) -> Result<String, ()> {
+        let target = self.targets.primary().ok_or(())?;
+        self.web_request_uri( &target, subpath ).ok_or(())
+    }
+
+    /// Returns the target url of the websocket, including the proper protocol information.
+    /// When there are multiple targets, this is the url for the first one.
+    /// 
+    /// An example output would be
+    /// 
+    /// > `"wss://websocket.domain.com"`
+    #[allow(clippy::result_unit_err)]
+    pub fn get_web_socket_uri( &self ) -> Result<String, ()> {
+        let target = self.targets.primary().ok_or(())?;
+        self.web_socket_uri( &target ).ok_or(())
+    }
+
+    /// Returns the url the given request is forwarded to, including the proper protocol
+    /// information and, if nesting is enabled, the path and query of the request. When
+    /// there are multiple targets, this is the url for the first one.
+    /// 
+    /// ```
+    /// use poem::{ Request, http::Uri };
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173/" )
+    ///     .web_secure()
+    ///     .enable_nesting()
+    ///     .finish();
+    /// let uri = |uri| config.get_request_uri( &Request::builder().uri( Uri::from_static( uri ) ).finish() );
+    /// 
+    /// assert_eq!( uri( "/api/items?page=2&limit=50" ), Ok( "https://localhost:5173/api/items?page=2&limit=50".into() ) );
+    /// assert_eq!( uri( "/api/items" ), Ok( "https://localhost:5173/api/items".into() ) );
+    /// assert_eq!( uri( "/api/items?" ), Ok( "https://localhost:5173/api/items?".into() ) );
+    /// assert_eq!( uri( "/api/a%20b?q=a%26b%3Dc&x=%2F" ), Ok( "https://localhost:5173/api/a%20b?q=a%26b%3Dc&x=%2F".into() ) );
+    /// assert_eq!( uri( "https://example.com/api/items?a=1&b=2" ), Ok( "https://localhost:5173/api/items?a=1&b=2".into() ) );
+    /// ```
+    #[allow(clippy::result_unit_err)]
+    pub fn get_request_uri( &self, req: &Request ) -> Result<String, ()> {
+        self.get_web_request_uri( Some( request_path( req.uri() ) ) )
+    }
+
+    /// Returns the url of a request forwarded to the given target, or `None` if web
+    /// requests aren't forwarded.
+    fn web_request_uri( &self, target: &Target, subpath: Option<String> ) -> Option<String> {
+        let base = target.web_base( self.web_secure, self.proxy_port )?;
+
+        let sub = match subpath {
+            Some( sub ) if self.support_nesting => {
+                let path = path_and_query( &sub );
+                let path = target.rewrite_path( match &self.strip_prefix {
+                    Some( prefix ) => rewrite::strip_prefix( &path, prefix ),
+                    None => path,
+                });
+                self.trailing_slash.apply( path )
+            },
+            _ => "".into(),
+        };
+
+        Some( base+&sub )
+    }
+
+    /// Returns the url of a websocket forwarded to the given target, or `None` if
+    /// websockets aren't forwarded.
+    fn web_socket_uri( &self, target: &Target ) -> Option<String> {
+        target.ws_base( self.ws_secure, self.proxy_port )
+    }
+
+    /// Returns whether responses to `GET` requests for `path` may be served from
+    /// the cache, or stored in it, as far as the [cache policies](ProxyConfig::cache_policy)
+    /// go. Whether a response is stored also depends on the request and the
+    /// response themselves.
+    /// 
+    /// ```
+    /// use poem_proxy::{ CachePolicy, ProxyConfig };
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .enable_nesting()
+    ///     .enable_cache()
+    ///     .cache_policy( "/api", &CachePolicy::disabled() )
+    ///     .cache_policy( "/api/catalog", &CachePolicy::new() )
+    ///     .finish();
+    /// 
+    /// assert!( config.caches_path( "/static/x" ) );
+    /// assert!( !config.caches_path( "/api/x" ) );
+    /// assert!( config.caches_path( "/api/catalog/x" ) );
+    /// assert!( config.caches_path( "/apis/x" ) );
+    /// ```
+    pub fn caches_path( &self, path: &str ) -> bool {
+        self.cache.is_some()
+            && cache::policy_for( &self.cache_policies, path ).map_or( true, CachePolicy::is_enabled )
+    }
+
+    /// Returns how long to wait for the proxied server to respond to a request
+    /// whose body is `content_length` bytes long, or `None` if there is no limit.
+    /// 
+    /// ```
+    /// use std::time::Duration;
+    /// use poem_proxy::ProxyConfig;
+    /// 
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .upstream_timeout( Duration::from_secs( 5 ) )
+    ///     .timeout_per_megabyte( Duration::from_secs( 2 ) )
+    ///     .finish();
+    /// 
+    /// assert_eq!( config.upstream_timeout_for( 0 ), Some( Duration::from_secs( 5 ) ) );
+    /// assert_eq!( config.upstream_timeout_for( 10 * 1_048_576 ), Some( Duration::from_secs( 25 ) ) );
+    /// ```
+    pub fn upstream_timeout_for( &self, content_length: u64 ) -> Option<Duration> {
+        const MEGABYTE: f64 = 1_048_576.0;
+
+        self.upstream_timeout.map( |base| {
+            base + self.timeout_per_megabyte.mul_f64( content_length as f64 / MEGABYTE )
+        })
+    }
+
+    /// Returns the http client for requests to the given target, which is shared by
+    /// every target unless keep-alive is honored per target, or the target has a
+    /// redirect limit of its own.
+    fn client_for( &self, target: &Target ) -> Result<reqwest::Client> {
+        let max_redirects = self.max_redirects_for( target );
+        if self.honor_keep_alive || target.max_redirects().is_some() {
+            self.clients.client_for( target.address(), || self.build_client( max_redirects ) )
+        } else {
+            self.clients.shared_client( || self.build_client( max_redirects ) )
+        }
+    }
+
+    /// Returns an http client for the given target without any connections open, for
+    /// retrying a request whose connection failed. The client it replaces is dropped for
+    /// the requests after it too, since its other connections are likely to be just as
+    /// stale. For the shared client, that closes the idle connections to every target.
+    fn fresh_client( &self, target: &Target ) -> Result<reqwest::Client> {
+        if self.honor_keep_alive || target.max_redirects().is_some() {
+            self.clients.forget( target.address() );
+            self.client_for( target )
+        } else {
+            self.clients.replace_shared( || self.build_client( self.max_redirects_for( target ) ) )
+        }
+    }
+
+    /// Returns the most redirects followed for a request to the given target.
+    fn max_redirects_for( &self, target: &Target ) -> usize {
+        target.max_redirects().unwrap_or( self.max_redirects )
+    }
+
+    /// Builds a new http client for reaching the proxied server, with all of the
+    /// connection settings of this configuration applied, following at most
+    /// `max_redirects` redirects.
+    fn build_client( &self, max_redirects: usize ) -> Result<reqwest::Client> {
+
+        // Names are always resolved through the pool, which counts the connections
+        let resolver: Arc<dyn Resolve> = match &self.dns {
+            Some( resolver ) => resolver.clone(),
+            None => Arc::new( CachingResolver::new( None, None ) ),
+        };
+        self.build_client_with( max_redirects, Some( self.clients.counting( resolver ) ) )
     }
 
-}
+    /// Builds a new http client like [build_client](ProxyConfig::build_client), looking
+    /// names up with `resolver`, or the system resolver if there is none.
+    fn build_client_with<R: Resolve + 'static>( &self, max_redirects: usize, resolver: Option<Arc<R>> ) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some( max ) = self.max_connections_per_host {
+            builder = builder.pool_max_idle_per_host( max );
+        }
+        if self.upstream_http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder = builder.redirect( self.redirect_mode.policy( max_redirects ) );
+        if let Some( resolver ) = resolver {
+            builder = builder.dns_resolver( resolver );
+        }
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate( certificate.clone() );
+        }
+        builder = builder.danger_accept_invalid_certs( self.danger_accept_invalid_certs );
 
-/// # Convenience Functions
-/// 
-/// These functions make it possible to get information from the ProxyConfig struct.
-impl ProxyConfig {
+        let built = builder.build().map_err( |e| e.to_string() );
+        #[cfg(feature = "fault-injection")]
+        let built = match self.fault_injection.as_ref().map_or( false, FaultInjection::fails_client ) {
+            true => Err( "injected fault".to_owned() ),
+            false => built,
+        };
+        built.map_err( |e| {
+            unexpected( self, &format!( "could not build the http client: {}", e ) );
+            Error::from_string( "The proxy could not reach the proxied server!", StatusCode::INTERNAL_SERVER_ERROR )
+        })
+    }
 
-    /// Returns the target url of the request, including the proper protocol information
-    /// and the correct pathing if nesting is enabled
-    /// 
-    /// An example output would be
-    /// 
-    /// > `"https://proxy.domain.com"`
-    pub fn get_web_request_uri( &self, subpath: Option<String> ) -> Result<String, ()> {
-        let Some( secure ) = self.web_secure else {
-            return Err(());
+    /// Starts the health checks in the background, unless they are off, already
+    /// running, or there is no runtime to run them on yet.
+    fn start_health_checks( &self ) {
+        let Some( path ) = &self.health_check_path else {
+            return;
+        };
+        if tokio::runtime::Handle::try_current().is_err() || self.health_checker.start() {
+            return;
+        }
+        // Health checks aren't forwarded requests, so their connections aren't counted
+        let Ok( client ) = self.build_client_with( 0, self.dns.clone() ) else {
+            return;
         };
 
-        let base = if secure {
-            format!( "https://{}", self.proxy_target )
-        } else {
-            format!( "http://{}", self.proxy_target )
+        let probe = health::Probe {
+            path: path.clone(),
+            interval: self.health_check_interval,
+            status: self.health_check_status,
+            secure: self.web_secure.or( self.ws_secure ).unwrap_or( false ),
+            port: self.proxy_port,
         };
+        tokio::spawn( health::run( Arc::downgrade( &self.health_checker ), self.targets.clone(), client, probe ) );
+    }
 
-        let sub = if self.support_nesting && subpath.is_some() {
-            subpath.unwrap()
-        } else {
-            "".into()
+    /// Whether the request is [long-polling](ProxyConfig::long_poll_path).
+    fn is_long_poll( &self, req: &Request ) -> bool {
+        self.long_poll_paths.iter().any( |path| rewrite::covers( req.original_uri().path(), path ) )
+            || self.long_poll_header.as_ref().map_or( false, |name| req.headers().contains_key( name ) )
+    }
+
+    /// Returns the server named by the upstream header of a request, or `None` if the
+    /// request doesn't have the header. Servers that aren't allowed are rejected with
+    /// `403 Forbidden`.
+    fn routed_target( &self, req: &Request ) -> Result<Option<Target>> {
+        let Some( name ) = &self.upstream_header else {
+            return Ok( None );
         };
 
-        println!( "base: {} | sub: {}", base, sub );
+        let mut values = req.headers().get_all( name ).iter();
+        let value = match ( values.next(), values.next() ) {
+            ( None, _ ) => return Ok( None ),
+            ( Some( value ), None ) => value.to_str().ok(),
+            ( Some( _ ), Some( _ ) ) => None,
+        };
 
-        Ok( base+&sub )
+        value.and_then( |value| self.allowed_upstreams.iter().find( |allowed| allowed.matches( value ) ) )
+            .map( |allowed| Some( allowed.clone() ) )
+            .ok_or_else( || Error::from_string( "The proxy is not allowed to forward to this server!", StatusCode::FORBIDDEN ) )
     }
 
-    /// Returns the target url of the websocket, including the proper protocol information.
-    /// 
-    /// An example output would be
-    /// 
-    /// > `"wss://websocket.domain.com"`
-    pub fn get_web_socket_uri( &self ) -> Result<String, ()> {
-        let Some( secure ) = self.ws_secure else {
-            return Err(());
+    /// Makes sure the proxy is allowed to connect to the port of the given uri,
+    /// returning a `403 Forbidden` error if it isn't.
+    fn check_port( &self, uri: &str ) -> Result<()> {
+        let Some( allowed ) = &self.allowed_ports else {
+            return Ok( () );
         };
 
-        Ok(
-            if secure {
-                format!( "wss://{}", self.proxy_target )
-            } else {
-                format!( "ws://{}", self.proxy_target )
-            }
-        )
+        let port = reqwest::Url::parse( uri ).ok()
+            .and_then( |url| url.port_or_known_default() );
+
+        match port {
+            Some( port ) if allowed.contains( &port ) => Ok( () ),
+            _ => Err( Error::from_string( "The proxy is not allowed to connect to this port!", StatusCode::FORBIDDEN ) ),
+        }
     }
 
 }
 
-/// The websocket-enabled proxy handler
+/// The websocket-enabled proxy handler, which takes its configuration from the data of
+/// the endpoint. See [ProxyEndpoint] for an endpoint that holds its own.
 #[handler]
 pub async fn proxy( 
     req: &Request, 
-    headers: &HeaderMap,
     config: Data<&ProxyConfig>,
+    body: Body,
+    ) -> Result<Response> {
+    serve( req, &config, body ).await
+}
+
+/// Serves a request with the given configuration, logging how it went and formatting
+/// the errors of the proxy itself.
+async fn serve( req: &Request, config: &ProxyConfig, body: Body ) -> Result<Response> {
+    config.start_health_checks();
+    let method = req.method().clone();
+
+    // Everything logged while handling the request is tied to it, which costs next to
+    // nothing when no one is listening
+    let span = tracing::info_span!( "proxy",
+        method = %method,
+        path = %req.original_uri().path(),
+        upstream = field::Empty,
+        upstream_status = field::Empty,
+    );
+    let started = Instant::now();
+    let body = metrics::count_request( &config.metrics, body );
+    let meter = config.byte_key.as_ref()
+        .map( |key| Meter::new( key( req ), config.byte_ledger.clone(), config.byte_hook.clone() ) );
+    let body = match &meter {
+        Some( meter ) => meter.count_request( body ),
+        None => body,
+    };
+    let result = handle( req, req.headers(), config, method, body ).instrument( span.clone() ).await;
+    span.in_scope( || {
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok( response ) => tracing::info!( status = response.status().as_u16(), elapsed_ms, "request handled" ),
+            Err( error ) if error.status().is_server_error() => tracing::error!( status = error.status().as_u16(), elapsed_ms, %error, "request failed" ),
+            Err( error ) => tracing::info!( status = error.status().as_u16(), elapsed_ms, %error, "request refused" ),
+        }
+    });
+
+    config.metrics.record_answer( match &result {
+        Ok( response ) => response.status(),
+        Err( error ) => error.status(),
+    });
+
+    // Errors from the proxied server are responses, so every error here is our own.
+    // Counting the bytes of one takes turning it into its response
+    let response = match result {
+        Ok( response ) => metrics::count_response( &config.metrics, response ),
+        Err( error ) if config.error_format == ErrorFormat::Text && meter.is_none() => return Err( error ),
+        Err( error ) => config.error_format.apply( error ).await,
+    };
+    Ok( match meter {
+        Some( meter ) => meter.count_response( response ),
+        None => response,
+    })
+}
+
+/// Handles a request for the proxy endpoint, forwarding it as a web request or websocket.
+async fn handle(
+    req: &Request,
+    headers: &HeaderMap,
+    config: &ProxyConfig,
     method: Method,
     body: Body,
     ) -> Result<Response> {
 
+    // Shed load before doing any real work
+    let load = ActiveGuard::new( &config.active_requests );
+    if let Some( threshold ) = config.overload_threshold {
+        let active = config.active_requests.load( Ordering::Relaxed );
+        let waiting = config.overload_queue.as_ref()
+            .zip( client::client_ip( req, &config.trusted_proxies ) );
+
+        match waiting {
+            Some( ( line, ip ) ) => {
+                // Clients already in line get the room before anyone new
+                let room = ( threshold + 1 ).saturating_sub( active );
+                if !line.admit( ip, room ) {
+                    return Err( queued( line.join( ip, config.retry_after ), config.retry_after ) )
+                }
+            },
+            None if active > threshold => {
+                return Err( throttled( StatusCode::SERVICE_UNAVAILABLE, "Proxy endpoint is overloaded!", config.retry_after ) )
+            },
+            None => {},
+        }
+    }
+
+    // Keep any one client from hogging the proxy
+    let client = match ( config.max_connections_per_client, client::client_ip( req, &config.trusted_proxies ) ) {
+        ( Some( max ), Some( ip ) ) => match config.client_requests.acquire( ip, max ) {
+            Some( guard ) => Some( guard ),
+            None => return Err( throttled( StatusCode::TOO_MANY_REQUESTS, "Too many requests from this client!", config.retry_after ) ),
+        },
+        _ => None,
+    };
+
+    // Only let in the clients the authenticator vouches for
+    let identity = match &config.authenticator {
+        Some( authenticator ) => auth::authenticate( &**authenticator, req ).await?,
+        None => None,
+    };
+
+    // Pick the server this request goes to, unless the request names it
+    let cost = config.cost_selector.as_ref().map( |selector| selector( req ) );
+    let target = match config.routed_target( req )? {
+        Some( target ) => target,
+        None => match config.targets.select( cost ) {
+            Some( target ) => target,
+            None => return Err( throttled( StatusCode::SERVICE_UNAVAILABLE, "Proxy endpoint has no targets to forward to!", config.retry_after ) ),
+        },
+    };
+    let active = ( load, client, target.begin() );
+
     // If we need a websocket connection,
     if let Ok( ws ) = WebSocket::from_request_without_body( req ).await {
 
         // Get the websocket URI if websockets are supported, otherwise return an error
-        let Ok( uri ) = config.get_web_socket_uri() else {
+        let Some( uri ) = config.web_socket_uri( &target ) else {
             return Err( Error::from_string( "Proxy endpoint not configured to support websockets!", StatusCode::NOT_IMPLEMENTED ) )
         };
+        config.check_port( &uri )?;
+        if config.ws_shutdown.is_raised() {
+            return Err( throttled( StatusCode::SERVICE_UNAVAILABLE, "The proxy is shutting down!", config.retry_after ) );
+        }
+        if let Some( max ) = config.ws_max_handshake_size {
+            if websocket::header_size( req.headers() ) > max {
+                return Err( Error::from_string( "The websocket handshake's headers are too large!", StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE ) );
+            }
+        }
+        if let Some( allowed ) = &config.ws_allowed_subprotocols {
+            if !websocket::subprotocols_allowed( req.headers(), allowed ) {
+                return Err( Error::from_string( "The requested websocket subprotocol is not allowed!", StatusCode::FORBIDDEN ) );
+            }
+        }
+        Span::current().record( "upstream", uri.as_str() );
         
         // Generate websocket request:
-        let mut w_request = http::Request::builder().uri( &uri );
-        for (key, value) in headers.iter() {
-            w_request = w_request.header( key, value ); 
+        let mut headers = headers.clone();
+        if let Some( name ) = &config.upstream_header {
+            headers.remove( name );
         }
+        if !config.transparent {
+            config.missing_host.apply( req, &target.address_for( config.proxy_port ), &mut headers )?;
+            if config.add_forwarded_headers {
+                forwarded::add( req, &mut headers, &config.trusted_proxies );
+            }
+        }
+        target.rewrite_headers( &mut headers );
+        if let Some( name ) = &config.identity_header {
+            auth::set_identity( &mut headers, name, identity.as_deref() );
+        }
+        if let Some( authorization ) = &config.upstream_authorization {
+            headers.insert( header::AUTHORIZATION, authorization.clone() );
+        }
+        config.non_ascii_headers.apply( &mut headers )?;
+        let Ok( handshake ) = websocket::handshake( &uri, &headers ) else {
+            return Err( Error::from_string( "The proxied server's websocket url is invalid!", StatusCode::BAD_GATEWAY ) )
+        };
+
+        // Keep a burst of new websockets from flooding the server with handshakes
+        let handshaking = match &config.ws_handshakes {
+            Some( handshakes ) => match handshakes.clone().try_acquire_owned() {
+                Ok( permit ) => Some( permit ),
+                Err( _ ) => return Err( throttled( StatusCode::SERVICE_UNAVAILABLE, "Too many websockets are being opened!", config.retry_after ) ),
+            },
+            None => None,
+        };
+
+        // Connect to the server before accepting the client's websocket, so the client
+        // can be told which subprotocol the server picked
+        let connected = websocket::connect( &handshake, config.ws_connect_retries, config.ws_socks5_proxy.as_ref() ).await;
+        drop( handshaking );
+        let ws = match connected.as_ref().ok().and_then( |( _, protocol )| protocol.clone() ) {
+            Some( protocol ) => ws.protocols( [ protocol ] ),
+            None => ws,
+        };
 
         // Start the websocket connection
-        return Ok( 
+        let half_close = config.ws_half_close;
+        let inflight_frames = config.ws_max_inflight_frames;
+        let buffered = config.ws_buffered.clone();
+        let max_frame_size = config.ws_max_frame_size;
+        let retirement = config.ws_close_on_removal.then( || target.retirement() );
+        let shutdown = config.ws_shutdown.signal();
+        let open = config.ws_shutdown.open();
+        let idle_timeout = config.ws_idle_timeout;
+        let keepalive = config.ws_keepalive;
+        let recorder = websocket::CloseRecorder::new( config.ws_closes.clone(), config.ws_close_hook.clone() );
+        let client_metrics = config.metrics.clone();
+        let server_metrics = config.metrics.clone();
+        let client_tap = config.ws_tap.clone().map( |tap| ( tap, uri.clone() ) );
+        let server_tap = client_tap.clone();
+        let span = Span::current();
+        Ok( 
             ws.on_upgrade(move |socket| async move {
-                let ( mut clientsink, mut clientstream ) = socket.split();
+                let ( mut clientsink, clientstream ) = socket.split();
+                let mut clientstream = websocket::read_ahead( clientstream, inflight_frames, buffered.clone() );
                 
-                // Start connection to server
-                let ( mut serversocket, _ ) = connect_async( w_request.body(()).unwrap() ).await.unwrap();
-                let ( mut serversink, mut serverstream ) = serversocket.split();
+                // Let the client know if there is no connection to the server
+                let serversocket = match connected {
+                    Ok( ( socket, _ ) ) => socket,
+                    Err( error ) => {
+                        tracing::warn!( %error, "websocket to the proxied server could not be opened" );
+                        let reason = "The proxied server could not be reached";
+                        recorder.observe( Some( CloseCode::Error.into() ), reason );
+                        let _ = clientsink.send( Message::Close( Some( ( CloseCode::Error, reason.into() ) ) ) ).await;
+                        return;
+                    },
+                };
+                tracing::info!( "websocket opened" );
+                let ( mut serversink, serverstream ) = serversocket.split();
+                let mut serverstream = websocket::read_ahead( serverstream, inflight_frames, buffered );
+
+                // Close both peers as soon as either sends a message that is too big
+                if let Some( max ) = max_frame_size {
+                    let ( to_server, to_client ) = websocket::close_frames( CloseCode::Size, "The message is too big" );
+                    clientstream = websocket::limit_size( clientstream, max, websocket::client_message_size, to_server );
+                    serverstream = websocket::limit_size( serverstream, max, websocket::server_message_size, to_client );
+                }
+
+                // Say goodbye to both peers when the proxy shuts down
+                let ( to_server, to_client ) = websocket::close_frames( CloseCode::Away, "The proxy is shutting down" );
+                clientstream = websocket::close_when( clientstream, shutdown.clone(), to_server );
+                serverstream = websocket::close_when( serverstream, shutdown, to_client );
+
+                // Or if the target is removed from the pool
+                if let Some( retirement ) = retirement {
+                    let ( to_server, to_client ) = websocket::close_frames( CloseCode::Away, "The proxied server was removed" );
+                    clientstream = websocket::close_when( clientstream, retirement.clone(), to_server );
+                    serverstream = websocket::close_when( serverstream, retirement, to_client );
+                }
+
+                // Or once the client stops answering keepalive pings. The pongs are
+                // only for the proxy, and don't count as activity
+                if let Some( interval ) = keepalive {
+                    let pongs = Arc::new( websocket::Activity::new() );
+                    clientstream = websocket::take_pongs( clientstream, pongs.clone() );
+
+                    let silent = websocket::watch_idle( pongs, interval * 2 );
+                    let ( to_server, to_client ) = websocket::close_frames( CloseCode::Away, "The client stopped answering pings" );
+                    clientstream = websocket::close_when( clientstream, silent.clone(), to_server );
+                    serverstream = websocket::close_when( serverstream, silent, to_client );
+                }
+
+                // Or once neither peer has sent anything for a while
+                if let Some( timeout ) = idle_timeout {
+                    let activity = Arc::new( websocket::Activity::new() );
+                    let client_activity = activity.clone();
+                    let server_activity = activity.clone();
+                    clientstream = clientstream.inspect( move |_| client_activity.touch() ).boxed();
+                    serverstream = serverstream.inspect( move |_| server_activity.touch() ).boxed();
+
+                    let idle = websocket::watch_idle( activity, timeout );
+                    let ( to_server, to_client ) = websocket::close_frames( CloseCode::Away, "The websocket was idle for too long" );
+                    clientstream = websocket::close_when( clientstream, idle.clone(), to_server );
+                    serverstream = websocket::close_when( serverstream, idle, to_client );
+                }
+
+                // The pings are sent along with the server's messages, though they
+                // don't count as activity either
+                if let Some( interval ) = keepalive {
+                    serverstream = websocket::ping_every( serverstream, interval );
+                }
 
                 // Tie both threads so if one exits the other does too
                 let client_live = Arc::new( RwLock::new( true ) );
                 let server_live = client_live.clone();
 
+                // The websocket counts as active on its target until both threads are done
+                let client_active = Arc::new( ( active, open ) );
+                let server_active = client_active.clone();
+
+                // Both threads watch for close frames, and the close is recorded once
+                // they are both done
+                let client_recorder = Arc::new( recorder );
+                let server_recorder = client_recorder.clone();
+
                 // Relay client messages to the server we are proxying
                 tokio::spawn( async move {
-                    while let Some( Ok( msg ) ) = clientstream.next().await {
+                    let _active = client_active;
+                    let mut closed = false;
+                    let mut dropped = false;
+                    let mut index = 0;
+                    loop {
+                        let Some( Ok( msg ) ) = clientstream.next().await else {
+                            dropped = !closed;
+                            break;
+                        };
+                        closed = msg.is_close();
+                        client_recorder.observe_client( &msg );
+                        client_metrics.record_from_client( websocket::client_message_size( &msg ) );
+                        if let Some( ( tap, uri ) ) = &client_tap {
+                            if tap.sample() {
+                                tap.record( FrameDirection::ClientToServer, uri, index, msg.clone() );
+                            }
+                        }
+                        index += 1;
 
                         // When a message is received, forward it to the server
-                        // Break the loop if there are errors
-                        match serversink.send( msg.into() ).await { 
-                            Err( _ ) => break,
-                            _ => {},
+                        // Break the loop if there are errors. A server that already
+                        // sent its close frame can't be sent another, since its socket
+                        // queues the reply itself, which only has to be flushed
+                        let sent = match serversink.send( websocket::to_server_message( msg ) ).await {
+                            Err( _ ) if closed => serversink.flush().await,
+                            sent => sent,
                         };
+                        if sent.is_err() {
+                            closed = false;
+                            break;
+                        }
+
+                        // When half-closing, the client can't send anything after its
+                        // close frame, but the server may still have more to say
+                        if closed && half_close { break };
 
                         // Stop the connection if it is no longer live
-                        // let j = *connection_live.read().await;
                         if !*client_live.read().await { break };
                     };
 
-                    // Stop the other thread that is paired with this one
-                    *client_live.write().await = false;
+                    // Close frames are relayed like any other message, and the stream
+                    // ends once the closing handshake is done. A client that went away
+                    // without one can't be heard from again, so the server is told in
+                    // its place
+                    if dropped {
+                        let _ = serversink.send( websocket::to_server_message( Message::Close( Some( ( CloseCode::Away, "The client went away".into() ) ) ) ) ).await;
+                    }
+
+                    // Stop the other thread that is paired with this one, unless
+                    // it should be left open to finish the closing handshake
+                    if !( closed && half_close ) {
+                        *client_live.write().await = false;
+                    }
                 });
                 
                 // Relay server messages to the client
                 tokio::spawn( async move {
-                    while let Some( Ok( msg ) ) = serverstream.next().await {
+                    let _active = server_active;
+                    let mut closed = false;
+                    let mut dropped = false;
+                    let mut index = 0;
+                    loop {
+                        let Some( Ok( msg ) ) = serverstream.next().await else {
+                            dropped = !closed;
+                            break;
+                        };
+                        closed = msg.is_close();
+                        server_recorder.observe_server( &msg );
+                        let Some( msg ) = websocket::to_client_message( msg ) else { continue };
+                        server_metrics.record_to_client( websocket::client_message_size( &msg ) );
+                        if let Some( ( tap, uri ) ) = &server_tap {
+                            if tap.sample() {
+                                tap.record( FrameDirection::ServerToClient, uri, index, msg.clone() );
+                            }
+                        }
+                        index += 1;
 
                         // When a server message is received, forward it to the
-                        // client, and break the loop if there are errors
-                        match clientsink.send( msg.into() ).await {
-                            Err( _ ) => break,
-                            _ => {},
+                        // client, and break the loop if there are errors. Likewise
+                        // for a client that already sent its close frame
+                        let sent = match clientsink.send( msg ).await {
+                            Err( _ ) if closed => clientsink.flush().await,
+                            sent => sent,
                         };
+                        if sent.is_err() {
+                            closed = false;
+                            break;
+                        }
+
+                        if closed && half_close { break };
 
                         // Stop the connection if it is no longer live
                         if !*server_live.read().await { break };
                     };
 
+                    // Likewise for a server that went away
+                    if dropped {
+                        let _ = clientsink.send( Message::Close( Some( ( CloseCode::Error, "The proxied server went away".into() ) ) ) ).await;
+                    }
+
                     // Stop the other thread that is paired with this one
-                    *server_live.write().await = false;
+                    if !( closed && half_close ) {
+                        *server_live.write().await = false;
+                    }
                 });
-            }).into_response()
-        );
+            }.instrument( span ) ).into_response()
+        )
     } 
     
     // Not using websocket (http/https):
     else {
-        
-        // Update the uri to point to the proxied server
-        // let request_uri = target.to_owned() + &req.uri().to_string();
+        let forward = forward_request( req, config, &target, cost, method, body, identity.as_deref() );
 
-        // Get the websocket URI if websockets are supported, otherwise return an error
-        let Ok( uri ) = config.get_web_request_uri( Some( req.uri().to_string() ) ) else {
-            return Err( Error::from_string( "Proxy endpoint not configured to support web requests!", StatusCode::NOT_IMPLEMENTED ) )
+        // The deadline covers everything the proxy does for the request, including
+        // reading the body and running any user callbacks
+        let timeout = match config.is_long_poll( req ) {
+            true => Some( config.long_poll_timeout ),
+            false => [ config.request_timeout, config.max_request_duration ].into_iter().flatten().min(),
         };
+        match timeout {
+            Some( timeout ) => tokio::time::timeout( timeout, forward ).await
+                .unwrap_or_else( |_| Err( Error::from_string( "The request took too long to complete!", StatusCode::GATEWAY_TIMEOUT ) ) ),
+            None => forward.await,
+        }
+    }
+}
 
-        // Now generate a request for the proxied server, based on information
-        // that we have from the current request
-        let client = reqwest::Client::new();
-        let res = match method {
-            Method::GET => {
-                client.get( uri )
-                    .headers( req.headers().clone() )
-                    .body( body.into_bytes().await.unwrap() )
-                    .send()
-                    .await
-            },
-            Method::POST => {
-                client.post( uri )
-                    .headers( req.headers().clone() )
-                    .body( body.into_bytes().await.unwrap() )
-                    .send()
-                    .await
-            },
-            _ => {
-                return Err( Error::from_string( "Unsupported Method! The proxy endpoint currently only supports GET and POST requests!", StatusCode::METHOD_NOT_ALLOWED ) )
+/// Forwards a web (http/https) request to the given target and relays the response.
+async fn forward_request(
+    req: &Request,
+    config: &ProxyConfig,
+    target: &Target,
+    cost: Option<Cost>,
+    method: Method,
+    body: Body,
+    identity: Option<&str>,
+    ) -> Result<Response> {
+    let mut timer = ( config.server_timing && !config.transparent ).then( RequestTimer::start );
+
+    // A streamed response has to be done by the time the request has lasted its longest
+    let long_poll = config.is_long_poll( req );
+    let longest = match long_poll {
+        true => Some( config.long_poll_timeout ),
+        false => config.max_request_duration,
+    };
+    let deadline = longest.map( |max| tokio::time::Instant::now() + max );
+
+    // The proxied server may expect another method than the client sent
+    let upstream_method = config.method_rewrites.get( &method ).unwrap_or( &method ).clone();
+
+    // Requests that could have been replayed may not be safe to forward
+    config.early_data.check( &upstream_method, req.headers() )?;
+
+    // Send clients that asked for https over to it
+    if let Some( redirect ) = config.insecure_requests.redirect( req ) {
+        return Ok( redirect );
+    }
+
+    // A `TRACE` or `OPTIONS` request that can't be forwarded any further is ours to answer
+    if let Some( response ) = max_forwards::answer( req ) {
+        return Ok( response );
+    }
+
+    // Only forward bodies we know how to read
+    headers::check_transfer_encoding( req.headers() )?;
+    content_type::check( &config.content_types, req.original_uri().path(), req.headers() )?;
+
+    // Get the request URI if web requests are supported, otherwise return an error
+    let Some( uri ) = config.web_request_uri( target, Some( request_path( req.uri() ) ) ) else {
+        return Err( Error::from_string( "Proxy endpoint not configured to support web requests!", StatusCode::NOT_IMPLEMENTED ) )
+    };
+    config.check_port( &uri )?;
+
+    // Inject any faults that have been configured for chaos testing
+    #[cfg(feature = "fault-injection")]
+    if let Some( faults ) = &config.fault_injection {
+        if let Some( result ) = faults.inject().await.into_result( config.retry_after ) {
+            return result;
+        }
+    }
+
+    // Serve the response from the cache if there is a fresh copy of it
+    let policy = cache::policy_for( &config.cache_policies, req.original_uri().path() );
+    let ttl = policy.and_then( CachePolicy::ttl_override );
+    let base_key = policy.map_or_else( || uri.clone(), |policy| policy.key( &uri, req.headers() ) );
+    let mut cache_key = base_key.clone();
+    let cache = config.cache.as_ref()
+        .filter( |_| cache::is_cacheable_request( &method, req.headers() ) )
+        .filter( |_| config.caches_path( req.original_uri().path() ) );
+    let mut stale = None;
+    if let Some( cache ) = cache {
+
+        // The entry for the url tells which variant of the response to look for
+        let mut cached = cache.get( &base_key ).await;
+        if let Some( entry ) = &cached {
+            let key = cache::variant_key( &base_key, &entry.headers, req.headers() );
+            if key != base_key {
+                cached = cache.get( &key ).await;
+                cache_key = key;
             }
-        };
+        }
 
-        // Check on the response and forward everything from the server to our client,
-        // including headers and the body of the response, among other things.
-        match res {
-            Ok( result ) => {
-                let mut res = Response::default();
-                res.extensions().clone_from( &result.extensions() );
-                result.headers().iter().for_each(|(key, val)| {
-                    res.headers_mut().insert( key, val.to_owned() );
-                });
-                res.set_status( result.status() );
-                res.set_version( result.version() );
-                res.set_body( result.bytes().await.unwrap() );
-                Ok( res )
+        match cached {
+            Some( cached ) if cached.is_fresh() => {
+                config.cache_counters.record_hit( cached.status );
+                return Ok( cached_response( config, req.headers(), cached ) );
             },
 
-            // The request to the back-end server failed. Why?
-            Err( error ) => {
-                Err( Error::from_string( error.to_string(), error.status().unwrap_or( StatusCode::BAD_GATEWAY ) ) )
+            // Stale responses are revalidated with the proxied server below
+            Some( cached ) => stale = Some( cached ),
+            None => {},
+        }
+    }
+
+    // Answer retries of a request with the response to the original one
+    let idempotency = match &config.idempotency {
+        Some( store ) => store.claim( &method, &uri, req.headers() ).await,
+        None => None,
+    };
+    let idempotency = match idempotency {
+        Some( Claim::Replay( response ) ) => {
+            config.cache_counters.record_collapsed();
+            check_status( config, response.status )?;
+            return Ok( forward_response( config, response.status, &response.headers, response.body ) );
+        },
+        Some( Claim::Forward( pending ) ) => Some( pending ),
+        None => None,
+    };
+
+    // Now generate a request for the proxied server, based on information
+    // that we have from the current request
+    let mut target = target.clone();
+    let mut client = config.client_for( &target )?;
+    let mut headers = upstream_headers( req, config, &target, identity, stale.as_ref() )?;
+
+    // Headers that don't depend on the target are kept apart, to add them again if
+    // the request fails over to another one
+    let mut additions = HeaderMap::new();
+    if upstream_method != method && body::is_empty( req.headers() ) && expects_body( &upstream_method ) {
+        additions.insert( header::CONTENT_LENGTH, 0.into() );
+    }
+
+    // Read the body of the request, unless it can be streamed to the proxied server
+    let limit = config.body_size_selector.as_ref()
+        .and_then( |selector| selector( req ) )
+        .or( config.max_body_size );
+    let stream_upload = config.capture.is_none()
+        && config.redirect_mode != RedirectMode::PreserveMethod
+        && ( body::is_unbounded( req.headers() )
+            || body::declared_length( req.headers() ).map_or( false, |length| length >= config.upload_stream_threshold as u64 ) );
+    let mut upload = None;
+    let mut upload_failure = None;
+    let body = if stream_upload {
+        let ( stream, failure ) = body::stream( body, req.headers(), limit, config.upload_counters.clone() )?;
+        upload = Some( stream );
+        upload_failure = Some( failure );
+        Bytes::new()
+    } else {
+        body::read( body, req.headers(), limit ).await?
+    };
+    headers.extend( additions.clone() );
+
+    // Give the proxied server as long as the request warrants
+    let timeout = match &config.timeout_selector {
+        _ if long_poll => Some( config.long_poll_timeout ),
+        Some( selector ) => Some( selector( req ) ),
+        None => config.upstream_timeout_for( body::declared_length( req.headers() ).unwrap_or( body.len() as u64 ) ),
+    };
+
+    // A streamed body can only be sent once, which is why it isn't used along
+    // with redirects that resend it
+    let mut send = |client: &reqwest::Client, uri: &str, headers: &HeaderMap| {
+        let mut builder = client.request( upstream_method.clone(), uri )
+            .headers( headers.clone() );
+        builder = match upload.take() {
+            Some( upload ) => builder.body( upload ),
+            None => builder.body( body.clone() ),
+        };
+        if let Some( timeout ) = timeout {
+            builder = builder.timeout( timeout );
+        }
+        if let Some( hook ) = &config.request_hook {
+            builder = hook( builder, req );
+        }
+        config.clients.record_request( uri );
+        builder.send()
+    };
+
+    let capture = config.capture.as_ref()
+        .and_then( |capture| capture.begin( &upstream_method, &uri, &headers, &body ) );
+
+    let mut target_uri = uri.clone();
+    if let Some( timer ) = &mut timer {
+        timer.sent();
+    }
+    let mut res = send( &client, &target_uri, &headers ).await;
+
+    // Try the other targets if this one can't be reached, as long as the request can
+    // be sent again without harm and didn't name its server itself
+    let mut tried = vec![ target.address().to_owned() ];
+    let mut _failover_active = None;
+    let can_resend = !stream_upload && is_idempotent( &upstream_method );
+    let routed = config.upstream_header.as_ref().map_or( false, |name| req.headers().contains_key( name ) );
+    for _ in 0..config.failover_attempts {
+        if routed || !matches!( &res, Err( error ) if can_resend && error.is_connect() ) {
+            break;
+        }
+        let Some( next ) = config.targets.select_except( cost, &tried ) else {
+            break;
+        };
+        let Some( next_uri ) = config.web_request_uri( &next, Some( request_path( req.uri() ) ) ) else {
+            break;
+        };
+        config.check_port( &next_uri )?;
+        if let Err( error ) = res {
+            report_upstream_error( config, req, error );
+        }
+
+        _failover_active = Some( next.begin() );
+        tried.push( next.address().to_owned() );
+        client = config.client_for( &next )?;
+        headers = upstream_headers( req, config, &next, identity, stale.as_ref() )?;
+        headers.extend( additions.clone() );
+        res = send( &client, &next_uri, &headers ).await;
+        target = next;
+        target_uri = next_uri;
+    }
+
+    // Send the request to the same target again if it failed in a way that might not
+    // last, as long as it can be sent again without harm
+    for attempt in 0..config.retry_attempts {
+        if !( can_resend && is_transient( &res ) ) {
+            break;
+        }
+        if let Err( error ) = res {
+            report_upstream_error( config, req, error );
+
+            // The connection may be what failed, so the retry goes out on a new one
+            client = config.fresh_client( &target )?;
+        }
+
+        tokio::time::sleep( retry::backoff( attempt ) ).await;
+        res = send( &client, &target_uri, &headers ).await;
+    }
+    Span::current().record( "upstream", target_uri.as_str() );
+
+    // Follow the redirects the http client left for us, keeping the original method
+    if config.redirect_mode == RedirectMode::PreserveMethod {
+        for _ in 0..config.max_redirects_for( &target ) {
+            let Ok( result ) = &res else { break };
+            let Some( target ) = redirect::preserved_target( result.status(), result.url(), result.headers() ) else {
+                break;
+            };
+
+            config.check_port( target.as_str() )?;
+            res = send( &client, target.as_str(), &headers ).await;
+        }
+        if let Ok( result ) = &res {
+            if redirect::preserved_target( result.status(), result.url(), result.headers() ).is_some() {
+                return Err( Error::from_string( "The proxied server redirected the request too many times!", StatusCode::BAD_GATEWAY ) );
+            }
+        }
+    }
+
+    if let Some( timer ) = &mut timer {
+        timer.answered();
+    }
+
+    // Check on the response and forward everything from the server to our client,
+    // including headers and the body of the response, among other things.
+    match res {
+        Ok( result ) => {
+            if config.honor_keep_alive {
+                config.clients.record_response( target.address(), result.headers() );
+            }
+
+            let mut status = result.status();
+            Span::current().record( "upstream_status", status.as_u16() );
+            let version = result.version();
+            let mut headers = result.headers().clone();
+            if !config.transparent {
+                content_type::apply_default( &config.default_content_types, req.original_uri().path(), status, &mut headers );
+            }
+            let upstream_addr = result.remote_addr();
+
+            // The stale response is still current, so it can be served again
+            if let ( Some( cache ), Some( cached ) ) = ( cache, &stale ) {
+                if status == StatusCode::NOT_MODIFIED {
+                    let mut cached = cached.clone();
+                    cache::refresh( &mut cached, &headers, config.negative_cache_ttl, ttl );
+                    cache.put( &cache_key, cached.clone() ).await;
+                    config.cache_counters.record_hit( cached.status );
+                    return Ok( cached_response( config, req.headers(), cached ) );
+                }
+            }
+
+            // Or the proxied server is failing, and the stale response may stand in for
+            // the one it couldn't send
+            if status.is_server_error() {
+                if let Some( cached ) = stale.filter( |cached| cache::is_usable_on_error( cached, req.headers() ) ) {
+                    config.cache_counters.record_hit( cached.status );
+                    return Ok( stale_response( config, req.headers(), cached ) );
+                }
+            }
+            if cache.is_some() {
+                config.cache_counters.record_miss();
+            }
+
+            // Or the proxied server's errors are to be answered like any other failure
+            if config.handle_error_statuses || ( config.treat_upstream_errors_as_failures && status.is_server_error() ) {
+                if let Err( error ) = result.error_for_status_ref() {
+                    return Err( upstream_error( config, req, error ) );
+                }
+            }
+
+            // Make sure the server sent something the client can use
+            if let Some( error_status ) = config.validate_content_negotiation {
+                if !negotiation::is_acceptable( req.headers(), status, &headers ) {
+                    return Err( Error::from_string( "The proxied server responded with an unacceptable content type!", error_status ) );
+                }
+            }
+
+            // Decode compressed bodies, which the client then gets as they are
+            let coding = config.decompress_upstream.then( || decompress::coding( status, &headers ) ).flatten();
+            if coding.is_some() {
+                decompress::strip( &mut headers );
+            }
+
+            // Pass large responses, and those to long-polls, through as they arrive, unless
+            // they have to be kept or decoded
+            let lifetime = cache.and_then( |_| cache::freshness_lifetime( status, &headers, config.negative_cache_ttl, ttl ) );
+            let streamed = lifetime.is_none() && idempotency.is_none() && capture.is_none() && coding.is_none()
+                && ( long_poll || config.stream_selector.as_ref()
+                    .and_then( |selector| selector( req ) )
+                    .unwrap_or_else( || result.content_length().map_or( true, |length| length >= config.stream_threshold as u64 ) ) );
+            let mut transform = config.response_transform.as_ref()
+                .filter( |_| method != Method::HEAD && upstream_method != Method::HEAD )
+                .and_then( |transform| transform.begin( status, &headers ) );
+            let limit = config.max_response_body_size;
+            let ( body, stream ) = if method == Method::HEAD || upstream_method == Method::HEAD {
+                ( Bytes::new(), None )
+            } else if streamed {
+                body::check_response_length( result.content_length(), limit )?;
+                ( Bytes::new(), Some( result ) )
+            } else {
+                body::check_response_length( result.content_length(), limit )?;
+                let mut body = body::read_response( result, limit ).await.map_err( |e| upstream_error( config, req, e ) )??;
+                if let Some( coding ) = coding {
+                    body = decompress::decode( coding, &body, limit )?;
+                }
+                match transform.take() {
+                    Some( transform ) => ( transform::buffered( transform, body ), None ),
+                    None => ( body, None ),
+                }
+            };
+
+            // Keep a copy of the response around if the server allows it
+            if let Some( cache ) = cache {
+                if let Some( lifetime ) = lifetime {
+                    let response = CachedResponse {
+                        status, headers: headers.clone(), body: body.clone(),
+                        expires_at: SystemTime::now() + lifetime,
+                    };
+                    if let Some( entry ) = cache::variants_entry( &response ) {
+                        cache.put( &base_key, entry ).await;
+                    }
+                    cache.put( &cache::variant_key( &base_key, &headers, req.headers() ), response ).await;
+                }
+            }
+
+            // Let http-only clients know how a gRPC call went
+            if let Some( mapping ) = &config.grpc_status_mapping {
+                status = grpc::mapped_status( mapping, &headers ).unwrap_or( status );
+            }
+
+            if let Some( capture ) = capture {
+                capture.finish( status, &headers, &body, upstream_addr );
+            }
+            if let Some( pending ) = idempotency {
+                pending.finish( status, &headers, &body );
+            }
+
+            check_status( config, status )?;
+
+            let mut res = match stream {
+                Some( result ) => {
+                    let mut length = result.content_length();
+                    let mut body = result.bytes_stream()
+                        .map( |chunk| chunk.map_err( |e| std::io::Error::new( std::io::ErrorKind::Other, e ) ) )
+                        .boxed();
+                    if let Some( limit ) = limit {
+                        body = body::limit_response( body, limit );
+                    }
+                    if let Some( transform ) = transform {
+                        body = transform::streamed( transform, body );
+                        length = None;
+                    }
+                    if let Some( deadline ) = deadline {
+                        body = cut_off( body, deadline );
+                    }
+                    let mut res = forward_response( config, status, &headers, Body::from_bytes_stream( body ) );
+
+                    // Without a length, the body is sent chunked
+                    if let Some( length ) = length {
+                        res.headers_mut().insert( header::CONTENT_LENGTH, length.into() );
+                    }
+                    res
+                },
+                None => forward_response( config, status, &headers, body ),
+            };
+
+            // A response to HEAD has no body, but still tells the length the body of
+            // a GET would have
+            if method == Method::HEAD {
+                if let Some( length ) = headers.get( header::CONTENT_LENGTH ) {
+                    res.headers_mut().insert( header::CONTENT_LENGTH, length.clone() );
+                }
+            }
+            res.set_version( version );
+
+            // Tell whoever is debugging which server answered
+            let address_header = config.upstream_address_header.as_ref().filter( |_| !config.transparent );
+            if let ( Some( name ), Some( addr ) ) = ( address_header, upstream_addr ) {
+                match addr.to_string().parse() {
+                    Ok( value ) => { res.headers_mut().insert( name.clone(), value ); },
+                    Err( _ ) => unexpected( config, &format!( "{} is not a valid header value", addr ) ),
+                }
+            }
+
+            // And how long it took
+            if let Some( timer ) = &timer {
+                res.headers_mut().append( timing::SERVER_TIMING, timer.header() );
+            }
+            if let Some( origin ) = config.timing_allow_origin.as_ref().filter( |_| !config.transparent ) {
+                res.headers_mut().insert( timing::TIMING_ALLOW_ORIGIN, origin.clone() );
+            }
+            Ok( res )
+        },
+
+        // The request to the back-end server failed. Why? If the client's upload was cut
+        // off, the fault is the client's. Otherwise, a stale response may still stand in
+        // for the one it couldn't send
+        Err( error ) => {
+            if let Some( failure ) = upload_failure.and_then( |failure| failure.take() ) {
+                return Err( failure );
+            }
+            let error = upstream_error( config, req, error );
+            match stale.filter( |cached| cache::is_usable_on_error( cached, req.headers() ) ) {
+                Some( cached ) => {
+                    config.cache_counters.record_hit( cached.status );
+                    Ok( stale_response( config, req.headers(), cached ) )
+                },
+                None => Err( error ),
             }
+        },
+    }
+}
+
+/// Returns the path and query of a request uri, put back together from its parts so
+/// that the query is forwarded exactly once and just as it was sent, percent-encoding
+/// and all. Absolute uris, as sent over HTTP/2, have their scheme and authority dropped.
+fn request_path( uri: &poem::http::Uri ) -> String {
+    match uri.query() {
+        Some( query ) => format!( "{}?{}", uri.path(), query ),
+        None => uri.path().to_owned(),
+    }
+}
+
+/// Returns the part of a request uri that is appended to the target's url: its path
+/// (always starting with a `/`) and query. Absolute uris, as sent over HTTP/2, have
+/// their scheme and authority dropped.
+fn path_and_query( uri: &str ) -> String {
+    if uri.is_empty() || uri.starts_with( '/' ) {
+        return uri.to_owned();
+    }
+
+    match uri.parse::<poem::http::Uri>() {
+        Ok( parsed ) if parsed.scheme().is_some() => {
+            parsed.path_and_query().map_or( "/".into(), |p| p.as_str().to_owned() )
+        },
+        _ => format!( "/{}", uri ),
+    }
+}
+
+/// Builds the headers a request is forwarded to `target` with.
+fn upstream_headers( req: &Request, config: &ProxyConfig, target: &Target, identity: Option<&str>, stale: Option<&CachedResponse> ) -> Result<HeaderMap> {
+    let mut headers = req.headers().clone();
+    if let Some( name ) = &config.upstream_header {
+        headers.remove( name );
+    }
+    headers::remove_hop_by_hop( &mut headers, config.transparent );
+    max_forwards::decrement( req.method(), &mut headers );
+    if !config.transparent {
+        config.missing_host.apply( req, &target.address_for( config.proxy_port ), &mut headers )?;
+        if config.add_forwarded_headers {
+            forwarded::add( req, &mut headers, &config.trusted_proxies );
+        }
+    }
+    target.rewrite_headers( &mut headers );
+    if let Some( name ) = &config.identity_header {
+        auth::set_identity( &mut headers, name, identity );
+    }
+    if let Some( authorization ) = &config.upstream_authorization {
+        headers.insert( header::AUTHORIZATION, authorization.clone() );
+    }
+    config.non_ascii_headers.apply( &mut headers )?;
+    if !config.transparent {
+        if config.normalize_headers {
+            headers = headers::normalize( headers );
         }
+        if config.propagate_trace_context {
+            trace::continue_trace( &mut headers );
+        }
+        if let Some( name ) = &config.http_version_header {
+            headers.insert( name.clone(), headers::http_version( req.version() ) );
+        }
+        if let Some( cached ) = stale {
+            cache::add_validators( &mut headers, cached );
+        }
+    }
+    Ok( headers )
+}
+
+/// Whether requests with `method` usually carry a body, and so have to say how long it
+/// is even when there is none.
+fn expects_body( method: &Method ) -> bool {
+    matches!( *method, Method::POST | Method::PUT | Method::PATCH )
+}
+
+/// Whether sending a request with `method` twice has the same effect as sending it once.
+fn is_idempotent( method: &Method ) -> bool {
+    matches!( *method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE )
+}
+
+/// Whether a request that got `res` is likely to succeed if sent again: the connection
+/// failed or was reset, or the server said it is briefly unable to answer. Timeouts
+/// aren't, since waiting as long again is unlikely to help.
+fn is_transient( res: &reqwest::Result<reqwest::Response> ) -> bool {
+    match res {
+        Ok( result ) => matches!( result.status(), StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE ),
+        Err( error ) => ( error.is_connect() || error.is_request() ) && !error.is_timeout(),
+    }
+}
+
+/// Keeps the statuses the client isn't meant to see from reaching it, returning the
+/// error to send instead.
+fn check_status( config: &ProxyConfig, status: StatusCode ) -> Result<()> {
+    match &config.status_filter {
+        Some( filter ) if !filter.allows( status ) => {
+            Err( Error::from_string( "The proxied server could not handle the request!", config.blocked_status ) )
+        },
+        _ => Ok( () ),
+    }
+}
+
+/// Builds the error the client is answered with when the proxied server fails, after
+/// letting the error hook (if any) know what happened.
+fn upstream_error( config: &ProxyConfig, req: &Request, error: reqwest::Error ) -> Error {
+    let error = report_upstream_error( config, req, error );
+    match ( config.error_pages.get( &error.kind() ), &config.error_handler ) {
+        ( Some( page ), _ ) => page.render( &error ),
+        ( None, Some( handler ) ) => error::handled( handler( &error ) ),
+        ( None, None ) => Error::from_string( error.to_string(), error.status() ),
     }
-}
\ No newline at end of file
+}
+
+/// Lets the error hook (if any) know the proxied server failed, such as before the
+/// request is tried again.
+fn report_upstream_error( config: &ProxyConfig, req: &Request, error: reqwest::Error ) -> ProxyError {
+    let error = ProxyError::from( error );
+    tracing::warn!( %error, "the proxied server failed to answer" );
+    if let Some( hook ) = &config.error_hook {
+        hook( req, &error );
+    }
+    error
+}
+
+/// Reports a condition the proxy should never run into, which points to a bug. In
+/// strict mode this panics, otherwise the message is logged as an error and the caller
+/// carries on as best it can.
+fn unexpected( config: &ProxyConfig, message: &str ) {
+    if config.strict_mode {
+        panic!( "poem-proxy: {}", message );
+    }
+    tracing::error!( "{}", message );
+}
+
+/// Builds an error for when the proxy turns a client away, telling it when to retry
+/// through the `Retry-After` header. The delay is rounded up to whole seconds.
+fn throttled( status: StatusCode, message: &str, retry_after: Duration ) -> Error {
+    Error::from_response( Response::builder()
+        .status( status )
+        .header( header::RETRY_AFTER, whole_seconds( retry_after ) )
+        .body( message.to_owned() ) )
+}
+
+/// Builds an error for when the proxy turns a client away for overload while keeping
+/// its place in line, telling it its position and to retry after `retry_after` for
+/// each place up to its own.
+fn queued( position: usize, retry_after: Duration ) -> Error {
+    let wait = retry_after.saturating_mul( u32::try_from( position ).unwrap_or( u32::MAX ) );
+    Error::from_response( Response::builder()
+        .status( StatusCode::SERVICE_UNAVAILABLE )
+        .header( header::RETRY_AFTER, whole_seconds( wait ) )
+        .header( "x-queue-position", position )
+        .body( "Proxy endpoint is overloaded!" ) )
+}
+
+/// Rounds a delay up to whole seconds, as `Retry-After` has no finer unit.
+fn whole_seconds( delay: Duration ) -> u64 {
+    delay.as_secs() + u64::from( delay.subsec_nanos() > 0 )
+}
+
+/// Builds the response sent to the client out of a cached response, which is just
+/// `304 Not Modified` if the client's conditional headers show it already has it.
+fn cached_response( config: &ProxyConfig, request: &HeaderMap, cached: CachedResponse ) -> Response {
+    if cache::is_not_modified( request, &cached ) {
+        let headers = cache::not_modified_headers( &cached );
+        return forward_response( config, StatusCode::NOT_MODIFIED, &headers, Bytes::new() );
+    }
+
+    forward_response( config, cached.status, &cached.headers, cached.body )
+}
+
+/// Ends a streamed response body with an error once `deadline` passes, which aborts the
+/// response part way rather than letting it run on.
+fn cut_off( body: BoxStream<'static, std::io::Result<Bytes>>, deadline: tokio::time::Instant ) -> BoxStream<'static, std::io::Result<Bytes>> {
+    let expired = Box::pin( tokio::time::sleep_until( deadline ) );
+    stream::unfold( Some( ( body, expired ) ), |state| async move {
+        let ( mut body, mut expired ) = state?;
+        match future::select( body.next(), &mut expired ).await {
+            Either::Left( ( Some( chunk ), _ ) ) => Some( ( chunk, Some( ( body, expired ) ) ) ),
+            Either::Left( ( None, _ ) ) => None,
+            Either::Right( _ ) => {
+                let error = std::io::Error::new( std::io::ErrorKind::TimedOut, "The request took too long to complete!" );
+                Some( ( Err( error ), None ) )
+            },
+        }
+    }).boxed()
+}
+
+/// Builds the response sent to the client out of a stale cached response, served because
+/// the proxied server failed to send a current one. The `Warning` header tells the
+/// client that the response may be out of date.
+fn stale_response( config: &ProxyConfig, request: &HeaderMap, cached: CachedResponse ) -> Response {
+    let mut res = cached_response( config, request, cached );
+    res.headers_mut().append( header::WARNING, HeaderValue::from_static( "111 - \"Revalidation Failed\"" ) );
+    res
+}
+
+/// Builds the response sent to the client out of a response from the proxied server,
+/// whether it was just received or kept in the cache.
+fn forward_response( config: &ProxyConfig, status: StatusCode, headers: &HeaderMap, body: impl Into<Body> ) -> Response {
+    let mut headers = headers.clone();
+    headers::remove_hop_by_hop( &mut headers, config.transparent );
+
+    let mut res = Response::default();
+    headers.iter().for_each(|(key, val)| {
+
+        // The framing the proxied server used for the body may no longer apply, so the
+        // length of the body is set by the caller instead, or it is sent chunked
+        if key == header::TRANSFER_ENCODING || key == header::CONTENT_LENGTH {
+            return;
+        }
+
+        // Headers are appended rather than inserted so that repeated headers,
+        // such as multiple cookies, all make it to the client
+        if key != header::SET_COOKIE || config.transparent {
+            res.headers_mut().append( key, val.to_owned() );
+            return;
+        }
+        let mut cookie = val.to_owned();
+        if let Some( rewrite ) = &config.rewrite_cookies {
+            cookie = rewrite.apply( &cookie );
+        }
+        if let Some( policy ) = &config.cookie_policy {
+            cookie = policy.apply( &cookie );
+        }
+        res.headers_mut().append( key, cookie );
+    });
+    res.set_status( status );
+    res.set_body( body );
+    res
+}
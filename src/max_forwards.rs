@@ -0,0 +1,64 @@
+//! Handling of the `Max-Forwards` header, which limits how many proxies a `TRACE` or
+//! `OPTIONS` request passes through
+//! ([RFC 9110](https://www.rfc-editor.org/rfc/rfc9110#section-7.6.2)).
+//!
+//! A proxy that receives one of these with `Max-Forwards: 0` answers it itself, as the
+//! last recipient, and otherwise passes it on with the count lowered by one. This makes
+//! it possible to find out how far along a chain of proxies a request gets. The header
+//! means nothing on other methods, so it is left alone there, as are values that aren't
+//! a number.
+
+use poem::{ Request, Response, http::{ HeaderMap, HeaderValue, Method, StatusCode, header } };
+
+/// Returns the response the proxy answers with itself if the request has used up its
+/// forwards.
+pub(crate) fn answer( req: &Request ) -> Option<Response> {
+    if remaining( req.method(), req.headers() )? > 0 {
+        return None;
+    }
+
+    match *req.method() {
+        Method::TRACE => Some( trace( req ) ),
+        _ => Some( Response::builder().status( StatusCode::OK ).header( header::CONTENT_LENGTH, 0 ).finish() ),
+    }
+}
+
+/// Lowers the number of forwards left in the headers of a request being forwarded.
+pub(crate) fn decrement( method: &Method, headers: &mut HeaderMap ) {
+    if let Some( remaining ) = remaining( method, headers ) {
+        headers.insert( header::MAX_FORWARDS, HeaderValue::from( remaining.saturating_sub( 1 ) ) );
+    }
+}
+
+/// Returns the number of forwards a request has left, if its method counts them.
+fn remaining( method: &Method, headers: &HeaderMap ) -> Option<u64> {
+    if *method != Method::TRACE && *method != Method::OPTIONS {
+        return None;
+    }
+
+    headers.get( header::MAX_FORWARDS )?
+        .to_str().ok()?
+        .trim()
+        .parse().ok()
+}
+
+/// Echoes a `TRACE` request back to the client the way it was received, leaving out the
+/// headers that carry credentials.
+fn trace( req: &Request ) -> Response {
+    let mut message = format!( "{} {} {:?}\r\n", req.method(), req.original_uri(), req.version() );
+    for ( name, value ) in req.headers() {
+        if *name == header::AUTHORIZATION || *name == header::PROXY_AUTHORIZATION || *name == header::COOKIE {
+            continue;
+        }
+        message.push_str( name.as_str() );
+        message.push_str( ": " );
+        message.push_str( &String::from_utf8_lossy( value.as_bytes() ) );
+        message.push_str( "\r\n" );
+    }
+    message.push_str( "\r\n" );
+
+    Response::builder()
+        .status( StatusCode::OK )
+        .content_type( "message/http" )
+        .body( message )
+}
@@ -0,0 +1,97 @@
+//! Accounting of the bytes each request moves through the proxy, for billing or quotas.
+//!
+//! Requests are attributed to a key picked by a callback, such as their route or their
+//! client. The body of a request is counted as the proxy reads it from the client, and
+//! the body of the response as it is sent back, so streamed bodies are counted as they
+//! go rather than by what their headers claim. A request is only recorded once both are
+//! done with, which for a streamed response is when its last byte is sent or the client
+//! goes away.
+
+use std::{ collections::HashMap, sync::{ Arc, Mutex, atomic::{ AtomicU64, Ordering } } };
+use futures_util::{ StreamExt, TryStreamExt };
+use poem::{ Body, Response, http::StatusCode };
+use crate::{ ByteHook, Opaque };
+
+/// The number of body bytes exchanged with clients, either for a single request or in
+/// total for a key. Headers aren't counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ByteCount {
+
+    /// The bytes of request bodies received from the client
+    pub request: u64,
+
+    /// The bytes of response bodies sent to the client
+    pub response: u64,
+}
+
+/// The totals of every key, shared by every clone of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct ByteLedger {
+    totals: Mutex<HashMap<String, ByteCount>>,
+}
+
+impl ByteLedger {
+    pub(crate) fn totals( &self ) -> HashMap<String, ByteCount> {
+        self.totals.lock().unwrap_or_else( |e| e.into_inner() ).clone()
+    }
+}
+
+/// Counts the bytes of a single request, recording them once every copy of it has
+/// been dropped.
+pub(crate) struct Meter {
+    key: String,
+    request: AtomicU64,
+    response: AtomicU64,
+    ledger: Arc<ByteLedger>,
+    hook: Option<Opaque<ByteHook>>,
+}
+
+impl Meter {
+
+    pub(crate) fn new( key: String, ledger: Arc<ByteLedger>, hook: Option<Opaque<ByteHook>> ) -> Arc<Meter> {
+        Arc::new( Meter { key, request: AtomicU64::new( 0 ), response: AtomicU64::new( 0 ), ledger, hook } )
+    }
+
+    /// Counts the bytes of a request body as they are read.
+    pub(crate) fn count_request( self: &Arc<Self>, body: Body ) -> Body {
+        let meter = self.clone();
+        Body::from_bytes_stream( body.into_bytes_stream().inspect_ok( move |chunk| {
+            meter.request.fetch_add( chunk.len() as u64, Ordering::Relaxed );
+        }))
+    }
+
+    /// Counts the bytes of a response body as they are sent. The bodies of upgraded
+    /// connections, such as websockets, aren't counted.
+    pub(crate) fn count_response( self: Arc<Self>, response: Response ) -> Response {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            return response;
+        }
+
+        let ( parts, body ) = response.into_parts();
+        let body = body.into_bytes_stream().inspect( move |chunk| {
+            if let Ok( chunk ) = chunk {
+                self.response.fetch_add( chunk.len() as u64, Ordering::Relaxed );
+            }
+        });
+        Response::from_parts( parts, Body::from_bytes_stream( body ) )
+    }
+}
+
+impl Drop for Meter {
+    fn drop( &mut self ) {
+        let count = ByteCount {
+            request: *self.request.get_mut(),
+            response: *self.response.get_mut(),
+        };
+
+        {
+            let mut totals = self.ledger.totals.lock().unwrap_or_else( |e| e.into_inner() );
+            let total = totals.entry( self.key.clone() ).or_default();
+            total.request += count.request;
+            total.response += count.response;
+        }
+        if let Some( hook ) = &self.hook {
+            hook( &self.key, count );
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Counters of the traffic through the proxy, cheap enough to always keep, for exporting
+//! to a metrics system such as Prometheus.
+
+use std::sync::{ Arc, atomic::{ AtomicU64, Ordering } };
+use futures_util::{ StreamExt, TryStreamExt };
+use hyper::body::HttpBody;
+use poem::{ Body, Response, http::StatusCode };
+
+/// A snapshot of the traffic through the proxy since it started. Obtained through
+/// [ProxyHandle::metrics_snapshot](crate::ProxyHandle::metrics_snapshot).
+///
+/// A request is counted once the proxy has answered it, by the status of the answer,
+/// whether that came from the proxied server, the cache or the proxy itself. Body bytes
+/// are counted as they go through, so a streamed body the client cuts off is only
+/// counted as far as it got, while bodies whose size is known up front, such as
+/// buffered responses, are counted whole as they are sent. The bodies of the proxy's
+/// own error responses aren't counted.
+///
+/// The counts only ever go up, apart from the open websockets, so rates are found by
+/// comparing two snapshots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProxyMetrics {
+
+    /// The number of requests answered, including websocket upgrades
+    pub requests: u64,
+
+    /// The number of requests answered with a `2xx` status
+    pub success: u64,
+
+    /// The number of requests answered with a `3xx` status
+    pub redirection: u64,
+
+    /// The number of requests answered with a `4xx` status
+    pub client_error: u64,
+
+    /// The number of requests answered with a `5xx` status
+    pub server_error: u64,
+
+    /// The number of websockets currently open, including those still being connected
+    /// to the proxied server
+    pub open_websockets: usize,
+
+    /// The bytes of request bodies and websocket messages received from clients
+    pub bytes_from_clients: u64,
+
+    /// The bytes of response bodies and websocket messages sent to clients
+    pub bytes_to_clients: u64,
+}
+
+/// The counters behind [ProxyMetrics], shared by every clone of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct MetricCounters {
+    requests: AtomicU64,
+    success: AtomicU64,
+    redirection: AtomicU64,
+    client_error: AtomicU64,
+    server_error: AtomicU64,
+    bytes_from_clients: AtomicU64,
+    bytes_to_clients: AtomicU64,
+}
+
+impl MetricCounters {
+    pub(crate) fn snapshot( &self, open_websockets: usize ) -> ProxyMetrics {
+        ProxyMetrics {
+            requests: self.requests.load( Ordering::Relaxed ),
+            success: self.success.load( Ordering::Relaxed ),
+            redirection: self.redirection.load( Ordering::Relaxed ),
+            client_error: self.client_error.load( Ordering::Relaxed ),
+            server_error: self.server_error.load( Ordering::Relaxed ),
+            open_websockets,
+            bytes_from_clients: self.bytes_from_clients.load( Ordering::Relaxed ),
+            bytes_to_clients: self.bytes_to_clients.load( Ordering::Relaxed ),
+        }
+    }
+
+    /// Records a request answered with the given status.
+    pub(crate) fn record_answer( &self, status: StatusCode ) {
+        self.requests.fetch_add( 1, Ordering::Relaxed );
+        let class = match status.as_u16() / 100 {
+            2 => &self.success,
+            3 => &self.redirection,
+            4 => &self.client_error,
+            5 => &self.server_error,
+            _ => return,
+        };
+        class.fetch_add( 1, Ordering::Relaxed );
+    }
+
+    /// Records the bytes of a websocket message from a client.
+    pub(crate) fn record_from_client( &self, bytes: usize ) {
+        self.bytes_from_clients.fetch_add( bytes as u64, Ordering::Relaxed );
+    }
+
+    /// Records the bytes of a websocket message to a client.
+    pub(crate) fn record_to_client( &self, bytes: usize ) {
+        self.bytes_to_clients.fetch_add( bytes as u64, Ordering::Relaxed );
+    }
+}
+
+/// Counts the bytes of a request body as they are read.
+pub(crate) fn count_request( counters: &Arc<MetricCounters>, body: Body ) -> Body {
+    let counters = counters.clone();
+    Body::from_bytes_stream( body.into_bytes_stream().inspect_ok( move |chunk| {
+        counters.record_from_client( chunk.len() );
+    }))
+}
+
+/// Counts the bytes of a response body as they are sent. A body of known size is left
+/// as it is, so the response keeps its length, and counted whole. The bodies of
+/// upgraded connections are counted message by message instead.
+pub(crate) fn count_response( counters: &Arc<MetricCounters>, response: Response ) -> Response {
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return response;
+    }
+
+    let ( parts, body ) = response.into_parts();
+    let body: hyper::Body = body.into();
+    if let Some( size ) = body.size_hint().exact() {
+        counters.bytes_to_clients.fetch_add( size, Ordering::Relaxed );
+        return Response::from_parts( parts, body.into() );
+    }
+
+    let counters = counters.clone();
+    let body = Body::from( body ).into_bytes_stream().inspect( move |chunk| {
+        if let Ok( chunk ) = chunk {
+            counters.record_to_client( chunk.len() );
+        }
+    });
+    Response::from_parts( parts, Body::from_bytes_stream( body ) )
+}
@@ -0,0 +1,50 @@
+//! Verification that the proxied server honored the client's `Accept` header.
+
+use poem::http::{ HeaderMap, StatusCode, header };
+
+/// Whether a response with the given status and headers satisfies the `Accept` header
+/// of the request. Only successful responses that carry a body with a `Content-Type`
+/// are checked, and requests without an `Accept` header accept anything.
+pub(crate) fn is_acceptable( request: &HeaderMap, status: StatusCode, response: &HeaderMap ) -> bool {
+    if !status.is_success() || status == StatusCode::NO_CONTENT {
+        return true;
+    }
+
+    let Some( content_type ) = response.get( header::CONTENT_TYPE ).and_then( |v| v.to_str().ok() ) else {
+        return true;
+    };
+    let media_type = content_type.split( ';' ).next().unwrap_or_default().trim().to_ascii_lowercase();
+    let Some( ( main, sub ) ) = media_type.split_once( '/' ) else {
+        return false;
+    };
+
+    let mut ranges = request.get_all( header::ACCEPT )
+        .iter()
+        .filter_map( |value| value.to_str().ok() )
+        .flat_map( |value| value.split( ',' ) )
+        .filter( |range| !range.trim().is_empty() )
+        .peekable();
+
+    if ranges.peek().is_none() {
+        return true;
+    }
+
+    ranges.any( |range| {
+        let mut params = range.split( ';' );
+        let range = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+
+        // A quality of zero means the type is explicitly not acceptable
+        let refused = params
+            .filter_map( |param| param.trim().strip_prefix( "q=" ) )
+            .any( |q| q.trim().parse::<f32>().map( |q| q <= 0.0 ).unwrap_or( false ) );
+
+        let matches = match range.split_once( '/' ) {
+            Some( ( "*", "*" ) ) => true,
+            Some( ( range_main, "*" ) ) => range_main == main,
+            Some( ( range_main, range_sub ) ) => range_main == main && range_sub == sub,
+            None => false,
+        };
+
+        matches && !refused
+    })
+}
@@ -0,0 +1,238 @@
+//! Management of the http clients used to reach the proxied server.
+//!
+//! Some servers advertise how long and how many requests they are willing to serve on
+//! a single connection through the `Keep-Alive` header (`Keep-Alive: timeout=5, max=100`).
+//! When the proxy is configured to honor these hints, it keeps one pooled client per host
+//! and replaces it before the server is expected to close its connections, so that
+//! requests are not sent down a connection that is about to be dropped.
+//!
+//! Otherwise, every request is sent with a single shared client, which keeps its own
+//! pool of connections to each host.
+
+use std::{
+    collections::{ HashMap, hash_map::Entry },
+    net::IpAddr,
+    sync::{ Arc, Mutex, atomic::{ AtomicU64, Ordering } },
+    time::{ Duration, Instant },
+};
+use hyper::client::connect::dns::Name;
+use poem::{ Result, http::{ HeaderMap, HeaderValue, Uri } };
+use reqwest::dns::{ Resolve, Resolving };
+
+/// The parameters of a `Keep-Alive` response header that the proxy cares about.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct KeepAliveHint {
+
+    /// The number of requests the server is willing to serve on a connection
+    max: Option<u32>,
+
+    /// How long the server will keep an idle connection open
+    timeout: Option<Duration>,
+}
+
+impl KeepAliveHint {
+
+    /// Parses the value of a `Keep-Alive` header. Unknown or malformed parameters are
+    /// ignored, as the header is only ever a hint.
+    pub(crate) fn parse( value: &HeaderValue ) -> KeepAliveHint {
+        let mut hint = KeepAliveHint::default();
+        let Ok( value ) = value.to_str() else {
+            return hint;
+        };
+
+        for param in value.split( ',' ) {
+            let Some( ( key, val ) ) = param.split_once( '=' ) else {
+                continue;
+            };
+            match key.trim().to_ascii_lowercase().as_str() {
+                "max" => hint.max = val.trim().parse().ok(),
+                "timeout" => hint.timeout = val.trim().parse().ok().map( Duration::from_secs ),
+                _ => {},
+            }
+        }
+
+        hint
+    }
+}
+
+/// A client for a single host, along with what is known about its connections.
+#[derive(Debug)]
+struct HostClient {
+    client: reqwest::Client,
+
+    /// How many requests have been handed out since this client was created
+    served: u32,
+
+    /// When this client was last handed out
+    last_used: Instant,
+
+    /// The most recent keep-alive hint sent back by the host
+    hint: KeepAliveHint,
+}
+
+impl HostClient {
+
+    /// Wraps a fresh client, which will open new connections to the host.
+    fn new( client: reqwest::Client ) -> HostClient {
+        HostClient {
+            client,
+            served: 0,
+            last_used: Instant::now(),
+            hint: KeepAliveHint::default(),
+        }
+    }
+
+    /// Whether the server is expected to have closed (or be about to close) the
+    /// connections held by this client.
+    fn is_exhausted( &self ) -> bool {
+
+        // Leave one request of headroom so the final request allowed on the connection
+        // is never the one that races the server closing it
+        let max_reached = self.hint.max
+            .map( |max| self.served >= max.saturating_sub( 1 ).max( 1 ) )
+            .unwrap_or( false );
+
+        // Likewise, give up on idle connections a little before the server does
+        let idle_expired = self.hint.timeout
+            .map( |timeout| self.last_used.elapsed() >= timeout.mul_f32( 0.9 ) )
+            .unwrap_or( false );
+
+        max_reached || idle_expired
+    }
+}
+
+/// How many requests to the proxied servers went out over a connection that was already
+/// open, and how many connections had to be opened. Obtained through
+/// [ProxyHandle::connection_stats](crate::ProxyHandle::connection_stats).
+///
+/// The http client looks up the name of a server once for every connection it opens,
+/// which is how connections are counted. Servers given by IP address are never looked
+/// up, so requests to them aren't counted at all. Connections opened to follow
+/// redirects count as well, though the redirected requests don't.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+
+    /// The number of requests sent over a connection that was already open
+    pub reused: u64,
+
+    /// The number of connections opened, or attempted, to send requests over
+    pub opened: u64,
+}
+
+/// A resolver that counts the connections the http client opens, since it looks a name
+/// up for every new connection.
+struct CountingResolver {
+    inner: Arc<dyn Resolve>,
+    opened: Arc<AtomicU64>,
+}
+
+impl Resolve for CountingResolver {
+    fn resolve( &self, name: Name ) -> Resolving {
+        self.opened.fetch_add( 1, Ordering::Relaxed );
+        self.inner.resolve( name )
+    }
+}
+
+/// A set of pooled clients, one per upstream host, that are rotated according to the
+/// keep-alive hints each host sends back.
+#[derive(Debug, Default)]
+pub(crate) struct ClientPool {
+    hosts: Mutex<HashMap<String, HostClient>>,
+
+    /// The client used for every host when keep-alive hints aren't honored, created
+    /// with the first request
+    shared: Mutex<Option<reqwest::Client>>,
+
+    /// The number of requests sent to servers with a name
+    sent: AtomicU64,
+
+    /// The number of connections opened to servers with a name, shared with the
+    /// resolvers that count them
+    opened: Arc<AtomicU64>,
+}
+
+impl ClientPool {
+
+    /// Returns the client that should be used for the next request to `host`, replacing
+    /// the current one first if its connections are expected to have been closed.
+    /// New clients are created with `build`, whose errors are passed on.
+    pub(crate) fn client_for( &self, host: &str, build: impl Fn() -> Result<reqwest::Client> ) -> Result<reqwest::Client> {
+        let mut hosts = self.hosts.lock().unwrap_or_else( |e| e.into_inner() );
+
+        let entry = match hosts.entry( host.to_owned() ) {
+            Entry::Occupied( entry ) => entry.into_mut(),
+            Entry::Vacant( entry ) => entry.insert( HostClient::new( build()? ) ),
+        };
+
+        if entry.is_exhausted() {
+            let hint = entry.hint;
+            *entry = HostClient::new( build()? );
+            entry.hint = hint;
+        }
+
+        entry.served += 1;
+        entry.last_used = Instant::now();
+        Ok( entry.client.clone() )
+    }
+
+    /// Returns the client shared by requests to every host, creating it with `build`
+    /// the first time.
+    pub(crate) fn shared_client( &self, build: impl Fn() -> Result<reqwest::Client> ) -> Result<reqwest::Client> {
+        let mut shared = self.shared.lock().unwrap_or_else( |e| e.into_inner() );
+        let client = match &*shared {
+            Some( client ) => client.clone(),
+            None => shared.insert( build()? ).clone(),
+        };
+        Ok( client )
+    }
+
+    /// Replaces the client shared by requests to every host with a new one created with
+    /// `build`, dropping the idle connections of the old one.
+    pub(crate) fn replace_shared( &self, build: impl Fn() -> Result<reqwest::Client> ) -> Result<reqwest::Client> {
+        let client = build()?;
+        *self.shared.lock().unwrap_or_else( |e| e.into_inner() ) = Some( client.clone() );
+        Ok( client )
+    }
+
+    /// Wraps the resolver of a new client, so the connections it opens are counted.
+    pub(crate) fn counting( &self, inner: Arc<dyn Resolve> ) -> Arc<impl Resolve> {
+        Arc::new( CountingResolver { inner, opened: self.opened.clone() } )
+    }
+
+    /// Records a request about to be sent to `uri`, unless its server is given by IP
+    /// address, since connections to those can't be counted.
+    pub(crate) fn record_request( &self, uri: &str ) {
+        let host = uri.parse::<Uri>().ok().and_then( |uri| uri.host().map( str::to_owned ) );
+        if let Some( host ) = host {
+            if host.trim_start_matches( '[' ).trim_end_matches( ']' ).parse::<IpAddr>().is_err() {
+                self.sent.fetch_add( 1, Ordering::Relaxed );
+            }
+        }
+    }
+
+    /// Returns how often requests reused a connection, rather than opening a new one.
+    pub(crate) fn stats( &self ) -> ConnectionStats {
+        let opened = self.opened.load( Ordering::Relaxed );
+        ConnectionStats {
+            reused: self.sent.load( Ordering::Relaxed ).saturating_sub( opened ),
+            opened,
+        }
+    }
+
+    /// Drops the client for `host`, closing its idle connections.
+    pub(crate) fn forget( &self, host: &str ) {
+        self.hosts.lock().unwrap_or_else( |e| e.into_inner() ).remove( host );
+    }
+
+    /// Records the keep-alive hint (if any) that `host` sent back with a response.
+    pub(crate) fn record_response( &self, host: &str, headers: &HeaderMap ) {
+        let Some( value ) = headers.get( "keep-alive" ) else {
+            return;
+        };
+
+        let mut hosts = self.hosts.lock().unwrap_or_else( |e| e.into_inner() );
+        if let Some( entry ) = hosts.get_mut( host ) {
+            entry.hint = KeepAliveHint::parse( value );
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! A line for the clients turned away while the proxy is overloaded.
+//!
+//! Rather than holding requests open until there is room, the proxy answers them right
+//! away and keeps their clients' places in line. Each client is told where it stands
+//! and when to come back, and as room frees up it goes to the clients at the front of
+//! the line first. A client that doesn't come back in time loses its place, so clients
+//! that gave up don't hold up everyone behind them.
+
+use std::{ collections::VecDeque, net::IpAddr, sync::Mutex, time::{ Duration, Instant } };
+
+/// The clients waiting for room, in the order they were turned away, shared by every
+/// clone of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct WaitingLine {
+    places: Mutex<VecDeque<Place>>,
+}
+
+/// A client's place in line.
+#[derive(Debug)]
+struct Place {
+    client: IpAddr,
+
+    /// When the client loses its place if it hasn't come back.
+    expires: Instant,
+}
+
+impl WaitingLine {
+
+    /// Returns whether a request from `client` may go ahead when there is room for
+    /// `room` more requests. Clients that are waiting get the room in the order they
+    /// are in line, and leave the line when let through.
+    pub(crate) fn admit( &self, client: IpAddr, room: usize ) -> bool {
+        let mut places = self.places.lock().unwrap_or_else( |e| e.into_inner() );
+        prune( &mut places );
+
+        match places.iter().position( |place| place.client == client ) {
+            Some( index ) if index < room => {
+                places.remove( index );
+                true
+            },
+            Some( _ ) => false,
+            None => places.len() < room,
+        }
+    }
+
+    /// Puts `client` in line if it isn't already, returning its position, starting at
+    /// 1 for the front of the line. The client is to come back after `retry_after` for
+    /// each place up to and including its own, and keeps its place for one more
+    /// `retry_after` past that.
+    pub(crate) fn join( &self, client: IpAddr, retry_after: Duration ) -> usize {
+        let mut places = self.places.lock().unwrap_or_else( |e| e.into_inner() );
+        prune( &mut places );
+
+        let index = match places.iter().position( |place| place.client == client ) {
+            Some( index ) => index,
+            None => {
+                places.push_back( Place { client, expires: Instant::now() } );
+                places.len() - 1
+            },
+        };
+
+        let position = index + 1;
+        places[index].expires = Instant::now() + retry_after.saturating_mul( u32::try_from( position + 1 ).unwrap_or( u32::MAX ) );
+        position
+    }
+}
+
+/// Drops the clients that didn't come back in time.
+fn prune( places: &mut VecDeque<Place> ) {
+    let now = Instant::now();
+    places.retain( |place| place.expires > now );
+}
@@ -0,0 +1,63 @@
+//! How the proxy handles redirects sent back by the proxied server.
+
+use poem::http::{ HeaderMap, StatusCode, header };
+use reqwest::{ Url, redirect::Policy };
+
+/// The most redirects that are followed for a single request, unless configured otherwise
+pub(crate) const MAX_REDIRECTS: usize = 10;
+
+/// The ways in which the proxy can handle redirects from the proxied server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedirectMode {
+
+    /// Follow redirects the way browsers do: a `301`, `302` or `303` turns the request
+    /// into a `GET` without a body, while a `307` or `308` repeats it as is. A request
+    /// whose body was [streamed](crate::ProxyConfig::upload_stream_threshold) can't be
+    /// repeated, so a `307` or `308` for it is sent back to the client instead.
+    #[default]
+    Follow,
+
+    /// Follow redirects, repeating the request with its original method and body for
+    /// `301` and `302` as well, as if they were a `307` or `308`. A `303` still turns
+    /// the request into a `GET`, since that is what it is meant for.
+    PreserveMethod,
+
+    /// Don't follow redirects, and send them back to the client instead.
+    PassThrough,
+}
+
+impl RedirectMode {
+
+    /// Returns the redirect policy the http client should use for this mode, following
+    /// at most `max` redirects.
+    pub(crate) fn policy( &self, max: usize ) -> Policy {
+        match self {
+            // The original url counts towards the limit of the client
+            RedirectMode::Follow => Policy::limited( max + 1 ),
+            RedirectMode::PassThrough => Policy::none(),
+
+            // The client would change the method of these, so they are handed back to
+            // the proxy to follow itself
+            RedirectMode::PreserveMethod => Policy::custom( move |attempt| {
+                if matches!( attempt.status(), StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND ) {
+                    attempt.stop()
+                } else if attempt.previous().len() > max {
+                    attempt.error( "too many redirects" )
+                } else {
+                    attempt.follow()
+                }
+            }),
+        }
+    }
+}
+
+/// Returns where a `301` or `302` response points to, if the proxy should follow it
+/// itself to preserve the method of the request.
+pub(crate) fn preserved_target( status: StatusCode, url: &Url, headers: &HeaderMap ) -> Option<Url> {
+    if !matches!( status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND ) {
+        return None;
+    }
+
+    let location = headers.get( header::LOCATION )?.to_str().ok()?;
+    url.join( location ).ok()
+}
@@ -0,0 +1,13 @@
+//! How long to wait between attempts at reaching the proxied server, shared by retried
+//! requests and websocket connections.
+
+use std::time::Duration;
+
+/// How long to wait before the first retry of a failed connection or request to the proxied
+/// server. The wait doubles with every retry after that.
+const RETRY_BACKOFF: Duration = Duration::from_millis( 100 );
+
+/// Returns how long to wait before retry number `attempt`, counting from zero.
+pub(crate) fn backoff( attempt: u32 ) -> Duration {
+    RETRY_BACKOFF * 2u32.saturating_pow( attempt )
+}
@@ -0,0 +1,239 @@
+//! Rewriting of requests before they are forwarded to a particular target.
+
+use poem::http::{ HeaderMap, HeaderName, HeaderValue };
+
+/// Rules for rewriting the path of requests forwarded to a target. The rules are tried
+/// in the order they were added, and the first one that matches is applied.
+///
+/// ```
+/// use poem_proxy::{ PathRewrite, ProxyConfig };
+///
+/// let config = ProxyConfig::new( "legacy-backend:8080" )
+///     .add_target( "new-backend:8080" )
+///     .web_insecure()
+///     .enable_nesting()
+///     .rewrite_path( "legacy-backend:8080", PathRewrite::new()
+///         .replace_prefix( "/api/v2", "/api" ) ) // The legacy backend only knows the old paths
+///     .finish();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PathRewrite {
+    rules: Vec<PathRule>,
+}
+
+/// A single rule of a [PathRewrite].
+#[derive(Clone, Debug)]
+enum PathRule {
+
+    /// Replaces the first prefix with the second
+    Prefix( String, String ),
+
+    /// Matches the first template, filling its variables into the second
+    Template( String, String ),
+}
+
+impl PathRewrite {
+
+    /// Creates a new set of rules that leaves paths as they are.
+    pub fn new() -> PathRewrite {
+        PathRewrite::default()
+    }
+
+    /// Replaces the `from` prefix of matching paths with `to`.
+    pub fn replace_prefix( &mut self, from: impl Into<String>, to: impl Into<String> ) -> &mut PathRewrite {
+        self.rules.push( PathRule::Prefix( from.into(), to.into() ) );
+        self
+    }
+
+    /// Rewrites paths matching the `from` template to the `to` template. A segment
+    /// written as `{name}` in `from` matches any single segment of the path, and every
+    /// `{name}` in `to` is replaced with what it matched. The path must have
+    /// exactly as many segments as `from` to match, and its query string is kept.
+    ///
+    /// ```
+    /// use poem_proxy::PathRewrite;
+    ///
+    /// let mut rewrite = PathRewrite::new();
+    /// rewrite.template( "/users/{id}/posts", "/v2/users/{id}/items" )
+    ///     .template( "/orgs/{org}/members/{user}", "/v2/members/{user}?org={org}" );
+    ///
+    /// assert_eq!( rewrite.apply( "/users/42/posts" ), "/v2/users/42/items" );
+    /// assert_eq!( rewrite.apply( "/users/42/posts?page=2" ), "/v2/users/42/items?page=2" );
+    /// assert_eq!( rewrite.apply( "/orgs/acme/members/bob" ), "/v2/members/bob?org=acme" );
+    ///
+    /// // Paths that don't match any template are left as they are
+    /// assert_eq!( rewrite.apply( "/users/42/comments" ), "/users/42/comments" );
+    /// assert_eq!( rewrite.apply( "/users/42/posts/7" ), "/users/42/posts/7" );
+    /// ```
+    pub fn template( &mut self, from: impl Into<String>, to: impl Into<String> ) -> &mut PathRewrite {
+        self.rules.push( PathRule::Template( from.into(), to.into() ) );
+        self
+    }
+
+    /// Applies the rules to a path, which may be followed by a query string, and
+    /// returns the path the request is forwarded with.
+    pub fn apply( &self, path: &str ) -> String {
+        let rewritten = self.rules.iter().find_map( |rule| match rule {
+            PathRule::Prefix( from, to ) => replace_prefix( path, from, to ),
+            PathRule::Template( from, to ) => fill_template( path, from, to ),
+        });
+
+        match rewritten {
+            Some( rewritten ) if !rewritten.starts_with( '/' ) => format!( "/{}", rewritten ),
+            Some( rewritten ) => rewritten,
+            None => path.to_owned(),
+        }
+    }
+}
+
+/// Replaces the `from` prefix of `path` with `to`, if `path` starts with it.
+fn replace_prefix( path: &str, from: &str, to: &str ) -> Option<String> {
+    let rest = path.strip_prefix( from )?;
+
+    // Only match whole segments, so `/api` doesn't rewrite `/apiary`
+    ( rest.is_empty() || rest.starts_with( [ '/', '?' ] ) || from.ends_with( '/' ) )
+        .then( || format!( "{}{}", to, rest ) )
+}
+
+/// Whether `path` is `prefix` or lies below it. Paths are matched by whole segments, so
+/// `/api` covers `/api/users` but not `/apis`.
+pub(crate) fn covers( path: &str, prefix: &str ) -> bool {
+    match path.strip_prefix( prefix ) {
+        Some( rest ) => rest.is_empty() || rest.starts_with( '/' ),
+        None => false,
+    }
+}
+
+/// Removes `prefix` from the start of `path`, if it starts with it, leaving `/` if
+/// nothing else is left. Any query string is kept.
+pub(crate) fn strip_prefix( path: &str, prefix: &str ) -> String {
+    match replace_prefix( path, prefix.trim_end_matches( '/' ), "" ) {
+        Some( rest ) if !rest.starts_with( '/' ) => format!( "/{}", rest ),
+        Some( rest ) => rest,
+        None => path.to_owned(),
+    }
+}
+
+/// The ways in which the proxy can treat the trailing slash of forwarded paths, for
+/// servers that answer `/docs` and `/docs/` differently. The root path is always
+/// forwarded as `/`, and the query string is left alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlashPolicy {
+
+    /// Forward paths the way the client sent them.
+    #[default]
+    Preserve,
+
+    /// End every path with a slash, so `/docs` is forwarded as `/docs/`.
+    Add,
+
+    /// Remove any slashes ending a path, so `/docs/` is forwarded as `/docs`.
+    Strip,
+}
+
+impl TrailingSlashPolicy {
+
+    /// Applies the policy to `path`, which may include a query string.
+    pub(crate) fn apply( &self, path: String ) -> String {
+        let ( route, query ) = match path.find( '?' ) {
+            Some( i ) => path.split_at( i ),
+            None => ( path.as_str(), "" ),
+        };
+
+        let route = match self {
+            TrailingSlashPolicy::Preserve => return path,
+            _ if route.trim_end_matches( '/' ).is_empty() => return path,
+            TrailingSlashPolicy::Add if route.ends_with( '/' ) => return path,
+            TrailingSlashPolicy::Add => format!( "{}/", route ),
+            TrailingSlashPolicy::Strip => route.trim_end_matches( '/' ).to_owned(),
+        };
+        route + query
+    }
+}
+
+/// Matches `path` against the `from` template, returning the `to` template with the
+/// captured variables filled in.
+fn fill_template( path: &str, from: &str, to: &str ) -> Option<String> {
+    let ( path, query ) = match path.split_once( '?' ) {
+        Some( ( path, query ) ) => ( path, Some( query ) ),
+        None => ( path, None ),
+    };
+
+    let variable = |segment: &str| segment.strip_prefix( '{' )?.strip_suffix( '}' ).map( str::to_owned );
+
+    let mut captures = Vec::new();
+    let mut segments = path.trim_start_matches( '/' ).split( '/' );
+    for pattern in from.trim_start_matches( '/' ).split( '/' ) {
+        let segment = segments.next()?;
+        match variable( pattern ) {
+            Some( name ) if !segment.is_empty() => captures.push( ( name, segment ) ),
+            None if pattern == segment => {},
+            _ => return None,
+        }
+    }
+    if segments.next().is_some() {
+        return None;
+    }
+
+    // Variables may also appear in the query string of `to`, such as `?id={id}`
+    let mut rewritten = to.to_owned();
+    for ( name, value ) in captures {
+        rewritten = rewritten.replace( &format!( "{{{}}}", name ), value );
+    }
+
+    match query {
+        Some( query ) if rewritten.contains( '?' ) => Some( format!( "{}&{}", rewritten, query ) ),
+        Some( query ) => Some( format!( "{}?{}", rewritten, query ) ),
+        None => Some( rewritten ),
+    }
+}
+
+/// Rules for rewriting the headers of requests forwarded to a target. Headers are
+/// removed first, then set.
+///
+/// ```
+/// use poem::http::{ HeaderName, HeaderValue };
+/// use poem_proxy::{ HeaderRewrite, ProxyConfig };
+///
+/// let config = ProxyConfig::new( "backend:8080" )
+///     .web_insecure()
+///     .rewrite_headers( "backend:8080", HeaderRewrite::new()
+///         .remove( HeaderName::from_static( "cookie" ) )
+///         .set( HeaderName::from_static( "x-tenant" ), HeaderValue::from_static( "acme" ) ) )
+///     .finish();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRewrite {
+    remove: Vec<HeaderName>,
+    set: Vec<( HeaderName, HeaderValue )>,
+}
+
+impl HeaderRewrite {
+
+    /// Creates a new set of rules that leaves headers as they are.
+    pub fn new() -> HeaderRewrite {
+        HeaderRewrite::default()
+    }
+
+    /// Sets a header, replacing any value the request already had for it.
+    pub fn set( &mut self, name: HeaderName, value: HeaderValue ) -> &mut HeaderRewrite {
+        self.set.push( ( name, value ) );
+        self
+    }
+
+    /// Removes a header from the request.
+    pub fn remove( &mut self, name: HeaderName ) -> &mut HeaderRewrite {
+        self.remove.push( name );
+        self
+    }
+
+    /// Applies the rules to the headers of a request.
+    pub(crate) fn apply( &self, headers: &mut HeaderMap ) {
+        for name in &self.remove {
+            crate::headers::remove_in_order( headers, name.as_str() );
+        }
+        for ( name, value ) in &self.set {
+            headers.insert( name.clone(), value.clone() );
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Connecting to the proxied server through a SOCKS5 proxy.
+//!
+//! Some networks only let outbound connections out through a SOCKS5 proxy. The proxy is
+//! asked to connect to the server by name, so names are resolved on its side of the
+//! network rather than ours. The handshake is small enough to be done here: no
+//! authentication or a username and password ([RFC 1928](https://www.rfc-editor.org/rfc/rfc1928)
+//! and [RFC 1929](https://www.rfc-editor.org/rfc/rfc1929)).
+
+use std::{ io, net::IpAddr };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpStream };
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A SOCKS5 proxy that websockets to the proxied server are opened through.
+///
+/// ```
+/// use poem_proxy::{ ProxyConfig, Socks5Proxy };
+///
+/// let config = ProxyConfig::new( "chat.internal:8080" )
+///     .ws_insecure()
+///     .ws_socks5_proxy( Socks5Proxy::new( "127.0.0.1:1080" )
+///         .credentials( "proxy-user", "hunter2" ) )
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Socks5Proxy {
+
+    /// The address (host and port) of the SOCKS5 proxy
+    address: String,
+
+    /// The username and password to log in with, if the proxy asks for them
+    credentials: Option<( String, String )>,
+}
+
+impl Socks5Proxy {
+
+    /// Creates a SOCKS5 proxy at the given address, which needs no authentication.
+    pub fn new( address: impl Into<String> ) -> Socks5Proxy {
+        Socks5Proxy { address: address.into(), credentials: None }
+    }
+
+    /// Sets the username and password to log in to the proxy with.
+    pub fn credentials( &mut self, username: impl Into<String>, password: impl Into<String> ) -> &mut Socks5Proxy {
+        self.credentials = Some( ( username.into(), password.into() ) );
+        self
+    }
+
+    /// Opens a connection to `host` on `port` through the proxy.
+    pub(crate) async fn connect( &self, host: &str, port: u16 ) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect( &self.address ).await?;
+
+        // Agree on how to authenticate
+        let methods: &[u8] = match self.credentials {
+            Some( _ ) => &[ NO_AUTHENTICATION, USERNAME_PASSWORD ],
+            None => &[ NO_AUTHENTICATION ],
+        };
+        let mut greeting = vec![ VERSION, methods.len() as u8 ];
+        greeting.extend_from_slice( methods );
+        stream.write_all( &greeting ).await?;
+
+        let mut choice = [ 0; 2 ];
+        stream.read_exact( &mut choice ).await?;
+        match ( choice, &self.credentials ) {
+            ( [ VERSION, NO_AUTHENTICATION ], _ ) => {},
+            ( [ VERSION, USERNAME_PASSWORD ], Some( ( username, password ) ) ) => {
+                let mut login = vec![ 1 ];
+                login.push( field_length( username )? );
+                login.extend_from_slice( username.as_bytes() );
+                login.push( field_length( password )? );
+                login.extend_from_slice( password.as_bytes() );
+                stream.write_all( &login ).await?;
+
+                let mut status = [ 0; 2 ];
+                stream.read_exact( &mut status ).await?;
+                if status[1] != 0 {
+                    return Err( failure( "the SOCKS5 proxy rejected the credentials" ) );
+                }
+            },
+            ( [ VERSION, NO_ACCEPTABLE_METHODS ], _ ) => return Err( failure( "the SOCKS5 proxy requires authentication" ) ),
+            _ => return Err( failure( "the SOCKS5 proxy sent an invalid reply" ) ),
+        }
+
+        // Ask for the connection, by address if there is one and by name otherwise
+        let mut request = vec![ VERSION, CONNECT, 0 ];
+        match host.trim_start_matches( '[' ).trim_end_matches( ']' ).parse::<IpAddr>() {
+            Ok( IpAddr::V4( ip ) ) => {
+                request.push( IPV4 );
+                request.extend_from_slice( &ip.octets() );
+            },
+            Ok( IpAddr::V6( ip ) ) => {
+                request.push( IPV6 );
+                request.extend_from_slice( &ip.octets() );
+            },
+            Err( _ ) => {
+                request.push( DOMAIN_NAME );
+                request.push( field_length( host )? );
+                request.extend_from_slice( host.as_bytes() );
+            },
+        }
+        request.extend_from_slice( &port.to_be_bytes() );
+        stream.write_all( &request ).await?;
+
+        // The reply ends with the address the proxy connected from, which isn't needed
+        let mut reply = [ 0; 4 ];
+        stream.read_exact( &mut reply ).await?;
+        if reply[0] != VERSION {
+            return Err( failure( "the SOCKS5 proxy sent an invalid reply" ) );
+        }
+        if reply[1] != 0 {
+            return Err( failure( &format!( "the SOCKS5 proxy could not connect (reply code {})", reply[1] ) ) );
+        }
+        let address_length = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => stream.read_u8().await? as usize,
+            _ => return Err( failure( "the SOCKS5 proxy sent an invalid reply" ) ),
+        };
+        let mut bound = vec![ 0; address_length + 2 ];
+        stream.read_exact( &mut bound ).await?;
+
+        Ok( stream )
+    }
+}
+
+/// Returns the length of a field of the handshake, which can't be longer than 255 bytes.
+fn field_length( field: &str ) -> io::Result<u8> {
+    u8::try_from( field.len() ).map_err( |_| io::Error::new( io::ErrorKind::InvalidInput, "SOCKS5 fields can't be longer than 255 bytes" ) )
+}
+
+fn failure( message: &str ) -> io::Error {
+    io::Error::new( io::ErrorKind::Other, message )
+}
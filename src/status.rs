@@ -0,0 +1,29 @@
+//! Filtering of the statuses the proxied server responds with.
+//!
+//! Gateways often don't want clients to see certain statuses from the servers behind
+//! them, such as a raw `500` that may come with a stack trace. Responses with a blocked
+//! status are replaced with a generic error instead of being forwarded.
+
+use poem::http::StatusCode;
+
+/// Which response statuses may be forwarded to the client.
+#[derive(Clone, Debug)]
+pub(crate) enum StatusFilter {
+
+    /// Only these statuses are forwarded
+    Allow( Vec<StatusCode> ),
+
+    /// Every status but these is forwarded
+    Deny( Vec<StatusCode> ),
+}
+
+impl StatusFilter {
+
+    /// Whether a response with `status` may be forwarded to the client.
+    pub(crate) fn allows( &self, status: StatusCode ) -> bool {
+        match self {
+            StatusFilter::Allow( statuses ) => statuses.contains( &status ),
+            StatusFilter::Deny( statuses ) => !statuses.contains( &status ),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Sampling of the messages relayed over websockets.
+//!
+//! Copies of a sample of the messages are handed to a user-provided [FrameSink] on a
+//! separate task, so a slow sink never holds up the relay. Messages that aren't
+//! sampled are relayed without being copied at all.
+
+use std::{ sync::Arc, time::SystemTime };
+use async_trait::async_trait;
+use poem::web::websocket::Message;
+use crate::Opaque;
+
+/// Which way a websocket message was relayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameDirection {
+
+    /// From the client to the proxied server
+    ClientToServer,
+
+    /// From the proxied server to the client
+    ServerToClient,
+}
+
+/// A copy of a message relayed over a websocket.
+#[derive(Clone, Debug)]
+pub struct TappedFrame {
+
+    /// Which way the message was relayed
+    pub direction: FrameDirection,
+
+    /// The url the websocket was forwarded to
+    pub uri: String,
+
+    /// The position of the message among those relayed in the same direction over the
+    /// same websocket, starting at 0
+    pub index: u64,
+
+    /// When the message was relayed
+    pub relayed_at: SystemTime,
+
+    /// The message itself
+    pub message: Message,
+}
+
+/// Somewhere to send copies of websocket messages, such as a log file or a debugging
+/// service.
+#[async_trait]
+pub trait FrameSink: Send + Sync {
+
+    /// Records a copy of a relayed message.
+    async fn tap( &self, frame: TappedFrame );
+}
+
+/// Settings for copying a sample of the messages relayed over websockets.
+///
+/// ```
+/// use std::sync::Arc;
+/// use poem_proxy::{ FrameSink, ProxyConfig, TappedFrame, WebSocketTap };
+///
+/// struct PrintSink;
+///
+/// #[poem_proxy::async_trait]
+/// impl FrameSink for PrintSink {
+///     async fn tap( &self, frame: TappedFrame ) {
+///         println!( "{} #{} {:?}: {:?}", frame.uri, frame.index, frame.direction, frame.message );
+///     }
+/// }
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .ws_insecure()
+///     .tap_websockets( WebSocketTap::new( Arc::new( PrintSink ) )
+///         .sample_rate( 0.05 ) ) // 5% of messages
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct WebSocketTap {
+    sink: Opaque<dyn FrameSink>,
+
+    /// The fraction of messages to copy, between 0 and 1
+    sample_rate: f64,
+}
+
+impl WebSocketTap {
+
+    /// Creates new tap settings sending copies to `sink`. By default, every message is
+    /// copied.
+    pub fn new( sink: Arc<dyn FrameSink> ) -> WebSocketTap {
+        WebSocketTap { sink: Opaque( sink ), sample_rate: 1.0 }
+    }
+
+    /// Sets the fraction of messages to copy, between `0.0` (none) and `1.0` (all). Each
+    /// message is sampled on its own, in both directions.
+    pub fn sample_rate( &mut self, rate: f64 ) -> &mut WebSocketTap {
+        self.sample_rate = rate;
+        self
+    }
+
+    /// Decides whether to copy the next message.
+    pub(crate) fn sample( &self ) -> bool {
+        rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Sends a copy of a message to the sink in the background.
+    pub(crate) fn record( &self, direction: FrameDirection, uri: &str, index: u64, message: Message ) {
+        let frame = TappedFrame {
+            direction,
+            uri: uri.to_owned(),
+            index,
+            relayed_at: SystemTime::now(),
+            message,
+        };
+
+        let sink = self.sink.clone();
+        tokio::spawn( async move {
+            sink.tap( frame ).await;
+        });
+    }
+}
@@ -0,0 +1,430 @@
+//! The servers that requests can be forwarded to.
+//!
+//! A target is given as an address, optionally prefixed with a scheme
+//! (`https://backend:8443`). Targets with a scheme are always reached with the matching
+//! protocol, while the proxy's `web_secure`/`ws_secure` settings decide for targets
+//! without one. This makes it possible to mix secure and insecure servers in one pool.
+
+use std::{ fmt, sync::{
+    Arc, RwLock,
+    atomic::{ AtomicUsize, Ordering },
+} };
+use poem::http::HeaderMap;
+use tokio::sync::watch;
+use crate::rewrite::{ HeaderRewrite, PathRewrite };
+
+/// How demanding a request is to serve, which decides the tier of targets it is
+/// forwarded to. See [ProxyConfig::cost_selector](crate::ProxyConfig::cost_selector).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Cost {
+
+    /// A request any server can handle, like a small `GET`.
+    #[default]
+    Light,
+
+    /// A request that should go to a more capable server, like a large upload.
+    Heavy,
+}
+
+/// A single server that requests can be forwarded to.
+#[derive(Clone, Debug)]
+pub(crate) struct Target {
+
+    /// The host (and port, if any) of the server, without a scheme
+    address: String,
+
+    /// Whether the server must be reached securely, if the target specified it
+    secure: Option<bool>,
+
+    /// The number of requests and websockets currently being forwarded to the server,
+    /// shared by every copy of the target
+    active: Arc<AtomicUsize>,
+
+    /// How the paths of requests forwarded to this server are rewritten
+    path_rewrite: Option<Arc<PathRewrite>>,
+
+    /// How the headers of requests forwarded to this server are rewritten
+    header_rewrite: Option<Arc<HeaderRewrite>>,
+
+    /// The cost of the requests this server is reserved for. If not set, it takes
+    /// requests of any cost
+    tier: Option<Cost>,
+
+    /// The most redirects followed for a request to this server. If not set, the
+    /// limit of the configuration applies
+    max_redirects: Option<usize>,
+
+    /// Set once the target has been removed from the pool, shared by every copy of
+    /// the target
+    retired: Arc<watch::Sender<bool>>,
+}
+
+/// Counts a request as active for as long as it is alive.
+#[derive(Debug)]
+pub(crate) struct ActiveGuard( Arc<AtomicUsize> );
+
+impl ActiveGuard {
+
+    /// Counts a request as active on `counter` until the returned guard is dropped.
+    pub(crate) fn new( counter: &Arc<AtomicUsize> ) -> ActiveGuard {
+        counter.fetch_add( 1, Ordering::Relaxed );
+        ActiveGuard( counter.clone() )
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop( &mut self ) {
+        self.0.fetch_sub( 1, Ordering::Relaxed );
+    }
+}
+
+impl Target {
+
+    /// Parses a target, splitting off its scheme if it has one.
+    pub(crate) fn parse( target: &str ) -> Target {
+        let target = target.trim();
+        let schemes = [ ( "https://", true ), ( "wss://", true ), ( "http://", false ), ( "ws://", false ) ];
+
+        for ( scheme, secure ) in schemes {
+            if let Some( address ) = strip_prefix_ignore_case( target, scheme ) {
+                return Target::with_scheme( address, Some( secure ) );
+            }
+        }
+
+        Target::with_scheme( target, None )
+    }
+
+    fn with_scheme( address: &str, secure: Option<bool> ) -> Target {
+        Target {
+            address: address.trim_end_matches( '/' ).into(),
+            secure,
+            active: Arc::default(),
+            path_rewrite: None,
+            header_rewrite: None,
+            tier: None,
+            max_redirects: None,
+            retired: Arc::new( watch::channel( false ).0 ),
+        }
+    }
+
+    /// Returns a copy of the target for another pool, which counts its requests and is
+    /// removed separately from this one.
+    fn detached( &self ) -> Target {
+        Target { active: Arc::default(), retired: Arc::new( watch::channel( false ).0 ), ..self.clone() }
+    }
+
+    /// Returns the path a request to this target should be forwarded to.
+    pub(crate) fn rewrite_path( &self, path: String ) -> String {
+        match &self.path_rewrite {
+            Some( rewrite ) => rewrite.apply( &path ),
+            None => path,
+        }
+    }
+
+    /// Rewrites the headers of a request to this target.
+    pub(crate) fn rewrite_headers( &self, headers: &mut HeaderMap ) {
+        if let Some( rewrite ) = &self.header_rewrite {
+            rewrite.apply( headers );
+        }
+    }
+
+    /// Marks a request as being forwarded to this target until the returned guard is
+    /// dropped.
+    pub(crate) fn begin( &self ) -> ActiveGuard {
+        ActiveGuard::new( &self.active )
+    }
+
+    /// Returns a receiver that learns when the target is removed from the pool, for
+    /// connections that should not outlive it.
+    pub(crate) fn retirement( &self ) -> watch::Receiver<bool> {
+        self.retired.subscribe()
+    }
+
+    /// Returns the number of requests currently being forwarded to this target.
+    pub(crate) fn active( &self ) -> usize {
+        self.active.load( Ordering::Relaxed )
+    }
+
+    /// Whether this target refers to the same server as `other`, which is given the
+    /// same way targets are configured. Schemes are not compared.
+    pub(crate) fn matches( &self, other: &str ) -> bool {
+        self.address.eq_ignore_ascii_case( Target::parse( other ).address() )
+    }
+
+    /// Returns the address of the server, without a scheme.
+    pub(crate) fn address( &self ) -> &str {
+        &self.address
+    }
+
+    /// Returns the most redirects followed for a request to this target, if it has a
+    /// limit of its own.
+    pub(crate) fn max_redirects( &self ) -> Option<usize> {
+        self.max_redirects
+    }
+
+    /// Splits the address into its host, its port (if it has one) and whatever path
+    /// follows them. IPv6 hosts keep their brackets.
+    fn split_address( &self ) -> ( &str, Option<&str>, &str ) {
+        let ( authority, path ) = self.address.split_at( self.address.find( '/' ).unwrap_or( self.address.len() ) );
+        let port_start = match authority.rfind( ']' ) {
+            Some( end ) => authority[ end.. ].find( ':' ).map( |i| end + i ),
+            None if authority.matches( ':' ).count() == 1 => authority.find( ':' ),
+
+            // A bare IPv6 address has no room for a port
+            None => None,
+        };
+
+        match port_start {
+            Some( i ) => ( &authority[ ..i ], Some( &authority[ i + 1.. ] ), path ),
+            None => ( authority, None, path ),
+        }
+    }
+
+    /// Returns the port the target was given with, if any.
+    pub(crate) fn port( &self ) -> Option<&str> {
+        self.split_address().1
+    }
+
+    /// Returns the address the server is reached at. `port` is the proxy's
+    /// `proxy_port` setting, which replaces any port the target was given with.
+    pub(crate) fn address_for( &self, port: Option<u16> ) -> String {
+        let Some( port ) = port else {
+            return self.address.clone();
+        };
+
+        let ( host, _, path ) = self.split_address();
+        match host.contains( ':' ) && !host.starts_with( '[' ) {
+            true => format!( "[{}]:{}{}", host, port, path ),
+            false => format!( "{}:{}{}", host, port, path ),
+        }
+    }
+
+    /// Returns the base url for web requests to this target, or `None` if web requests
+    /// aren't forwarded at all. `default` is the proxy's `web_secure` setting, and
+    /// `port` its `proxy_port`.
+    pub(crate) fn web_base( &self, default: Option<bool>, port: Option<u16> ) -> Option<String> {
+        let secure = self.secure.or( default );
+        default.map( |_| match secure {
+            Some( true ) => format!( "https://{}", self.address_for( port ) ),
+            _ => format!( "http://{}", self.address_for( port ) ),
+        })
+    }
+
+    /// Returns the base url for websockets to this target, or `None` if websockets
+    /// aren't forwarded at all. `default` is the proxy's `ws_secure` setting, and
+    /// `port` its `proxy_port`.
+    pub(crate) fn ws_base( &self, default: Option<bool>, port: Option<u16> ) -> Option<String> {
+        let secure = self.secure.or( default );
+        default.map( |_| match secure {
+            Some( true ) => format!( "wss://{}", self.address_for( port ) ),
+            _ => format!( "ws://{}", self.address_for( port ) ),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+
+    /// Formats the target the way it was configured, including its scheme if it had
+    /// one. Websocket schemes are shown as their web equivalents.
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self.secure {
+            Some( true ) => write!( f, "https://{}", self.address ),
+            Some( false ) => write!( f, "http://{}", self.address ),
+            None => f.write_str( &self.address ),
+        }
+    }
+}
+
+/// Strips `prefix` from the start of `value`, ignoring ASCII case.
+fn strip_prefix_ignore_case<'a>( value: &'a str, prefix: &str ) -> Option<&'a str> {
+    let head = value.get( ..prefix.len() )?;
+    head.eq_ignore_ascii_case( prefix ).then( || &value[ prefix.len().. ] )
+}
+
+/// A target in the pool, along with whether it is still taking new requests.
+#[derive(Debug)]
+struct PoolEntry {
+    target: Target,
+
+    /// Whether the target has been taken out of rotation, letting the requests already
+    /// forwarded to it finish
+    draining: bool,
+
+    /// Whether the target failed its last health check, which keeps it out of rotation
+    /// until it passes one
+    down: bool,
+}
+
+/// The set of servers requests are spread across, in round-robin order.
+#[derive(Debug, Default)]
+pub(crate) struct TargetPool {
+    entries: RwLock<Vec<PoolEntry>>,
+
+    /// The number of times a target has been selected
+    next: AtomicUsize,
+}
+
+impl TargetPool {
+
+    /// Creates a pool holding a single target.
+    pub(crate) fn new( target: Target ) -> TargetPool {
+        let pool = TargetPool::default();
+        pool.push( target );
+        pool
+    }
+
+    /// Returns a separate pool holding the same targets, in the same state, so that
+    /// changing one pool leaves the other alone.
+    pub(crate) fn copy( &self ) -> TargetPool {
+        let entries = self.read().iter()
+            .map( |entry| PoolEntry { target: entry.target.detached(), draining: entry.draining, down: entry.down } )
+            .collect();
+        TargetPool { entries: RwLock::new( entries ), next: AtomicUsize::new( 0 ) }
+    }
+
+    /// Returns every target in the pool, including those being drained.
+    pub(crate) fn list( &self ) -> Vec<Target> {
+        self.read().iter().map( |entry| entry.target.clone() ).collect()
+    }
+
+    /// Adds a target to the pool.
+    pub(crate) fn push( &self, target: Target ) {
+        self.write().push( PoolEntry { target, draining: false, down: false } );
+    }
+
+    /// Returns the first target in the pool.
+    pub(crate) fn primary( &self ) -> Option<Target> {
+        self.read().first().map( |entry| entry.target.clone() )
+    }
+
+    /// Returns the target the next request should be forwarded to, skipping those that
+    /// are being drained or are down. Given a cost, targets reserved for other costs are skipped
+    /// too, unless none of the remaining targets can take it.
+    pub(crate) fn select( &self, cost: Option<Cost> ) -> Option<Target> {
+        self.select_except( cost, &[] )
+    }
+
+    /// Like [select](TargetPool::select), but also skips the targets at the given
+    /// addresses, such as those a request already failed to reach.
+    pub(crate) fn select_except( &self, cost: Option<Cost>, excluded: &[String] ) -> Option<Target> {
+        let entries = self.read();
+        let mut available = entries.iter()
+            .filter( |entry| !entry.draining && !entry.down && !excluded.iter().any( |address| address == entry.target.address() ) )
+            .collect::<Vec<_>>();
+        if let Some( cost ) = cost {
+            let suited = available.iter()
+                .copied()
+                .filter( |entry| entry.target.tier.map_or( true, |tier| tier == cost ) )
+                .collect::<Vec<_>>();
+            if !suited.is_empty() {
+                available = suited;
+            }
+        }
+        if available.is_empty() {
+            return None;
+        }
+
+        let index = self.next.fetch_add( 1, Ordering::Relaxed ) % available.len();
+        Some( available[ index ].target.clone() )
+    }
+
+    /// Sets the rules for rewriting the paths of requests to the matching target,
+    /// returning whether it was found.
+    pub(crate) fn set_path_rewrite( &self, target: &str, rewrite: PathRewrite ) -> bool {
+        let rewrite = Arc::new( rewrite );
+        self.update( target, |t| t.path_rewrite = Some( rewrite.clone() ) )
+    }
+
+    /// Sets the rules for rewriting the headers of requests to the matching target,
+    /// returning whether it was found.
+    pub(crate) fn set_header_rewrite( &self, target: &str, rewrite: HeaderRewrite ) -> bool {
+        let rewrite = Arc::new( rewrite );
+        self.update( target, |t| t.header_rewrite = Some( rewrite.clone() ) )
+    }
+
+    /// Reserves the matching target for requests of the given cost, returning whether
+    /// it was found.
+    pub(crate) fn set_tier( &self, target: &str, tier: Cost ) -> bool {
+        self.update( target, |t| t.tier = Some( tier ) )
+    }
+
+    /// Sets the most redirects followed for requests to the matching target, returning
+    /// whether it was found.
+    pub(crate) fn set_max_redirects( &self, target: &str, max: usize ) -> bool {
+        self.update( target, |t| t.max_redirects = Some( max ) )
+    }
+
+    /// Applies `change` to every matching target, returning whether there were any.
+    fn update( &self, target: &str, change: impl Fn( &mut Target ) ) -> bool {
+        let mut entries = self.write();
+        let mut found = false;
+        for entry in entries.iter_mut().filter( |entry| entry.target.matches( target ) ) {
+            change( &mut entry.target );
+            found = true;
+        }
+        found
+    }
+
+    /// Takes the matching target out of rotation, returning whether it was found.
+    pub(crate) fn drain( &self, target: &str ) -> bool {
+        let mut entries = self.write();
+        let mut found = false;
+        for entry in entries.iter_mut().filter( |entry| entry.target.matches( target ) ) {
+            entry.draining = true;
+            found = true;
+        }
+        found
+    }
+
+    /// Removes the matching target from the pool, returning whether it was found.
+    /// Requests already forwarded to it are unaffected, though they can watch for the
+    /// removal through [Target::retirement].
+    pub(crate) fn remove( &self, target: &str ) -> bool {
+        let mut entries = self.write();
+        let before = entries.len();
+        entries.retain( |entry| {
+            let removed = entry.target.matches( target );
+            if removed {
+                entry.target.retired.send_replace( true );
+            }
+            !removed
+        });
+        entries.len() != before
+    }
+
+    /// Records whether the target at `address` passed its health check, returning
+    /// whether that changed anything.
+    pub(crate) fn set_up( &self, address: &str, up: bool ) -> bool {
+        let mut entries = self.write();
+        let mut changed = false;
+        for entry in entries.iter_mut().filter( |entry| entry.target.address() == address ) {
+            changed |= entry.down == up;
+            entry.down = !up;
+        }
+        changed
+    }
+
+    /// Returns whether the matching target passed its last health check, or `None` if
+    /// it isn't in the pool.
+    pub(crate) fn is_up( &self, target: &str ) -> Option<bool> {
+        self.read().iter()
+            .find( |entry| entry.target.matches( target ) )
+            .map( |entry| !entry.down )
+    }
+
+    /// Returns the number of requests currently being forwarded to the matching
+    /// target, or `None` if it isn't in the pool.
+    pub(crate) fn active( &self, target: &str ) -> Option<usize> {
+        self.read().iter()
+            .find( |entry| entry.target.matches( target ) )
+            .map( |entry| entry.target.active() )
+    }
+
+    fn read( &self ) -> std::sync::RwLockReadGuard<'_, Vec<PoolEntry>> {
+        self.entries.read().unwrap_or_else( |e| e.into_inner() )
+    }
+
+    fn write( &self ) -> std::sync::RwLockWriteGuard<'_, Vec<PoolEntry>> {
+        self.entries.write().unwrap_or_else( |e| e.into_inner() )
+    }
+}
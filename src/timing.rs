@@ -0,0 +1,61 @@
+//! `Server-Timing` headers, which show how long the proxy and the proxied server took
+//! to answer a request in the browser's developer tools and performance APIs
+//! ([Server Timing](https://www.w3.org/TR/server-timing/)).
+//!
+//! A response is timed in three phases: `proxy` is the time spent before the request
+//! was sent on, such as reading its body, `ttfb` the wait for the proxied server's
+//! response headers, and `total` everything up to the proxy's own response headers. The
+//! http client doesn't report when it opens a connection, so connecting to the proxied
+//! server is part of `ttfb`, as are any retries and failover. Streamed response bodies
+//! are still being sent when the headers go out, so they aren't part of `total`.
+
+use std::time::{ Duration, Instant };
+use poem::http::HeaderValue;
+
+pub(crate) const SERVER_TIMING: &str = "server-timing";
+pub(crate) const TIMING_ALLOW_ORIGIN: &str = "timing-allow-origin";
+
+/// The times at which a request passed each phase.
+#[derive(Debug)]
+pub(crate) struct RequestTimer {
+    started: Instant,
+    sent: Option<Instant>,
+    answered: Option<Instant>,
+}
+
+impl RequestTimer {
+
+    pub(crate) fn start() -> RequestTimer {
+        RequestTimer { started: Instant::now(), sent: None, answered: None }
+    }
+
+    /// Records that the request is being sent to the proxied server.
+    pub(crate) fn sent( &mut self ) {
+        self.sent = Some( Instant::now() );
+    }
+
+    /// Records that the proxied server's response headers have arrived.
+    pub(crate) fn answered( &mut self ) {
+        self.answered = Some( Instant::now() );
+    }
+
+    /// Returns the `Server-Timing` header describing the phases so far, in
+    /// milliseconds.
+    pub(crate) fn header( &self ) -> HeaderValue {
+        let now = Instant::now();
+        let sent = self.sent.unwrap_or( now );
+        let answered = self.answered.unwrap_or( now );
+        let value = format!(
+            "proxy;dur={}, ttfb;dur={}, total;dur={}",
+            millis( sent - self.started ),
+            millis( answered.saturating_duration_since( sent ) ),
+            millis( now - self.started ),
+        );
+        HeaderValue::try_from( value ).expect( "timings are a valid header value" )
+    }
+}
+
+/// Formats a duration as milliseconds with a tenth of a millisecond of precision.
+fn millis( duration: Duration ) -> String {
+    format!( "{:.1}", duration.as_secs_f64() * 1000.0 )
+}
@@ -0,0 +1,73 @@
+//! Propagation of [W3C trace context](https://www.w3.org/TR/trace-context/) headers.
+//!
+//! The proxy takes part in a distributed trace as a span of its own. When a request
+//! arrives with a `traceparent` header, the trace is continued with a fresh span id for
+//! the proxy, so the proxied server sees the proxy as its parent. When there is no
+//! (valid) `traceparent`, a new trace is started. Any `tracestate` is forwarded as is.
+
+use poem::http::{ HeaderMap, HeaderValue };
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// The parts of a `traceparent` header that are carried over to the next hop.
+struct TraceParent {
+    trace_id: String,
+    flags: String,
+}
+
+impl TraceParent {
+
+    /// Parses a version 00 `traceparent` header, returning `None` if it is malformed.
+    fn parse( value: &HeaderValue ) -> Option<TraceParent> {
+        let mut parts = value.to_str().ok()?.trim().split( '-' );
+        let ( version, trace_id, parent_id, flags ) = ( parts.next()?, parts.next()?, parts.next()?, parts.next()? );
+
+        let is_hex = |part: &str, len: usize| {
+            part.len() == len && part.bytes().all( |b| matches!( b, b'0'..=b'9' | b'a'..=b'f' ) )
+        };
+        let is_zero = |part: &str| part.bytes().all( |b| b == b'0' );
+
+        if version != "00" || parts.next().is_some()
+            || !is_hex( trace_id, 32 ) || is_zero( trace_id )
+            || !is_hex( parent_id, 16 ) || is_zero( parent_id )
+            || !is_hex( flags, 2 ) {
+            return None;
+        }
+
+        Some( TraceParent { trace_id: trace_id.into(), flags: flags.into() } )
+    }
+}
+
+/// Rewrites the trace context headers of a request that is about to be forwarded, so
+/// that the proxy becomes the parent of the proxied server's span.
+pub(crate) fn continue_trace( headers: &mut HeaderMap ) {
+    let parent = headers.get( TRACEPARENT ).and_then( TraceParent::parse );
+
+    let ( trace_id, flags ) = match parent {
+        Some( parent ) => ( parent.trace_id, parent.flags ),
+
+        // Start a new, sampled trace. The tracestate belonged to the broken trace.
+        None => {
+            crate::headers::remove_in_order( headers, TRACESTATE );
+            ( format!( "{:032x}", non_zero::<u128>() ), "01".into() )
+        },
+    };
+
+    let value = format!( "00-{}-{:016x}-{}", trace_id, non_zero::<u64>(), flags );
+    headers.insert( TRACEPARENT, HeaderValue::from_str( &value ).expect( "traceparent is a valid header value" ) );
+}
+
+/// Returns a random id, which the trace context spec requires to be non-zero.
+fn non_zero<T>() -> T
+where
+    T: Default + PartialEq,
+    rand::distributions::Standard: rand::distributions::Distribution<T>,
+{
+    loop {
+        let id = rand::random::<T>();
+        if id != T::default() {
+            return id;
+        }
+    }
+}
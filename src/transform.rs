@@ -0,0 +1,218 @@
+//! Transformation of response bodies as they are relayed to the client.
+//!
+//! A [BodyTransform] sees the body of a response one chunk at a time, so streamed
+//! responses are transformed without being buffered. It keeps whatever state it needs
+//! from one chunk to the next, such as a tag that was cut in half, and may hold bytes
+//! back until a later chunk or the end of the body. Compressed bodies are never
+//! transformed, since their chunks aren't meaningful on their own.
+
+use std::{ io, sync::Arc };
+use bytes::{ Bytes, BytesMut };
+use futures_util::{ StreamExt, future, stream::{ self, BoxStream } };
+use poem::http::{ HeaderMap, StatusCode, header };
+use crate::{ Opaque, content_type };
+
+/// A transformation of a single response body, fed its chunks in order.
+///
+/// ```
+/// use bytes::Bytes;
+/// use poem_proxy::BodyTransform;
+///
+/// /// Uppercases ASCII text.
+/// struct Uppercase;
+///
+/// impl BodyTransform for Uppercase {
+///     fn transform( &mut self, chunk: Bytes ) -> Bytes {
+///         chunk.to_ascii_uppercase().into()
+///     }
+/// }
+/// ```
+pub trait BodyTransform: Send {
+
+    /// Transforms the next chunk of the body, returning the bytes to send in its place.
+    /// These may be empty if the chunk is held back.
+    fn transform( &mut self, chunk: Bytes ) -> Bytes;
+
+    /// Returns the bytes to send once the body has ended, such as those held back. By
+    /// default there are none.
+    fn finish( &mut self ) -> Bytes {
+        Bytes::new()
+    }
+}
+
+type TransformFactory = dyn Fn() -> Box<dyn BodyTransform> + Send + Sync;
+
+type BodyRewrite = dyn Fn( &Bytes ) -> Bytes + Send + Sync;
+
+/// Settings for transforming the bodies of responses, with a fresh [BodyTransform] for
+/// each response.
+///
+/// ```
+/// use bytes::Bytes;
+/// use poem_proxy::{ BodyTransform, ProxyConfig, ResponseTransform };
+///
+/// /// Drops HTML comments, even those split across chunks.
+/// #[derive(Default)]
+/// struct StripComments {
+///     in_comment: bool,
+///     pending: Vec<u8>,
+/// }
+///
+/// impl BodyTransform for StripComments {
+///     fn transform( &mut self, chunk: Bytes ) -> Bytes {
+///         self.pending.extend_from_slice( &chunk );
+///         let mut out = Vec::new();
+///         loop {
+///             let marker: &[u8] = if self.in_comment { b"-->" } else { b"<!--" };
+///             match self.pending.windows( marker.len() ).position( |w| w == marker ) {
+///                 Some( at ) => {
+///                     if !self.in_comment {
+///                         out.extend_from_slice( &self.pending[..at] );
+///                     }
+///                     self.pending.drain( ..at + marker.len() );
+///                     self.in_comment = !self.in_comment;
+///                 },
+///                 None => {
+///                     // Keep what could be the start of a marker for the next chunk
+///                     let keep = self.pending.len().min( marker.len() - 1 );
+///                     let done: Vec<u8> = self.pending.drain( ..self.pending.len() - keep ).collect();
+///                     if !self.in_comment {
+///                         out.extend( done );
+///                     }
+///                     return out.into();
+///                 },
+///             }
+///         }
+///     }
+///
+///     fn finish( &mut self ) -> Bytes {
+///         match self.in_comment {
+///             true => Bytes::new(),
+///             false => std::mem::take( &mut self.pending ).into(),
+///         }
+///     }
+/// }
+///
+/// let config = ProxyConfig::new( "localhost:5173" )
+///     .web_insecure()
+///     .transform_responses( ResponseTransform::new( || Box::new( StripComments::default() ) )
+///         .content_type( "text/html" ) )
+///     .finish();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ResponseTransform {
+    factory: Opaque<TransformFactory>,
+
+    /// The media types of the responses to transform, lowercase and without
+    /// parameters. A type may end in `/*` to cover every subtype.
+    content_types: Vec<String>,
+}
+
+impl ResponseTransform {
+
+    /// Creates new transform settings, calling `factory` for a transform for each
+    /// response. Until content types are added, every response is transformed.
+    pub fn new( factory: impl Fn() -> Box<dyn BodyTransform> + Send + Sync + 'static ) -> ResponseTransform {
+        ResponseTransform { factory: Opaque( Arc::new( factory ) ), content_types: Vec::new() }
+    }
+
+    /// Creates transform settings that rewrite each body as a whole, once it has been
+    /// read in full, for changes that can't be made a chunk at a time. Until content
+    /// types are added, every response is rewritten.
+    ///
+    /// ```
+    /// use bytes::Bytes;
+    /// use poem_proxy::{ ProxyConfig, ResponseTransform };
+    ///
+    /// let config = ProxyConfig::new( "localhost:5173" )
+    ///     .web_insecure()
+    ///     .transform_responses( ResponseTransform::whole_body( |body| {
+    ///         let text = String::from_utf8_lossy( body );
+    ///         Bytes::from( text.replace( "\"/static/", "\"/app/static/" ) )
+    ///     }).content_type( "application/json" ) )
+    ///     .finish();
+    /// ```
+    pub fn whole_body( rewrite: impl Fn( &Bytes ) -> Bytes + Send + Sync + 'static ) -> ResponseTransform {
+        let rewrite: Arc<BodyRewrite> = Arc::new( rewrite );
+        ResponseTransform::new( move || Box::new( WholeBody { rewrite: rewrite.clone(), body: BytesMut::new() } ) )
+    }
+
+    /// Adds a media type of the responses to transform, such as `text/html`. Types are
+    /// matched without their parameters, and `type/*` covers every subtype. Responses
+    /// without a `Content-Type` are then left alone.
+    pub fn content_type( &mut self, media_type: impl Into<String> ) -> &mut ResponseTransform {
+        self.content_types.push( content_type::media_type( &media_type.into() ) );
+        self
+    }
+
+    /// Starts a transform for a response with the given status and headers, if it has
+    /// a body the transform applies to.
+    pub(crate) fn begin( &self, status: StatusCode, headers: &HeaderMap ) -> Option<Box<dyn BodyTransform>> {
+        if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+            return None;
+        }
+        let encoded = headers.get( header::CONTENT_ENCODING )
+            .map_or( false, |encoding| !encoding.as_bytes().eq_ignore_ascii_case( b"identity" ) );
+        if encoded {
+            return None;
+        }
+
+        let covered = self.content_types.is_empty() || headers.get( header::CONTENT_TYPE )
+            .and_then( |value| value.to_str().ok() )
+            .map_or( false, |value| {
+                let media_type = content_type::media_type( value );
+                self.content_types.iter().any( |allowed| content_type::matches( allowed, &media_type ) )
+            });
+        covered.then( || ( self.factory )() )
+    }
+}
+
+/// Holds a body back until it has ended, then rewrites it as a whole.
+struct WholeBody {
+    rewrite: Arc<BodyRewrite>,
+    body: BytesMut,
+}
+
+impl BodyTransform for WholeBody {
+    fn transform( &mut self, chunk: Bytes ) -> Bytes {
+        self.body.extend_from_slice( &chunk );
+        Bytes::new()
+    }
+
+    fn finish( &mut self ) -> Bytes {
+        ( self.rewrite )( &std::mem::take( &mut self.body ).freeze() )
+    }
+}
+
+/// Transforms a body that was read in full.
+pub(crate) fn buffered( mut transform: Box<dyn BodyTransform>, body: Bytes ) -> Bytes {
+    let head = transform.transform( body );
+    let tail = transform.finish();
+    match tail.is_empty() {
+        true => head,
+        false => [ head, tail ].concat().into(),
+    }
+}
+
+/// Transforms a body as it streams through, dropping the chunks that come out empty.
+/// The transform is finished once the body ends, but not if it fails.
+pub(crate) fn streamed( transform: Box<dyn BodyTransform>, body: BoxStream<'static, io::Result<Bytes>> ) -> BoxStream<'static, io::Result<Bytes>> {
+    stream::unfold( ( body, Some( transform ) ), |( mut body, mut transform )| async move {
+        let current = transform.as_mut()?;
+        let chunk = match body.next().await {
+            Some( Ok( chunk ) ) => Ok( current.transform( chunk ) ),
+            Some( Err( error ) ) => {
+                transform = None;
+                Err( error )
+            },
+            None => {
+                let tail = current.finish();
+                transform = None;
+                Ok( tail )
+            },
+        };
+        Some( ( chunk, ( body, transform ) ) )
+    })
+    .filter( |chunk| future::ready( !matches!( chunk, Ok( bytes ) if bytes.is_empty() ) ) )
+    .boxed()
+}
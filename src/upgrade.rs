@@ -0,0 +1,50 @@
+//! Handling of the `Upgrade-Insecure-Requests` header.
+//!
+//! Browsers send `Upgrade-Insecure-Requests: 1` with navigations to say they would rather
+//! be on https ([W3C spec](https://www.w3.org/TR/upgrade-insecure-requests/)). When the
+//! proxy terminates TLS itself, the proxied server only ever sees plain http, so it can't
+//! tell whether the client is already secure. The proxy can answer the header for it.
+
+use poem::{ Request, Response, http::{ Method, StatusCode, header, uri::{ Authority, Scheme } } };
+
+/// The ways in which the proxy can handle requests carrying `Upgrade-Insecure-Requests: 1`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InsecureRequestPolicy {
+
+    /// Forward the header to the proxied server, leaving it to decide what to do.
+    #[default]
+    Forward,
+
+    /// Redirect `GET` and `HEAD` requests made over plain http to the same url on https,
+    /// with `307 Temporary Redirect`. Requests that are already secure are forwarded.
+    Redirect,
+}
+
+impl InsecureRequestPolicy {
+
+    /// Returns the redirect to send the client instead of forwarding the request, if
+    /// the policy calls for one.
+    pub(crate) fn redirect( &self, req: &Request ) -> Option<Response> {
+        let wants_upgrade = req.headers().get( "upgrade-insecure-requests" )
+            .map( |v| v.as_bytes() == b"1" )
+            .unwrap_or( false );
+
+        if *self != InsecureRequestPolicy::Redirect || !wants_upgrade
+            || *req.scheme() == Scheme::HTTPS
+            || ( req.method() != Method::GET && req.method() != Method::HEAD ) {
+            return None;
+        }
+
+        // Without a host there's nowhere to redirect to
+        // The https url is on the default port, whichever port the client used for http
+        let authority = req.headers().get( header::HOST )?.to_str().ok()?.parse::<Authority>().ok()?;
+        let host = authority.host();
+        let path = req.uri().path_and_query().map( |p| p.as_str() ).unwrap_or( "/" );
+
+        Some( Response::builder()
+            .status( StatusCode::TEMPORARY_REDIRECT )
+            .header( header::LOCATION, format!( "https://{}{}", host, path ) )
+            .header( header::VARY, "Upgrade-Insecure-Requests" )
+            .finish() )
+    }
+}
@@ -0,0 +1,522 @@
+//! Helpers for relaying websocket messages between the client and the proxied server.
+
+use std::{
+    sync::{ Arc, Mutex, atomic::{ AtomicU64, AtomicUsize, Ordering } },
+    time::{ Duration, Instant },
+};
+use futures_util::{ Stream, StreamExt, future::{ self, Either }, stream::{ self, BoxStream } };
+use poem::{ http::{ self, HeaderMap }, web::websocket::{ CloseCode, Message } };
+use tokio::{ net::TcpStream, sync::{ Semaphore, mpsc, watch } };
+use tokio_tungstenite::{ MaybeTlsStream, WebSocketStream, client_async, connect_async, tungstenite };
+use crate::{ CloseHook, Opaque, Socks5Proxy, retry };
+
+/// The code reported for a websocket that ended without a close frame
+const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// The payload of the pings the proxy sends to keep a websocket alive, which tells the
+/// pongs answering them apart from those meant for the proxied server
+const KEEPALIVE_PAYLOAD: &[u8] = b"poem-proxy keepalive";
+
+/// How a websocket relayed by the proxy was closed, as told to the
+/// [close hook](crate::ProxyConfig::on_ws_close).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WebSocketClose {
+
+    /// The code of the first close frame sent by either peer. A websocket that ended
+    /// without one, such as when a connection dropped, is reported with `1006`, and a
+    /// close frame without a code is reported with `1005`.
+    pub code: u16,
+
+    /// The reason given along with the code, if any
+    pub reason: String,
+}
+
+impl WebSocketClose {
+
+    /// Whether the websocket ended in some way other than a normal (`1000`), going away
+    /// (`1001`) or codeless (`1005`) close.
+    pub fn is_abnormal( &self ) -> bool {
+        !matches!( self.code, 1000 | 1001 | 1005 )
+    }
+}
+
+/// How many websockets relayed by the proxy were closed cleanly, and how many weren't.
+/// Obtained through [ProxyHandle::websocket_stats](crate::ProxyHandle::websocket_stats),
+/// see [WebSocketClose::is_abnormal] for which closes count as abnormal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WebSocketStats {
+
+    /// The number of websockets closed with a normal or going away close frame
+    pub clean: u64,
+
+    /// The number of websockets that ended any other way
+    pub abnormal: u64,
+}
+
+/// The counters behind [WebSocketStats], shared by every clone of a configuration.
+#[derive(Debug, Default)]
+pub(crate) struct CloseCounters {
+    clean: AtomicU64,
+    abnormal: AtomicU64,
+}
+
+impl CloseCounters {
+    pub(crate) fn stats( &self ) -> WebSocketStats {
+        WebSocketStats {
+            clean: self.clean.load( Ordering::Relaxed ),
+            abnormal: self.abnormal.load( Ordering::Relaxed ),
+        }
+    }
+}
+
+/// The signal that closes every websocket when the proxy shuts down, along with the
+/// number of websockets still open, shared by every clone of a configuration.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    signal: watch::Sender<bool>,
+    open: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown { signal: watch::channel( false ).0, open: watch::channel( 0 ).0 }
+    }
+}
+
+impl Shutdown {
+
+    /// Whether the proxy has started shutting down, after which no websockets are
+    /// opened.
+    pub(crate) fn is_raised( &self ) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Returns the signal that is raised when the proxy shuts down.
+    pub(crate) fn signal( &self ) -> watch::Receiver<bool> {
+        self.signal.subscribe()
+    }
+
+    /// Counts a websocket as open until the returned guard is dropped.
+    pub(crate) fn open( self: &Arc<Self> ) -> OpenGuard {
+        self.open.send_modify( |open| *open += 1 );
+        OpenGuard( self.clone() )
+    }
+
+    pub(crate) fn open_count( &self ) -> usize {
+        *self.open.borrow()
+    }
+
+    /// Raises the signal, then waits up to `grace` for the websockets to close,
+    /// returning how many are still open.
+    pub(crate) async fn raise( &self, grace: Duration ) -> usize {
+        self.signal.send_replace( true );
+        let mut open = self.open.subscribe();
+        let _ = tokio::time::timeout( grace, open.wait_for( |open| *open == 0 ) ).await;
+        self.open_count()
+    }
+}
+
+/// Counts a websocket as open for as long as it is alive.
+#[derive(Debug)]
+pub(crate) struct OpenGuard( Arc<Shutdown> );
+
+impl Drop for OpenGuard {
+    fn drop( &mut self ) {
+        self.0.open.send_modify( |open| *open -= 1 );
+    }
+}
+
+/// Keeps track of how a single websocket was closed, recording it once both relay tasks
+/// are done with it and the recorder is dropped.
+pub(crate) struct CloseRecorder {
+    close: Mutex<Option<WebSocketClose>>,
+    counters: Arc<CloseCounters>,
+    hook: Option<Opaque<CloseHook>>,
+}
+
+impl CloseRecorder {
+
+    pub(crate) fn new( counters: Arc<CloseCounters>, hook: Option<Opaque<CloseHook>> ) -> CloseRecorder {
+        CloseRecorder { close: Mutex::default(), counters, hook }
+    }
+
+    /// Records a close frame sent by either peer, unless the other peer already sent
+    /// one first.
+    pub(crate) fn observe( &self, code: Option<u16>, reason: &str ) {
+        let mut close = self.close.lock().unwrap_or_else( |e| e.into_inner() );
+        close.get_or_insert_with( || WebSocketClose {
+            code: code.unwrap_or( 1005 ),
+            reason: reason.to_owned(),
+        });
+    }
+
+    /// Records the close frame in a message from the client, if it is one.
+    pub(crate) fn observe_client( &self, msg: &Message ) {
+        if let Message::Close( frame ) = msg {
+            match frame {
+                Some( ( code, reason ) ) => self.observe( Some( ( *code ).into() ), reason ),
+                None => self.observe( None, "" ),
+            }
+        }
+    }
+
+    /// Records the close frame in a message from the proxied server, if it is one.
+    pub(crate) fn observe_server( &self, msg: &tungstenite::Message ) {
+        if let tungstenite::Message::Close( frame ) = msg {
+            match frame {
+                Some( frame ) => self.observe( Some( frame.code.into() ), &frame.reason ),
+                None => self.observe( None, "" ),
+            }
+        }
+    }
+}
+
+impl Drop for CloseRecorder {
+    fn drop( &mut self ) {
+        let close = self.close.get_mut().unwrap_or_else( |e| e.into_inner() ).take()
+            .unwrap_or( WebSocketClose { code: ABNORMAL_CLOSURE, reason: String::new() } );
+
+        let counter = match close.is_abnormal() {
+            true => &self.counters.abnormal,
+            false => &self.counters.clean,
+        };
+        counter.fetch_add( 1, Ordering::Relaxed );
+        match close.is_abnormal() {
+            true => tracing::warn!( code = close.code, reason = %close.reason, "websocket closed abnormally" ),
+            false => tracing::info!( code = close.code, reason = %close.reason, "websocket closed" ),
+        }
+
+        if let Some( hook ) = &self.hook {
+            hook( &close );
+        }
+    }
+}
+
+/// Whether every subprotocol the client asked for in `headers` is one of `allowed`.
+/// Headers that aren't text can't be checked, so they are never allowed.
+pub(crate) fn subprotocols_allowed( headers: &HeaderMap, allowed: &[String] ) -> bool {
+    headers.get_all( http::header::SEC_WEBSOCKET_PROTOCOL ).iter().all( |value| match value.to_str() {
+        Ok( value ) => value.split( ',' )
+            .map( str::trim )
+            .filter( |protocol| !protocol.is_empty() )
+            .all( |protocol| allowed.iter().any( |allowed| allowed == protocol ) ),
+        Err( _ ) => false,
+    })
+}
+
+/// The size of `headers` as they are sent in an HTTP/1.1 handshake, counting the
+/// separator after each name and the line break after each value.
+pub(crate) fn header_size( headers: &HeaderMap ) -> usize {
+    headers.iter().map( |( name, value )| name.as_str().len() + value.len() + 4 ).sum()
+}
+
+/// Builds the handshake request for a websocket to the proxied server at `uri`, sending
+/// `headers` along with it. This fails if `uri` isn't a valid url, which is checked
+/// before the client's websocket is accepted.
+pub(crate) fn handshake( uri: &str, headers: &HeaderMap ) -> Result<http::Request<()>, http::Error> {
+    let mut request = http::Request::builder().uri( uri );
+    for ( key, value ) in headers.iter() {
+        request = request.header( key, value );
+    }
+    request.body( () )
+}
+
+/// Opens a websocket to the proxied server with the `handshake` request. If no
+/// connection can be made, such as when the server's name can't be resolved or it
+/// refuses the connection, this is retried up to `retries` times. Once connected, a
+/// failed handshake is not retried. The connection is made through the `socks` proxy,
+/// if there is one.
+///
+/// Along with the websocket, this returns the subprotocol the server picked from
+/// those offered in `Sec-WebSocket-Protocol`, if any.
+pub(crate) async fn connect( handshake: &http::Request<()>, retries: u32, socks: Option<&Socks5Proxy> ) -> Result<( WebSocketStream<MaybeTlsStream<TcpStream>>, Option<String> ), tungstenite::Error> {
+    let mut attempt = 0;
+    loop {
+
+        // Every attempt needs a request of its own
+        let mut request = http::Request::new( () );
+        *request.uri_mut() = handshake.uri().clone();
+        *request.headers_mut() = handshake.headers().clone();
+
+        let connected = match socks {
+            Some( socks ) => connect_through( socks, request ).await,
+            None => connect_async( request ).await,
+        };
+        match connected {
+            Ok( ( socket, response ) ) => {
+                let protocol = response.headers().get( http::header::SEC_WEBSOCKET_PROTOCOL )
+                    .and_then( |value| value.to_str().ok() )
+                    .map( str::to_owned );
+                return Ok( ( socket, protocol ) );
+            },
+            Err( tungstenite::Error::Io( _ ) ) if attempt < retries => {
+                tokio::time::sleep( retry::backoff( attempt ) ).await;
+                attempt += 1;
+            },
+            Err( error ) => return Err( error ),
+        }
+    }
+}
+
+/// Opens a websocket with `request` through a SOCKS5 proxy. Only plain (`ws`) websockets
+/// can be opened this way, as with a direct connection.
+async fn connect_through( socks: &Socks5Proxy, request: http::Request<()> ) -> Result<( WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::handshake::client::Response ), tungstenite::Error> {
+    let uri = request.uri();
+    if uri.scheme_str() == Some( "wss" ) {
+        return Err( tungstenite::Error::Url( tungstenite::error::UrlError::TlsFeatureNotEnabled ) );
+    }
+    let Some( host ) = uri.host() else {
+        return Err( tungstenite::Error::Url( tungstenite::error::UrlError::NoHostName ) );
+    };
+
+    let stream = socks.connect( host, uri.port_u16().unwrap_or( 80 ) ).await?;
+    client_async( request, MaybeTlsStream::Plain( stream ) ).await
+}
+
+/// Reads `stream` ahead of its consumer on a separate task, buffering up to `frames`
+/// messages in between. Once the buffer is full, reading stops until the consumer
+/// catches up, which pushes back on the peer sending the messages. Without a limit,
+/// messages are read only as they are consumed. The messages waiting in the buffer are
+/// counted in `buffered`, which is shared by every websocket.
+pub(crate) fn read_ahead<S>( stream: S, frames: Option<usize>, buffered: Arc<AtomicUsize> ) -> BoxStream<'static, S::Item>
+where
+    S: Stream + Send + Unpin + 'static,
+    S::Item: Send + 'static,
+{
+    let Some( frames ) = frames else {
+        return stream.boxed();
+    };
+
+    // Room is only made once a message has been taken off the count, so the count of a
+    // websocket never goes over the limit, not even for a moment
+    let room = Arc::new( Semaphore::new( frames.max( 1 ) ) );
+    let ( sender, receiver ) = mpsc::unbounded_channel();
+    let reader_room = room.clone();
+    let reader_count = buffered.clone();
+    tokio::spawn( async move {
+        let mut stream = stream;
+        loop {
+            let Ok( permit ) = reader_room.acquire().await else { break };
+            let Some( item ) = stream.next().await else { break };
+            permit.forget();
+            reader_count.fetch_add( 1, Ordering::Relaxed );
+
+            // The consumer is gone, so there's no one left to read for
+            if sender.send( item ).is_err() {
+                reader_count.fetch_sub( 1, Ordering::Relaxed );
+                break;
+            }
+        }
+    });
+
+    let buffer = ReadAhead { receiver, room, buffered };
+    stream::unfold( buffer, |mut buffer| async move {
+        let item = buffer.receiver.recv().await?;
+        buffer.buffered.fetch_sub( 1, Ordering::Relaxed );
+        buffer.room.add_permits( 1 );
+        Some( ( item, buffer ) )
+    }).boxed()
+}
+
+/// The receiving end of [read_ahead], which takes the messages nobody will read off the
+/// count once it is dropped.
+struct ReadAhead<T> {
+    receiver: mpsc::UnboundedReceiver<T>,
+    room: Arc<Semaphore>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl<T> Drop for ReadAhead<T> {
+    fn drop( &mut self ) {
+        self.room.close();
+        self.receiver.close();
+        while self.receiver.try_recv().is_ok() {
+            self.buffered.fetch_sub( 1, Ordering::Relaxed );
+        }
+    }
+}
+
+/// Returns the close frames that end a websocket with `code` and `reason`: the one the
+/// client sends to the server, and the one the server sends to the client.
+pub(crate) fn close_frames( code: CloseCode, reason: &str ) -> ( Message, tungstenite::Message ) {
+    let to_server = Message::Close( Some( ( code, reason.into() ) ) );
+    let to_client = to_server.clone();
+    ( to_server, to_server_message( to_client ) )
+}
+
+/// Converts a message from the client into the same kind of message for the proxied
+/// server, so that text is relayed as text and binary as binary.
+pub(crate) fn to_server_message( msg: Message ) -> tungstenite::Message {
+    match msg {
+        Message::Text( text ) => tungstenite::Message::Text( text ),
+        Message::Binary( data ) => tungstenite::Message::Binary( data ),
+        Message::Ping( data ) => tungstenite::Message::Ping( data ),
+        Message::Pong( data ) => tungstenite::Message::Pong( data ),
+        Message::Close( frame ) => tungstenite::Message::Close( frame.map( |( code, reason )| tungstenite::protocol::CloseFrame {
+            code: u16::from( code ).into(),
+            reason: reason.into(),
+        })),
+    }
+}
+
+/// Converts a message from the proxied server into the same kind of message for the
+/// client. Raw frames are only ever written, never read, so there are none to relay.
+pub(crate) fn to_client_message( msg: tungstenite::Message ) -> Option<Message> {
+    match msg {
+        tungstenite::Message::Text( text ) => Some( Message::Text( text ) ),
+        tungstenite::Message::Binary( data ) => Some( Message::Binary( data ) ),
+        tungstenite::Message::Ping( data ) => Some( Message::Ping( data ) ),
+        tungstenite::Message::Pong( data ) => Some( Message::Pong( data ) ),
+        tungstenite::Message::Close( frame ) => Some( Message::Close( frame.map( |frame| ( u16::from( frame.code ).into(), frame.reason.into_owned() ) ) ) ),
+        tungstenite::Message::Frame( _ ) => None,
+    }
+}
+
+/// The size of the payload of a message from the client.
+pub(crate) fn client_message_size( msg: &Message ) -> usize {
+    match msg {
+        Message::Text( text ) => text.len(),
+        Message::Binary( data ) | Message::Ping( data ) | Message::Pong( data ) => data.len(),
+        Message::Close( frame ) => frame.as_ref().map_or( 0, |( _, reason )| reason.len() ),
+    }
+}
+
+/// The size of the payload of a message from the proxied server.
+pub(crate) fn server_message_size( msg: &tungstenite::Message ) -> usize {
+    msg.len()
+}
+
+/// Ends `stream` with the `close` message in place of the first message whose payload
+/// is larger than `max` bytes, as though the peer had sent it. Relaying the message
+/// closes the connection on the other side too.
+pub(crate) fn limit_size<S, T, E>( stream: S, max: usize, size: fn( &T ) -> usize, close: T ) -> BoxStream<'static, Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>> + Send + Unpin + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    stream::unfold( Some( ( stream, close ) ), move |state| async move {
+        let ( mut stream, close ) = state?;
+        match stream.next().await? {
+            Ok( msg ) if size( &msg ) > max => {
+                tracing::info!( size = size( &msg ), max, "websocket message too big" );
+                Some( ( Ok( close ), None ) )
+            },
+            item => Some( ( item, Some( ( stream, close ) ) ) ),
+        }
+    }).boxed()
+}
+
+/// Ends `stream` with the `close` message as soon as `signal` turns true, as though the
+/// peer had sent it. Relaying the message closes the connection on the other side too.
+pub(crate) fn close_when<S, T, E>( stream: S, mut signal: watch::Receiver<bool>, close: T ) -> BoxStream<'static, Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>> + Send + Unpin + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    let signal = Box::pin( async move {
+
+        // Nothing is left to raise the signal, so it never will be
+        if signal.wait_for( |raised| *raised ).await.is_err() {
+            future::pending::<()>().await;
+        }
+    });
+
+    stream::unfold( Some( ( stream, signal, close ) ), |state| async move {
+        let ( mut stream, mut signal, close ) = state?;
+        match future::select( stream.next(), &mut signal ).await {
+            Either::Left( ( Some( item ), _ ) ) => Some( ( item, Some( ( stream, signal, close ) ) ) ),
+            Either::Left( ( None, _ ) ) => None,
+            Either::Right( _ ) => Some( ( Ok( close ), None ) ),
+        }
+    }).boxed()
+}
+
+/// Mixes a keepalive ping into `stream` every `interval`, as though the peer had sent
+/// it, for as long as the stream lasts.
+pub(crate) fn ping_every<S, E>( stream: S, interval: Duration ) -> BoxStream<'static, Result<tungstenite::Message, E>>
+where
+    S: Stream<Item = Result<tungstenite::Message, E>> + Send + Unpin + 'static,
+    E: Send + 'static,
+{
+    let timer = Box::pin( tokio::time::sleep( interval ) );
+    stream::unfold( ( stream, timer ), move |( mut stream, mut timer )| async move {
+        match future::select( stream.next(), &mut timer ).await {
+            Either::Left( ( Some( item ), _ ) ) => Some( ( item, ( stream, timer ) ) ),
+            Either::Left( ( None, _ ) ) => None,
+            Either::Right( _ ) => {
+                timer.as_mut().reset( tokio::time::Instant::now() + interval );
+                Some( ( Ok( tungstenite::Message::Ping( KEEPALIVE_PAYLOAD.to_vec() ) ), ( stream, timer ) ) )
+            },
+        }
+    }).boxed()
+}
+
+/// Takes the pongs answering keepalive pings out of `stream`, recording when each one
+/// arrived in `pongs`. Other pongs are left for the proxied server.
+pub(crate) fn take_pongs<S, E>( stream: S, pongs: Arc<Activity> ) -> BoxStream<'static, Result<Message, E>>
+where
+    S: Stream<Item = Result<Message, E>> + Send + Unpin + 'static,
+    E: Send + 'static,
+{
+    stream.filter( move |item| future::ready( match item {
+        Ok( Message::Pong( payload ) ) if payload == KEEPALIVE_PAYLOAD => {
+            pongs.touch();
+            false
+        },
+        _ => true,
+    })).boxed()
+}
+
+/// The time of the last message relayed in either direction of a websocket, which both
+/// relay tasks update without waiting on each other.
+#[derive(Debug)]
+pub(crate) struct Activity {
+    started: Instant,
+
+    /// Milliseconds from `started` to the last message
+    last: AtomicU64,
+}
+
+impl Activity {
+
+    pub(crate) fn new() -> Activity {
+        Activity { started: Instant::now(), last: AtomicU64::new( 0 ) }
+    }
+
+    /// Records that a message was just relayed.
+    pub(crate) fn touch( &self ) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last.fetch_max( now, Ordering::Relaxed );
+    }
+
+    /// Returns how long it has been since the last message.
+    fn idle( &self ) -> Duration {
+        let last = Duration::from_millis( self.last.load( Ordering::Relaxed ) );
+        self.started.elapsed().saturating_sub( last )
+    }
+}
+
+/// Watches a websocket in the background, returning a signal that is raised once no
+/// message has been relayed for `timeout`. The watch ends with the websocket, once
+/// every copy of the signal has been dropped.
+pub(crate) fn watch_idle( activity: Arc<Activity>, timeout: Duration ) -> watch::Receiver<bool> {
+    let ( sender, receiver ) = watch::channel( false );
+    tokio::spawn( async move {
+        loop {
+            let idle = activity.idle();
+            if idle >= timeout {
+                sender.send_replace( true );
+                break;
+            }
+
+            tokio::time::sleep( timeout - idle ).await;
+            if sender.is_closed() {
+                break;
+            }
+        }
+    });
+    receiver
+}
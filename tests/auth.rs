@@ -0,0 +1,196 @@
+//! Authenticating clients before their requests are forwarded, and logging in to the
+//! proxied server.
+
+mod common;
+
+use std::{ sync::Arc, time::{ SystemTime, UNIX_EPOCH } };
+use poem::{ EndpointExt, IntoResponse, Request, endpoint::make, handler, http::{ HeaderName, StatusCode, header }, web::{ Data, websocket::WebSocket } };
+use poem_proxy::{ Authentication, ClientAuthenticator, ProxyConfig };
+use tokio::sync::mpsc;
+use tokio_tungstenite::{ connect_async, tungstenite::client::IntoClientRequest };
+use common::{ client, echo, echoed, serve, serve_proxy };
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn base64url_encode( bytes: &[u8] ) -> String {
+    let bits = bytes.iter().flat_map( |byte| ( 0..8 ).rev().map( move |i| byte >> i & 1 ) ).collect::<Vec<_>>();
+    bits.chunks( 6 )
+        .map( |chunk| BASE64URL[ chunk.iter().chain( std::iter::repeat( &0 ) ).take( 6 ).fold( 0, |n, bit| n << 1 | *bit as usize ) ] as char )
+        .collect()
+}
+
+fn base64url_decode( text: &str ) -> Option<Vec<u8>> {
+    let bits = text.bytes()
+        .map( |c| BASE64URL.iter().position( |&b| b == c ) )
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .flat_map( |n| ( 0..6 ).rev().map( move |i| ( n >> i & 1 ) as u8 ) )
+        .collect::<Vec<_>>();
+    Some( bits.chunks_exact( 8 ).map( |chunk| chunk.iter().fold( 0, |n, bit| n << 1 | bit ) ).collect() )
+}
+
+/// Signs a token with `secret`. This stands in for HMAC-SHA256, which the tests have no
+/// crate for; what matters here is that a token can't be changed without the secret.
+fn sign( secret: &str, input: &str ) -> String {
+    let hash = secret.bytes().chain( input.bytes() )
+        .fold( 0xcbf29ce484222325u64, |hash, byte| ( hash ^ byte as u64 ).wrapping_mul( 0x100000001b3 ) );
+    base64url_encode( &hash.to_be_bytes() )
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since( UNIX_EPOCH ).unwrap().as_secs()
+}
+
+fn jwt( secret: &str, subject: &str, expires: u64 ) -> String {
+    let header = base64url_encode( br#"{"alg":"HS256","typ":"JWT"}"# );
+    let claims = base64url_encode( serde_json::json!({ "sub": subject, "exp": expires }).to_string().as_bytes() );
+    let input = format!( "{}.{}", header, claims );
+    format!( "{}.{}", input, sign( secret, &input ) )
+}
+
+/// Lets in clients with a bearer JWT signed with its secret that hasn't expired, naming
+/// them by its subject.
+struct JwtAuthenticator( &'static str );
+
+#[poem_proxy::async_trait]
+impl ClientAuthenticator for JwtAuthenticator {
+    async fn authenticate( &self, req: &Request ) -> Authentication {
+        let subject = req.header( header::AUTHORIZATION )
+            .and_then( |value| value.strip_prefix( "Bearer " ) )
+            .and_then( |token| {
+                let ( input, signature ) = token.rsplit_once( '.' )?;
+                let ( _, claims ) = input.split_once( '.' )?;
+                if sign( self.0, input ) != signature {
+                    return None
+                }
+                let claims: serde_json::Value = serde_json::from_slice( &base64url_decode( claims )? ).ok()?;
+                match claims[ "exp" ].as_u64()? > now() {
+                    true => claims[ "sub" ].as_str().map( str::to_owned ),
+                    false => None,
+                }
+            });
+        match subject {
+            Some( subject ) => Authentication::Allow( Some( subject ) ),
+            None => Authentication::Deny,
+        }
+    }
+}
+
+#[tokio::test]
+async fn forwards_only_requests_from_authenticated_clients() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .client_authenticator( Arc::new( JwtAuthenticator( "s3cr3t" ) ) )
+        .identity_header( HeaderName::from_static( "x-authenticated-user" ) )
+        .finish() ).await;
+    let client = client();
+    let bearer = |token: &str| format!( "Bearer {}", token );
+
+    // The identity is the authenticator's, whatever the client claims
+    let valid = jwt( "s3cr3t", "alice", now() + 60 );
+    let seen = echoed( client.get( &proxy ).header( "authorization", bearer( &valid ) ).header( "x-authenticated-user", "root" ) ).await;
+    assert_eq!( seen[ "headers" ][ "x-authenticated-user" ], "alice" );
+
+    let forged = jwt( "guess", "alice", now() + 60 );
+    let expired = jwt( "s3cr3t", "alice", now() - 60 );
+    let ( signed, _ ) = valid.rsplit_once( '.' ).unwrap();
+    let ( header, _ ) = signed.split_once( '.' ).unwrap();
+    let tampered = format!( "{}.{}.{}", header, base64url_encode( br#"{"sub":"root","exp":99999999999}"# ), sign( "s3cr3t", signed ) );
+    for token in [ forged, expired, tampered, "not-a-jwt".to_owned() ] {
+        let res = client.get( &proxy ).header( "authorization", bearer( &token ) ).send().await.unwrap();
+        assert_eq!( res.status(), StatusCode::UNAUTHORIZED, "{}", token );
+    }
+    assert_eq!( client.get( &proxy ).send().await.unwrap().status(), StatusCode::UNAUTHORIZED );
+}
+
+/// A websocket backend that reports the `Authorization` header of each handshake.
+#[handler]
+fn authorization_backend( req: &Request, ws: WebSocket, seen: Data<&mpsc::UnboundedSender<Option<String>>> ) -> impl IntoResponse {
+    let _ = seen.send( req.header( header::AUTHORIZATION ).map( str::to_owned ) );
+    ws.on_upgrade( |_| async {} )
+}
+
+#[tokio::test]
+async fn logs_in_to_the_proxied_server_with_its_own_credentials() {
+    let backend = serve( echo ).await;
+    let ( seen, mut handshakes ) = mpsc::unbounded_channel::<Option<String>>();
+    let ws_backend = serve( authorization_backend.data( seen ) ).await;
+    let client = client();
+
+    let basic = ProxyConfig::new( backend.to_string() ).web_insecure().upstream_basic_auth( "proxy", "hunter2" ).finish();
+    let bearer = ProxyConfig::new( backend.to_string() ).web_insecure().upstream_bearer_token( "t0k3n" ).unwrap().finish();
+    for ( config, expected ) in [ ( basic, "Basic cHJveHk6aHVudGVyMg==" ), ( bearer, "Bearer t0k3n" ) ] {
+        let proxy = serve_proxy( config ).await;
+        assert_eq!( echoed( client.get( &proxy ) ).await[ "headers" ][ "authorization" ], expected );
+        // The client's own credentials are for the proxy, not the server
+        let seen = echoed( client.get( &proxy ).header( "authorization", "Bearer the-clients" ) ).await;
+        assert_eq!( seen[ "headers" ][ "authorization" ], expected );
+    }
+
+    let basic = ProxyConfig::new( ws_backend.to_string() ).ws_insecure().upstream_basic_auth( "proxy", "hunter2" ).finish();
+    let bearer = ProxyConfig::new( ws_backend.to_string() ).ws_insecure().upstream_bearer_token( "t0k3n" ).unwrap().finish();
+    for ( config, expected ) in [ ( basic, "Basic cHJveHk6aHVudGVyMg==" ), ( bearer, "Bearer t0k3n" ) ] {
+        let proxy = serve( poem_proxy::ProxyEndpoint::new( config ) ).await;
+        let mut request = format!( "ws://{}/", proxy ).into_client_request().unwrap();
+        request.headers_mut().insert( "authorization", "Bearer the-clients".parse().unwrap() );
+        connect_async( request ).await.unwrap();
+        assert_eq!( handshakes.recv().await.unwrap().as_deref(), Some( expected ) );
+    }
+
+    let mut config = ProxyConfig::new( backend.to_string() );
+    assert!( config.upstream_bearer_token( "t0k3n\r\nx-injected: 1" ).is_err() );
+}
+
+/// What a request to the proxied server is signed over: its method, path and query,
+/// `Authorization` header and body.
+fn signed_over( method: &str, path: &str, authorization: &str, body: &[u8] ) -> String {
+    format!( "{}\n{}\n{}\n{}", method, path, authorization, String::from_utf8_lossy( body ) )
+}
+
+#[tokio::test]
+async fn signs_requests_as_they_are_sent_to_the_proxied_server() {
+    // The server checks the signature against the request it got
+    let backend = serve( make( |req: Request| async move {
+        let method = req.method().to_string();
+        let path = req.uri().to_string();
+        let authorization = req.header( header::AUTHORIZATION ).unwrap_or_default().to_owned();
+        let signature = req.header( "x-signature" ).unwrap_or_default().to_owned();
+        let body = req.into_body().into_bytes().await.unwrap();
+        match signature == sign( "s3cr3t", &signed_over( &method, &path, &authorization, &body ) ) {
+            true => StatusCode::OK,
+            false => StatusCode::UNAUTHORIZED,
+        }
+    })).await;
+
+    // The hook sees the request after the proxy has stripped the prefix and logged in.
+    // Bodies are only there to sign once they are buffered
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .upload_stream_threshold( 64 * 1024 )
+        .strip_prefix( "/api" )
+        .upstream_bearer_token( "t0k3n" ).unwrap()
+        .before_send( |builder, _| {
+            let request = builder.try_clone().unwrap().build().unwrap();
+            let path = match request.url().query() {
+                Some( query ) => format!( "{}?{}", request.url().path(), query ),
+                None => request.url().path().to_owned(),
+            };
+            let authorization = request.headers().get( header::AUTHORIZATION ).map_or( "", |value| value.to_str().unwrap() );
+            let body = request.body().and_then( |body| body.as_bytes() ).unwrap_or_default();
+            let signature = sign( "s3cr3t", &signed_over( request.method().as_str(), &path, authorization, body ) );
+            builder.header( "x-signature", signature )
+        })
+        .finish() ).await;
+    let client = client();
+
+    let res = client.post( format!( "{}/api/orders?dry-run=1", proxy ) ).body( r#"{"item":42}"# ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    let res = client.get( format!( "{}/api/orders", proxy ) ).header( "authorization", "Bearer the-clients" ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+
+    // Which the server would otherwise turn away
+    let res = client.get( format!( "http://{}/orders", backend ) ).header( "authorization", "Bearer t0k3n" ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::UNAUTHORIZED );
+}
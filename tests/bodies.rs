@@ -0,0 +1,577 @@
+//! Relaying the bodies of requests and responses, whole or as they arrive.
+
+mod common;
+
+use std::{ sync::{ Arc, atomic::{ AtomicUsize, Ordering } }, time::{ Duration, Instant } };
+use bytes::Bytes;
+use futures_util::{ StreamExt, stream };
+use poem::{ Body, Request, Response, endpoint::{ make, make_sync }, http::StatusCode };
+use poem_proxy::{ BodyTransform, ProxyConfig, ResponseTransform, UploadStats };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpStream };
+use common::{ client, send_raw, serve, serve_proxy };
+
+/// Serves a backend that sends its response in chunks, without saying how long it is.
+async fn chunked_backend() -> String {
+    serve( make_sync( |_: Request| {
+        let chunks = [ "first ", "second ", "third" ].map( Ok::<_, std::io::Error> );
+        Response::builder().body( Body::from_bytes_stream( stream::iter( chunks ) ) )
+    })).await.to_string()
+}
+
+#[tokio::test]
+async fn buffered_chunked_responses_are_sent_with_their_length() {
+    let proxy = serve_proxy( ProxyConfig::new( chunked_backend().await )
+        .web_insecure()
+        .stream_threshold( 1024 * 1024 )
+        .stream_selector( |_| Some( false ) )
+        .finish() ).await;
+
+    let res = client().get( &proxy ).send().await.unwrap();
+    assert_eq!( res.headers()[ "content-length" ], "18" );
+    assert!( res.headers().get( "transfer-encoding" ).is_none() );
+    assert_eq!( res.text().await.unwrap(), "first second third" );
+}
+
+#[tokio::test]
+async fn streamed_chunked_responses_keep_their_framing() {
+    let proxy = serve_proxy( ProxyConfig::new( chunked_backend().await ).web_insecure().finish() ).await;
+
+    let res = client().get( &proxy ).send().await.unwrap();
+    assert!( res.headers().get( "content-length" ).is_none() );
+    assert_eq!( res.headers()[ "transfer-encoding" ], "chunked" );
+    assert_eq!( res.text().await.unwrap(), "first second third" );
+}
+
+#[tokio::test]
+async fn aborts_streamed_responses_that_last_too_long() {
+
+    // A chunk every 100ms, for two seconds
+    let backend = serve( make_sync( |_: Request| {
+        let chunks = stream::unfold( 0, |sent| async move {
+            tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+            ( sent < 20 ).then( || ( Ok::<_, std::io::Error>( "chunk " ), sent + 1 ) )
+        });
+        Response::builder().body( Body::from_bytes_stream( chunks ) )
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .max_request_duration( Duration::from_millis( 500 ) )
+        .finish() ).await;
+
+    let started = Instant::now();
+    let res = client().get( &proxy ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert!( res.bytes().await.is_err() );
+    assert!( started.elapsed() < Duration::from_millis( 1500 ), "{:?}", started.elapsed() );
+}
+
+#[tokio::test]
+async fn answers_requests_that_take_too_long_to_start_with_a_timeout() {
+    let backend = serve( make( |_: Request| async {
+        tokio::time::sleep( Duration::from_secs( 2 ) ).await;
+        "late"
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .max_request_duration( Duration::from_millis( 300 ) )
+        .finish() ).await;
+
+    assert_eq!( client().get( &proxy ).send().await.unwrap().status(), StatusCode::GATEWAY_TIMEOUT );
+}
+
+#[tokio::test]
+async fn only_forwards_bodies_framed_in_a_way_it_understands() {
+    let backend = serve( make( |mut req: Request| async move { req.take_body().into_string().await.unwrap() } ) ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+    let request = |coding: &str| format!(
+        "POST / HTTP/1.1\r\nhost: proxy\r\ntransfer-encoding: {}\r\nconnection: close\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        coding,
+    );
+
+    let chunked = send_raw( &proxy, request( "chunked" ).as_bytes() ).await;
+    assert!( chunked.starts_with( "HTTP/1.1 200" ), "{}", chunked );
+    assert!( chunked.ends_with( "hello" ), "{}", chunked );
+
+    let bogus = send_raw( &proxy, request( "bogus, chunked" ).as_bytes() ).await;
+    assert!( bogus.starts_with( "HTTP/1.1 501" ), "{}", bogus );
+    assert!( bogus.contains( "bogus" ), "{}", bogus );
+}
+
+#[tokio::test]
+async fn streams_empty_and_tiny_bodies_intact() {
+    let backend = serve( make( |mut req: Request| async move {
+        let body = req.take_body().into_bytes().await.unwrap();
+        match req.uri().path() {
+            "/chunked" => Response::builder().body( Body::from_bytes_stream( stream::iter( [ Ok::<_, std::io::Error>( body ) ] ) ) ),
+            _ => Response::builder().body( body ),
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .stream_threshold( 0 )
+        .upload_stream_threshold( 0 )
+        .finish() ).await;
+    let client = client();
+
+    for body in [ "", "x" ] {
+        let res = client.post( format!( "{}/sized", proxy ) ).body( body ).send().await.unwrap();
+        assert_eq!( res.status(), StatusCode::OK );
+        assert_eq!( res.headers()[ "content-length" ], body.len().to_string().as_str() );
+        assert_eq!( res.text().await.unwrap(), body );
+
+        let res = client.post( format!( "{}/chunked", proxy ) ).body( body ).send().await.unwrap();
+        assert_eq!( res.status(), StatusCode::OK );
+        assert_eq!( res.text().await.unwrap(), body );
+    }
+}
+
+#[tokio::test]
+async fn forwards_multipart_uploads_byte_for_byte() {
+    let backend = serve( make( |mut req: Request| async move {
+        let content_type = req.content_type().unwrap_or_default().to_owned();
+        let body = req.take_body().into_bytes().await.unwrap();
+        Response::builder().header( "x-received-content-type", content_type ).body( body )
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+
+    let boundary = "----proxy-test-boundary-7MA4YWxkTrZu0gW";
+    let file: Vec<u8> = ( 0..4 * 1024 * 1024 ).map( |i| ( i % 251 ) as u8 ).collect();
+    let mut form = Vec::new();
+    form.extend_from_slice( format!( "--{}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nholiday\r\n", boundary ).as_bytes() );
+    form.extend_from_slice( format!( "--{}\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"beach.raw\"\r\n", boundary ).as_bytes() );
+    form.extend_from_slice( b"Content-Type: application/octet-stream\r\n\r\n" );
+    form.extend_from_slice( &file );
+    form.extend_from_slice( format!( "\r\n--{}--\r\n", boundary ).as_bytes() );
+
+    let content_type = format!( "multipart/form-data; boundary={}", boundary );
+    let res = client().post( &proxy ).header( "content-type", &content_type ).body( form.clone() ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.headers()[ "x-received-content-type" ], content_type.as_str() );
+    assert!( res.bytes().await.unwrap() == form );
+}
+
+#[tokio::test]
+async fn streams_large_responses_as_they_arrive() {
+    let file: Vec<u8> = ( 0..8 * 1024 * 1024 ).map( |i| ( i % 253 ) as u8 ).collect();
+    let sized = file.clone();
+    let ( read_first, first_read ) = tokio::sync::mpsc::unbounded_channel::<()>();
+    let first_read = Arc::new( tokio::sync::Mutex::new( Some( first_read ) ) );
+    let backend = serve( make( move |req: Request| {
+        let ( sized, first_read ) = ( sized.clone(), first_read.clone() );
+        async move {
+            if req.uri().path() == "/sized" {
+                return Response::builder().body( sized );
+            }
+
+            // The rest of the body is only sent once the client has the first megabyte
+            let mut chunks = sized.chunks( 1024 * 1024 ).map( Bytes::copy_from_slice ).collect::<Vec<_>>().into_iter();
+            let first = chunks.next().unwrap();
+            let rest = stream::once( async move {
+                first_read.lock().await.take().unwrap().recv().await;
+                Ok::<_, std::io::Error>( Bytes::new() )
+            }).chain( stream::iter( chunks.map( Ok ) ) );
+            Response::builder().body( Body::from_bytes_stream( stream::once( async { Ok( first ) } ).chain( rest ) ) )
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().enable_nesting().finish() ).await;
+    let client = client();
+
+    let res = client.get( format!( "{}/sized", proxy ) ).send().await.unwrap();
+    assert_eq!( res.headers()[ "content-length" ], file.len().to_string().as_str() );
+    assert!( res.bytes().await.unwrap() == file );
+
+    let mut res = client.get( format!( "{}/chunked", proxy ) ).send().await.unwrap();
+    assert_eq!( res.headers()[ "transfer-encoding" ], "chunked" );
+    let mut received = Vec::new();
+    while received.len() < 1024 * 1024 {
+        received.extend_from_slice( &res.chunk().await.unwrap().unwrap() );
+    }
+    read_first.send( () ).unwrap();
+    while let Some( chunk ) = res.chunk().await.unwrap() {
+        received.extend_from_slice( &chunk );
+    }
+    assert!( received == file );
+}
+
+#[tokio::test]
+async fn only_forwards_bodies_of_the_content_types_allowed_for_their_path() {
+    let forwarded = Arc::new( AtomicUsize::new( 0 ) );
+    let counter = forwarded.clone();
+    let backend = serve( make( move |mut req: Request| {
+        let counter = counter.clone();
+        async move {
+            counter.fetch_add( 1, Ordering::SeqCst );
+            req.take_body().into_bytes().await.unwrap()
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().enable_nesting()
+        .allow_content_types( "/api", [ "application/json" ] )
+        .allow_content_types( "/api/uploads", [ "image/*" ] )
+        .finish() ).await;
+    let client = client();
+    let post = |path: &str, content_type: Option<&str>| {
+        let request = client.post( format!( "{}{}", proxy, path ) ).body( "{}" );
+        match content_type {
+            Some( content_type ) => request.header( "content-type", content_type ),
+            None => request,
+        }.send()
+    };
+
+    let res = post( "/api/users", Some( "application/json; charset=utf-8" ) ).await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.text().await.unwrap(), "{}" );
+    assert_eq!( forwarded.load( Ordering::SeqCst ), 1 );
+
+    for ( path, content_type ) in [ ( "/api/users", Some( "text/plain" ) ), ( "/api/users", None ), ( "/api/uploads", Some( "application/json" ) ) ] {
+        let res = post( path, content_type ).await.unwrap();
+        assert_eq!( res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE, "{} {:?}", path, content_type );
+    }
+    assert_eq!( forwarded.load( Ordering::SeqCst ), 1 );
+
+    // Other paths, requests without a body and the more specific rule are all let through
+    for ( path, content_type ) in [ ( "/apis", Some( "text/plain" ) ), ( "/api/uploads/1", Some( "image/png" ) ) ] {
+        assert_eq!( post( path, content_type ).await.unwrap().status(), StatusCode::OK, "{}", path );
+    }
+    assert_eq!( client.get( format!( "{}/api/users", proxy ) ).send().await.unwrap().status(), StatusCode::OK );
+    assert_eq!( forwarded.load( Ordering::SeqCst ), 4 );
+}
+
+#[tokio::test]
+async fn streams_large_uploads_as_they_arrive() {
+    let ( got_first, mut first_received ) = tokio::sync::mpsc::unbounded_channel::<()>();
+    let backend = serve( make( move |mut req: Request| {
+        let got_first = got_first.clone();
+        async move {
+            let framing = match req.headers().get( "content-length" ) {
+                Some( length ) => format!( "length {}", length.to_str().unwrap() ),
+                None => format!( "{:?}", req.headers().get( "transfer-encoding" ) ),
+            };
+            let mut body = req.take_body().into_bytes_stream();
+            let mut received = Vec::new();
+            while let Some( chunk ) = body.next().await {
+                received.extend_from_slice( &chunk.unwrap() );
+                if received.len() >= 1024 * 1024 {
+                    let _ = got_first.send( () );
+                }
+            }
+            Response::builder().header( "x-framing", framing ).body( received )
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+    let file: Vec<u8> = ( 0..8 * 1024 * 1024 ).map( |i| ( i % 241 ) as u8 ).collect();
+
+    // The rest of the upload is only sent once the proxied server has the first megabyte
+    let chunks = file.chunks( 1024 * 1024 ).map( Bytes::copy_from_slice ).collect::<Vec<_>>();
+    let first = chunks[ 0 ].clone();
+    let rest = stream::once( async move {
+        first_received.recv().await;
+        Ok::<_, std::io::Error>( Bytes::new() )
+    }).chain( stream::iter( chunks.into_iter().skip( 1 ).map( Ok ) ) );
+    let upload = stream::once( async { Ok( first ) } ).chain( rest );
+    let res = tokio::time::timeout( Duration::from_secs( 10 ), client().post( &proxy ).body( reqwest::Body::wrap_stream( upload ) ).send() )
+        .await.expect( "the upload was buffered before being forwarded" ).unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.headers()[ "x-framing" ], "Some(\"chunked\")" );
+    assert!( res.bytes().await.unwrap() == file );
+
+    // Sized uploads keep their length
+    let res = client().post( &proxy ).body( file.clone() ).send().await.unwrap();
+    assert_eq!( res.headers()[ "x-framing" ], format!( "length {}", file.len() ).as_str() );
+    assert!( res.bytes().await.unwrap() == file );
+}
+
+#[tokio::test]
+async fn streams_or_buffers_responses_as_the_selector_decides() {
+    let release = Arc::new( tokio::sync::Notify::new() );
+    let released = release.clone();
+
+    // Every response is small and says how long it is, but its second half waits
+    let backend = serve( make( move |_: Request| {
+        let released = released.clone();
+        async move {
+            let rest = stream::once( async move {
+                released.notified().await;
+                Ok::<_, std::io::Error>( Bytes::from_static( b"second" ) )
+            });
+            let body = stream::once( async { Ok( Bytes::from_static( b"first " ) ) } ).chain( rest );
+            Response::builder().header( "content-length", "12" ).body( Body::from_bytes_stream( body ) )
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .stream_threshold( 1024 * 1024 )
+        .stream_selector( |req| req.uri().path().starts_with( "/download/" ).then_some( true ) )
+        .finish() ).await;
+    let client = client();
+
+    // The download starts before the backend is done with it
+    let first = tokio::time::timeout( Duration::from_secs( 5 ), async {
+        let mut res = client.get( format!( "{}/download/report.csv", proxy ) ).send().await.unwrap();
+        let first = res.chunk().await.unwrap().unwrap();
+        ( res, first )
+    }).await;
+    let ( mut res, first ) = first.expect( "the download was buffered" );
+    assert_eq!( first, "first " );
+    release.notify_one();
+    assert_eq!( res.chunk().await.unwrap().unwrap(), "second" );
+
+    // Everything else is small enough to be read in full first
+    let pending = client.get( format!( "{}/api/report", proxy ) ).send();
+    tokio::pin!( pending );
+    assert!( tokio::time::timeout( Duration::from_millis( 200 ), &mut pending ).await.is_err(), "the response was streamed" );
+    release.notify_one();
+    assert_eq!( pending.await.unwrap().text().await.unwrap(), "first second" );
+}
+
+/// Sends `request` as it is and stops writing, so that a body shorter than it claims
+/// to be is cut short rather than waited on. Returns everything the server answers.
+async fn send_and_stop( url: &str, request: &str ) -> String {
+    let mut stream = TcpStream::connect( url.trim_start_matches( "http://" ) ).await.unwrap();
+    stream.write_all( request.as_bytes() ).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end( &mut response ).await.unwrap();
+    String::from_utf8_lossy( &response ).into_owned()
+}
+
+#[tokio::test]
+async fn rejects_bodies_that_do_not_match_their_length() {
+    let ( received, mut bodies ) = tokio::sync::mpsc::unbounded_channel();
+    let backend = serve( make( move |mut req: Request| {
+        let received = received.clone();
+        async move {
+            let body = req.take_body().into_string().await;
+            let _ = received.send( body.as_ref().map_or_else( |_| "incomplete".to_owned(), |body| body.clone() ) );
+            body.unwrap_or_default()
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+
+    // A body that ends early never reaches the proxied server whole
+    let short = send_and_stop( &proxy, "POST / HTTP/1.1\r\nhost: proxy\r\ncontent-length: 10\r\n\r\nhello" ).await;
+    assert!( short.starts_with( "HTTP/1.1 400" ), "{}", short );
+    assert!( short.contains( "does not match its Content-Length of 10 bytes" ), "{}", short );
+    if let Ok( Some( body ) ) = tokio::time::timeout( Duration::from_millis( 200 ), bodies.recv() ).await {
+        assert_eq!( body, "incomplete" );
+    }
+
+    // Only the declared length is forwarded, and the rest is rejected as a request of its own
+    let long = send_and_stop( &proxy, "POST / HTTP/1.1\r\nhost: proxy\r\ncontent-length: 5\r\n\r\nhello, world" ).await;
+    let ( first, rest ) = long.split_once( "hello" ).expect( "the declared body to be echoed" );
+    assert!( first.starts_with( "HTTP/1.1 200" ), "{}", long );
+    assert!( rest.starts_with( "HTTP/1.1 400" ), "{}", long );
+    assert_eq!( bodies.recv().await.unwrap(), "hello" );
+    assert!( bodies.try_recv().is_err() );
+}
+
+/// Uppercases text and blanks out the word `secret`, even when it is split across
+/// chunks, counting the chunks it is given.
+struct Redact {
+    pending: Vec<u8>,
+    chunks: Arc<AtomicUsize>,
+}
+
+impl BodyTransform for Redact {
+    fn transform( &mut self, chunk: Bytes ) -> Bytes {
+        self.chunks.fetch_add( 1, Ordering::SeqCst );
+        self.pending.extend_from_slice( &chunk );
+        let text = String::from_utf8_lossy( &self.pending ).replace( "secret", "******" );
+
+        // Keep what could be the start of the word for the next chunk
+        let keep = ( 1.."secret".len() ).rev().find( |n| text.ends_with( &"secret"[ ..*n ] ) ).unwrap_or( 0 );
+        self.pending = text.as_bytes()[ text.len() - keep.. ].to_vec();
+        Bytes::from( text[ ..text.len() - keep ].to_ascii_uppercase() )
+    }
+
+    fn finish( &mut self ) -> Bytes {
+        Bytes::from( std::mem::take( &mut self.pending ).to_ascii_uppercase() )
+    }
+}
+
+#[tokio::test]
+async fn transforms_streamed_responses_chunk_by_chunk() {
+    let backend = serve( make_sync( |req: Request| {
+        let content_type = match req.uri().path() {
+            "/data" => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+        let chunks = [ "the sec", "ret is ", "out, the s", "ecret", " is safe" ].map( |chunk| Ok::<_, std::io::Error>( Bytes::from( chunk ) ) );
+        Response::builder().content_type( content_type ).body( Body::from_bytes_stream( stream::iter( chunks ) ) )
+    })).await;
+    let chunks = Arc::new( AtomicUsize::new( 0 ) );
+    let counted = chunks.clone();
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .transform_responses( ResponseTransform::new( move || Box::new( Redact { pending: Vec::new(), chunks: counted.clone() } ) )
+            .content_type( "text/*" ) )
+        .finish() ).await;
+
+    let res = client().get( format!( "{}/page", proxy ) ).send().await.unwrap();
+    assert!( res.headers().get( "content-length" ).is_none() );
+    assert_eq!( res.text().await.unwrap(), "THE ****** IS OUT, THE ****** IS SAFE" );
+    assert!( chunks.load( Ordering::SeqCst ) > 1, "the body was transformed in one piece" );
+
+    // Other content types go through untouched
+    let res = client().get( format!( "{}/data", proxy ) ).send().await.unwrap();
+    assert_eq!( res.text().await.unwrap(), "the secret is out, the secret is safe" );
+}
+
+/// The start of an image that happens to contain a url, in two chunks, which isn't
+/// valid UTF-8.
+const PNG: [ &[u8]; 2 ] = [ b"\x89PNG\r\n\x1a\n http://backend.internal", b" \xff\xfe\x00" ];
+
+#[tokio::test]
+async fn rewrites_urls_in_html_but_leaves_binary_bodies_alone() {
+    let backend = serve( make_sync( |req: Request| {
+        let ( content_type, chunks ): ( _, [ &'static [u8]; 2 ] ) = match req.uri().path() {
+            "/page" => ( "text/html; charset=utf-8", [ br#"<a href="http://backend.inte"#, br#"rnal/docs">Docs</a>"# ] ),
+            "/style.css" => ( "text/css", [ b"body { background: url(http://backend.internal/bg.png); ", b"}" ] ),
+            _ => ( "image/png", PNG ),
+        };
+        let chunks = chunks.map( |chunk| Ok::<_, std::io::Error>( Bytes::from_static( chunk ) ) );
+        Response::builder().content_type( content_type ).body( Body::from_bytes_stream( stream::iter( chunks ) ) )
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .rewrite_body( |body| Bytes::from( String::from_utf8_lossy( body ).replace( "http://backend.internal", "https://proxy.example" ) ) )
+        .finish() ).await;
+    let client = client();
+    let get = |path: &str| client.get( format!( "{}{}", proxy, path ) ).send();
+
+    assert_eq!( get( "/page" ).await.unwrap().text().await.unwrap(), r#"<a href="https://proxy.example/docs">Docs</a>"# );
+    assert_eq!( get( "/style.css" ).await.unwrap().text().await.unwrap(), "body { background: url(https://proxy.example/bg.png); }" );
+    assert_eq!( get( "/logo.png" ).await.unwrap().bytes().await.unwrap(), PNG.concat() );
+}
+
+/// Waits for the next thing the backend says about an upload.
+async fn next_upload_event( seen: &mut tokio::sync::mpsc::UnboundedReceiver<&'static str> ) -> &'static str {
+    tokio::time::timeout( Duration::from_secs( 5 ), seen.recv() ).await
+        .expect( "the backend to hear about the upload" )
+        .unwrap()
+}
+
+#[tokio::test]
+async fn aborts_and_counts_uploads_the_client_resets() {
+    let ( events, mut seen ) = tokio::sync::mpsc::unbounded_channel();
+    let backend = serve( make( move |mut req: Request| {
+        let events = events.clone();
+        async move {
+            let mut body = req.take_body().into_bytes_stream();
+            let mut received = 0;
+            while let Some( chunk ) = body.next().await {
+                match chunk {
+                    Ok( chunk ) if received == 0 => {
+                        received += chunk.len();
+                        let _ = events.send( "started" );
+                    },
+                    Ok( chunk ) => received += chunk.len(),
+                    Err( _ ) => {
+                        let _ = events.send( "aborted" );
+                        return received.to_string();
+                    },
+                }
+            }
+            let _ = events.send( "completed" );
+            received.to_string()
+        }
+    })).await;
+    let config = ProxyConfig::new( backend.to_string() ).web_insecure().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    // The client resets its connection a quarter of the way through
+    let mut stream = TcpStream::connect( proxy.trim_start_matches( "http://" ) ).await.unwrap();
+    stream.write_all( b"POST / HTTP/1.1\r\nhost: proxy\r\ncontent-length: 1048576\r\n\r\n" ).await.unwrap();
+    stream.write_all( &[ b'x'; 256 * 1024 ] ).await.unwrap();
+    assert_eq!( next_upload_event( &mut seen ).await, "started" );
+
+    // Lingering for no time at all makes closing the connection reset it, which
+    // doesn't block the way lingering otherwise does
+    #[allow(deprecated)]
+    stream.set_linger( Some( Duration::ZERO ) ).unwrap();
+    drop( stream );
+
+    assert_eq!( next_upload_event( &mut seen ).await, "aborted" );
+    assert_eq!( handle.upload_stats(), UploadStats { completed: 0, aborted: 1 } );
+
+    // Uploads sent to the end are counted apart
+    let res = client().post( &proxy ).body( vec![ b'x'; 1024 * 1024 ] ).send().await.unwrap();
+    assert_eq!( res.text().await.unwrap(), "1048576" );
+    assert_eq!( next_upload_event( &mut seen ).await, "started" );
+    assert_eq!( next_upload_event( &mut seen ).await, "completed" );
+    assert_eq!( handle.upload_stats(), UploadStats { completed: 1, aborted: 1 } );
+}
+
+/// The page in `compressed/`, as it was before being compressed.
+fn page() -> String {
+    ( 0..200 ).map( |i| format!( "<p>Line {} of a page the server compressed.</p>\n", i ) ).collect()
+}
+
+/// Serves a backend that answers with `body`, labeled with the `coding` it is in.
+async fn compressed_backend( body: Vec<u8>, coding: &'static str ) -> String {
+    let body = Bytes::from( body );
+    serve( make_sync( move |_: Request| {
+        Response::builder()
+            .header( "content-encoding", coding )
+            .header( "content-type", "text/html" )
+            .header( "etag", r#""v1""# )
+            .body( body.clone() )
+    })).await.to_string()
+}
+
+/// Returns the status, `Content-Encoding`, `Content-Length`, `ETag` and body of a
+/// response from a proxy to `backend`.
+async fn fetch_compressed( backend: String, decompress: bool ) -> ( StatusCode, Option<String>, Option<String>, Option<String>, Bytes ) {
+    let mut config = ProxyConfig::new( backend );
+    config.web_insecure();
+    if decompress {
+        config.decompress_upstream();
+    }
+    let res = client().get( serve_proxy( config.finish() ).await ).send().await.unwrap();
+    let header = |name: &str| res.headers().get( name ).map( |value| value.to_str().unwrap().to_owned() );
+    let ( encoding, length, etag ) = ( header( "content-encoding" ), header( "content-length" ), header( "etag" ) );
+    ( res.status(), encoding, length, etag, res.bytes().await.unwrap() )
+}
+
+#[tokio::test]
+async fn forwards_compressed_responses_as_they_are_unless_told_to_decode_them() {
+    let gzip = include_bytes!( "compressed/page.html.gz" ).to_vec();
+    let zlib = include_bytes!( "compressed/page.html.zz" ).to_vec();
+    let page = page();
+
+    // As the server sent them
+    for ( body, coding ) in [ ( gzip.clone(), "gzip" ), ( zlib.clone(), "deflate" ) ] {
+        let ( status, encoding, length, etag, received ) = fetch_compressed( compressed_backend( body.clone(), coding ).await, false ).await;
+        assert_eq!( status, StatusCode::OK );
+        assert_eq!( encoding.as_deref(), Some( coding ) );
+        assert_eq!( length, Some( body.len().to_string() ) );
+        assert_eq!( etag.as_deref(), Some( r#""v1""# ) );
+        assert_eq!( received, body );
+    }
+
+    // Or decoded, described as what the client actually gets
+    for ( body, coding ) in [ ( gzip.clone(), "gzip" ), ( zlib, "deflate" ) ] {
+        let ( status, encoding, length, etag, received ) = fetch_compressed( compressed_backend( body, coding ).await, true ).await;
+        assert_eq!( status, StatusCode::OK );
+        assert_eq!( encoding, None );
+        assert_eq!( length, Some( page.len().to_string() ) );
+        assert_eq!( etag.as_deref(), Some( r#"W/"v1""# ) );
+        assert_eq!( received, page );
+    }
+
+    // Members one after the other are all decoded, and padding after the last is ignored
+    let members = [ gzip.as_slice(), &gzip, &[ 0; 16 ] ].concat();
+    let ( status, _, length, _, received ) = fetch_compressed( compressed_backend( members, "gzip" ).await, true ).await;
+    assert_eq!( status, StatusCode::OK );
+    assert_eq!( length, Some( ( 2 * page.len() ).to_string() ) );
+    assert_eq!( received, page.repeat( 2 ) );
+
+    // But a body cut short can't be decoded
+    let truncated = gzip[ ..gzip.len() / 2 ].to_vec();
+    let ( status, _, _, _, _ ) = fetch_compressed( compressed_backend( truncated, "gzip" ).await, true ).await;
+    assert_eq!( status, StatusCode::BAD_GATEWAY );
+}
@@ -0,0 +1,318 @@
+//! Caching responses from the proxied server, and answering from the cache.
+
+mod common;
+
+use std::{ collections::HashMap, sync::{ Arc, Mutex, atomic::{ AtomicBool, AtomicUsize, Ordering } }, time::Duration };
+use poem::{ Request, Response, endpoint::make_sync, http::{ HeaderName, StatusCode } };
+use poem_proxy::{ CachedResponse, CachePolicy, CacheStats, CacheStore, ProxyConfig };
+use common::{ client, serve, serve_proxy };
+
+/// Serves a backend that answers with the given headers and a body naming how many
+/// requests it has answered, returning its address and that count.
+async fn counting_backend( headers: &'static [ ( &'static str, &'static str ) ] ) -> ( String, Arc<AtomicUsize> ) {
+    let hits = Arc::new( AtomicUsize::new( 0 ) );
+    let count = hits.clone();
+    let addr = serve( make_sync( move |_: Request| {
+        let hit = count.fetch_add( 1, Ordering::SeqCst ) + 1;
+        headers.iter()
+            .fold( Response::builder(), |res, ( name, value )| res.header( *name, *value ) )
+            .body( format!( "response {}", hit ) )
+    })).await;
+    ( addr.to_string(), hits )
+}
+
+/// Sends a `GET` for `path` through the proxy with the given headers, returning the
+/// status, headers and body of the response.
+async fn get( proxy: &str, path: &str, headers: &[ ( &str, &str ) ] ) -> ( StatusCode, reqwest::header::HeaderMap, String ) {
+    let req = headers.iter().fold( client().get( format!( "{}{}", proxy, path ) ), |req, ( name, value )| req.header( *name, *value ) );
+    let res = req.send().await.unwrap();
+    ( res.status(), res.headers().clone(), res.text().await.unwrap() )
+}
+
+/// A [CacheStore] that keeps responses in a map, so tests can look at what was stored.
+#[derive(Default)]
+struct MapStore( Mutex<HashMap<String, CachedResponse>> );
+
+#[poem_proxy::async_trait]
+impl CacheStore for MapStore {
+    async fn get( &self, key: &str ) -> Option<CachedResponse> {
+        self.0.lock().unwrap().get( key ).cloned()
+    }
+
+    async fn put( &self, key: &str, response: CachedResponse ) {
+        self.0.lock().unwrap().insert( key.into(), response );
+    }
+
+    async fn invalidate( &self, key: &str ) {
+        self.0.lock().unwrap().remove( key );
+    }
+}
+
+#[tokio::test]
+async fn keeps_responses_in_the_configured_store() {
+    let ( backend, hits ) = counting_backend( &[ ( "cache-control", "max-age=60" ) ] ).await;
+    let store = Arc::new( MapStore::default() );
+    let config = ProxyConfig::new( backend ).web_insecure().enable_nesting().cache_store( store.clone() ).finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    assert_eq!( get( &proxy, "/page", &[] ).await.2, "response 1" );
+    assert_eq!( get( &proxy, "/page", &[] ).await.2, "response 1" );
+    assert_eq!( hits.load( Ordering::SeqCst ), 1 );
+
+    // The response came out of the store it was put in
+    let stored = store.0.lock().unwrap().clone();
+    assert_eq!( stored.len(), 1 );
+    let ( key, response ) = stored.into_iter().next().unwrap();
+    assert!( key.contains( "/page" ), "{}", key );
+    assert_eq!( ( response.status, &response.body[ .. ] ), ( StatusCode::OK, &b"response 1"[ .. ] ) );
+    assert_eq!( handle.cache_stats(), CacheStats { hits: 1, misses: 1, ..CacheStats::default() } );
+}
+
+#[tokio::test]
+async fn answers_matching_conditional_requests_from_the_cache() {
+    let ( backend, hits ) = counting_backend( &[
+        ( "cache-control", "max-age=60" ),
+        ( "etag", "\"v1\"" ),
+        ( "last-modified", "Wed, 21 Oct 2015 07:28:00 GMT" ),
+    ]).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().enable_cache().finish() ).await;
+    get( &proxy, "/", &[] ).await;
+
+    let ( status, headers, body ) = get( &proxy, "/", &[ ( "if-none-match", "W/\"v1\"" ) ] ).await;
+    assert_eq!( status, StatusCode::NOT_MODIFIED );
+    assert_eq!( headers[ "etag" ], "\"v1\"" );
+    assert!( body.is_empty() );
+
+    let ( status, _, _ ) = get( &proxy, "/", &[ ( "if-modified-since", "Thu, 22 Oct 2015 07:28:00 GMT" ) ] ).await;
+    assert_eq!( status, StatusCode::NOT_MODIFIED );
+
+    // Conditions that don't match get the whole response
+    let ( status, _, body ) = get( &proxy, "/", &[ ( "if-none-match", "\"v2\"" ) ] ).await;
+    assert_eq!( ( status, body.as_str() ), ( StatusCode::OK, "response 1" ) );
+    assert_eq!( hits.load( Ordering::SeqCst ), 1 );
+}
+
+#[tokio::test]
+async fn revalidates_stale_responses_with_the_proxied_server() {
+
+    // The backend only says whether the response changed when it can
+    let seen = Arc::new( Mutex::new( Vec::new() ) );
+    let conditions = seen.clone();
+    let backend = serve( make_sync( move |req: Request| {
+        let condition = req.headers().get( "if-none-match" ).map( |value| value.to_str().unwrap().to_owned() );
+        conditions.lock().unwrap().push( condition.clone() );
+        let res = Response::builder().header( "cache-control", "max-age=0" ).header( "etag", "\"v1\"" );
+        match condition.as_deref() {
+            Some( "\"v1\"" ) => res.status( StatusCode::NOT_MODIFIED ).finish(),
+            _ => res.body( "the original" ),
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().enable_cache().finish() ).await;
+
+    for _ in 0..2 {
+        let ( status, _, body ) = get( &proxy, "/", &[] ).await;
+        assert_eq!( ( status, body.as_str() ), ( StatusCode::OK, "the original" ) );
+    }
+    assert_eq!( *seen.lock().unwrap(), [ None, Some( "\"v1\"".to_owned() ) ] );
+}
+
+#[tokio::test]
+async fn keeps_a_response_for_each_variant() {
+    let hits = Arc::new( AtomicUsize::new( 0 ) );
+    let count = hits.clone();
+    let backend = serve( make_sync( move |req: Request| {
+        let hit = count.fetch_add( 1, Ordering::SeqCst ) + 1;
+        let language = req.headers().get( "accept-language" ).map_or( "none", |value| value.to_str().unwrap() ).to_owned();
+        Response::builder()
+            .header( "cache-control", "max-age=60" )
+            .header( "vary", "Accept-Language" )
+            .body( format!( "{} {}", language, hit ) )
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().enable_cache().finish() ).await;
+
+    assert_eq!( get( &proxy, "/", &[ ( "accept-language", "en" ) ] ).await.2, "en 1" );
+    assert_eq!( get( &proxy, "/", &[ ( "accept-language", "fr" ) ] ).await.2, "fr 2" );
+    assert_eq!( get( &proxy, "/", &[] ).await.2, "none 3" );
+
+    // Each variant is answered from the cache from then on
+    assert_eq!( get( &proxy, "/", &[ ( "accept-language", "en" ) ] ).await.2, "en 1" );
+    assert_eq!( get( &proxy, "/", &[ ( "accept-language", "fr" ) ] ).await.2, "fr 2" );
+    assert_eq!( get( &proxy, "/", &[] ).await.2, "none 3" );
+    assert_eq!( hits.load( Ordering::SeqCst ), 3 );
+}
+
+/// Serves a backend that answers `404 Not Found` with how many requests it has seen.
+async fn missing_backend() -> ( String, Arc<AtomicUsize> ) {
+    let hits = Arc::new( AtomicUsize::new( 0 ) );
+    let count = hits.clone();
+    let addr = serve( make_sync( move |_: Request| {
+        let hit = count.fetch_add( 1, Ordering::SeqCst ) + 1;
+        Response::builder().status( StatusCode::NOT_FOUND ).body( format!( "missing {}", hit ) )
+    })).await;
+    ( addr.to_string(), hits )
+}
+
+#[tokio::test]
+async fn keeps_negative_responses_for_their_max_age() {
+    let ( backend, hits ) = missing_backend().await;
+    let proxy = serve_proxy( ProxyConfig::new( backend )
+        .web_insecure()
+        .enable_cache()
+        .cache_negative_responses( Duration::from_millis( 300 ) )
+        .finish() ).await;
+
+    for _ in 0..2 {
+        let ( status, _, body ) = get( &proxy, "/", &[] ).await;
+        assert_eq!( ( status, body.as_str() ), ( StatusCode::NOT_FOUND, "missing 1" ) );
+    }
+    assert_eq!( hits.load( Ordering::SeqCst ), 1 );
+
+    tokio::time::sleep( Duration::from_millis( 400 ) ).await;
+    assert_eq!( get( &proxy, "/", &[] ).await.2, "missing 2" );
+}
+
+#[tokio::test]
+async fn negative_responses_are_only_cached_when_asked() {
+    let ( backend, hits ) = missing_backend().await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().enable_cache().finish() ).await;
+
+    get( &proxy, "/", &[] ).await;
+    get( &proxy, "/", &[] ).await;
+    assert_eq!( hits.load( Ordering::SeqCst ), 2 );
+}
+
+/// Serves a backend that answers with the given `Cache-Control` until `failing` is set,
+/// and with `500 Internal Server Error` after that.
+async fn failing_backend( cache_control: &'static str ) -> ( String, Arc<AtomicBool> ) {
+    let failing = Arc::new( AtomicBool::new( false ) );
+    let fail = failing.clone();
+    let addr = serve( make_sync( move |_: Request| match fail.load( Ordering::SeqCst ) {
+        true => Response::builder().status( StatusCode::INTERNAL_SERVER_ERROR ).body( "broken" ),
+        false => Response::builder().header( "cache-control", cache_control ).body( "the original" ),
+    })).await;
+    ( addr.to_string(), failing )
+}
+
+#[tokio::test]
+async fn serves_stale_responses_when_the_proxied_server_fails() {
+    let ( backend, failing ) = failing_backend( "max-age=0, stale-if-error=60" ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().enable_cache().finish() ).await;
+    get( &proxy, "/", &[] ).await;
+
+    failing.store( true, Ordering::SeqCst );
+    let ( status, headers, body ) = get( &proxy, "/", &[] ).await;
+    assert_eq!( ( status, body.as_str() ), ( StatusCode::OK, "the original" ) );
+    assert_eq!( headers[ "warning" ], "111 - \"Revalidation Failed\"" );
+}
+
+#[tokio::test]
+async fn passes_errors_on_when_stale_responses_may_not_stand_in() {
+    for cache_control in [ "max-age=0", "max-age=0, stale-if-error=60, must-revalidate" ] {
+        let ( backend, failing ) = failing_backend( cache_control ).await;
+        let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().enable_cache().finish() ).await;
+        get( &proxy, "/", &[] ).await;
+
+        failing.store( true, Ordering::SeqCst );
+        let ( status, headers, body ) = get( &proxy, "/", &[] ).await;
+        assert_eq!( ( status, body.as_str() ), ( StatusCode::INTERNAL_SERVER_ERROR, "broken" ), "{}", cache_control );
+        assert!( headers.get( "warning" ).is_none() );
+    }
+
+    // Unless the client allows it
+    let ( backend, failing ) = failing_backend( "max-age=0" ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().enable_cache().finish() ).await;
+    get( &proxy, "/", &[] ).await;
+    failing.store( true, Ordering::SeqCst );
+    assert_eq!( get( &proxy, "/", &[ ( "cache-control", "stale-if-error=60" ) ] ).await.2, "the original" );
+}
+
+#[tokio::test]
+async fn applies_the_policy_of_the_most_specific_path() {
+    let ( backend, hits ) = counting_backend( &[] ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend )
+        .web_insecure()
+        .enable_nesting()
+        .enable_cache()
+        .cache_policy( "/static", CachePolicy::new().ttl( Duration::from_secs( 60 ) ).ignore_query() )
+        .cache_policy( "/static/live", &CachePolicy::disabled() )
+        .cache_policy( "/images", CachePolicy::new().ttl( Duration::from_secs( 60 ) ).key_header( HeaderName::from_static( "accept" ) ) )
+        .finish() ).await;
+
+    // Cached for the policy's lifetime, though the server gave none, whatever the query
+    assert_eq!( get( &proxy, "/static/app.js?v=1", &[] ).await.2, "response 1" );
+    assert_eq!( get( &proxy, "/static/app.js?v=2", &[] ).await.2, "response 1" );
+
+    // Never cached under the more specific path
+    assert_eq!( get( &proxy, "/static/live/feed", &[] ).await.2, "response 2" );
+    assert_eq!( get( &proxy, "/static/live/feed", &[] ).await.2, "response 3" );
+
+    // Cached separately for each value of the key header
+    assert_eq!( get( &proxy, "/images/logo", &[ ( "accept", "image/webp" ) ] ).await.2, "response 4" );
+    assert_eq!( get( &proxy, "/images/logo", &[ ( "accept", "image/png" ) ] ).await.2, "response 5" );
+    assert_eq!( get( &proxy, "/images/logo", &[ ( "accept", "image/webp" ) ] ).await.2, "response 4" );
+
+    // And not at all elsewhere, as the server gave no lifetime
+    assert_eq!( get( &proxy, "/other", &[] ).await.2, "response 6" );
+    assert_eq!( get( &proxy, "/other", &[] ).await.2, "response 7" );
+    assert_eq!( hits.load( Ordering::SeqCst ), 7 );
+}
+
+#[tokio::test]
+async fn reports_how_often_the_cache_saved_a_request() {
+    let backend = serve( make_sync( |req: Request| match req.uri().path() {
+        "/missing" => Response::builder().status( StatusCode::NOT_FOUND ).body( "missing" ),
+        _ => Response::builder().header( "cache-control", "max-age=60" ).body( "found" ),
+    })).await;
+    let config = ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .enable_cache()
+        .cache_negative_responses( Duration::from_secs( 60 ) )
+        .idempotency_keys( Duration::from_secs( 60 ) )
+        .finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    assert_eq!( handle.cache_stats().hit_ratio(), None );
+
+    for _ in 0..3 {
+        get( &proxy, "/page", &[] ).await;
+    }
+    for _ in 0..2 {
+        get( &proxy, "/missing", &[] ).await;
+    }
+    for _ in 0..2 {
+        let res = client().post( format!( "{}/orders", proxy ) ).header( "idempotency-key", "order-1" ).body( "order" ).send().await.unwrap();
+        assert_eq!( res.text().await.unwrap(), "found" );
+    }
+
+    let stats = handle.cache_stats();
+    assert_eq!( stats, CacheStats { hits: 3, misses: 2, negative_hits: 1, collapsed: 1 } );
+    assert_eq!( stats.hit_ratio(), Some( 0.6 ) );
+}
+
+#[tokio::test]
+async fn purges_cached_responses_by_path_and_prefix() {
+    let ( backend, hits ) = counting_backend( &[ ( "cache-control", "max-age=60" ) ] ).await;
+    let config = ProxyConfig::new( backend ).web_insecure().enable_nesting().enable_cache().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    let paths = [ "/products/1", "/products/2?color=red", "/products", "/productsale", "/about" ];
+    for path in paths {
+        get( &proxy, path, &[] ).await;
+    }
+    assert_eq!( hits.load( Ordering::SeqCst ), 5 );
+
+    assert_eq!( handle.invalidate_cache_prefix( "/products/" ).await, 2 );
+    assert_eq!( handle.invalidate_cache( "/about" ).await, 1 );
+    assert_eq!( handle.invalidate_cache( "/about" ).await, 0 );
+
+    // Only the purged paths are fetched again
+    for path in paths {
+        get( &proxy, path, &[] ).await;
+    }
+    assert_eq!( hits.load( Ordering::SeqCst ), 8 );
+    assert_eq!( get( &proxy, "/products", &[] ).await.2, "response 3" );
+    assert_eq!( get( &proxy, "/productsale", &[] ).await.2, "response 4" );
+    assert_eq!( get( &proxy, "/products/1", &[] ).await.2, "response 6" );
+}
@@ -0,0 +1,217 @@
+//! Helpers shared by the integration tests, which run the proxy and the servers it
+//! forwards to on ephemeral local ports.
+
+// Each test crate only uses some of the helpers
+#![allow(dead_code)]
+
+use std::{ net::SocketAddr, sync::{ Arc, Mutex } };
+use poem::{ IntoEndpoint, Server, listener::{ Acceptor, Listener, TcpListener } };
+use poem_proxy::{ ProxyConfig, ProxyEndpoint };
+
+/// Serves `endpoint` on a free local port in the background, returning its address.
+pub async fn serve<E>( endpoint: E ) -> SocketAddr
+where
+    E: IntoEndpoint + Send + 'static,
+    E::Endpoint: 'static,
+{
+    let acceptor = TcpListener::bind( "127.0.0.1:0" ).into_acceptor().await.expect( "a free port" );
+    let addr = *acceptor.local_addr()[ 0 ].as_socket_addr().expect( "a tcp address" );
+    tokio::spawn( Server::new_with_acceptor( acceptor ).run( endpoint ) );
+    addr
+}
+
+/// Serves a proxy endpoint with the given configuration, returning its base url.
+pub async fn serve_proxy( config: ProxyConfig ) -> String {
+    format!( "http://{}", serve( ProxyEndpoint::new( config ) ).await )
+}
+
+/// Returns a client that talks to the proxy directly, whatever the environment's proxy
+/// settings.
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder().no_proxy().build().expect( "a client" )
+}
+
+/// Returns a local port nothing is listening on.
+pub async fn closed_port() -> u16 {
+    let listener = tokio::net::TcpListener::bind( "127.0.0.1:0" ).await.expect( "a free port" );
+    listener.local_addr().expect( "a local address" ).port()
+}
+
+/// Sends `request` to the server at `url` as it is, without a client checking it first,
+/// returning the whole response once the server closes the connection.
+pub async fn send_raw( url: &str, request: &[ u8 ] ) -> String {
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+    let mut stream = tokio::net::TcpStream::connect( url.trim_start_matches( "http://" ) ).await.expect( "a connection" );
+    stream.write_all( request ).await.expect( "the request to be sent" );
+    let mut response = Vec::new();
+    stream.read_to_end( &mut response ).await.expect( "a response" );
+    String::from_utf8_lossy( &response ).into_owned()
+}
+
+/// Serves a backend that reads the head of each request as raw bytes, passing it on
+/// as it was received, and answers `200 OK` before closing the connection. Returns its
+/// address and the request heads it reads.
+pub async fn raw_backend() -> ( String, tokio::sync::mpsc::UnboundedReceiver<String> ) {
+    raw_backend_answering( "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok" ).await
+}
+
+/// Like [raw_backend], but answers every request with `response` as it is.
+pub async fn raw_backend_answering( response: &'static str ) -> ( String, tokio::sync::mpsc::UnboundedReceiver<String> ) {
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+    let listener = tokio::net::TcpListener::bind( "127.0.0.1:0" ).await.expect( "a free port" );
+    let addr = listener.local_addr().expect( "a local address" );
+    let ( heads, seen ) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn( async move {
+        loop {
+            let ( mut stream, _ ) = listener.accept().await.expect( "a connection" );
+            let mut head = Vec::new();
+            let mut buf = [ 0; 1024 ];
+            while !head.ends_with( b"\r\n\r\n" ) {
+                match stream.read( &mut buf ).await {
+                    Ok( 0 ) | Err( _ ) => break,
+                    Ok( read ) => head.extend_from_slice( &buf[ ..read ] ),
+                }
+            }
+            let _ = heads.send( String::from_utf8_lossy( &head ).into_owned() );
+            let _ = stream.write_all( response.as_bytes() ).await;
+        }
+    });
+    ( addr.to_string(), seen )
+}
+
+/// A backend that answers with what it received, as a JSON object with the `method`, the
+/// `uri`, the `version` and the `headers` of the request, repeated headers joined with
+/// commas.
+#[poem::handler]
+pub fn echo( req: &poem::Request ) -> poem::web::Json<serde_json::Value> {
+    let mut headers = serde_json::Map::new();
+    for name in req.headers().keys() {
+        let values: Vec<_> = req.headers().get_all( name ).iter().map( |value| String::from_utf8_lossy( value.as_bytes() ) ).collect();
+        headers.insert( name.to_string(), values.join( ", " ).into() );
+    }
+    poem::web::Json( serde_json::json!({
+        "method": req.method().as_str(),
+        "uri": req.uri().to_string(),
+        "version": format!( "{:?}", req.version() ),
+        "headers": headers,
+    }))
+}
+
+/// Returns what the [echo] backend received for a request through the proxy.
+pub async fn echoed( req: reqwest::RequestBuilder ) -> serde_json::Value {
+    let body = req.send().await.expect( "a response" ).bytes().await.expect( "a body" );
+    serde_json::from_slice( &body ).expect( "an echo" )
+}
+
+/// A `tracing` subscriber that keeps everything logged while it is the default, since
+/// there is no ready-made one to use here. Events are kept as `LEVEL message` followed
+/// by their other fields, and spans as their name and the fields recorded on them.
+#[derive(Clone, Default)]
+pub struct Logs( Arc<Mutex<LogsInner>> );
+
+#[derive(Default)]
+struct LogsInner {
+    events: Vec<String>,
+    spans: Vec<( &'static tracing::Metadata<'static>, Vec<( String, String )> )>,
+
+    /// The spans entered and not yet exited, innermost last
+    entered: Vec<tracing::span::Id>,
+}
+
+impl Logs {
+
+    /// Captures what is logged on this thread until the returned guard is dropped.
+    /// Tests relying on this must run the proxy on the test's own thread, as
+    /// `#[tokio::test]` does by default.
+    pub fn capture() -> ( Logs, tracing::subscriber::DefaultGuard ) {
+        let logs = Logs::default();
+        let guard = tracing::subscriber::set_default( logs.clone() );
+        ( logs, guard )
+    }
+
+    /// Returns the events logged so far.
+    pub fn events( &self ) -> Vec<String> {
+        self.0.lock().unwrap().events.clone()
+    }
+
+    /// Returns the value recorded for `field` on the spans called `name`, in the order
+    /// they were opened.
+    pub fn span_fields( &self, name: &str, field: &str ) -> Vec<Option<String>> {
+        self.0.lock().unwrap().spans.iter()
+            .filter( |( span, _ )| span.name() == name )
+            .map( |( _, fields )| fields.iter().find( |( key, _ )| key == field ).map( |( _, value )| value.clone() ) )
+            .collect()
+    }
+}
+
+/// Collects the fields of an event or span as text.
+#[derive(Default)]
+struct Fields( Vec<( String, String )> );
+
+impl tracing::field::Visit for Fields {
+    fn record_str( &mut self, field: &tracing::field::Field, value: &str ) {
+        self.0.push( ( field.name().to_owned(), value.to_owned() ) );
+    }
+
+    fn record_debug( &mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug ) {
+        self.0.push( ( field.name().to_owned(), format!( "{:?}", value ) ) );
+    }
+}
+
+impl tracing::Subscriber for Logs {
+    fn enabled( &self, _: &tracing::Metadata<'_> ) -> bool {
+        true
+    }
+
+    fn new_span( &self, span: &tracing::span::Attributes<'_> ) -> tracing::span::Id {
+        let mut fields = Fields::default();
+        span.record( &mut fields );
+        let mut inner = self.0.lock().unwrap();
+        inner.spans.push( ( span.metadata(), fields.0 ) );
+        tracing::span::Id::from_u64( inner.spans.len() as u64 )
+    }
+
+    fn record( &self, span: &tracing::span::Id, values: &tracing::span::Record<'_> ) {
+        let mut fields = Fields::default();
+        values.record( &mut fields );
+        if let Some( ( _, recorded ) ) = self.0.lock().unwrap().spans.get_mut( span.into_u64() as usize - 1 ) {
+            recorded.extend( fields.0 );
+        }
+    }
+
+    fn record_follows_from( &self, _: &tracing::span::Id, _: &tracing::span::Id ) {}
+
+    fn event( &self, event: &tracing::Event<'_> ) {
+        let mut fields = Fields::default();
+        event.record( &mut fields );
+        let mut line = event.metadata().level().to_string();
+        for ( name, value ) in fields.0 {
+            match name.as_str() {
+                "message" => line.push_str( &format!( " {}", value ) ),
+                _ => line.push_str( &format!( " {}={}", name, value ) ),
+            }
+        }
+        self.0.lock().unwrap().events.push( line );
+    }
+
+    fn enter( &self, span: &tracing::span::Id ) {
+        self.0.lock().unwrap().entered.push( span.clone() );
+    }
+
+    fn exit( &self, span: &tracing::span::Id ) {
+        let mut inner = self.0.lock().unwrap();
+        if let Some( index ) = inner.entered.iter().rposition( |entered| entered == span ) {
+            inner.entered.remove( index );
+        }
+    }
+
+    fn current_span( &self ) -> tracing_core::span::Current {
+        let inner = self.0.lock().unwrap();
+        match inner.entered.last() {
+            Some( span ) => tracing_core::span::Current::new( span.clone(), inner.spans[ span.into_u64() as usize - 1 ].0 ),
+            None => tracing_core::span::Current::none(),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! How the proxy opens, reuses and replaces its connections to the proxied server.
+
+mod common;
+
+use std::{ collections::HashSet, net::SocketAddr, sync::{ Arc, Mutex, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use poem::{ Request, Response, endpoint::make_sync };
+use poem_proxy::{ Addrs, Certificate, ConnectionStats, Name, ProxyConfig, Resolve, Resolving };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpListener };
+use tokio_native_tls::{ TlsAcceptor, native_tls };
+use common::{ client, serve, serve_proxy };
+
+/// Returns the address of a backend by name rather than IP address, since connections
+/// are only counted for servers whose names are looked up.
+fn by_name( backend: &str ) -> String {
+    backend.replace( "127.0.0.1", "localhost" )
+}
+
+/// Sends `count` requests through the proxy, one after the other.
+async fn send( proxy: &str, count: usize ) {
+    let client = client();
+    for _ in 0..count {
+        let res = client.get( proxy ).send().await.unwrap();
+        assert_eq!( res.text().await.unwrap(), "ok" );
+    }
+}
+
+/// Serves a backend that answers with the given `Keep-Alive` header, returning its
+/// address along with the client addresses it saw, one per connection.
+async fn keep_alive_backend( keep_alive: &'static str ) -> ( String, Arc<Mutex<HashSet<String>>> ) {
+    let peers = Arc::new( Mutex::new( HashSet::new() ) );
+    let seen = peers.clone();
+    let addr = serve( make_sync( move |req: Request| {
+        seen.lock().unwrap().insert( req.remote_addr().to_string() );
+        Response::builder().header( "keep-alive", keep_alive ).body( "ok" )
+    })).await;
+    ( addr.to_string(), peers )
+}
+
+#[tokio::test]
+async fn rotates_connections_before_the_keep_alive_max() {
+    let ( backend, peers ) = keep_alive_backend( "timeout=5, max=3" ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().honor_keep_alive().finish() ).await;
+
+    // With a request of headroom, each connection serves two requests
+    send( &proxy, 6 ).await;
+    assert_eq!( peers.lock().unwrap().len(), 3 );
+}
+
+#[tokio::test]
+async fn ignores_keep_alive_hints_unless_asked() {
+    let ( backend, peers ) = keep_alive_backend( "timeout=5, max=3" ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().finish() ).await;
+
+    send( &proxy, 6 ).await;
+    assert_eq!( peers.lock().unwrap().len(), 1 );
+}
+
+#[tokio::test]
+async fn counts_reused_and_opened_connections() {
+    let ( backend, peers ) = keep_alive_backend( "timeout=5" ).await;
+    let config = ProxyConfig::new( by_name( &backend ) ).web_insecure().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    send( &proxy, 5 ).await;
+    assert_eq!( peers.lock().unwrap().len(), 1 );
+    assert_eq!( handle.connection_stats(), ConnectionStats { reused: 4, opened: 1 } );
+}
+
+#[tokio::test]
+async fn counts_the_connections_opened_by_rotation() {
+    let ( backend, peers ) = keep_alive_backend( "timeout=5, max=3" ).await;
+    let config = ProxyConfig::new( by_name( &backend ) ).web_insecure().honor_keep_alive().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    send( &proxy, 6 ).await;
+    assert_eq!( peers.lock().unwrap().len(), 3 );
+    assert_eq!( handle.connection_stats(), ConnectionStats { reused: 3, opened: 3 } );
+}
+
+/// A resolver that sends every name to the local machine, counting its lookups.
+#[derive(Default)]
+struct CountingResolver( AtomicUsize );
+
+impl Resolve for CountingResolver {
+    fn resolve( &self, _: Name ) -> Resolving {
+        self.0.fetch_add( 1, Ordering::SeqCst );
+        let addrs: Addrs = Box::new( std::iter::once( SocketAddr::from( ( [ 127, 0, 0, 1 ], 0 ) ) ) );
+        Box::pin( async move { Ok( addrs ) } )
+    }
+}
+
+#[tokio::test]
+async fn looks_names_up_again_once_their_ttl_is_over() {
+    // Every request needs a connection, and so an address, of its own
+    let backend = serve( make_sync( |_: Request| Response::builder().header( "connection", "close" ).body( "ok" ) ) ).await;
+    let resolver = Arc::new( CountingResolver::default() );
+    let proxy = serve_proxy( ProxyConfig::new( format!( "backend.test:{}", backend.port() ) )
+        .web_insecure()
+        .dns_resolver( resolver.clone() )
+        .dns_cache_ttl( Duration::from_millis( 300 ) )
+        .finish() ).await;
+
+    send( &proxy, 3 ).await;
+    assert_eq!( resolver.0.load( Ordering::SeqCst ), 1 );
+
+    tokio::time::sleep( Duration::from_millis( 400 ) ).await;
+    send( &proxy, 3 ).await;
+    assert_eq!( resolver.0.load( Ordering::SeqCst ), 2 );
+}
+
+#[tokio::test]
+async fn serves_a_hundred_requests_over_one_connection() {
+    let ( backend, peers ) = keep_alive_backend( "timeout=5" ).await;
+    let config = ProxyConfig::new( by_name( &backend ) ).web_insecure().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    send( &proxy, 100 ).await;
+    assert_eq!( peers.lock().unwrap().len(), 1 );
+    assert_eq!( handle.connection_stats(), ConnectionStats { reused: 99, opened: 1 } );
+}
+
+/// Serves a backend over TLS with a self-signed certificate for `localhost`, answering
+/// every request with `secure`. Returns its address and that certificate.
+async fn self_signed_backend() -> ( String, Certificate ) {
+    let certificate = include_bytes!( "certs/localhost.pem" );
+    let identity = native_tls::Identity::from_pkcs8( certificate, include_bytes!( "certs/localhost.key" ) ).unwrap();
+    let acceptor = TlsAcceptor::from( native_tls::TlsAcceptor::new( identity ).unwrap() );
+    let listener = TcpListener::bind( "127.0.0.1:0" ).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn( async move {
+        while let Ok( ( stream, _ ) ) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn( async move {
+                let Ok( mut stream ) = acceptor.accept( stream ).await else { return };
+                let mut head = Vec::new();
+                while !head.ends_with( b"\r\n\r\n" ) {
+                    match stream.read_u8().await {
+                        Ok( byte ) => head.push( byte ),
+                        Err( _ ) => return,
+                    }
+                }
+                let _ = stream.write_all( b"HTTP/1.1 200 OK\r\ncontent-length: 6\r\nconnection: close\r\n\r\nsecure" ).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    ( format!( "localhost:{}", addr.port() ), Certificate::from_pem( certificate ).unwrap() )
+}
+
+#[tokio::test]
+async fn only_trusts_self_signed_certificates_when_told_to() {
+    let ( backend, certificate ) = self_signed_backend().await;
+    let through = |config: &mut ProxyConfig| {
+        let config = config.web_secure().finish();
+        async move { client().get( serve_proxy( config ).await ).send().await.unwrap() }
+    };
+
+    let res = through( &mut ProxyConfig::new( backend.clone() ) ).await;
+    assert_eq!( res.status(), 502 );
+
+    for res in [
+        through( ProxyConfig::new( backend.clone() ).add_root_certificate( certificate ) ).await,
+        through( ProxyConfig::new( backend ).danger_accept_invalid_certs() ).await,
+    ] {
+        assert_eq!( res.status(), 200 );
+        assert_eq!( res.text().await.unwrap(), "secure" );
+    }
+}
@@ -0,0 +1,268 @@
+//! What clients get, and the error hook hears, when forwarding a request fails.
+
+mod common;
+
+use std::{ sync::{ Arc, Mutex }, time::{ Duration, Instant } };
+use poem::{ Request, Response, endpoint::make, handler, http::{ HeaderValue, StatusCode } };
+use poem_proxy::{ CacheStore, CachedResponse, ErrorFormat, ErrorPage, ProxyConfig, ProxyError, ProxyErrorKind };
+use tokio::{ io::AsyncReadExt, net::TcpListener };
+use common::{ client, closed_port, serve, serve_proxy };
+
+/// Serves a backend that accepts connections and reads the request, but closes the
+/// connection without answering. Returns its address.
+async fn hanging_up_backend() -> String {
+    let listener = TcpListener::bind( "127.0.0.1:0" ).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn( async move {
+        loop {
+            let ( mut stream, _ ) = listener.accept().await.unwrap();
+            let mut buf = [ 0; 1024 ];
+            let _ = stream.read( &mut buf ).await;
+        }
+    });
+    addr.to_string()
+}
+
+/// Returns a configuration for a proxy to `backend`, along with the kinds of the
+/// errors its hook is given.
+fn hooked( backend: String ) -> ( ProxyConfig, Arc<Mutex<Vec<ProxyErrorKind>>> ) {
+    let kinds = Arc::new( Mutex::new( Vec::new() ) );
+    let heard = kinds.clone();
+    let mut config = ProxyConfig::new( backend );
+    config.web_insecure().on_upstream_error( move |_, error| heard.lock().unwrap().push( error.kind() ) );
+    ( config, kinds )
+}
+
+#[tokio::test]
+async fn answers_a_server_that_hung_up_with_a_bad_gateway() {
+    let ( config, kinds ) = hooked( hanging_up_backend().await );
+    let proxy = serve_proxy( config ).await;
+
+    assert_eq!( client().get( &proxy ).send().await.unwrap().status(), StatusCode::BAD_GATEWAY );
+    assert_eq!( *kinds.lock().unwrap(), [ ProxyErrorKind::ClosedPrematurely ] );
+}
+
+#[tokio::test]
+async fn answers_a_server_that_hung_up_with_its_error_page() {
+    let ( mut config, kinds ) = hooked( hanging_up_backend().await );
+    config.error_page( ProxyErrorKind::ClosedPrematurely, &ErrorPage::new( StatusCode::BAD_GATEWAY, "The backend hung up" ) );
+    let proxy = serve_proxy( config.finish() ).await;
+
+    let res = client().get( &proxy ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::BAD_GATEWAY );
+    assert_eq!( res.text().await.unwrap(), "The backend hung up" );
+    assert_eq!( *kinds.lock().unwrap(), [ ProxyErrorKind::ClosedPrematurely ] );
+}
+
+#[tokio::test]
+async fn tells_the_hook_a_timeout_from_a_connect_failure() {
+    let alerts = Arc::new( Mutex::new( Vec::new() ) );
+    let proxy_to = |backend: String| {
+        let alerts = alerts.clone();
+        let mut config = ProxyConfig::new( backend );
+        config.web_insecure().upstream_timeout( Duration::from_millis( 100 ) ).on_upstream_error( move |_, error| {
+            // Only page someone when the server can't be reached at all
+            let heard = match error {
+                ProxyError::Connect( inner ) if inner.is_connect() => "page",
+                ProxyError::Timeout( inner ) if inner.is_timeout() => "log",
+                _ => "unexpected",
+            };
+            alerts.lock().unwrap().push( heard );
+        });
+        serve_proxy( config )
+    };
+    let slow = serve( make( |_: Request| async {
+        tokio::time::sleep( Duration::from_secs( 2 ) ).await;
+        "late"
+    })).await;
+
+    let timing_out = proxy_to( slow.to_string() ).await;
+    assert_eq!( client().get( &timing_out ).send().await.unwrap().status(), StatusCode::BAD_GATEWAY );
+    assert_eq!( *alerts.lock().unwrap(), [ "log" ] );
+
+    let unreachable = proxy_to( format!( "127.0.0.1:{}", closed_port().await ) ).await;
+    assert_eq!( client().get( &unreachable ).send().await.unwrap().status(), StatusCode::BAD_GATEWAY );
+    assert_eq!( *alerts.lock().unwrap(), [ "log", "page" ] );
+}
+
+#[handler]
+fn ok() -> &'static str {
+    "ok"
+}
+
+/// A cache store that takes far too long to look anything up.
+struct SlowStore;
+
+#[poem_proxy::async_trait]
+impl CacheStore for SlowStore {
+    async fn get( &self, _: &str ) -> Option<CachedResponse> {
+        tokio::time::sleep( Duration::from_secs( 5 ) ).await;
+        None
+    }
+
+    async fn put( &self, _: &str, _: CachedResponse ) {}
+
+    async fn invalidate( &self, _: &str ) {}
+}
+
+#[tokio::test]
+async fn answers_with_a_timeout_when_a_callback_runs_past_the_deadline() {
+    let backend = serve( ok ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .cache_store( Arc::new( SlowStore ) )
+        .request_timeout( Duration::from_millis( 200 ) )
+        .finish() ).await;
+
+    let started = Instant::now();
+    assert_eq!( client().get( &proxy ).send().await.unwrap().status(), StatusCode::GATEWAY_TIMEOUT );
+    assert!( started.elapsed() < Duration::from_secs( 2 ), "{:?}", started.elapsed() );
+}
+
+/// Returns the status, content type and body of a response.
+async fn parts( res: reqwest::Response ) -> ( StatusCode, String, String ) {
+    let content_type = res.headers().get( "content-type" ).map_or( "", |value| value.to_str().unwrap() ).to_owned();
+    ( res.status(), content_type, res.text().await.unwrap() )
+}
+
+#[tokio::test]
+async fn wraps_its_own_errors_in_a_json_envelope_when_asked() {
+    let backend = serve( make( |req: Request| async move {
+        match req.uri().path() {
+            "/slow" => tokio::time::sleep( Duration::from_secs( 2 ) ).await,
+            "/broken" => return Response::builder().status( StatusCode::INTERNAL_SERVER_ERROR ).body( "the server's own page" ),
+            _ => {},
+        }
+        Response::builder().body( "ok" )
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .request_timeout( Duration::from_millis( 200 ) )
+        .max_connections_per_client( 1 )
+        .error_format( ErrorFormat::Json )
+        .finish() ).await;
+    let client = client();
+
+    // A request that takes too long, while a second one from the same client is turned away
+    let ( slow, limited ) = tokio::join!(
+        client.get( format!( "{}/slow", proxy ) ).send(),
+        async {
+            tokio::time::sleep( Duration::from_millis( 50 ) ).await;
+            client.get( format!( "{}/other", proxy ) ).send().await
+        },
+    );
+
+    let ( status, content_type, body ) = parts( slow.unwrap() ).await;
+    assert_eq!( ( status, content_type.as_str() ), ( StatusCode::GATEWAY_TIMEOUT, "application/json" ) );
+    let envelope: serde_json::Value = serde_json::from_str( &body ).unwrap();
+    assert_eq!( envelope[ "error" ][ "code" ], 504 );
+    assert!( envelope[ "error" ][ "message" ].is_string() );
+
+    let ( status, content_type, body ) = parts( limited.unwrap() ).await;
+    assert_eq!( ( status, content_type.as_str() ), ( StatusCode::TOO_MANY_REQUESTS, "application/json" ) );
+    let envelope: serde_json::Value = serde_json::from_str( &body ).unwrap();
+    assert_eq!( envelope[ "error" ][ "code" ], 429 );
+
+    // Errors from the proxied server are its own business
+    let ( status, _, body ) = parts( client.get( format!( "{}/broken", proxy ) ).send().await.unwrap() ).await;
+    assert_eq!( ( status, body.as_str() ), ( StatusCode::INTERNAL_SERVER_ERROR, "the server's own page" ) );
+}
+
+#[tokio::test]
+async fn answers_each_kind_of_failure_with_its_own_page() {
+    let proxy_to = |backend: String| {
+        let mut config = ProxyConfig::new( backend );
+        config.web_insecure()
+            .upstream_timeout( Duration::from_millis( 100 ) )
+            .error_page( ProxyErrorKind::Connect, &ErrorPage::new( StatusCode::SERVICE_UNAVAILABLE, "<h1>We'll be right back</h1>" ) )
+            .error_page( ProxyErrorKind::Timeout, ErrorPage::new( StatusCode::GATEWAY_TIMEOUT, r#"{"error": "upstream timed out", "code": {status}}"# )
+                .content_type( HeaderValue::from_static( "application/json" ) ) );
+        serve_proxy( config.finish() )
+    };
+    let slow = serve( make( |_: Request| async {
+        tokio::time::sleep( Duration::from_secs( 2 ) ).await;
+        "late"
+    })).await;
+
+    let unreachable = proxy_to( format!( "127.0.0.1:{}", closed_port().await ) ).await;
+    let ( status, content_type, body ) = parts( client().get( &unreachable ).send().await.unwrap() ).await;
+    assert_eq!( status, StatusCode::SERVICE_UNAVAILABLE );
+    assert!( content_type.starts_with( "text/html" ), "{}", content_type );
+    assert_eq!( body, "<h1>We'll be right back</h1>" );
+
+    let timing_out = proxy_to( slow.to_string() ).await;
+    let ( status, content_type, body ) = parts( client().get( &timing_out ).send().await.unwrap() ).await;
+    assert_eq!( status, StatusCode::GATEWAY_TIMEOUT );
+    assert_eq!( content_type, "application/json" );
+    assert_eq!( body, r#"{"error": "upstream timed out", "code": 504}"# );
+}
+
+#[tokio::test]
+async fn answers_upstream_failures_with_the_error_handler_response() {
+    let handled = |config: &mut ProxyConfig| {
+        config.web_insecure().on_error( |error| {
+            let reason = match error.kind() {
+                ProxyErrorKind::Connect => "unreachable",
+                ProxyErrorKind::Status => "failed",
+                _ => "other",
+            };
+            Response::builder()
+                .status( StatusCode::SERVICE_UNAVAILABLE )
+                .content_type( "application/json" )
+                .body( format!( r#"{{"error": "{}"}}"#, reason ) )
+        });
+        serve_proxy( config.finish() )
+    };
+    let failing = serve( make( |_: Request| async { Response::builder().status( StatusCode::INTERNAL_SERVER_ERROR ).body( "oops" ) } ) ).await;
+    let unreachable = format!( "127.0.0.1:{}", closed_port().await );
+
+    let proxy = handled( &mut ProxyConfig::new( unreachable.clone() ) ).await;
+    let res = parts( client().get( &proxy ).send().await.unwrap() ).await;
+    assert_eq!( res, ( StatusCode::SERVICE_UNAVAILABLE, "application/json".to_owned(), r#"{"error": "unreachable"}"#.to_owned() ) );
+
+    // The proxied server's own errors only go to the handler when asked
+    let proxy = handled( &mut ProxyConfig::new( failing.to_string() ) ).await;
+    assert_eq!( parts( client().get( &proxy ).send().await.unwrap() ).await.2, "oops" );
+    let proxy = handled( ProxyConfig::new( failing.to_string() ).handle_error_statuses() ).await;
+    assert_eq!( parts( client().get( &proxy ).send().await.unwrap() ).await.2, r#"{"error": "failed"}"# );
+
+    // An error page for the kind of failure comes first
+    let proxy = handled( ProxyConfig::new( unreachable ).error_page( ProxyErrorKind::Connect, &ErrorPage::new( StatusCode::BAD_GATEWAY, "down" ) ) ).await;
+    assert_eq!( parts( client().get( &proxy ).send().await.unwrap() ).await.2, "down" );
+}
+
+#[tokio::test]
+async fn treats_server_errors_as_failures_only_when_asked() {
+    let backend = serve( make( |_: Request| async { Response::builder().status( StatusCode::SERVICE_UNAVAILABLE ).body( "down for maintenance" ) } ) ).await;
+    let missing = serve( make( |_: Request| async { Response::builder().status( StatusCode::NOT_FOUND ).body( "no such page" ) } ) ).await;
+
+    // By default the server's answer is passed on as it is
+    let ( config, kinds ) = hooked( backend.to_string() );
+    let proxy = serve_proxy( config ).await;
+    let res = parts( client().get( &proxy ).send().await.unwrap() ).await;
+    assert_eq!( ( res.0, res.2.as_str() ), ( StatusCode::SERVICE_UNAVAILABLE, "down for maintenance" ) );
+    assert!( kinds.lock().unwrap().is_empty() );
+
+    // Otherwise it goes down the error path, keeping its status
+    let ( mut config, kinds ) = hooked( backend.to_string() );
+    let proxy = serve_proxy( config.treat_upstream_errors_as_failures().finish() ).await;
+    let res = parts( client().get( &proxy ).send().await.unwrap() ).await;
+    assert_eq!( res.0, StatusCode::SERVICE_UNAVAILABLE );
+    assert_ne!( res.2, "down for maintenance" );
+    assert_eq!( *kinds.lock().unwrap(), [ ProxyErrorKind::Status ] );
+
+    // Client errors are still the server's to explain
+    let ( mut config, kinds ) = hooked( missing.to_string() );
+    let proxy = serve_proxy( config.treat_upstream_errors_as_failures().finish() ).await;
+    let res = parts( client().get( &proxy ).send().await.unwrap() ).await;
+    assert_eq!( ( res.0, res.2.as_str() ), ( StatusCode::NOT_FOUND, "no such page" ) );
+    assert!( kinds.lock().unwrap().is_empty() );
+
+    // And the error handler answers in the server's place
+    let ( mut config, _ ) = hooked( backend.to_string() );
+    config.treat_upstream_errors_as_failures().on_error( |error| Response::builder().status( error.status() ).body( "try again later" ) );
+    let proxy = serve_proxy( config.finish() ).await;
+    let res = parts( client().get( &proxy ).send().await.unwrap() ).await;
+    assert_eq!( ( res.0, res.2.as_str() ), ( StatusCode::SERVICE_UNAVAILABLE, "try again later" ) );
+}
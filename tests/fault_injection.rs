@@ -0,0 +1,113 @@
+//! The faults injected for chaos testing, which only exist with the `fault-injection`
+//! feature: `cargo test --features fault-injection`.
+
+#![cfg(feature = "fault-injection")]
+
+mod common;
+
+use std::time::{ Duration, Instant };
+use poem::{ Endpoint, Request, handler, http::StatusCode };
+use poem_proxy::{ FaultInjection, ProxyConfig, ProxyEndpoint };
+use common::{ client, serve, serve_proxy };
+
+const REQUESTS: usize = 400;
+
+#[handler]
+fn ok() -> &'static str {
+    "ok"
+}
+
+/// Serves a proxy to a backend that always answers, with the given faults injected.
+async fn faulty_proxy( faults: &FaultInjection ) -> String {
+    let backend = serve( ok ).await;
+    serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().fault_injection( faults ).finish() ).await
+}
+
+/// Sends a request and reads the whole response, returning its status, or the error if
+/// the connection was dropped along the way.
+async fn fetch( client: &reqwest::Client, url: &str ) -> reqwest::Result<StatusCode> {
+    let res = client.get( url ).send().await?;
+    let status = res.status();
+    res.bytes().await?;
+    Ok( status )
+}
+
+/// Asserts that `hits` out of [REQUESTS] is close enough to the configured `rate`. The
+/// band is about four standard deviations wide on either side for a rate of `0.3`.
+fn assert_rate( hits: usize, rate: f64 ) {
+    let realized = hits as f64 / REQUESTS as f64;
+    assert!( ( realized - rate ).abs() < 0.1, "{} of {} requests were faulted, expected about {}", hits, REQUESTS, rate );
+}
+
+#[tokio::test]
+async fn injects_errors_at_the_configured_rate() {
+    let proxy = faulty_proxy( FaultInjection::new().error( 0.3, StatusCode::IM_A_TEAPOT ) ).await;
+    let client = client();
+
+    let mut errors = 0;
+    for _ in 0..REQUESTS {
+        match fetch( &client, &proxy ).await.unwrap() {
+            StatusCode::IM_A_TEAPOT => errors += 1,
+            status => assert_eq!( status, StatusCode::OK ),
+        }
+    }
+    assert_rate( errors, 0.3 );
+}
+
+#[tokio::test]
+async fn drops_connections_at_the_configured_rate() {
+    let proxy = faulty_proxy( FaultInjection::new().drop_connection( 0.3 ) ).await;
+    let client = client();
+
+    let mut dropped = 0;
+    for _ in 0..REQUESTS {
+        match fetch( &client, &proxy ).await {
+            Ok( status ) => assert_eq!( status, StatusCode::OK ),
+            Err( _ ) => dropped += 1,
+        }
+    }
+    assert_rate( dropped, 0.3 );
+}
+
+#[tokio::test]
+async fn dropped_connections_never_complete_a_response() {
+    let proxy = faulty_proxy( FaultInjection::new().drop_connection( 1.0 ) ).await;
+    let client = client();
+
+    for _ in 0..10 {
+        assert!( fetch( &client, &proxy ).await.is_err() );
+    }
+}
+
+#[tokio::test]
+async fn delays_requests() {
+    let proxy = faulty_proxy( FaultInjection::new().delay( 1.0, Duration::from_millis( 200 ) ) ).await;
+
+    let started = Instant::now();
+    assert_eq!( fetch( &client(), &proxy ).await.unwrap(), StatusCode::OK );
+    assert!( started.elapsed() >= Duration::from_millis( 200 ) );
+}
+
+#[tokio::test]
+#[should_panic( expected = "poem-proxy: could not build the http client: injected fault" )]
+async fn unexpected_conditions_panic_in_strict_mode() {
+    let backend = serve( ok ).await;
+    let endpoint = ProxyEndpoint::new( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .strict_mode()
+        .fault_injection( FaultInjection::new().client_failure( 1.0 ) )
+        .finish() );
+
+    // Called directly, so the panic isn't caught by the server
+    let _ = endpoint.call( Request::builder().uri_str( "/" ).finish() ).await;
+}
+
+#[tokio::test]
+async fn unexpected_conditions_are_answered_with_an_error_otherwise() {
+    let proxy = faulty_proxy( FaultInjection::new().client_failure( 1.0 ) ).await;
+    let client = client();
+
+    for _ in 0..3 {
+        assert_eq!( fetch( &client, &proxy ).await.unwrap(), StatusCode::INTERNAL_SERVER_ERROR );
+    }
+}
@@ -0,0 +1,615 @@
+//! The headers the proxy forwards, adds and rewrites on the way to the proxied server
+//! and back.
+
+mod common;
+
+use std::sync::{ Arc, atomic::{ AtomicUsize, Ordering } };
+use poem::{ EndpointExt, IntoResponse, Request, Response, endpoint::{ make, make_sync }, handler, http::{ HeaderName, HeaderValue, StatusCode }, web::{ Data, websocket::WebSocket } };
+use poem_proxy::{ CookiePolicy, HeaderRewrite, MissingHostPolicy, NonAsciiHeaderPolicy, ProxyConfig, SameSite };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpStream, sync::mpsc };
+use common::{ client, echo, echoed, raw_backend, raw_backend_answering, send_raw, serve, serve_proxy };
+
+/// Cookies as a careless backend might set them, with a mix of attributes.
+const COOKIES: [ &str; 6 ] = [
+    "session=abc123",
+    "theme=dark; Path=/; secure",
+    "lang=en; SameSite=Strict; Max-Age=3600",
+    "token=a=b=c; HttpOnly; Secure; SameSite=none",
+    "empty=; Path=/account",
+    "tracking=x%3By; samesite=Lax; httponly",
+];
+
+/// Serves a backend that sets every one of [COOKIES] on each response.
+async fn cookie_backend() -> String {
+    serve( make_sync( |_: Request| {
+        COOKIES.iter().fold( Response::builder(), |res, cookie| res.header( "set-cookie", *cookie ) ).body( "ok" )
+    })).await.to_string()
+}
+
+/// Returns the cookies set by a response to a request through the proxy.
+async fn cookies_through( proxy: &str ) -> Vec<String> {
+    let res = client().get( proxy ).send().await.unwrap();
+    res.headers().get_all( "set-cookie" ).iter().map( |value| value.to_str().unwrap().to_owned() ).collect()
+}
+
+/// Returns the attributes of a cookie, in order, with the names lowercased.
+fn attributes( cookie: &str ) -> Vec<String> {
+    cookie.split( ';' ).skip( 1 ).map( |attribute| {
+        let attribute = attribute.trim();
+        match attribute.split_once( '=' ) {
+            Some( ( name, value ) ) => format!( "{}={}", name.to_ascii_lowercase(), value ),
+            None => attribute.to_ascii_lowercase(),
+        }
+    }).collect()
+}
+
+#[tokio::test]
+async fn forwards_every_cookie_as_it_was_set() {
+    let proxy = serve_proxy( ProxyConfig::new( cookie_backend().await ).web_insecure().finish() ).await;
+
+    assert_eq!( cookies_through( &proxy ).await, COOKIES );
+}
+
+#[tokio::test]
+async fn normalizes_cookie_attributes_while_keeping_names_and_values() {
+    let proxy = serve_proxy( ProxyConfig::new( cookie_backend().await )
+        .web_insecure()
+        .cookie_policy( CookiePolicy::new().secure().http_only().same_site( SameSite::Lax ) )
+        .finish() ).await;
+
+    let cookies = cookies_through( &proxy ).await;
+    assert_eq!( cookies.len(), COOKIES.len() );
+    for ( cookie, original ) in cookies.iter().zip( COOKIES ) {
+        assert_eq!( cookie.split( ';' ).next(), original.split( ';' ).next(), "{}", cookie );
+
+        let attributes = attributes( cookie );
+        let count = |name: &str| attributes.iter().filter( |a| a.split( '=' ).next() == Some( name ) ).count();
+        assert_eq!( count( "secure" ), 1, "{}", cookie );
+        assert_eq!( count( "httponly" ), 1, "{}", cookie );
+        assert_eq!( count( "samesite" ), 1, "{}", cookie );
+        assert!( attributes.contains( &"samesite=Lax".to_owned() ), "{}", cookie );
+    }
+
+    // Attributes the policy doesn't cover are left alone
+    assert!( attributes( &cookies[ 2 ] ).contains( &"max-age=3600".to_owned() ) );
+    assert!( attributes( &cookies[ 4 ] ).contains( &"path=/account".to_owned() ) );
+}
+
+#[tokio::test]
+async fn same_site_none_cookies_are_made_secure() {
+    let proxy = serve_proxy( ProxyConfig::new( cookie_backend().await )
+        .web_insecure()
+        .cookie_policy( CookiePolicy::new().same_site( SameSite::None ) )
+        .finish() ).await;
+
+    for cookie in cookies_through( &proxy ).await {
+        let attributes = attributes( &cookie );
+        assert!( attributes.contains( &"samesite=None".to_owned() ), "{}", cookie );
+        assert_eq!( attributes.iter().filter( |a| *a == "secure" ).count(), 1, "{}", cookie );
+    }
+}
+
+/// Returns the parts of a `traceparent` header.
+fn trace_parts( traceparent: &serde_json::Value ) -> Vec<String> {
+    traceparent.as_str().expect( "a traceparent" ).split( '-' ).map( str::to_owned ).collect()
+}
+
+#[tokio::test]
+async fn continues_incoming_traces_as_the_parent_of_the_proxied_server() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().propagate_trace_context().finish() ).await;
+
+    let seen = echoed( client().get( &proxy )
+        .header( "traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01" )
+        .header( "tracestate", "vendor=opaque" ) ).await;
+
+    // Same trace and flags, with the proxy's own span
+    let parts = trace_parts( &seen[ "headers" ][ "traceparent" ] );
+    assert_eq!( parts[ 0 ], "00" );
+    assert_eq!( parts[ 1 ], "0af7651916cd43dd8448eb211c80319c" );
+    assert_ne!( parts[ 2 ], "b7ad6b7169203331" );
+    assert_eq!( parts[ 2 ].len(), 16 );
+    assert_eq!( parts[ 3 ], "01" );
+    assert_eq!( seen[ "headers" ][ "tracestate" ], "vendor=opaque" );
+}
+
+#[tokio::test]
+async fn starts_a_trace_when_there_is_no_valid_one() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().propagate_trace_context().finish() ).await;
+
+    let seen = echoed( client().get( &proxy ) ).await;
+    let parts = trace_parts( &seen[ "headers" ][ "traceparent" ] );
+    assert_eq!( parts.len(), 4 );
+    assert_eq!( ( parts[ 1 ].len(), parts[ 2 ].len(), parts[ 3 ].as_str() ), ( 32, 16, "01" ) );
+
+    // A malformed trace is replaced, along with the state that belonged to it
+    let seen = echoed( client().get( &proxy )
+        .header( "traceparent", "00-00000000000000000000000000000000-b7ad6b7169203331-01" )
+        .header( "tracestate", "vendor=opaque" ) ).await;
+    assert_ne!( trace_parts( &seen[ "headers" ][ "traceparent" ] )[ 1 ], "00000000000000000000000000000000" );
+    assert!( seen[ "headers" ].get( "tracestate" ).is_none() );
+}
+
+#[tokio::test]
+async fn leaves_trace_context_alone_unless_asked() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+
+    let seen = echoed( client().get( &proxy ) ).await;
+    assert!( seen[ "headers" ].get( "traceparent" ).is_none() );
+}
+
+#[tokio::test]
+async fn forwards_extension_methods_as_they_were_sent() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().enable_nesting().finish() ).await;
+
+    for method in [ "PROPFIND", "FOOBAR", "fooBar" ] {
+        let method = reqwest::Method::from_bytes( method.as_bytes() ).unwrap();
+        let seen = echoed( client().request( method.clone(), format!( "{}/files/a", proxy ) )
+            .header( "depth", "1" )
+            .body( "<propfind/>" ) ).await;
+        assert_eq!( seen[ "method" ], method.as_str() );
+        assert_eq!( seen[ "uri" ], "/files/a" );
+        assert_eq!( seen[ "headers" ][ "depth" ], "1" );
+        assert_eq!( seen[ "headers" ][ "content-length" ], "11" );
+    }
+}
+
+#[tokio::test]
+async fn forwards_every_standard_method_with_its_body() {
+    let backend = serve( make( |mut req: Request| async move {
+        let body = req.take_body().into_string().await.unwrap();
+        Response::builder()
+            .header( "allow", "GET, PUT, PATCH, DELETE, HEAD, OPTIONS" )
+            .body( format!( "{} {}", req.method(), body ) )
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+    let client = client();
+
+    for method in [ "GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS" ] {
+        let method = reqwest::Method::from_bytes( method.as_bytes() ).unwrap();
+        let res = client.request( method.clone(), &proxy ).body( "payload" ).send().await.unwrap();
+        assert_eq!( res.status(), StatusCode::OK );
+        assert_eq!( res.headers()[ "allow" ], "GET, PUT, PATCH, DELETE, HEAD, OPTIONS" );
+        assert_eq!( res.text().await.unwrap(), format!( "{} payload", method ) );
+    }
+
+    // HEAD gets the length of the body it would have had, without the body
+    let res = client.head( &proxy ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.headers()[ "content-length" ], "5" );
+    assert!( res.bytes().await.unwrap().is_empty() );
+}
+
+#[tokio::test]
+async fn rejects_header_values_with_control_characters() {
+    let seen = Arc::new( AtomicUsize::new( 0 ) );
+    let count = seen.clone();
+    let backend = serve( make_sync( move |_: Request| {
+        count.fetch_add( 1, Ordering::SeqCst );
+        "ok"
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+
+    let request = |value: &str| format!( "GET / HTTP/1.1\r\nHost: proxy\r\nX-Note: {}\r\nConnection: close\r\n\r\n", value );
+    assert!( send_raw( &proxy, request( "a\x01b" ).as_bytes() ).await.starts_with( "HTTP/1.1 400 Bad Request\r\n" ) );
+    assert!( send_raw( &proxy, request( "a\rX-Injected: yes" ).as_bytes() ).await.starts_with( "HTTP/1.1 400 Bad Request\r\n" ) );
+    assert_eq!( seen.load( Ordering::SeqCst ), 0 );
+
+    assert!( send_raw( &proxy, request( "a normal value" ).as_bytes() ).await.starts_with( "HTTP/1.1 200 OK\r\n" ) );
+    assert_eq!( seen.load( Ordering::SeqCst ), 1 );
+}
+
+#[tokio::test]
+async fn bridges_mixed_case_headers_to_http2_servers() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().upstream_http2().finish() ).await;
+
+    let response = send_raw( &proxy, concat!(
+        "GET / HTTP/1.1\r\n",
+        "Host: proxy\r\n",
+        "X-Request-ID: 42\r\n",
+        "Accept-LANGUAGE: en\r\n",
+        "Keep-Alive: timeout=5\r\n",
+        "X-Hop: 1\r\n",
+        "Connection: close, X-Hop\r\n",
+        "\r\n",
+    ).as_bytes() ).await;
+    assert!( response.starts_with( "HTTP/1.1 200 OK\r\n" ), "{}", response );
+    let seen: serde_json::Value = serde_json::from_str( response.split( "\r\n\r\n" ).nth( 1 ).unwrap() ).unwrap();
+
+    // The names arrive in lowercase over HTTP/2, without the connection's own headers
+    assert_eq!( seen[ "version" ], "HTTP/2.0" );
+    let headers = seen[ "headers" ].as_object().unwrap();
+    assert_eq!( headers[ "x-request-id" ], "42" );
+    assert_eq!( headers[ "accept-language" ], "en" );
+    for name in [ "connection", "keep-alive", "x-hop" ] {
+        assert!( !headers.contains_key( name ), "{} was forwarded", name );
+    }
+}
+
+#[tokio::test]
+async fn checks_that_responses_are_of_an_accepted_type_when_asked() {
+    let backend = serve( make_sync( |_: Request| Response::builder().content_type( "application/json; charset=utf-8" ).body( "{}" ) ) ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .validate_content_negotiation( StatusCode::NOT_ACCEPTABLE )
+        .finish() ).await;
+    let status = |accept: &'static str| {
+        let req = client().get( &proxy ).header( "accept", accept );
+        async move { req.send().await.unwrap().status() }
+    };
+
+    assert_eq!( status( "application/json" ).await, StatusCode::OK );
+    assert_eq!( status( "text/html, application/*;q=0.5" ).await, StatusCode::OK );
+    assert_eq!( status( "text/html" ).await, StatusCode::NOT_ACCEPTABLE );
+    assert_eq!( status( "application/json;q=0, text/html" ).await, StatusCode::NOT_ACCEPTABLE );
+}
+
+/// Returns the names of the headers in a raw request head, in the order they were sent.
+fn header_names( head: &str ) -> Vec<String> {
+    head.split( "\r\n" )
+        .skip( 1 )
+        .filter_map( |line| line.split_once( ':' ) )
+        .map( |( name, _ )| name.to_ascii_lowercase() )
+        .collect()
+}
+
+#[tokio::test]
+async fn forwards_headers_in_the_order_the_client_sent_them() {
+    let ( backend, mut seen ) = raw_backend().await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.clone() )
+        .web_insecure()
+        .rewrite_headers( &backend, HeaderRewrite::new().remove( HeaderName::from_static( "x-internal" ) ) )
+        .finish() ).await;
+
+    let response = send_raw( &proxy, concat!(
+        "GET / HTTP/1.1\r\n",
+        "Host: proxy\r\n",
+        "X-Zulu: 1\r\n",
+        "Accept: */*\r\n",
+        "X-Internal: secret\r\n",
+        "X-Alpha: 2\r\n",
+        "X-Zulu: 3\r\n",
+        "User-Agent: test\r\n",
+        "X-Mike: 4\r\n",
+        "Connection: close\r\n",
+        "\r\n",
+    ).as_bytes() ).await;
+    assert!( response.starts_with( "HTTP/1.1 200 OK\r\n" ), "{}", response );
+
+    // The client's headers come through in its order, even with one removed from the
+    // middle, and those the proxy adds itself follow them
+    let sent = [ "host", "x-zulu", "x-zulu", "accept", "x-alpha", "user-agent", "x-mike" ];
+    let forwarded = header_names( &seen.recv().await.unwrap() );
+    assert_eq!( forwarded[ ..sent.len() ], sent );
+    assert!( forwarded[ sent.len().. ].iter().all( |name| name.starts_with( "x-forwarded-" ) ), "{:?}", forwarded );
+}
+
+#[tokio::test]
+async fn names_the_target_as_the_host_of_requests_without_one() {
+    let ( backend, mut seen ) = raw_backend().await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.clone() ).web_insecure().finish() ).await;
+
+    let response = send_raw( &proxy, b"GET /old HTTP/1.0\r\n\r\n" ).await;
+    assert!( response.starts_with( "HTTP/1.0 200 OK\r\n" ), "{}", response );
+    let head = seen.recv().await.unwrap();
+    assert!( head.contains( &format!( "\r\nhost: {}\r\n", backend ) ), "{}", head );
+}
+
+#[tokio::test]
+async fn rejects_requests_without_a_host_when_asked() {
+    let ( backend, mut seen ) = raw_backend().await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().missing_host( MissingHostPolicy::Reject ).finish() ).await;
+
+    let response = send_raw( &proxy, b"GET /old HTTP/1.0\r\n\r\n" ).await;
+    assert!( response.starts_with( "HTTP/1.0 400 Bad Request\r\n" ), "{}", response );
+    assert!( seen.try_recv().is_err() );
+
+    // Requests with a host are forwarded as usual
+    let response = send_raw( &proxy, b"GET /old HTTP/1.0\r\nHost: proxy\r\n\r\n" ).await;
+    assert!( response.starts_with( "HTTP/1.0 200 OK\r\n" ), "{}", response );
+    assert!( seen.recv().await.unwrap().contains( "\r\nhost: proxy\r\n" ) );
+}
+
+#[tokio::test]
+async fn tells_the_server_which_http_version_the_client_spoke() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .forward_http_version( HeaderName::from_static( "x-forwarded-http-version" ) )
+        .finish() ).await;
+    let http2 = reqwest::Client::builder().no_proxy().http2_prior_knowledge().build().unwrap();
+
+    let seen = echoed( http2.get( &proxy ) ).await;
+    assert_eq!( seen[ "headers" ][ "x-forwarded-http-version" ], "HTTP/2" );
+
+    // Whatever the client claims
+    let seen = echoed( client().get( &proxy ).header( "x-forwarded-http-version", "HTTP/2" ) ).await;
+    assert_eq!( seen[ "headers" ][ "x-forwarded-http-version" ], "HTTP/1.1" );
+}
+
+/// A response carrying every kind of hop-by-hop header, and one named by `Connection`.
+const HOP_BY_HOP_RESPONSE: &str = concat!(
+    "HTTP/1.1 200 OK\r\n",
+    "content-length: 2\r\n",
+    "keep-alive: timeout=5\r\n",
+    "proxy-authenticate: Basic realm=\"proxy\"\r\n",
+    "x-hop: 1\r\n",
+    "x-end-to-end: 2\r\n",
+    "connection: keep-alive, x-hop\r\n",
+    "\r\n",
+    "ok",
+);
+
+/// A request carrying every kind of hop-by-hop header, and one named by `Connection`.
+const HOP_BY_HOP_REQUEST: &str = concat!(
+    "GET / HTTP/1.1\r\n",
+    "host: proxy\r\n",
+    "accept: */*\r\n",
+    "te: trailers\r\n",
+    "keep-alive: timeout=5\r\n",
+    "upgrade: h2c\r\n",
+    "proxy-authorization: Basic cHJveHk6c2VjcmV0\r\n",
+    "proxy-connection: keep-alive\r\n",
+    "x-hop: 1\r\n",
+    "x-end-to-end: 2\r\n",
+    "connection: close, x-hop\r\n",
+    "\r\n",
+);
+
+#[tokio::test]
+async fn removes_hop_by_hop_headers_in_both_directions() {
+    let ( backend, mut seen ) = raw_backend_answering( HOP_BY_HOP_RESPONSE ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().finish() ).await;
+
+    let response = send_raw( &proxy, HOP_BY_HOP_REQUEST.as_bytes() ).await;
+    assert_eq!( seen.recv().await.unwrap(), concat!(
+        "GET / HTTP/1.1\r\n",
+        "host: proxy\r\n",
+        "accept: */*\r\n",
+        "x-end-to-end: 2\r\n",
+        "x-forwarded-for: 127.0.0.1\r\n",
+        "x-forwarded-proto: http\r\n",
+        "x-forwarded-host: proxy\r\n",
+        "\r\n",
+    ) );
+
+    let head = response.split( "\r\n\r\n" ).next().unwrap().to_ascii_lowercase();
+    assert!( head.contains( "\r\nx-end-to-end: 2" ), "{}", head );
+    for name in [ "keep-alive", "proxy-authenticate", "x-hop" ] {
+        assert!( !head.contains( &format!( "\r\n{}:", name ) ), "{} was forwarded:\n{}", name, head );
+    }
+}
+
+#[tokio::test]
+async fn only_removes_what_connection_names_when_transparent() {
+    let ( backend, mut seen ) = raw_backend_answering( HOP_BY_HOP_RESPONSE ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend ).web_insecure().transparent().finish() ).await;
+
+    let response = send_raw( &proxy, HOP_BY_HOP_REQUEST.as_bytes() ).await;
+    assert_eq!( seen.recv().await.unwrap(), concat!(
+        "GET / HTTP/1.1\r\n",
+        "host: proxy\r\n",
+        "accept: */*\r\n",
+        "te: trailers\r\n",
+        "keep-alive: timeout=5\r\n",
+        "upgrade: h2c\r\n",
+        "proxy-authorization: Basic cHJveHk6c2VjcmV0\r\n",
+        "proxy-connection: keep-alive\r\n",
+        "x-end-to-end: 2\r\n",
+        "\r\n",
+    ) );
+
+    let head = response.split( "\r\n\r\n" ).next().unwrap().to_ascii_lowercase();
+    assert!( head.contains( "\r\nproxy-authenticate: basic realm=\"proxy\"" ), "{}", head );
+    assert!( !head.contains( "\r\nx-hop:" ), "{}", head );
+}
+
+#[tokio::test]
+async fn tells_the_proxied_server_about_the_client() {
+    let backend = serve( echo ).await.to_string();
+    let proxy = serve_proxy( ProxyConfig::new( backend.clone() ).web_insecure().finish() ).await;
+    let host = proxy.trim_start_matches( "http://" );
+
+    let seen = echoed( client().get( &proxy ) ).await;
+    assert_eq!( seen[ "headers" ][ "x-forwarded-for" ], "127.0.0.1" );
+    assert_eq!( seen[ "headers" ][ "x-forwarded-proto" ], "http" );
+    assert_eq!( seen[ "headers" ][ "x-forwarded-host" ], host );
+
+    // Earlier hops are kept, but an untrusted client can't claim another scheme or host
+    let spoofed = |url: &str| client().get( url )
+        .header( "x-forwarded-for", "203.0.113.7" )
+        .header( "x-forwarded-proto", "https" )
+        .header( "x-forwarded-host", "example.com" );
+    let seen = echoed( spoofed( &proxy ) ).await;
+    assert_eq!( seen[ "headers" ][ "x-forwarded-for" ], "203.0.113.7, 127.0.0.1" );
+    assert_eq!( seen[ "headers" ][ "x-forwarded-proto" ], "http" );
+    assert_eq!( seen[ "headers" ][ "x-forwarded-host" ], host );
+
+    let trusting = serve_proxy( ProxyConfig::new( backend.clone() ).web_insecure()
+        .trusted_proxies( [ "127.0.0.1".parse().unwrap() ] ).finish() ).await;
+    let seen = echoed( spoofed( &trusting ) ).await;
+    assert_eq!( seen[ "headers" ][ "x-forwarded-for" ], "203.0.113.7, 127.0.0.1" );
+    assert_eq!( seen[ "headers" ][ "x-forwarded-proto" ], "https" );
+    assert_eq!( seen[ "headers" ][ "x-forwarded-host" ], "example.com" );
+
+    let disabled = serve_proxy( ProxyConfig::new( backend ).web_insecure().disable_forwarded_headers().finish() ).await;
+    let seen = echoed( client().get( &disabled ) ).await;
+    for name in [ "x-forwarded-for", "x-forwarded-proto", "x-forwarded-host" ] {
+        assert!( seen[ "headers" ].get( name ).is_none(), "{} was added", name );
+    }
+}
+
+#[tokio::test]
+async fn gives_responses_without_a_content_type_the_default_for_their_path() {
+    let backend = serve( make_sync( |req: Request| match req.uri().path() {
+        "/files/typed.csv" => Response::builder().header( "content-type", "text/csv" ).body( "a,b" ),
+        "/files/none" => Response::builder().status( StatusCode::NO_CONTENT ).finish(),
+        _ => Response::builder().body( "untyped" ),
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .default_content_type( "/", HeaderValue::from_static( "text/plain; charset=utf-8" ) )
+        .default_content_type( "/files", HeaderValue::from_static( "application/octet-stream" ) )
+        .finish() ).await;
+    let client = client();
+    let get = |path: &str| client.get( format!( "{}{}", proxy, path ) ).send();
+
+    for ( path, content_type ) in [ ( "/index", "text/plain; charset=utf-8" ), ( "/files/report", "application/octet-stream" ) ] {
+        let res = get( path ).await.unwrap();
+        assert_eq!( res.headers()[ "content-type" ], content_type, "{}", path );
+        assert_eq!( res.headers()[ "x-content-type-options" ], "nosniff", "{}", path );
+        assert_eq!( res.text().await.unwrap(), "untyped" );
+    }
+
+    // The proxied server's own type, and responses without a body, are left alone
+    let res = get( "/files/typed.csv" ).await.unwrap();
+    assert_eq!( res.headers()[ "content-type" ], "text/csv" );
+    assert!( res.headers().get( "x-content-type-options" ).is_none() );
+    let res = get( "/files/none" ).await.unwrap();
+    assert!( res.headers().get( "content-type" ).is_none() );
+    assert!( res.headers().get( "x-content-type-options" ).is_none() );
+}
+
+#[tokio::test]
+async fn honors_max_forwards_on_trace_and_options() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+    let client = client();
+    let request = |method: &[u8], max_forwards: &str| client.request( reqwest::Method::from_bytes( method ).unwrap(), &proxy )
+        .header( "max-forwards", max_forwards )
+        .header( "authorization", "Bearer s3cr3t" );
+
+    // With forwards left, the count is lowered on the way
+    assert_eq!( echoed( request( b"OPTIONS", "5" ) ).await[ "headers" ][ "max-forwards" ], "4" );
+    assert_eq!( echoed( request( b"TRACE", "1" ) ).await[ "headers" ][ "max-forwards" ], "0" );
+
+    // Without, the proxy is the last stop
+    let res = request( b"OPTIONS", "0" ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.text().await.unwrap(), "" );
+    let res = request( b"TRACE", "0" ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    let trace = res.text().await.unwrap();
+    assert!( trace.starts_with( "TRACE / HTTP/1.1\r\n" ), "{}", trace );
+    assert!( trace.contains( "max-forwards: 0\r\n" ), "{}", trace );
+    assert!( !trace.contains( "s3cr3t" ), "{}", trace );
+
+    // Other methods don't count forwards
+    assert_eq!( echoed( request( b"GET", "0" ) ).await[ "headers" ][ "max-forwards" ], "0" );
+}
+
+#[tokio::test]
+async fn tells_clients_how_long_each_phase_of_the_request_took() {
+    let backend = serve( make( |_: Request| async {
+        tokio::time::sleep( std::time::Duration::from_millis( 150 ) ).await;
+        Response::builder().header( "server-timing", "db;dur=12" ).header( "timing-allow-origin", "*" ).body( "slow" )
+    })).await;
+    let timed = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .server_timing()
+        .timing_allow_origin( HeaderValue::from_static( "https://app.example.com" ) )
+        .finish() ).await;
+    // The first request also waits for the proxy's http client to be built
+    client().get( &timed ).send().await.unwrap();
+    let res = client().get( &timed ).send().await.unwrap();
+
+    // The server's own timings are kept alongside the proxy's
+    let timings = res.headers().get_all( "server-timing" ).iter().map( |value| value.to_str().unwrap().to_owned() ).collect::<Vec<_>>();
+    assert_eq!( timings[0], "db;dur=12" );
+    let phases = timings[1].split( ", " )
+        .map( |phase| {
+            let ( name, duration ) = phase.split_once( ";dur=" ).unwrap();
+            ( name, duration.parse::<f64>().unwrap() )
+        })
+        .collect::<Vec<_>>();
+    let names = phases.iter().map( |( name, _ )| *name ).collect::<Vec<_>>();
+    assert_eq!( names, [ "proxy", "ttfb", "total" ] );
+    let ( proxy, ttfb, total ) = ( phases[0].1, phases[1].1, phases[2].1 );
+    assert!( ( 0.0..50.0 ).contains( &proxy ), "{:?}", phases );
+    assert!( ( 150.0..1000.0 ).contains( &ttfb ), "{:?}", phases );
+    assert!( total + 0.2 >= proxy + ttfb && total < 1000.0, "{:?}", phases );
+    assert_eq!( res.headers()[ "timing-allow-origin" ], "https://app.example.com" );
+
+    // Nothing is added unless asked for
+    let untimed = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().finish() ).await;
+    let res = client().get( &untimed ).send().await.unwrap();
+    assert_eq!( res.headers().get_all( "server-timing" ).iter().collect::<Vec<_>>(), [ "db;dur=12" ] );
+    assert_eq!( res.headers()[ "timing-allow-origin" ], "*" );
+}
+
+/// A websocket backend that reports the `x-city` header of each handshake.
+#[handler]
+fn city_backend( req: &Request, ws: WebSocket, seen: Data<&mpsc::UnboundedSender<Option<Vec<u8>>>> ) -> impl IntoResponse {
+    let _ = seen.send( req.headers().get( "x-city" ).map( |value| value.as_bytes().to_vec() ) );
+    ws.on_upgrade( |_| async {} )
+}
+
+/// Opens a websocket to `proxy` with `x-city` set to a Latin-1 value, returning the head of
+/// the response and the connection.
+async fn city_handshake( proxy: String ) -> ( String, TcpStream ) {
+    let mut socket = TcpStream::connect( proxy ).await.unwrap();
+    socket.write_all( b"GET / HTTP/1.1\r\nhost: localhost\r\nupgrade: websocket\r\nconnection: upgrade\r\n\
+        sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\nsec-websocket-version: 13\r\nx-city: Montr\xe9al\r\n\r\n" ).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with( b"\r\n\r\n" ) {
+        let mut byte = [ 0 ];
+        socket.read_exact( &mut byte ).await.unwrap();
+        head.push( byte[0] );
+    }
+    ( String::from_utf8( head ).unwrap(), socket )
+}
+
+#[tokio::test]
+async fn deals_with_header_values_that_are_not_ascii_as_configured() {
+    let backend = serve( echo ).await;
+    let ( seen, mut handshakes ) = mpsc::unbounded_channel::<Option<Vec<u8>>>();
+    let ws_backend = serve( city_backend.data( seen ) ).await;
+    let city = HeaderValue::from_bytes( b"Montr\xe9al" ).unwrap();
+    let config = |backend: String, policy| {
+        let mut config = ProxyConfig::new( backend );
+        config.web_insecure().ws_insecure().non_ascii_headers( policy );
+        config
+    };
+
+    // Web servers are sent what the policy leaves of the header, which the echo shows
+    // with the byte beyond ASCII replaced
+    for ( policy, expected ) in [
+        ( NonAsciiHeaderPolicy::Forward, Some( "Montr\u{fffd}al" ) ),
+        ( NonAsciiHeaderPolicy::Sanitize, Some( "Montr?al" ) ),
+        ( NonAsciiHeaderPolicy::Drop, None ),
+    ] {
+        let proxy = serve_proxy( config( backend.to_string(), policy ) ).await;
+        let seen = echoed( client().get( &proxy ).header( "x-city", city.clone() ).header( "x-country", "Canada" ) ).await;
+        assert_eq!( seen[ "headers" ][ "x-city" ].as_str(), expected, "{:?}", policy );
+        assert_eq!( seen[ "headers" ][ "x-country" ], "Canada" );
+    }
+    let proxy = serve_proxy( config( backend.to_string(), NonAsciiHeaderPolicy::Reject ) ).await;
+    let res = client().get( &proxy ).header( "x-city", city.clone() ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::BAD_REQUEST );
+
+    // Nor can the test's websocket client, so the handshakes are sent by hand. The proxy's
+    // can't either, so websockets only connect when the header is dealt with
+    for ( policy, expected ) in [ ( NonAsciiHeaderPolicy::Sanitize, Some( b"Montr?al".to_vec() ) ), ( NonAsciiHeaderPolicy::Drop, None ) ] {
+        let proxy = serve( poem_proxy::ProxyEndpoint::new( config( ws_backend.to_string(), policy ).finish() ) ).await;
+        let ( head, _ ) = city_handshake( proxy.to_string() ).await;
+        assert!( head.starts_with( "HTTP/1.1 101" ), "{}", head );
+        assert_eq!( handshakes.recv().await.unwrap(), expected, "{:?}", policy );
+    }
+
+    let proxy = serve( poem_proxy::ProxyEndpoint::new( config( ws_backend.to_string(), NonAsciiHeaderPolicy::Forward ).finish() ) ).await;
+    let ( head, mut socket ) = city_handshake( proxy.to_string() ).await;
+    assert!( head.starts_with( "HTTP/1.1 101" ), "{}", head );
+    let mut close = [ 0; 4 ];
+    socket.read_exact( &mut close ).await.unwrap();
+    assert_eq!( ( close[0], u16::from_be_bytes( [ close[2], close[3] ] ) ), ( 0x88, 1011 ) );
+
+    let proxy = serve( poem_proxy::ProxyEndpoint::new( config( ws_backend.to_string(), NonAsciiHeaderPolicy::Reject ).finish() ) ).await;
+    let ( head, _ ) = city_handshake( proxy.to_string() ).await;
+    assert!( head.starts_with( "HTTP/1.1 400" ), "{}", head );
+    assert!( handshakes.try_recv().is_err() );
+}
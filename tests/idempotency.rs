@@ -0,0 +1,55 @@
+//! Replaying the response to a write for retries that carry the same idempotency key.
+
+mod common;
+
+use std::{ sync::{ Arc, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use poem::{ Request, endpoint::make };
+use poem_proxy::ProxyConfig;
+use common::{ client, serve, serve_proxy };
+
+/// Serves a proxy with idempotency keys to a backend that takes `delay` to answer each
+/// write with how many it has seen, returning the proxy's url and that count.
+async fn idempotent_proxy( delay: Duration ) -> ( String, Arc<AtomicUsize> ) {
+    let writes = Arc::new( AtomicUsize::new( 0 ) );
+    let count = writes.clone();
+    let backend = serve( make( move |_: Request| {
+        let write = count.fetch_add( 1, Ordering::SeqCst ) + 1;
+        async move {
+            tokio::time::sleep( delay ).await;
+            format!( "write {}", write )
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .idempotency_keys( Duration::from_secs( 60 ) )
+        .finish() ).await;
+    ( proxy, writes )
+}
+
+/// Sends a `POST` through the proxy with the given idempotency key, returning the body
+/// of the response.
+async fn post( proxy: &str, key: &str ) -> String {
+    client().post( proxy ).header( "idempotency-key", key ).body( "data" ).send().await.unwrap().text().await.unwrap()
+}
+
+#[tokio::test]
+async fn answers_retries_with_the_first_response() {
+    let ( proxy, writes ) = idempotent_proxy( Duration::ZERO ).await;
+
+    assert_eq!( post( &proxy, "a" ).await, "write 1" );
+    assert_eq!( post( &proxy, "a" ).await, "write 1" );
+    assert_eq!( writes.load( Ordering::SeqCst ), 1 );
+
+    // Other keys are writes of their own
+    assert_eq!( post( &proxy, "b" ).await, "write 2" );
+    assert_eq!( writes.load( Ordering::SeqCst ), 2 );
+}
+
+#[tokio::test]
+async fn retries_wait_for_the_first_request_to_finish() {
+    let ( proxy, writes ) = idempotent_proxy( Duration::from_millis( 200 ) ).await;
+
+    let ( first, second ) = tokio::join!( post( &proxy, "a" ), post( &proxy, "a" ) );
+    assert_eq!( ( first.as_str(), second.as_str() ), ( "write 1", "write 1" ) );
+    assert_eq!( writes.load( Ordering::SeqCst ), 1 );
+}
@@ -0,0 +1,331 @@
+//! Limits on how much of the proxy a single client can take up.
+
+mod common;
+
+use std::{ net::{ IpAddr, Ipv4Addr }, sync::Arc, time::Duration };
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use poem::{ Body, Request, Response, endpoint::{ make, make_sync }, http::StatusCode };
+use poem_proxy::ProxyConfig;
+use common::{ client, serve, serve_proxy };
+
+/// Serves a proxy that lets each client have one request in flight, to a backend that
+/// takes a while to answer, trusting the local address as a proxy if `trusted`.
+async fn limited_proxy( trusted: bool ) -> String {
+    let backend = serve( make( |_: Request| async {
+        tokio::time::sleep( Duration::from_millis( 300 ) ).await;
+        "ok"
+    })).await;
+    let mut config = ProxyConfig::new( backend.to_string() );
+    config.web_insecure().max_connections_per_client( 1 );
+    if trusted {
+        config.trusted_proxies( [ IpAddr::V4( Ipv4Addr::LOCALHOST ) ] );
+    }
+    serve_proxy( config.finish() ).await
+}
+
+/// Sends two requests through the proxy at once, on behalf of the given clients,
+/// returning the statuses they got in order.
+async fn concurrently( proxy: &str, first: &str, second: &str ) -> Vec<StatusCode> {
+    let send = |forwarded_for: &str| client().get( proxy ).header( "x-forwarded-for", forwarded_for ).send();
+    let ( first, second ) = tokio::join!( send( first ), send( second ) );
+    let mut statuses = vec![ first.unwrap().status(), second.unwrap().status() ];
+    statuses.sort();
+    statuses
+}
+
+#[tokio::test]
+async fn turns_away_clients_over_their_limit() {
+    let proxy = limited_proxy( true ).await;
+
+    let res = tokio::join!(
+        client().get( &proxy ).header( "x-forwarded-for", "10.0.0.1" ).send(),
+        async {
+            tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+            client().get( &proxy ).header( "x-forwarded-for", "10.0.0.1" ).send().await
+        },
+    );
+    assert_eq!( res.0.unwrap().status(), StatusCode::OK );
+    let turned_away = res.1.unwrap();
+    assert_eq!( turned_away.status(), StatusCode::TOO_MANY_REQUESTS );
+    assert!( turned_away.headers().contains_key( "retry-after" ) );
+}
+
+#[tokio::test]
+async fn tells_clients_apart_by_the_address_a_trusted_proxy_forwarded() {
+    let proxy = limited_proxy( true ).await;
+    assert_eq!( concurrently( &proxy, "10.0.0.1", "10.0.0.2" ).await, [ StatusCode::OK, StatusCode::OK ] );
+
+    // The last address that isn't a trusted proxy is the client
+    assert_eq!( concurrently( &proxy, "10.0.0.3, 127.0.0.1", "10.0.0.3" ).await, [ StatusCode::OK, StatusCode::TOO_MANY_REQUESTS ] );
+}
+
+#[tokio::test]
+async fn ignores_forwarded_addresses_from_anyone_else() {
+    let proxy = limited_proxy( false ).await;
+    assert_eq!( concurrently( &proxy, "10.0.0.1", "10.0.0.2" ).await, [ StatusCode::OK, StatusCode::TOO_MANY_REQUESTS ] );
+}
+
+/// Sends two requests through the proxy at once, the second shortly after the first,
+/// returning the response to the second.
+async fn second_of_two( proxy: &str ) -> reqwest::Response {
+    let ( _, second ) = tokio::join!(
+        client().get( proxy ).send(),
+        async {
+            tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+            client().get( proxy ).send().await
+        },
+    );
+    second.unwrap()
+}
+
+#[tokio::test]
+async fn tells_clients_turned_away_when_to_retry() {
+    let backend = serve( make( |_: Request| async {
+        tokio::time::sleep( Duration::from_millis( 300 ) ).await;
+        "ok"
+    })).await;
+
+    // Over the limit of a single client, in whole seconds rounded up
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .max_connections_per_client( 1 )
+        .retry_after( Duration::from_millis( 2500 ) )
+        .finish() ).await;
+    let res = second_of_two( &proxy ).await;
+    assert_eq!( res.status(), StatusCode::TOO_MANY_REQUESTS );
+    assert_eq!( res.headers()[ "retry-after" ], "3" );
+
+    // Overloaded
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .overload_threshold( 1 )
+        .retry_after( Duration::from_secs( 7 ) )
+        .finish() ).await;
+    let res = second_of_two( &proxy ).await;
+    assert_eq!( res.status(), StatusCode::SERVICE_UNAVAILABLE );
+    assert_eq!( res.headers()[ "retry-after" ], "7" );
+
+    // Without a target to forward to, defaulting to 5 seconds
+    let config = ProxyConfig::new( backend.to_string() ).web_insecure().finish();
+    config.handle().drain_target( &backend.to_string() );
+    let res = client().get( serve_proxy( config ).await ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::SERVICE_UNAVAILABLE );
+    assert_eq!( res.headers()[ "retry-after" ], "5" );
+}
+
+#[tokio::test]
+async fn limits_request_bodies_by_the_size_picked_for_each_request() {
+    let backend = serve( make( |req: Request| async move {
+        req.into_body().into_bytes().await.unwrap().len().to_string()
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .max_body_size( 1024 )
+        .body_size_selector( |req| match req.uri().path() {
+            "/small" => Some( 16 ),
+            "/big" => Some( 1024 * 1024 ),
+            _ => None,
+        })
+        .finish() ).await;
+    let client = client();
+    let post = |path: &str, size: usize| client.post( format!( "{}{}", proxy, path ) ).body( vec![ b'x'; size ] ).send();
+
+    assert_eq!( post( "/small", 16 ).await.unwrap().text().await.unwrap(), "16" );
+    assert_eq!( post( "/small", 17 ).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE );
+
+    assert_eq!( post( "/big", 512 * 1024 ).await.unwrap().text().await.unwrap(), "524288" );
+    assert_eq!( post( "/big", 1024 * 1024 + 1 ).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE );
+
+    // Anywhere else the overall limit applies
+    assert_eq!( post( "/other", 1024 ).await.unwrap().status(), StatusCode::OK );
+    assert_eq!( post( "/other", 2048 ).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE );
+}
+
+#[tokio::test]
+async fn sheds_requests_past_the_overload_threshold() {
+    let gate = Arc::new( tokio::sync::Semaphore::new( 0 ) );
+    let held = gate.clone();
+    let backend = serve( make( move |_: Request| {
+        let held = held.clone();
+        async move {
+            held.acquire().await.unwrap().forget();
+            "ok"
+        }
+    })).await;
+    let config = ProxyConfig::new( backend.to_string() ).web_insecure().overload_threshold( 3 ).finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    // Saturate the proxy with requests the backend holds on to
+    let client = client();
+    let saturating: Vec<_> = ( 0..3 ).map( |_| tokio::spawn( client.get( &proxy ).send() ) ).collect();
+    while handle.total_active_requests() < 3 {
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+
+    let shed = client.get( &proxy ).send().await.unwrap();
+    assert_eq!( shed.status(), StatusCode::SERVICE_UNAVAILABLE );
+    assert!( shed.headers().contains_key( "retry-after" ) );
+    assert_eq!( handle.total_active_requests(), 3 );
+
+    // Once they are done, requests are let through again
+    gate.add_permits( 4 );
+    for request in saturating {
+        assert_eq!( request.await.unwrap().unwrap().status(), StatusCode::OK );
+    }
+    assert_eq!( client.get( &proxy ).send().await.unwrap().status(), StatusCode::OK );
+}
+
+#[tokio::test]
+async fn turns_away_request_bodies_over_the_limit_without_forwarding_them() {
+    let ( received, mut uploads ) = tokio::sync::mpsc::unbounded_channel();
+    let backend = serve( make( move |req: Request| {
+        let received = received.clone();
+        async move {
+            let body = req.into_body().into_bytes().await;
+            let _ = received.send( body.as_ref().map_or( "aborted".to_owned(), |body| body.len().to_string() ) );
+            body.map( |body| body.len().to_string() ).unwrap_or_default()
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().max_body_size( 1024 ).finish() ).await;
+    let client = client();
+    let chunked = |size: usize| {
+        let chunks = ( 0..size / 256 ).map( |_| Ok::<_, std::io::Error>( vec![ b'x'; 256 ] ) );
+        reqwest::Body::wrap_stream( futures_util::stream::iter( chunks.collect::<Vec<_>>() ) )
+    };
+
+    // Right at the limit is fine, whether the length is given or not
+    assert_eq!( client.post( &proxy ).body( vec![ b'x'; 1024 ] ).send().await.unwrap().text().await.unwrap(), "1024" );
+    assert_eq!( client.post( &proxy ).body( chunked( 1024 ) ).send().await.unwrap().text().await.unwrap(), "1024" );
+    assert_eq!( uploads.recv().await.unwrap(), "1024" );
+    assert_eq!( uploads.recv().await.unwrap(), "1024" );
+
+    // A body that says it is too large never reaches the proxied server
+    let res = client.post( &proxy ).body( vec![ b'x'; 1025 ] ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::PAYLOAD_TOO_LARGE );
+
+    // One that turns out to be is cut off on its way there
+    let res = client.post( &proxy ).body( chunked( 2048 ) ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::PAYLOAD_TOO_LARGE );
+    if let Ok( upload ) = tokio::time::timeout( Duration::from_millis( 200 ), uploads.recv() ).await {
+        assert_eq!( upload.unwrap(), "aborted" );
+    }
+    assert!( uploads.try_recv().is_err() );
+}
+
+#[tokio::test]
+async fn cuts_off_response_bodies_over_the_limit() {
+    let backend = serve( make_sync( |req: Request| {
+        let ( kind, size ) = req.uri().path()[ 1.. ].split_once( '/' ).unwrap();
+        let size = size.parse::<usize>().unwrap();
+        match kind {
+            "sized" => Response::builder().body( vec![ b'x'; size ] ),
+            _ => {
+                let chunks = ( 0..size ).step_by( 100 ).map( |_| Ok::<_, std::io::Error>( vec![ b'x'; 100 ] ) ).collect::<Vec<_>>();
+                Response::builder().body( Body::from_bytes_stream( futures_util::stream::iter( chunks ).map_ok( Bytes::from ) ) )
+            },
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .max_response_body_size( 1000 )
+        .stream_selector( |req| Some( !req.uri().path().starts_with( "/buffered" ) ) )
+        .finish() ).await;
+    let client = client();
+    let get = |path: &str| client.get( format!( "{}{}", proxy, path ) ).send();
+
+    for path in [ "/sized/1000", "/streamed/1000", "/buffered/1000" ] {
+        let res = get( path ).await.unwrap();
+        assert_eq!( res.status(), StatusCode::OK, "{}", path );
+        assert_eq!( res.bytes().await.unwrap().len(), 1000, "{}", path );
+    }
+
+    // Those known to be too large in time are turned away
+    for path in [ "/sized/1001", "/buffered/1100" ] {
+        assert_eq!( get( path ).await.unwrap().status(), StatusCode::BAD_GATEWAY, "{}", path );
+    }
+
+    // A streamed one is already under way, so it is cut off part way
+    let mut res = get( "/streamed/1100" ).await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    let mut received = 0;
+    let ended = loop {
+        match res.chunk().await {
+            Ok( Some( chunk ) ) => received += chunk.len(),
+            Ok( None ) => break Ok( () ),
+            Err( e ) => break Err( e ),
+        }
+    };
+    assert!( ended.is_err(), "the body ended normally after {} bytes", received );
+    assert!( received <= 1000, "{} bytes were relayed", received );
+}
+
+#[tokio::test]
+async fn tells_clients_turned_away_for_overload_their_place_in_line() {
+    let gate = Arc::new( tokio::sync::Semaphore::new( 0 ) );
+    let held = gate.clone();
+    let backend = serve( make( move |req: Request| {
+        let held = held.clone();
+        async move {
+            if req.uri().path() == "/held" {
+                held.acquire().await.unwrap().forget();
+            }
+            "ok"
+        }
+    })).await;
+    let config = ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .overload_threshold( 2 )
+        .overload_queue()
+        .retry_after( Duration::from_secs( 2 ) )
+        .trusted_proxies( [ IpAddr::V4( Ipv4Addr::LOCALHOST ) ] )
+        .finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    let client = client();
+    let send = |forwarded_for: &str| client.get( &proxy ).header( "x-forwarded-for", forwarded_for ).send();
+
+    // Saturate the proxy with requests the backend holds on to
+    let saturating: Vec<_> = ( 0..2 )
+        .map( |_| tokio::spawn( client.get( format!( "{}/held", proxy ) ).header( "x-forwarded-for", "10.0.0.9" ).send() ) )
+        .collect();
+    while handle.total_active_requests() < 2 {
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+
+    // Returns the position the client is told, and how long it is told to wait
+    let probe = |forwarded_for: &'static str| {
+        let res = send( forwarded_for );
+        async move {
+            let res = res.await.unwrap();
+            assert_eq!( res.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", forwarded_for );
+            let header = |name| res.headers()[ name ].to_str().unwrap().parse::<u64>().unwrap();
+            ( header( "x-queue-position" ), header( "retry-after" ) )
+        }
+    };
+    assert_eq!( probe( "10.0.0.1" ).await, ( 1, 2 ) );
+    assert_eq!( probe( "10.0.0.2" ).await, ( 2, 4 ) );
+    assert_eq!( probe( "10.0.0.3" ).await, ( 3, 6 ) );
+
+    // As room frees up, those ahead go first and the last one in line moves up
+    gate.add_permits( 1 );
+    while handle.total_active_requests() > 1 {
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+    assert_eq!( probe( "10.0.0.3" ).await, ( 3, 6 ) );
+    assert_eq!( send( "10.0.0.1" ).await.unwrap().status(), StatusCode::OK );
+    assert_eq!( probe( "10.0.0.3" ).await, ( 2, 4 ) );
+    assert_eq!( send( "10.0.0.2" ).await.unwrap().status(), StatusCode::OK );
+
+    // Newcomers wait behind those already in line, even with room to spare
+    assert_eq!( probe( "10.0.0.4" ).await, ( 2, 4 ) );
+    assert_eq!( send( "10.0.0.3" ).await.unwrap().status(), StatusCode::OK );
+    gate.add_permits( 1 );
+    for request in saturating {
+        assert_eq!( request.await.unwrap().unwrap().status(), StatusCode::OK );
+    }
+}
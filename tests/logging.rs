@@ -0,0 +1,118 @@
+//! What the proxy logs through `tracing`.
+
+mod common;
+
+use std::{ sync::{ Arc, Mutex }, time::Duration };
+use poem::{ Request, Response, endpoint::make_sync, http::{ HeaderName, StatusCode } };
+use poem_proxy::{ CaptureSink, CapturedExchange, ProxyConfig, TrafficCapture };
+use common::{ Logs, client, closed_port, serve, serve_proxy };
+
+/// A capture sink that keeps everything it is given.
+#[derive(Default)]
+struct KeptCaptures( Mutex<Vec<CapturedExchange>> );
+
+#[poem_proxy::async_trait]
+impl CaptureSink for KeptCaptures {
+    async fn capture( &self, exchange: CapturedExchange ) {
+        self.0.lock().unwrap().push( exchange );
+    }
+}
+
+#[test]
+fn logs_targets_whose_port_is_overridden() {
+    let ( logs, _guard ) = Logs::capture();
+    let config = ProxyConfig::new( "localhost:5173" ).add_target( "backend:8080" ).with_port( 9000 ).web_insecure().finish();
+
+    assert_eq!( config.get_web_request_uri( None ), Ok( "http://localhost:9000".into() ) );
+    assert_eq!( logs.events(), [
+        "DEBUG target port overridden target=localhost:5173 port=9000 given=5173",
+        "DEBUG target port overridden target=backend:8080 port=9000 given=8080",
+    ]);
+}
+
+#[test]
+fn says_nothing_without_a_conflict() {
+    let ( logs, _guard ) = Logs::capture();
+    ProxyConfig::new( "localhost" ).with_port( 9000 ).add_target( "backend" ).web_insecure().finish();
+    ProxyConfig::new( "localhost:5173" ).add_target( "backend" ).web_insecure().finish();
+
+    assert!( logs.events().is_empty(), "{:?}", logs.events() );
+}
+
+#[tokio::test]
+async fn records_the_upstream_status_on_the_request_span() {
+    let ( logs, _guard ) = Logs::capture();
+    let backend = serve( make_sync( |_: Request| Response::builder().status( StatusCode::CREATED ).body( "made" ) ) ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().enable_nesting().finish() ).await;
+
+    assert_eq!( client().post( format!( "{}/things", proxy ) ).send().await.unwrap().status(), StatusCode::CREATED );
+    assert_eq!( logs.span_fields( "proxy", "method" ), [ Some( "POST".to_owned() ) ] );
+    assert_eq!( logs.span_fields( "proxy", "path" ), [ Some( "/things".to_owned() ) ] );
+    assert_eq!( logs.span_fields( "proxy", "upstream" ), [ Some( format!( "http://{}/things", backend ) ) ] );
+    assert_eq!( logs.span_fields( "proxy", "upstream_status" ), [ Some( "201".to_owned() ) ] );
+    assert!( logs.events().iter().any( |event| event.starts_with( "INFO request handled status=201 elapsed_ms=" ) ), "{:?}", logs.events() );
+}
+
+#[tokio::test]
+async fn logs_requests_the_proxied_server_never_answered_as_errors() {
+    let ( logs, _guard ) = Logs::capture();
+    let proxy = serve_proxy( ProxyConfig::new( format!( "127.0.0.1:{}", closed_port().await ) ).web_insecure().finish() ).await;
+
+    assert_eq!( client().get( &proxy ).send().await.unwrap().status(), StatusCode::BAD_GATEWAY );
+    assert_eq!( logs.span_fields( "proxy", "upstream_status" ), [ None ] );
+    assert!( logs.events().iter().any( |event| event.starts_with( "ERROR request failed status=502" ) ), "{:?}", logs.events() );
+}
+
+#[tokio::test]
+async fn captures_a_sample_of_the_traffic_with_secrets_masked() {
+    let backend = serve( make_sync( |_: Request| Response::builder().header( "set-cookie", "session=abc" ).body( "response body" ) ) ).await;
+    let captures = Arc::new( KeptCaptures::default() );
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .capture_traffic( TrafficCapture::new( captures.clone() )
+            .sample_rate( 0.25 )
+            .max_body_size( 8 )
+            .redact( HeaderName::from_static( "x-api-key" ) ) )
+        .finish() ).await;
+
+    let client = client();
+    for _ in 0..400 {
+        let res = client.post( &proxy )
+            .header( "authorization", "Bearer token" )
+            .header( "x-api-key", "key" )
+            .header( "x-other", "visible" )
+            .body( "request body" )
+            .send().await.unwrap();
+        assert_eq!( res.text().await.unwrap(), "response body" );
+    }
+    tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+
+    // A quarter of 400 is 100, give or take a few standard deviations
+    let captures = captures.0.lock().unwrap();
+    assert!( ( 60..=140 ).contains( &captures.len() ), "{} captures", captures.len() );
+
+    let capture = &captures[ 0 ];
+    assert_eq!( capture.status, StatusCode::OK );
+    assert_eq!( capture.request_headers[ "authorization" ], "[REDACTED]" );
+    assert_eq!( capture.request_headers[ "x-api-key" ], "[REDACTED]" );
+    assert_eq!( capture.request_headers[ "x-other" ], "visible" );
+    assert_eq!( capture.response_headers[ "set-cookie" ], "[REDACTED]" );
+    assert_eq!( &capture.request_body[ .. ], b"request " );
+    assert_eq!( &capture.response_body[ .. ], b"response" );
+}
+
+#[tokio::test]
+async fn records_the_address_of_the_server_that_answered() {
+    let backend = serve( make_sync( |_: Request| "ok" ) ).await;
+    let captures = Arc::new( KeptCaptures::default() );
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .upstream_address_header( HeaderName::from_static( "x-upstream-addr" ) )
+        .capture_traffic( &TrafficCapture::new( captures.clone() ) )
+        .finish() ).await;
+
+    let res = client().get( &proxy ).send().await.unwrap();
+    assert_eq!( res.headers()[ "x-upstream-addr" ], backend.to_string().as_str() );
+    tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+    assert_eq!( captures.0.lock().unwrap()[ 0 ].upstream_addr, Some( backend ) );
+}
@@ -0,0 +1,137 @@
+//! What the proxy counts about the requests it forwards.
+
+mod common;
+
+use std::{ collections::HashMap, sync::{ Arc, Mutex }, time::Duration };
+use futures_util::{ SinkExt, StreamExt };
+use poem::{ IntoResponse, Request, Response, Route, endpoint::make, get, handler, http::StatusCode, web::websocket::WebSocket };
+use poem_proxy::{ ByteCount, ProxyConfig, ProxyHandle, ProxyMetrics };
+use tokio_tungstenite::{ connect_async, tungstenite };
+use common::{ client, serve, serve_proxy };
+
+#[tokio::test]
+async fn accounts_the_bytes_of_each_route() {
+    // Requests to the api are answered with their body twice over
+    let backend = serve( make( |req: Request| async move {
+        match req.uri().path() {
+            "/logo.svg" => vec![ b'<'; 10 ],
+            _ => req.into_body().into_bytes().await.unwrap().repeat( 2 ),
+        }
+    })).await;
+    let accounted = Arc::new( Mutex::new( Vec::new() ) );
+    let heard = accounted.clone();
+    let config = ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .account_bytes( |req| match req.original_uri().path() {
+            path if path.starts_with( "/api/" ) => "api".into(),
+            _ => "static".into(),
+        })
+        .on_bytes_accounted( move |key, count| heard.lock().unwrap().push( ( key.to_owned(), count ) ) )
+        .finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    let client = client();
+
+    for size in [ 100, 2000 ] {
+        let res = client.post( format!( "{}/api/upload", proxy ) ).body( vec![ b'x'; size ] ).send().await.unwrap();
+        assert_eq!( res.bytes().await.unwrap().len(), size * 2 );
+    }
+    for _ in 0..3 {
+        assert_eq!( client.get( format!( "{}/logo.svg", proxy ) ).send().await.unwrap().bytes().await.unwrap().len(), 10 );
+    }
+
+    // A request is recorded once its response is done with, just after the client has it
+    let expected = HashMap::from( [
+        ( "api".to_owned(), ByteCount { request: 2100, response: 4200 } ),
+        ( "static".to_owned(), ByteCount { request: 0, response: 30 } ),
+    ] );
+    for _ in 0..50 {
+        if handle.byte_totals() == expected {
+            break;
+        }
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+    assert_eq!( handle.byte_totals(), expected );
+
+    let mut accounted = accounted.lock().unwrap().clone();
+    accounted.sort_by_key( |( key, count )| ( key.clone(), count.request ) );
+    assert_eq!( accounted, [
+        ( "api".to_owned(), ByteCount { request: 100, response: 200 } ),
+        ( "api".to_owned(), ByteCount { request: 2000, response: 4000 } ),
+        ( "static".to_owned(), ByteCount { request: 0, response: 10 } ),
+        ( "static".to_owned(), ByteCount { request: 0, response: 10 } ),
+        ( "static".to_owned(), ByteCount { request: 0, response: 10 } ),
+    ]);
+}
+
+#[handler]
+fn echo_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |mut socket| async move {
+        while let Some( Ok( msg ) ) = socket.next().await {
+            if socket.send( msg ).await.is_err() {
+                break
+            }
+        }
+    })
+}
+
+/// Waits for the metrics of `handle` to be `expected`, since the bytes of a response are
+/// counted as it is sent, and returns them.
+async fn settled_metrics( handle: &ProxyHandle, expected: ProxyMetrics ) -> ProxyMetrics {
+    for _ in 0..50 {
+        if handle.metrics_snapshot() == expected {
+            break;
+        }
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+    handle.metrics_snapshot()
+}
+
+#[tokio::test]
+async fn counts_requests_by_status_class_websockets_and_bytes() {
+    let backend = serve( Route::new()
+        .at( "/ok", make( |req: Request| async move { req.into_body().into_bytes().await.unwrap() } ) )
+        .at( "/cached", make( |_: Request| async { StatusCode::NOT_MODIFIED } ) )
+        .at( "/missing", make( |_: Request| async { Response::builder().status( StatusCode::NOT_FOUND ).body( "nope" ) } ) )
+        .at( "/broken", make( |_: Request| async { Response::builder().status( StatusCode::INTERNAL_SERVER_ERROR ).body( "oops" ) } ) )
+        .at( "/", get( echo_backend ) ) ).await;
+    let config = ProxyConfig::new( backend.to_string() ).web_insecure().ws_insecure().enable_nesting().finish();
+    let handle = config.handle();
+    let proxy = serve( poem_proxy::ProxyEndpoint::new( config ) ).await;
+    let client = client();
+    assert_eq!( handle.metrics_snapshot(), ProxyMetrics::default() );
+
+    for body in [ "hello", "hello world" ] {
+        assert_eq!( client.post( format!( "http://{}/ok", proxy ) ).body( body ).send().await.unwrap().text().await.unwrap(), body );
+    }
+    for ( path, status ) in [ ( "/cached", 304 ), ( "/missing", 404 ), ( "/broken", 500 ) ] {
+        let res = client.get( format!( "http://{}{}", proxy, path ) ).send().await.unwrap();
+        assert_eq!( res.status(), status );
+        res.bytes().await.unwrap();
+    }
+    let http = ProxyMetrics {
+        requests: 5,
+        success: 2,
+        redirection: 1,
+        client_error: 1,
+        server_error: 1,
+        open_websockets: 0,
+        bytes_from_clients: 16,
+        bytes_to_clients: 16 + 8,
+    };
+    assert_eq!( settled_metrics( &handle, http ).await, http );
+
+    // A websocket counts as a request, and as open until it closes, along with the bytes
+    // of each message. Websockets are opened to the root of the proxied server
+    let ( mut socket, _ ) = connect_async( format!( "ws://{}/", proxy ) ).await.unwrap();
+    socket.send( tungstenite::Message::Text( "ping!".into() ) ).await.unwrap();
+    assert_eq!( socket.next().await.unwrap().unwrap(), tungstenite::Message::Text( "ping!".into() ) );
+    let open = ProxyMetrics { requests: 6, open_websockets: 1, bytes_from_clients: http.bytes_from_clients + 5, bytes_to_clients: http.bytes_to_clients + 5, ..http };
+    assert_eq!( settled_metrics( &handle, open ).await, open );
+
+    socket.close( None ).await.unwrap();
+    while socket.next().await.is_some() {}
+    let closed = ProxyMetrics { open_websockets: 0, ..open };
+    assert_eq!( settled_metrics( &handle, closed ).await, closed );
+}
@@ -0,0 +1,152 @@
+//! Following the redirects the proxied server sends back.
+
+mod common;
+
+use poem::{ Request, Response, endpoint::make, http::StatusCode };
+use poem_proxy::{ InsecureRequestPolicy, ProxyConfig, RedirectMode };
+use common::{ echo, serve, serve_proxy };
+
+/// Serves a backend that redirects `/307` and `/302` to `/target` with that status, and
+/// answers `/target` with the method and body it was sent. Returns its address.
+async fn redirecting_backend() -> String {
+    serve( make( |req: Request| async move {
+        let redirect = |status| Response::builder().status( status ).header( "location", "/target" ).finish();
+        match req.uri().path() {
+            "/307" => redirect( StatusCode::TEMPORARY_REDIRECT ),
+            "/302" => redirect( StatusCode::FOUND ),
+            _ => {
+                let method = req.method().clone();
+                let body = req.into_body().into_string().await.unwrap();
+                Response::builder().body( format!( "{} {}", method, body ) )
+            },
+        }
+    })).await.to_string()
+}
+
+/// Serves a proxy handling redirects in the given mode, returning its url. Request
+/// bodies smaller than `stream_from` are read whole rather than streamed.
+async fn redirecting_proxy( mode: RedirectMode, stream_from: usize ) -> String {
+    serve_proxy( ProxyConfig::new( redirecting_backend().await )
+        .web_insecure()
+        .enable_nesting()
+        .redirect_mode( mode )
+        .upload_stream_threshold( stream_from )
+        .finish() ).await
+}
+
+/// Sends a `POST` with a body to `path` through the proxy, returning the response.
+/// Redirects that make it back are left for the test to see.
+async fn post( proxy: &str, path: &str ) -> reqwest::Response {
+    let client = reqwest::Client::builder().no_proxy().redirect( reqwest::redirect::Policy::none() ).build().unwrap();
+    client.post( format!( "{}{}", proxy, path ) ).body( "data" ).send().await.unwrap()
+}
+
+#[tokio::test]
+async fn follows_redirects_the_way_browsers_do() {
+    let proxy = redirecting_proxy( RedirectMode::Follow, 1024 ).await;
+
+    assert_eq!( post( &proxy, "/307" ).await.text().await.unwrap(), "POST data" );
+    assert_eq!( post( &proxy, "/302" ).await.text().await.unwrap(), "GET " );
+}
+
+#[tokio::test]
+async fn leaves_repeating_streamed_bodies_to_the_client() {
+    let proxy = redirecting_proxy( RedirectMode::Follow, 0 ).await;
+
+    let res = post( &proxy, "/307" ).await;
+    assert_eq!( res.status(), StatusCode::TEMPORARY_REDIRECT );
+    assert_eq!( res.headers()[ "location" ], "/target" );
+    assert_eq!( post( &proxy, "/302" ).await.text().await.unwrap(), "GET " );
+}
+
+#[tokio::test]
+async fn keeps_the_method_and_body_when_asked() {
+    let proxy = redirecting_proxy( RedirectMode::PreserveMethod, 0 ).await;
+
+    assert_eq!( post( &proxy, "/307" ).await.text().await.unwrap(), "POST data" );
+    assert_eq!( post( &proxy, "/302" ).await.text().await.unwrap(), "POST data" );
+}
+
+#[tokio::test]
+async fn passes_redirects_back_when_asked() {
+    let proxy = redirecting_proxy( RedirectMode::PassThrough, 0 ).await;
+
+    for ( path, status ) in [ ( "/307", StatusCode::TEMPORARY_REDIRECT ), ( "/302", StatusCode::FOUND ) ] {
+        let res = post( &proxy, path ).await;
+        assert_eq!( res.status(), status );
+        assert_eq!( res.headers()[ "location" ], "/target" );
+    }
+}
+
+/// Sends a request asking to be upgraded to https through a proxy with the given policy,
+/// returning the response without following it anywhere.
+async fn upgrade_insecure( policy: InsecureRequestPolicy, method: reqwest::Method ) -> reqwest::Response {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .enable_nesting()
+        .upgrade_insecure_requests( policy )
+        .finish() ).await;
+    let client = reqwest::Client::builder().no_proxy().redirect( reqwest::redirect::Policy::none() ).build().unwrap();
+    client.request( method, format!( "{}/page?tab=2", proxy ) )
+        .header( "upgrade-insecure-requests", "1" )
+        .send().await.unwrap()
+}
+
+#[tokio::test]
+async fn redirects_requests_asking_for_https_when_asked() {
+    let res = upgrade_insecure( InsecureRequestPolicy::Redirect, reqwest::Method::GET ).await;
+    assert_eq!( res.status(), StatusCode::TEMPORARY_REDIRECT );
+    assert_eq!( res.headers()[ "location" ], "https://127.0.0.1/page?tab=2" );
+    assert_eq!( res.headers()[ "vary" ], "Upgrade-Insecure-Requests" );
+
+    // Only navigations are redirected
+    let res = upgrade_insecure( InsecureRequestPolicy::Redirect, reqwest::Method::POST ).await;
+    assert_eq!( res.status(), StatusCode::OK );
+}
+
+#[tokio::test]
+async fn forwards_requests_asking_for_https_by_default() {
+    let res = upgrade_insecure( InsecureRequestPolicy::Forward, reqwest::Method::GET ).await;
+    assert_eq!( res.status(), StatusCode::OK );
+    let seen: serde_json::Value = serde_json::from_str( &res.text().await.unwrap() ).unwrap();
+    assert_eq!( seen[ "uri" ], "/page?tab=2" );
+    assert_eq!( seen[ "headers" ][ "upgrade-insecure-requests" ], "1" );
+}
+
+/// Serves a backend that redirects `/hops/{n}` to `/hops/{n - 1}`, answering `/hops/0`
+/// with its name. Returns its address.
+async fn hopping_backend( name: &'static str ) -> String {
+    serve( make( move |req: Request| async move {
+        match req.uri().path().trim_start_matches( "/hops/" ).parse::<usize>() {
+            Ok( 0 ) | Err( _ ) => Response::builder().body( name ),
+            Ok( n ) => Response::builder().status( StatusCode::FOUND ).header( "location", format!( "/hops/{}", n - 1 ) ).finish(),
+        }
+    })).await.to_string()
+}
+
+#[tokio::test]
+async fn limits_the_redirects_followed_for_each_target() {
+    let ( strict, lenient ) = ( hopping_backend( "strict" ).await, hopping_backend( "lenient" ).await );
+    let proxy = serve_proxy( ProxyConfig::new( &strict )
+        .add_target( &lenient )
+        .web_insecure()
+        .enable_nesting()
+        .max_redirects( 1 )
+        .target_max_redirects( &lenient, 3 )
+        .finish() ).await;
+    let client = reqwest::Client::builder().no_proxy().redirect( reqwest::redirect::Policy::none() ).build().unwrap();
+
+    // Requests alternate between the targets, and each has its own limit
+    for ( hops, expected ) in [ ( 1, [ "200 lenient", "200 strict" ] ), ( 3, [ "200 lenient", "502 " ] ), ( 4, [ "502 ", "502 " ] ) ] {
+        let mut outcomes = Vec::new();
+        for _ in 0..2 {
+            let res = client.get( format!( "{}/hops/{}", proxy, hops ) ).send().await.unwrap();
+            let status = res.status().as_u16();
+            let body = if status == 200 { res.text().await.unwrap() } else { String::new() };
+            outcomes.push( format!( "{} {}", status, body ) );
+        }
+        outcomes.sort();
+        assert_eq!( outcomes, expected, "{} hops", hops );
+    }
+}
@@ -0,0 +1,110 @@
+//! Sending requests to the proxied server again when they fail in a way that may not last.
+
+mod common;
+
+use std::sync::{ Arc, atomic::{ AtomicUsize, Ordering } };
+use poem::{ Request, Response, endpoint::make_sync, http::StatusCode };
+use poem_proxy::ProxyConfig;
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::{ TcpListener, TcpStream } };
+use common::{ client, serve, serve_proxy };
+
+/// Serves a proxy retrying up to `retries` times, to a backend that answers its first
+/// `failures` requests with `503 Service Unavailable`. Returns the proxy's url and the
+/// number of requests the backend saw.
+async fn flaky_proxy( failures: usize, retries: u32 ) -> ( String, Arc<AtomicUsize> ) {
+    let seen = Arc::new( AtomicUsize::new( 0 ) );
+    let count = seen.clone();
+    let backend = serve( make_sync( move |_: Request| {
+        match count.fetch_add( 1, Ordering::SeqCst ) < failures {
+            true => Response::builder().status( StatusCode::SERVICE_UNAVAILABLE ).body( "unavailable" ),
+            false => Response::builder().body( "ok" ),
+        }
+    })).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().with_retries( retries ).finish() ).await;
+    ( proxy, seen )
+}
+
+#[tokio::test]
+async fn retries_idempotent_requests_until_they_succeed() {
+    let ( proxy, seen ) = flaky_proxy( 2, 3 ).await;
+
+    let res = client().get( &proxy ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.text().await.unwrap(), "ok" );
+    assert_eq!( seen.load( Ordering::SeqCst ), 3 );
+}
+
+#[tokio::test]
+async fn gives_the_last_failure_once_retries_run_out() {
+    let ( proxy, seen ) = flaky_proxy( 5, 2 ).await;
+
+    assert_eq!( client().get( &proxy ).send().await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE );
+    assert_eq!( seen.load( Ordering::SeqCst ), 3 );
+}
+
+#[tokio::test]
+async fn never_retries_writes() {
+    let ( proxy, seen ) = flaky_proxy( 1, 3 ).await;
+
+    assert_eq!( client().post( &proxy ).body( "data" ).send().await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE );
+    assert_eq!( seen.load( Ordering::SeqCst ), 1 );
+}
+
+/// Reads a request without a body from `stream`, returning whether there was one.
+async fn read_request( stream: &mut TcpStream ) -> bool {
+    let mut request = Vec::new();
+    let mut buf = [ 0; 1024 ];
+    while !request.ends_with( b"\r\n\r\n" ) {
+        match stream.read( &mut buf ).await {
+            Ok( 0 ) | Err( _ ) => return false,
+            Ok( read ) => request.extend_from_slice( &buf[ ..read ] ),
+        }
+    }
+    true
+}
+
+/// Serves a backend that answers every request with the number of the connection it
+/// came in on, except that it drops its first connection on the second request sent
+/// over it, the way a server that restarted would. Returns its address.
+async fn stale_connection_backend() -> String {
+    let listener = TcpListener::bind( "127.0.0.1:0" ).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn( async move {
+        for connection in 1.. {
+            let ( mut stream, _ ) = listener.accept().await.unwrap();
+            tokio::spawn( async move {
+                let mut served = 0;
+                while read_request( &mut stream ).await {
+                    if connection == 1 && served == 1 {
+                        return;
+                    }
+                    let body = format!( "connection {}", connection );
+                    let response = format!( "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", body.len(), body );
+                    stream.write_all( response.as_bytes() ).await.unwrap();
+                    served += 1;
+                }
+            });
+        }
+    });
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn retries_on_a_new_connection_when_a_reused_one_fails() {
+    let backend = stale_connection_backend().await;
+    let config = ProxyConfig::new( backend.replace( "127.0.0.1", "localhost" ) ).web_insecure().with_retries( 1 ).finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    let text = |res: reqwest::Response| async move { res.text().await.unwrap() };
+    assert_eq!( text( client().get( &proxy ).send().await.unwrap() ).await, "connection 1" );
+    assert_eq!( text( client().get( &proxy ).send().await.unwrap() ).await, "connection 2" );
+
+    // The first attempt reused the connection, and the retry opened a new one
+    let stats = handle.connection_stats();
+    assert_eq!( ( stats.opened, stats.reused ), ( 2, 1 ) );
+
+    // Which the requests after it keep using
+    assert_eq!( text( client().get( &proxy ).send().await.unwrap() ).await, "connection 2" );
+    assert_eq!( handle.connection_stats().opened, 2 );
+}
@@ -0,0 +1,136 @@
+//! Rewriting the paths and headers of requests on their way to the proxied server.
+
+mod common;
+
+use poem::http::{ HeaderName, HeaderValue, Method };
+use poem_proxy::{ HeaderRewrite, PathRewrite, ProxyConfig, TrailingSlashPolicy };
+use common::{ client, echo, echoed, serve, serve_proxy };
+
+#[tokio::test]
+async fn rewrites_requests_by_the_rules_of_the_target_they_go_to() {
+    let ( a, b ) = ( serve( echo ).await.to_string(), serve( echo ).await.to_string() );
+    let proxy = serve_proxy( ProxyConfig::new( a.clone() )
+        .add_target( b.clone() )
+        .web_insecure()
+        .enable_nesting()
+        .rewrite_path( &a, PathRewrite::new().replace_prefix( "/api", "/v1" ) )
+        .rewrite_headers( &a, HeaderRewrite::new().set( HeaderName::from_static( "x-tenant" ), HeaderValue::from_static( "acme" ) ) )
+        .rewrite_path( &b, PathRewrite::new().template( "/api/{item}", "/v2/items/{item}" ) )
+        .rewrite_headers( &b, HeaderRewrite::new().remove( HeaderName::from_static( "x-secret" ) ) )
+        .finish() ).await;
+
+    // The targets take turns, and each applies its own rules only
+    let mut seen = Vec::new();
+    for _ in 0..2 {
+        seen.push( echoed( client().get( format!( "{}/api/books", proxy ) ).header( "x-secret", "s3cret" ) ).await );
+    }
+    seen.sort_by_key( |seen| seen[ "uri" ].to_string() );
+    let ( first, second ) = ( &seen[ 0 ], &seen[ 1 ] );
+
+    assert_eq!( first[ "uri" ], "/v1/books" );
+    assert_eq!( first[ "headers" ][ "x-tenant" ], "acme" );
+    assert_eq!( first[ "headers" ][ "x-secret" ], "s3cret" );
+
+    assert_eq!( second[ "uri" ], "/v2/items/books" );
+    assert!( second[ "headers" ].get( "x-tenant" ).is_none() );
+    assert!( second[ "headers" ].get( "x-secret" ).is_none() );
+}
+
+#[tokio::test]
+async fn fills_path_templates_with_what_they_captured() {
+    let backend = serve( echo ).await.to_string();
+    let proxy = serve_proxy( ProxyConfig::new( backend.clone() )
+        .web_insecure()
+        .enable_nesting()
+        .rewrite_path( &backend, PathRewrite::new()
+            .template( "/users/{id}/posts", "/v2/users/{id}/items" )
+            .template( "/orgs/{org}/members/{user}", "/v2/members/{user}?org={org}" ) )
+        .finish() ).await;
+    let uri = |path: &str| {
+        let req = client().get( format!( "{}{}", proxy, path ) );
+        async move { echoed( req ).await[ "uri" ].as_str().unwrap().to_owned() }
+    };
+
+    assert_eq!( uri( "/users/42/posts" ).await, "/v2/users/42/items" );
+    assert_eq!( uri( "/users/42/posts?page=2" ).await, "/v2/users/42/items?page=2" );
+    assert_eq!( uri( "/orgs/acme/members/bob" ).await, "/v2/members/bob?org=acme" );
+
+    // Paths that don't fit a template are forwarded as they are
+    assert_eq!( uri( "/users/42/comments" ).await, "/users/42/comments" );
+    assert_eq!( uri( "/users/42/posts/7" ).await, "/users/42/posts/7" );
+}
+
+#[tokio::test]
+async fn forwards_requests_with_the_method_they_are_rewritten_to() {
+    let backend = serve( echo ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .method_rewrite( Method::GET, Method::POST )
+        .method_rewrite( Method::from_bytes( b"PURGE" ).unwrap(), Method::DELETE )
+        .finish() ).await;
+    let client = client();
+
+    // A GET has no body, so the POST it becomes says so
+    let get = echoed( client.get( &proxy ) ).await;
+    assert_eq!( get[ "method" ], "POST" );
+    assert_eq!( get[ "headers" ][ "content-length" ], "0" );
+
+    let purge = echoed( client.request( Method::from_bytes( b"PURGE" ).unwrap(), &proxy ) ).await;
+    assert_eq!( purge[ "method" ], "DELETE" );
+
+    // Methods without a rewrite go through as they are, body and all
+    let put = echoed( client.put( &proxy ).body( "abc" ) ).await;
+    assert_eq!( put[ "method" ], "PUT" );
+    assert_eq!( put[ "headers" ][ "content-length" ], "3" );
+}
+
+#[tokio::test]
+async fn strips_the_prefix_the_endpoint_is_mounted_under() {
+    let backend = serve( echo ).await.to_string();
+    let proxy = serve_proxy( ProxyConfig::new( backend.clone() ).web_insecure().enable_nesting().strip_prefix( "/api" ).finish() ).await;
+    let client = client();
+
+    for ( path, forwarded ) in [
+        ( "/api/users?page=2", "/users?page=2" ),
+        ( "/api", "/" ),
+        ( "/api/", "/" ),
+        ( "/apiary", "/apiary" ),
+        ( "/assets/app.js", "/assets/app.js" ),
+    ] {
+        let seen = echoed( client.get( format!( "{}{}", proxy, path ) ) ).await;
+        assert_eq!( seen[ "uri" ], forwarded, "{}", path );
+    }
+
+    // Without nesting there is no path to strip it from
+    let unnested = serve_proxy( ProxyConfig::new( backend ).web_insecure().strip_prefix( "/api" ).finish() ).await;
+    assert_eq!( echoed( client.get( format!( "{}/api/users", unnested ) ) ).await[ "uri" ], "/" );
+}
+
+#[tokio::test]
+async fn adds_or_strips_the_trailing_slash_of_forwarded_paths() {
+    let backend = serve( echo ).await.to_string();
+    let client = client();
+    let paths = [ "/docs", "/docs/", "/docs?next=/home/", "/docs//?next=/home/", "/", "/?next=/home" ];
+
+    for ( policy, forwarded ) in [
+        ( TrailingSlashPolicy::Preserve, [ "/docs", "/docs/", "/docs?next=/home/", "/docs//?next=/home/", "/", "/?next=/home" ] ),
+        ( TrailingSlashPolicy::Add, [ "/docs/", "/docs/", "/docs/?next=/home/", "/docs//?next=/home/", "/", "/?next=/home" ] ),
+        ( TrailingSlashPolicy::Strip, [ "/docs", "/docs", "/docs?next=/home/", "/docs?next=/home/", "/", "/?next=/home" ] ),
+    ] {
+        let proxy = serve_proxy( ProxyConfig::new( backend.clone() ).web_insecure().enable_nesting().trailing_slash( policy ).finish() ).await;
+        for ( path, forwarded ) in paths.into_iter().zip( forwarded ) {
+            let seen = echoed( client.get( format!( "{}{}", proxy, path ) ) ).await;
+            assert_eq!( seen[ "uri" ], forwarded, "{:?} {}", policy, path );
+        }
+    }
+
+    // The policy applies to the path left once the prefix is stripped
+    let proxy = serve_proxy( ProxyConfig::new( backend )
+        .web_insecure()
+        .enable_nesting()
+        .strip_prefix( "/api" )
+        .trailing_slash( TrailingSlashPolicy::Add )
+        .finish() ).await;
+    assert_eq!( echoed( client.get( format!( "{}/api/users?page=2", proxy ) ) ).await[ "uri" ], "/users/?page=2" );
+    assert_eq!( echoed( client.get( format!( "{}/api", proxy ) ) ).await[ "uri" ], "/" );
+}
@@ -0,0 +1,388 @@
+//! Which server each request is forwarded to, and what the proxy is allowed to connect to.
+
+mod common;
+
+use std::{ sync::{ Arc, atomic::{ AtomicBool, AtomicUsize, Ordering } }, time::Duration };
+use poem::{ EndpointExt, Request, Response, Route, endpoint::{ make, make_sync }, handler, http::{ HeaderName, StatusCode } };
+use poem_proxy::{ Cost, ProxyConfig, ProxyEndpoint };
+use common::{ client, closed_port, echo, echoed, serve, serve_proxy };
+
+#[handler]
+fn ok() -> &'static str {
+    "ok"
+}
+
+/// Serves a backend that answers with its `name` after `delay`, returning its address.
+async fn named( name: &'static str, delay: Duration ) -> String {
+    serve( make( move |_: Request| async move {
+        tokio::time::sleep( delay ).await;
+        name
+    })).await.to_string()
+}
+
+/// Returns the body of a `GET` for `/` through the proxy.
+async fn body_of( proxy: &str ) -> String {
+    client().get( proxy ).send().await.unwrap().text().await.unwrap()
+}
+
+/// Returns the status of a `GET` for `path` through the proxy.
+async fn status_of( proxy: &str, path: &str ) -> StatusCode {
+    client().get( format!( "{}{}", proxy, path ) ).send().await.unwrap().status()
+}
+
+#[tokio::test]
+async fn allows_connecting_to_allowed_ports() {
+    let backend = serve( ok ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .web_insecure()
+        .allowed_ports( [ 80, 443, backend.port() ] )
+        .finish() ).await;
+
+    assert_eq!( status_of( &proxy, "/" ).await, StatusCode::OK );
+}
+
+#[tokio::test]
+async fn refuses_to_connect_to_other_ports() {
+    for port in [ 22, 25 ] {
+        let proxy = serve_proxy( ProxyConfig::new( format!( "127.0.0.1:{}", port ) )
+            .web_insecure()
+            .allowed_ports( [ 80, 443, 8080 ] )
+            .finish() ).await;
+
+        assert_eq!( status_of( &proxy, "/" ).await, StatusCode::FORBIDDEN );
+    }
+
+    // Including the default port of the protocol, when none is given
+    let proxy = serve_proxy( ProxyConfig::new( "127.0.0.1" ).web_secure().allowed_ports( [ 80 ] ).finish() ).await;
+    assert_eq!( status_of( &proxy, "/" ).await, StatusCode::FORBIDDEN );
+}
+
+#[tokio::test]
+async fn allowed_ports_are_connected_to() {
+
+    // Nothing listens on 443 here, so the proxy gets as far as failing to connect
+    let proxy = serve_proxy( ProxyConfig::new( "127.0.0.1:443" ).web_insecure().allowed_ports( [ 443 ] ).finish() ).await;
+    assert_ne!( status_of( &proxy, "/" ).await, StatusCode::FORBIDDEN );
+}
+
+#[tokio::test]
+async fn checks_the_port_of_every_target() {
+    let backend = serve( ok ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() )
+        .add_target( "127.0.0.1:22" )
+        .web_insecure()
+        .allowed_ports( [ backend.port() ] )
+        .finish() ).await;
+
+    // Requests alternate between the two targets
+    let mut statuses = vec![ status_of( &proxy, "/" ).await, status_of( &proxy, "/" ).await ];
+    statuses.sort();
+    assert_eq!( statuses, [ StatusCode::OK, StatusCode::FORBIDDEN ] );
+}
+
+#[tokio::test]
+async fn changing_the_builder_leaves_finished_configurations_alone() {
+    let backend = serve( ok ).await;
+    let mut builder = ProxyConfig::new( backend.to_string() );
+    let config = builder.web_insecure().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    let unreachable = format!( "127.0.0.1:{}", closed_port().await );
+
+    builder.add_target( &unreachable ).target_max_redirects( &backend.to_string(), 0 );
+    assert_eq!( handle.list_targets(), [ backend.to_string() ] );
+    assert_eq!( builder.handle().list_targets(), [ backend.to_string(), unreachable.clone() ] );
+    for _ in 0..4 {
+        assert_eq!( status_of( &proxy, "/" ).await, StatusCode::OK );
+    }
+
+    // While the handle still changes the running endpoint
+    handle.add_target( &unreachable );
+    let mut statuses = vec![ status_of( &proxy, "/" ).await, status_of( &proxy, "/" ).await ];
+    statuses.sort();
+    assert_eq!( statuses, [ StatusCode::OK, StatusCode::BAD_GATEWAY ] );
+}
+
+#[tokio::test]
+async fn drained_targets_finish_their_requests_but_get_no_new_ones() {
+    let slow = named( "slow", Duration::from_millis( 300 ) ).await;
+    let fast = named( "fast", Duration::ZERO ).await;
+    let config = ProxyConfig::new( &slow ).add_target( &fast ).web_insecure().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+
+    // The first request goes to the slow backend, and is still there when it is drained
+    let pending = tokio::spawn( { let proxy = proxy.clone(); async move { body_of( &proxy ).await } } );
+    tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+    assert_eq!( handle.active_requests( &slow ), Some( 1 ) );
+    assert!( handle.drain_target( &slow ) );
+
+    for _ in 0..4 {
+        assert_eq!( body_of( &proxy ).await, "fast" );
+    }
+    assert_eq!( pending.await.unwrap(), "slow" );
+    assert_eq!( handle.active_requests( &slow ), Some( 0 ) );
+    assert_eq!( handle.list_targets(), [ slow, fast ] );
+}
+
+#[tokio::test]
+async fn targets_can_be_added_and_removed_while_running() {
+    let first = named( "first", Duration::ZERO ).await;
+    let second = named( "second", Duration::ZERO ).await;
+    let config = ProxyConfig::new( &first ).web_insecure().finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    assert_eq!( body_of( &proxy ).await, "first" );
+
+    handle.add_target( &second );
+    assert_eq!( handle.list_targets(), [ first.clone(), second.clone() ] );
+    let mut bodies = vec![ body_of( &proxy ).await, body_of( &proxy ).await ];
+    bodies.sort();
+    assert_eq!( bodies, [ "first", "second" ] );
+
+    assert!( handle.remove_target( &first ) );
+    assert!( !handle.remove_target( &first ) );
+    assert_eq!( handle.list_targets(), [ second ] );
+    for _ in 0..4 {
+        assert_eq!( body_of( &proxy ).await, "second" );
+    }
+    assert_eq!( handle.active_requests( &first ), None );
+}
+
+#[tokio::test]
+async fn fails_over_to_another_target_when_one_cant_be_reached() {
+    let live = named( "live", Duration::ZERO ).await;
+    let dead = format!( "127.0.0.1:{}", closed_port().await );
+    let proxy = serve_proxy( ProxyConfig::new( &dead ).add_target( &live ).web_insecure().failover( 1 ).finish() ).await;
+
+    for _ in 0..4 {
+        assert_eq!( body_of( &proxy ).await, "live" );
+    }
+
+    // Writes can't be sent twice, so they aren't failed over
+    let mut statuses = Vec::new();
+    for _ in 0..2 {
+        statuses.push( client().post( &proxy ).body( "data" ).send().await.unwrap().status() );
+    }
+    statuses.sort();
+    assert_eq!( statuses, [ StatusCode::OK, StatusCode::BAD_GATEWAY ] );
+}
+
+#[tokio::test]
+async fn answers_with_bad_gateway_without_failover() {
+    let live = named( "live", Duration::ZERO ).await;
+    let dead = format!( "127.0.0.1:{}", closed_port().await );
+    let proxy = serve_proxy( ProxyConfig::new( &dead ).add_target( &live ).web_insecure().finish() ).await;
+
+    let mut statuses = vec![ status_of( &proxy, "/" ).await, status_of( &proxy, "/" ).await ];
+    statuses.sort();
+    assert_eq!( statuses, [ StatusCode::OK, StatusCode::BAD_GATEWAY ] );
+}
+
+#[tokio::test]
+async fn cycles_through_the_targets_in_order() {
+    let ( a, b, c ) = ( named( "a", Duration::ZERO ).await, named( "b", Duration::ZERO ).await, named( "c", Duration::ZERO ).await );
+    let proxy = serve_proxy( ProxyConfig::new( a ).add_target( b ).add_target( c ).web_insecure().finish() ).await;
+
+    let mut bodies = Vec::new();
+    for _ in 0..6 {
+        bodies.push( body_of( &proxy ).await );
+    }
+    assert_eq!( bodies, [ "a", "b", "c", "a", "b", "c" ] );
+}
+
+#[tokio::test]
+async fn skips_a_dead_target_with_failover() {
+    let ( a, c ) = ( named( "a", Duration::ZERO ).await, named( "c", Duration::ZERO ).await );
+    let dead = format!( "127.0.0.1:{}", closed_port().await );
+    let proxy = serve_proxy( ProxyConfig::new( a ).add_target( dead ).add_target( c ).web_insecure().failover( 2 ).finish() ).await;
+
+    for _ in 0..6 {
+        let body = body_of( &proxy ).await;
+        assert!( body == "a" || body == "c", "{}", body );
+    }
+}
+
+#[tokio::test]
+async fn routes_by_header_to_allowed_upstreams_only() {
+    let default = named( "default", Duration::ZERO ).await;
+    let upstream = serve( echo ).await.to_string();
+    let other = named( "other", Duration::ZERO ).await;
+    let proxy = serve_proxy( ProxyConfig::new( default )
+        .web_insecure()
+        .route_by_header( HeaderName::from_static( "x-upstream" ), [ upstream.as_str() ] )
+        .finish() ).await;
+
+    // The header picks the server, and isn't passed on to it
+    let seen = echoed( client().get( &proxy ).header( "x-upstream", &upstream ) ).await;
+    assert_eq!( seen[ "uri" ], "/" );
+    assert!( seen[ "headers" ].get( "x-upstream" ).is_none(), "{}", seen );
+
+    // Servers that aren't listed can't be reached, even ones the proxy could connect to
+    let res = client().get( &proxy ).header( "x-upstream", &other ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::FORBIDDEN );
+    let res = client().get( &proxy ).header( "x-upstream", &upstream ).header( "x-upstream", &other ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::FORBIDDEN );
+
+    // Without the header, requests go to the targets
+    assert_eq!( body_of( &proxy ).await, "default" );
+}
+
+/// Serves a backend that answers with its `name`, and answers health checks with
+/// `204 No Content` for as long as `up` is set. Returns its address.
+async fn checked( name: &'static str, up: Arc<AtomicBool> ) -> String {
+    serve( make_sync( move |req: Request| match req.uri().path() {
+        "/healthz" if up.load( Ordering::SeqCst ) => Response::builder().status( StatusCode::NO_CONTENT ).finish(),
+        "/healthz" => Response::builder().status( StatusCode::SERVICE_UNAVAILABLE ).finish(),
+        _ => Response::builder().body( name ),
+    })).await.to_string()
+}
+
+/// Waits for `condition` to hold, for up to five seconds.
+async fn eventually( condition: impl Fn() -> bool ) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+    panic!( "the condition never held" );
+}
+
+#[tokio::test]
+async fn takes_targets_failing_health_checks_out_of_rotation() {
+    let ( a_up, b_up ) = ( Arc::new( AtomicBool::new( true ) ), Arc::new( AtomicBool::new( true ) ) );
+    let a = checked( "a", a_up.clone() ).await;
+    let b = checked( "b", b_up.clone() ).await;
+    let config = ProxyConfig::new( a.clone() )
+        .add_target( b.clone() )
+        .web_insecure()
+        .health_check_path( "/healthz" )
+        .health_check_interval( Duration::from_millis( 50 ) )
+        .health_check_status( StatusCode::NO_CONTENT )
+        .finish();
+    let handle = config.handle();
+    let proxy = serve_proxy( config ).await;
+    assert_eq!( handle.is_healthy( &b ), Some( true ) );
+
+    // A target that fails its check gets no requests
+    b_up.store( false, Ordering::SeqCst );
+    eventually( || handle.is_healthy( &b ) == Some( false ) ).await;
+    for _ in 0..4 {
+        assert_eq!( body_of( &proxy ).await, "a" );
+    }
+
+    // Until every target does
+    a_up.store( false, Ordering::SeqCst );
+    eventually( || handle.is_healthy( &a ) == Some( false ) ).await;
+    assert_eq!( status_of( &proxy, "/" ).await, StatusCode::SERVICE_UNAVAILABLE );
+
+    // And it is put back once it passes again
+    b_up.store( true, Ordering::SeqCst );
+    eventually( || handle.is_healthy( &b ) == Some( true ) ).await;
+    assert_eq!( body_of( &proxy ).await, "b" );
+}
+
+/// Serves a backend that reads the whole request, then answers with its `name`.
+async fn reading( name: &'static str ) -> String {
+    serve( make( move |req: Request| async move {
+        req.into_body().into_bytes().await.unwrap();
+        name
+    })).await.to_string()
+}
+
+#[tokio::test]
+async fn sends_heavy_requests_to_the_heavy_tier() {
+    let ( light, heavy ) = ( reading( "light" ).await, reading( "heavy" ).await );
+    let proxy = serve_proxy( ProxyConfig::new( light.clone() )
+        .add_target( heavy.clone() )
+        .target_tier( &light, Cost::Light )
+        .target_tier( &heavy, Cost::Heavy )
+        .web_insecure()
+        .cost_selector( |req| match req.header( "content-length" ).and_then( |length| length.parse::<usize>().ok() ) {
+            Some( length ) if length > 1024 => Cost::Heavy,
+            _ => Cost::Light,
+        })
+        .finish() ).await;
+    let client = client();
+
+    for _ in 0..3 {
+        let res = client.post( &proxy ).body( vec![ b'x'; 1024 * 1024 ] ).send().await.unwrap();
+        assert_eq!( res.text().await.unwrap(), "heavy" );
+        assert_eq!( client.get( &proxy ).send().await.unwrap().text().await.unwrap(), "light" );
+    }
+}
+
+#[tokio::test]
+async fn proxies_requests_to_an_endpoint_nested_in_a_route() {
+    let backend = serve( echo ).await;
+    let config = ProxyConfig::new( backend.to_string() ).web_insecure().enable_nesting().finish();
+    let app = Route::new()
+        .nest( "/api", ProxyEndpoint::new( config.clone() ) )
+        .at( "/legacy/*", poem_proxy::proxy.data( config ) )
+        .at( "/health", ok );
+    let app = format!( "http://{}", serve( app ).await );
+    let client = client();
+
+    let seen = echoed( client.post( format!( "{}/api/users?page=2", app ) ).body( "{}" ) ).await;
+    assert_eq!( seen[ "method" ], "POST" );
+    assert_eq!( seen[ "uri" ], "/users?page=2" );
+
+    // The handler taking its configuration from the data of the route still works
+    assert_eq!( echoed( client.get( format!( "{}/legacy/users", app ) ) ).await[ "method" ], "GET" );
+
+    // And the rest of the app is left alone
+    assert_eq!( client.get( format!( "{}/health", app ) ).send().await.unwrap().text().await.unwrap(), "ok" );
+}
+
+#[tokio::test]
+async fn shares_one_configuration_between_the_paths_it_is_mounted_under() {
+    // The backend says which connection each request came over, and counts its health checks
+    let ( up, checks ) = ( Arc::new( AtomicBool::new( true ) ), Arc::new( AtomicUsize::new( 0 ) ) );
+    let ( backend_up, backend_checks ) = ( up.clone(), checks.clone() );
+    let backend = serve( make_sync( move |req: Request| match req.uri().path() {
+        "/healthz" => {
+            backend_checks.fetch_add( 1, Ordering::SeqCst );
+            match backend_up.load( Ordering::SeqCst ) {
+                true => Response::builder().status( StatusCode::NO_CONTENT ).finish(),
+                false => Response::builder().status( StatusCode::SERVICE_UNAVAILABLE ).finish(),
+            }
+        },
+        path => Response::builder().body( format!( "{} {}", path, req.remote_addr() ) ),
+    })).await.to_string();
+    let shared = ProxyConfig::new( backend.clone() )
+        .web_insecure()
+        .enable_nesting()
+        .health_check_path( "/healthz" )
+        .health_check_interval( Duration::from_millis( 50 ) )
+        .health_check_status( StatusCode::NO_CONTENT )
+        .shared();
+    let app = Route::new()
+        .nest( "/api", shared.endpoint_for( "/api" ) )
+        .nest( "/auth", shared.endpoint_for( "/auth" ) );
+    let app = format!( "http://{}", serve( app ).await );
+    let handle = shared.handle();
+
+    // Both paths go through the one http client, and so over the one connection
+    let api = body_of( &format!( "{}/api/users", app ) ).await;
+    let auth = body_of( &format!( "{}/auth/login", app ) ).await;
+    let ( api_path, api_connection ) = api.split_once( ' ' ).unwrap();
+    let ( auth_path, auth_connection ) = auth.split_once( ' ' ).unwrap();
+    assert_eq!( ( api_path, auth_path ), ( "/api/users", "/auth/login" ) );
+    assert_eq!( api_connection, auth_connection );
+
+    // Into the one set of counters
+    let metrics = handle.metrics_snapshot();
+    assert_eq!( ( metrics.requests, metrics.success ), ( 2, 2 ) );
+
+    // And the target is checked once, not once per path
+    let counted = checks.load( Ordering::SeqCst );
+    tokio::time::sleep( Duration::from_millis( 500 ) ).await;
+    assert!( checks.load( Ordering::SeqCst ) - counted <= 12, "{} checks in 500ms", checks.load( Ordering::SeqCst ) - counted );
+
+    // So a failed check takes it out of rotation for both
+    up.store( false, Ordering::SeqCst );
+    eventually( || handle.is_healthy( &backend ) == Some( false ) ).await;
+    assert_eq!( status_of( &app, "/api/users" ).await, StatusCode::SERVICE_UNAVAILABLE );
+    assert_eq!( status_of( &app, "/auth/login" ).await, StatusCode::SERVICE_UNAVAILABLE );
+}
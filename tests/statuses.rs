@@ -0,0 +1,92 @@
+//! The statuses clients are answered with, and how those of the proxied server are
+//! translated or held back.
+
+mod common;
+
+use poem::{ Request, Response, endpoint::make_sync, http::StatusCode };
+use poem_proxy::{ EarlyDataPolicy, ProxyConfig };
+use common::{ client, serve, serve_proxy };
+
+/// Returns the status of a `GET` for `path` through the proxy.
+async fn status_of( proxy: &str, path: &str ) -> StatusCode {
+    client().get( format!( "{}{}", proxy, path ) ).send().await.unwrap().status()
+}
+
+/// Serves a gRPC-like backend that answers `/{code}` with `200 OK` and that code as its
+/// `grpc-status`, the way a call that failed before sending any messages is answered.
+async fn grpc_backend() -> String {
+    serve( make_sync( |req: Request| {
+        Response::builder()
+            .content_type( "application/grpc" )
+            .header( "grpc-status", req.uri().path().trim_start_matches( '/' ) )
+            .finish()
+    })).await.to_string()
+}
+
+/// Serves a backend that answers `/{code}` with that status and a body the client
+/// shouldn't get to see when the status is held back.
+async fn status_backend() -> String {
+    serve( make_sync( |req: Request| {
+        let code = req.uri().path().trim_start_matches( '/' ).parse().unwrap();
+        Response::builder().status( StatusCode::from_u16( code ).unwrap() ).body( "stack trace" )
+    })).await.to_string()
+}
+
+#[tokio::test]
+async fn holds_back_the_statuses_it_is_told_to() {
+    let backend = status_backend().await;
+    let denying = serve_proxy( ProxyConfig::new( backend.clone() )
+        .web_insecure()
+        .enable_nesting()
+        .deny_statuses( [StatusCode::INTERNAL_SERVER_ERROR] )
+        .finish() ).await;
+
+    assert_eq!( status_of( &denying, "/200" ).await, StatusCode::OK );
+    assert_eq!( status_of( &denying, "/503" ).await, StatusCode::SERVICE_UNAVAILABLE );
+    let replaced = client().get( format!( "{}/500", denying ) ).send().await.unwrap();
+    assert_eq!( replaced.status(), StatusCode::BAD_GATEWAY );
+    assert!( !replaced.text().await.unwrap().contains( "stack trace" ) );
+
+    let allowing = serve_proxy( ProxyConfig::new( backend )
+        .web_insecure()
+        .enable_nesting()
+        .allow_statuses( [StatusCode::OK, StatusCode::NOT_FOUND] )
+        .blocked_status( StatusCode::SERVICE_UNAVAILABLE )
+        .finish() ).await;
+
+    assert_eq!( status_of( &allowing, "/200" ).await, StatusCode::OK );
+    assert_eq!( status_of( &allowing, "/404" ).await, StatusCode::NOT_FOUND );
+    assert_eq!( status_of( &allowing, "/500" ).await, StatusCode::SERVICE_UNAVAILABLE );
+    assert_eq!( status_of( &allowing, "/418" ).await, StatusCode::SERVICE_UNAVAILABLE );
+}
+
+#[tokio::test]
+async fn translates_grpc_statuses_when_asked() {
+    let backend = grpc_backend().await;
+    let mapped = serve_proxy( ProxyConfig::new( backend.clone() )
+        .web_insecure()
+        .enable_nesting()
+        .map_grpc_status()
+        .grpc_status( 14, StatusCode::BAD_GATEWAY )
+        .finish() ).await;
+
+    assert_eq!( status_of( &mapped, "/5" ).await, StatusCode::NOT_FOUND );
+    assert_eq!( status_of( &mapped, "/0" ).await, StatusCode::OK );
+    assert_eq!( status_of( &mapped, "/14" ).await, StatusCode::BAD_GATEWAY );
+
+    let unmapped = serve_proxy( ProxyConfig::new( backend ).web_insecure().enable_nesting().finish() ).await;
+    assert_eq!( status_of( &unmapped, "/5" ).await, StatusCode::OK );
+}
+
+#[tokio::test]
+async fn turns_away_unsafe_requests_sent_as_early_data() {
+    let backend = serve( make_sync( |_: Request| "ok" ) ).await;
+    let proxy = serve_proxy( ProxyConfig::new( backend.to_string() ).web_insecure().early_data( EarlyDataPolicy::RejectUnsafe ).finish() ).await;
+    let send = |method| client().request( method, &proxy ).header( "early-data", "1" ).send();
+
+    assert_eq!( send( reqwest::Method::POST ).await.unwrap().status().as_u16(), 425 );
+    assert_eq!( send( reqwest::Method::GET ).await.unwrap().status(), StatusCode::OK );
+
+    // Requests after the handshake are forwarded whatever their method
+    assert_eq!( client().post( &proxy ).send().await.unwrap().status(), StatusCode::OK );
+}
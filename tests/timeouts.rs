@@ -0,0 +1,96 @@
+//! How long the proxy waits for the proxied server to answer.
+
+mod common;
+
+use std::{ sync::{ Arc, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use poem::{ Request, endpoint::make, http::{ HeaderName, StatusCode } };
+use poem_proxy::{ ProxyConfig, ProxyErrorKind };
+use common::{ client, serve, serve_proxy };
+
+/// Serves a backend that reads the whole request, then takes `delay` to answer with
+/// how many bytes it read. Returns its address.
+async fn slow_backend( delay: Duration ) -> String {
+    serve( make( move |req: Request| async move {
+        let read = req.into_body().into_bytes().await.unwrap().len();
+        tokio::time::sleep( delay ).await;
+        read.to_string()
+    })).await.to_string()
+}
+
+/// Returns a configuration for a proxy to `backend`, along with how many of its
+/// requests timed out.
+fn timed( backend: String ) -> ( ProxyConfig, Arc<AtomicUsize> ) {
+    let timeouts = Arc::new( AtomicUsize::new( 0 ) );
+    let count = timeouts.clone();
+    let mut config = ProxyConfig::new( backend );
+    config.web_insecure().on_upstream_error( move |_, error| {
+        if error.kind() == ProxyErrorKind::Timeout {
+            count.fetch_add( 1, Ordering::SeqCst );
+        }
+    });
+    ( config, timeouts )
+}
+
+/// Sends a `POST` with a body of `size` bytes through the proxy, returning its status.
+async fn post( proxy: &str, path: &str, size: usize ) -> StatusCode {
+    client().post( format!( "{}{}", proxy, path ) ).body( vec![ b'x'; size ] ).send().await.unwrap().status()
+}
+
+#[tokio::test]
+async fn gives_large_uploads_more_time() {
+    let ( mut config, timeouts ) = timed( slow_backend( Duration::from_millis( 600 ) ).await );
+    config.upstream_timeout( Duration::from_millis( 200 ) ).timeout_per_megabyte( Duration::from_secs( 2 ) );
+    let proxy = serve_proxy( config ).await;
+
+    // A kilobyte gets little more than the 200ms, and a megabyte over two seconds
+    assert_eq!( post( &proxy, "/", 1024 ).await, StatusCode::BAD_GATEWAY );
+    assert_eq!( timeouts.load( Ordering::SeqCst ), 1 );
+    assert_eq!( post( &proxy, "/", 1024 * 1024 ).await, StatusCode::OK );
+    assert_eq!( timeouts.load( Ordering::SeqCst ), 1 );
+}
+
+#[tokio::test]
+async fn picks_the_timeout_of_each_request() {
+    let ( mut config, timeouts ) = timed( slow_backend( Duration::from_millis( 300 ) ).await );
+    config.enable_nesting().timeout_selector( |req| match req.uri().path() {
+        "/slow" => Duration::from_secs( 2 ),
+        _ => Duration::from_millis( 100 ),
+    });
+    let proxy = serve_proxy( config ).await;
+
+    assert_eq!( post( &proxy, "/slow", 0 ).await, StatusCode::OK );
+    assert_eq!( timeouts.load( Ordering::SeqCst ), 0 );
+    assert_eq!( post( &proxy, "/fast", 0 ).await, StatusCode::BAD_GATEWAY );
+    assert_eq!( timeouts.load( Ordering::SeqCst ), 1 );
+}
+
+#[tokio::test]
+async fn waits_for_long_polls_past_the_usual_timeouts() {
+    let ( mut config, timeouts ) = timed( slow_backend( Duration::from_millis( 600 ) ).await );
+    config.enable_nesting()
+        .upstream_timeout( Duration::from_millis( 200 ) )
+        .max_request_duration( Duration::from_millis( 300 ) )
+        .long_poll_path( "/events" )
+        .long_poll_header( HeaderName::from_static( "x-long-poll" ) )
+        .long_poll_timeout( Duration::from_secs( 2 ) );
+    let proxy = serve_proxy( config ).await;
+
+    // The server answers a long-poll whenever it is ready
+    let res = client().get( format!( "{}/events/orders", proxy ) ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( res.text().await.unwrap(), "0" );
+    let res = client().get( format!( "{}/orders", proxy ) ).header( "x-long-poll", "1" ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::OK );
+    assert_eq!( timeouts.load( Ordering::SeqCst ), 0 );
+
+    // While other requests are cut off as usual
+    assert_eq!( client().get( format!( "{}/eventsource", proxy ) ).send().await.unwrap().status(), StatusCode::BAD_GATEWAY );
+    assert_eq!( timeouts.load( Ordering::SeqCst ), 1 );
+
+    // Up to the long-poll timeout
+    let ( mut config, _ ) = timed( slow_backend( Duration::from_millis( 600 ) ).await );
+    config.enable_nesting().long_poll_path( "/events" ).long_poll_timeout( Duration::from_millis( 300 ) );
+    let proxy = serve_proxy( config ).await;
+    let res = client().get( format!( "{}/events/orders", proxy ) ).send().await.unwrap();
+    assert_eq!( res.status(), StatusCode::GATEWAY_TIMEOUT );
+}
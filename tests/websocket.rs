@@ -0,0 +1,736 @@
+//! Relaying websockets between clients and the proxied server, and closing them.
+
+mod common;
+
+use std::{ sync::{ Arc, atomic::{ AtomicUsize, Ordering } }, time::Duration };
+use futures_util::{ SinkExt, StreamExt };
+use poem::{ EndpointExt, IntoResponse, handler, web::{ Data, websocket::{ CloseCode, Message, WebSocket } } };
+use poem_proxy::{ FrameDirection, FrameSink, ProxyConfig, Socks5Proxy, TappedFrame, WebSocketStats, WebSocketTap };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::{ TcpListener, TcpStream }, sync::mpsc };
+use tokio_tungstenite::{ connect_async, tungstenite::{ self, client::IntoClientRequest } };
+use common::{ Logs, closed_port, serve };
+
+/// What the websocket backend saw of the closing handshake.
+type Events = mpsc::UnboundedSender<String>;
+
+/// A websocket backend that reports the close frames it receives and how its side of
+/// the websocket ends. Sending it `close` makes it start the closing handshake itself.
+#[handler]
+fn closing_backend( ws: WebSocket, events: Data<&Events> ) -> impl IntoResponse {
+    let events = events.clone();
+    ws.on_upgrade( move |mut socket| async move {
+        while let Some( msg ) = socket.next().await {
+            match msg {
+                Ok( Message::Text( text ) ) if text == "close" => {
+                    socket.send( Message::Close( Some( ( CloseCode::Normal, "done".into() ) ) ) ).await.unwrap();
+                },
+                Ok( Message::Close( frame ) ) => {
+                    let _ = events.send( format!( "close {:?}", frame.map( |( code, reason )| ( u16::from( code ), reason ) ) ) );
+                },
+                Ok( _ ) => {},
+                Err( error ) => {
+                    let _ = events.send( format!( "error {}", error ) );
+                    return;
+                },
+            }
+        }
+        let _ = events.send( "end".into() );
+    })
+}
+
+/// Serves the closing backend behind a proxy with the given websocket settings,
+/// returning the proxy's websocket url and what the backend sees.
+async fn closing_proxy( configure: impl FnOnce( &mut ProxyConfig ) -> &mut ProxyConfig ) -> ( String, mpsc::UnboundedReceiver<String> ) {
+    let ( events, seen ) = mpsc::unbounded_channel();
+    let backend = serve( closing_backend.data( events ) ).await;
+    let mut config = ProxyConfig::new( backend.to_string() );
+    configure( config.ws_insecure() );
+    let proxy = serve( poem_proxy::ProxyEndpoint::new( config.finish() ) ).await;
+    ( format!( "ws://{}/", proxy ), seen )
+}
+
+/// Waits for the next thing the backend saw.
+async fn next_event( seen: &mut mpsc::UnboundedReceiver<String> ) -> String {
+    tokio::time::timeout( Duration::from_secs( 5 ), seen.recv() ).await
+        .expect( "the backend to see something" )
+        .expect( "the backend to still be reporting" )
+}
+
+#[tokio::test]
+async fn completes_a_closing_handshake_started_by_the_client() {
+    let ( url, mut seen ) = closing_proxy( |config| config.ws_half_close() ).await;
+    let ( mut socket, _ ) = connect_async( url ).await.unwrap();
+
+    socket.send( tungstenite::Message::Close( Some( tungstenite::protocol::CloseFrame {
+        code: 1000.into(),
+        reason: "bye".into(),
+    }))).await.unwrap();
+
+    // The client gets a close frame back and the connection ends cleanly, rather than
+    // being dropped
+    match socket.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( u16::from( frame.code ), 1000 ),
+        other => panic!( "expected a close frame, got {:?}", other ),
+    }
+    assert!( socket.next().await.is_none() );
+
+    // And so does the backend
+    assert_eq!( next_event( &mut seen ).await, r#"close Some((1000, "bye"))"# );
+    assert_eq!( next_event( &mut seen ).await, "end" );
+}
+
+#[tokio::test]
+async fn completes_a_closing_handshake_started_by_the_server() {
+    let ( url, mut seen ) = closing_proxy( |config| config.ws_half_close() ).await;
+    let ( mut socket, _ ) = connect_async( url ).await.unwrap();
+
+    socket.send( tungstenite::Message::Text( "close".into() ) ).await.unwrap();
+
+    // The client gets the backend's close frame, and its reply makes it to the backend
+    match socket.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => {
+            assert_eq!( u16::from( frame.code ), 1000 );
+            assert_eq!( frame.reason, "done" );
+        },
+        other => panic!( "expected a close frame, got {:?}", other ),
+    }
+    assert!( socket.next().await.is_none() );
+
+    assert_eq!( next_event( &mut seen ).await, r#"close Some((1000, "done"))"# );
+    assert_eq!( next_event( &mut seen ).await, "end" );
+}
+
+#[tokio::test]
+async fn closes_websockets_as_going_away_on_shutdown() {
+    let ( events, mut seen ) = mpsc::unbounded_channel();
+    let backend = serve( closing_backend.data( events ) ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().finish();
+    let handle = config.handle();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    while handle.open_websockets() == 0 {
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+
+    let shutdown = tokio::spawn( async move { handle.shutdown_websockets( Duration::from_secs( 5 ) ).await } );
+
+    // Both peers are told the proxy is going away
+    match socket.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( u16::from( frame.code ), 1001 ),
+        other => panic!( "expected a close frame, got {:?}", other ),
+    }
+    assert!( socket.next().await.is_none() );
+    assert!( next_event( &mut seen ).await.starts_with( "close Some((1001, " ) );
+    assert_eq!( shutdown.await.unwrap(), 0 );
+
+    // And new websockets are turned away
+    match connect_async( &url ).await {
+        Err( tungstenite::Error::Http( res ) ) => assert_eq!( res.status(), 503 ),
+        other => panic!( "expected 503 Service Unavailable, got {:?}", other.map( |( _, res )| res.status() ) ),
+    }
+}
+
+#[tokio::test]
+async fn closes_websockets_as_going_away_when_their_target_is_removed() {
+    let ( removed_events, mut removed_seen ) = mpsc::unbounded_channel();
+    let ( kept_events, mut kept_seen ) = mpsc::unbounded_channel();
+    let removed = serve( closing_backend.data( removed_events ) ).await.to_string();
+    let kept = serve( closing_backend.data( kept_events ) ).await.to_string();
+    let config = ProxyConfig::new( removed.clone() ).ws_insecure().ws_close_on_removal().finish();
+    let handle = config.handle();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+
+    // One websocket to the target being removed, and one to the target that stays
+    let ( mut doomed, _ ) = connect_async( &url ).await.unwrap();
+    handle.add_target( &kept );
+    handle.drain_target( &removed );
+    let ( mut survivor, _ ) = connect_async( &url ).await.unwrap();
+    while handle.active_requests( &kept ) != Some( 1 ) {
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+
+    assert!( handle.remove_target( &removed ) );
+
+    // Both peers of the removed target's websocket are told it is going away
+    match doomed.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( u16::from( frame.code ), 1001 ),
+        other => panic!( "expected a close frame, got {:?}", other ),
+    }
+    assert!( next_event( &mut removed_seen ).await.starts_with( "close Some((1001, " ) );
+
+    // While the other one is still relayed
+    survivor.send( tungstenite::Message::Text( "close".into() ) ).await.unwrap();
+    match survivor.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( frame.reason, "done" ),
+        other => panic!( "expected the backend's close frame, got {:?}", other ),
+    }
+    assert_eq!( next_event( &mut kept_seen ).await, r#"close Some((1000, "done"))"# );
+}
+
+#[tokio::test]
+async fn retries_connecting_to_a_server_that_is_not_up_yet() {
+    let port = closed_port().await;
+    let config = ProxyConfig::new( format!( "127.0.0.1:{}", port ) ).ws_insecure().ws_connect_retries( 4 ).finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+
+    // The server only starts listening after the first attempts were refused
+    let ( events, mut seen ) = mpsc::unbounded_channel();
+    tokio::spawn( async move {
+        tokio::time::sleep( Duration::from_millis( 150 ) ).await;
+        let listener = poem::listener::TcpListener::bind( format!( "127.0.0.1:{}", port ) );
+        poem::Server::new( listener ).run( closing_backend.data( events ) ).await
+    });
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+
+    socket.send( tungstenite::Message::Text( "close".into() ) ).await.unwrap();
+    match socket.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( frame.reason, "done" ),
+        other => panic!( "expected the backend's close frame, got {:?}", other ),
+    }
+    assert_eq!( next_event( &mut seen ).await, r#"close Some((1000, "done"))"# );
+}
+
+#[tokio::test]
+async fn closes_websockets_whose_server_cannot_be_reached() {
+    let config = ProxyConfig::new( format!( "127.0.0.1:{}", closed_port().await ) ).ws_insecure().finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+
+    match socket.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( u16::from( frame.code ), 1011 ),
+        other => panic!( "expected a close frame, got {:?}", other ),
+    }
+
+    // The proxy finishes the close, rather than leaving the client hanging
+    let end = tokio::time::timeout( Duration::from_secs( 5 ), socket.next() ).await.expect( "the websocket to end" );
+    assert!( end.as_ref().map_or( true, |msg| msg.is_err() ), "{:?}", end );
+}
+
+#[tokio::test]
+async fn rejects_websockets_whose_server_url_is_invalid() {
+    let config = ProxyConfig::new( "bad host:80" ).ws_insecure().finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+
+    match connect_async( &url ).await {
+        Err( tungstenite::Error::Http( res ) ) => assert_eq!( res.status(), 502 ),
+        other => panic!( "expected the upgrade to be rejected, got {:?}", other.map( |( _, res )| res ) ),
+    }
+}
+
+/// A websocket backend that sends `tick` every 50ms, without expecting anything back.
+#[handler]
+fn ticking_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |mut socket| async move {
+        while socket.send( Message::Text( "tick".into() ) ).await.is_ok() {
+            tokio::time::sleep( Duration::from_millis( 50 ) ).await;
+        }
+    })
+}
+
+#[tokio::test]
+async fn keeps_websockets_open_while_either_peer_is_talking() {
+    let backend = serve( ticking_backend ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().ws_idle_timeout( Duration::from_millis( 200 ) ).finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+
+    // The client never says a thing, for three times as long as the timeout
+    for _ in 0..12 {
+        match socket.next().await {
+            Some( Ok( tungstenite::Message::Text( text ) ) ) => assert_eq!( text, "tick" ),
+            other => panic!( "expected a tick, got {:?}", other ),
+        }
+    }
+}
+
+#[tokio::test]
+async fn closes_websockets_as_going_away_once_both_peers_are_idle() {
+    let ( url, mut seen ) = closing_proxy( |config| config.ws_idle_timeout( Duration::from_millis( 200 ) ) ).await;
+    let ( mut socket, _ ) = connect_async( url ).await.unwrap();
+
+    let started = std::time::Instant::now();
+    match socket.next().await {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( u16::from( frame.code ), 1001 ),
+        other => panic!( "expected a close frame, got {:?}", other ),
+    }
+    assert!( started.elapsed() >= Duration::from_millis( 150 ) );
+    assert!( next_event( &mut seen ).await.starts_with( "close Some((1001, " ) );
+}
+
+/// A websocket backend that sends 30 binary messages of a megabyte each, numbered by
+/// their first byte, as fast as it can.
+#[handler]
+fn flooding_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |mut socket| async move {
+        for index in 0..30u8 {
+            let mut message = vec![ 0; 1024 * 1024 ];
+            message[ 0 ] = index;
+            if socket.send( Message::Binary( message ) ).await.is_err() {
+                return;
+            }
+        }
+        let _ = socket.next().await;
+    })
+}
+
+#[tokio::test]
+async fn reads_no_more_messages_ahead_than_allowed() {
+    let backend = serve( flooding_backend ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().ws_max_inflight_frames( 2 ).finish();
+    let handle = config.handle();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+
+    // While the client doesn't read, the proxy fills its buffer and stops there
+    let mut most = 0;
+    for _ in 0..300 {
+        most = most.max( handle.buffered_websocket_messages() );
+        tokio::time::sleep( Duration::from_millis( 1 ) ).await;
+    }
+    assert_eq!( most, 2 );
+
+    for index in 0..30 {
+        match socket.next().await {
+            Some( Ok( tungstenite::Message::Binary( message ) ) ) => assert_eq!( message[ 0 ], index ),
+            other => panic!( "expected message {}, got {:?}", index, other.map( |msg| msg.map( |msg| msg.len() ).map_err( |error| error.to_string() ) ) ),
+        }
+        assert!( handle.buffered_websocket_messages() <= 2 );
+    }
+    assert_eq!( handle.buffered_websocket_messages(), 0 );
+}
+
+/// A websocket backend that hangs up without a close frame as soon as it is connected,
+/// which the proxy tells the client about with `1011 Internal Error`.
+#[handler]
+fn hanging_up_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |socket| async move { drop( socket ) } )
+}
+
+#[tokio::test]
+async fn counts_and_logs_websockets_that_end_without_a_clean_close() {
+    let ( logs, _guard ) = Logs::capture();
+    let ( events, _seen ) = mpsc::unbounded_channel::<String>();
+    let clean = serve( closing_backend.data( events ) ).await;
+    let hanging_up = serve( hanging_up_backend ).await;
+    let config = ProxyConfig::new( clean.to_string() ).ws_insecure().finish();
+    let handle = config.handle();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+
+    // One websocket the backend closes properly
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    socket.send( tungstenite::Message::Text( "close".into() ) ).await.unwrap();
+    while socket.next().await.is_some() {}
+
+    // And one it hangs up on
+    handle.add_target( &hanging_up.to_string() );
+    handle.drain_target( &clean.to_string() );
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    while let Some( Ok( _ ) ) = socket.next().await {}
+
+    for _ in 0..100 {
+        if handle.websocket_stats() == ( WebSocketStats { clean: 1, abnormal: 1 } ) {
+            break;
+        }
+        tokio::time::sleep( Duration::from_millis( 10 ) ).await;
+    }
+    assert_eq!( handle.websocket_stats(), WebSocketStats { clean: 1, abnormal: 1 } );
+    let closes: Vec<_> = logs.events().into_iter().filter( |event| event.contains( "websocket closed" ) ).collect();
+    assert_eq!( closes, [
+        r#"INFO websocket closed code=1000 reason=done"#,
+        r#"WARN websocket closed abnormally code=1011 reason=The proxied server went away"#,
+    ] );
+}
+
+/// A websocket backend that sends every message back.
+#[handler]
+fn echo_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |mut socket| async move {
+        while let Some( Ok( msg ) ) = socket.next().await {
+            if socket.send( msg ).await.is_err() {
+                break
+            }
+        }
+    })
+}
+
+/// A websocket backend that answers `big` with a kilobyte of text and echoes anything
+/// else but close frames, which the websocket answers itself.
+#[handler]
+fn big_answer_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |mut socket| async move {
+        while let Some( Ok( msg ) ) = socket.next().await {
+            let answer = match msg {
+                Message::Text( text ) if text == "big" => Message::Text( "x".repeat( 1024 ) ),
+                Message::Close( _ ) => continue,
+                msg => msg,
+            };
+            if socket.send( answer ).await.is_err() {
+                break
+            }
+        }
+    })
+}
+
+#[tokio::test]
+async fn relays_text_and_binary_messages_as_they_were_sent() {
+    let backend = serve( echo_backend ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().finish();
+    let ( mut socket, _ ) = connect_async( format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await ) ).await.unwrap();
+
+    // Bytes that are valid text still come back as binary, and the other way round
+    let sent = [
+        tungstenite::Message::Text( "hello".into() ),
+        tungstenite::Message::Binary( b"hello".to_vec() ),
+        tungstenite::Message::Binary( vec![ 0, 159, 146, 150 ] ),
+        tungstenite::Message::Text( String::new() ),
+    ];
+    for msg in sent {
+        socket.send( msg.clone() ).await.unwrap();
+        assert_eq!( socket.next().await.unwrap().unwrap(), msg );
+    }
+}
+
+#[tokio::test]
+async fn closes_websockets_whose_peers_send_messages_over_the_size_limit() {
+    let backend = serve( big_answer_backend ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().ws_max_frame_size( 16 ).finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let closed_with = |msg: Option<Result<tungstenite::Message, tungstenite::Error>>| match msg {
+        Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => u16::from( frame.code ),
+        other => panic!( "expected a close frame, got {:?}", other ),
+    };
+
+    // Messages up to the limit are relayed either way
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    socket.send( tungstenite::Message::Binary( vec![ 1; 16 ] ) ).await.unwrap();
+    assert_eq!( socket.next().await.unwrap().unwrap(), tungstenite::Message::Binary( vec![ 1; 16 ] ) );
+
+    // From the client
+    socket.send( tungstenite::Message::Text( "y".repeat( 17 ) ) ).await.unwrap();
+    assert_eq!( closed_with( socket.next().await ), 1009 );
+
+    // From the server
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    socket.send( tungstenite::Message::Text( "big".into() ) ).await.unwrap();
+    assert_eq!( closed_with( socket.next().await ), 1009 );
+}
+
+/// A SOCKS5 proxy that only lets in `user` with the password `pass`, and reports the
+/// host and port each client asked it to connect to. Names are resolved as localhost.
+async fn socks5_proxy() -> ( String, mpsc::UnboundedReceiver<String> ) {
+    let listener = TcpListener::bind( "127.0.0.1:0" ).await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let ( seen, requests ) = mpsc::unbounded_channel();
+    tokio::spawn( async move {
+        while let Ok( ( mut client, _ ) ) = listener.accept().await {
+            let seen = seen.clone();
+            tokio::spawn( async move {
+                let mut greeting = [ 0; 2 ];
+                client.read_exact( &mut greeting ).await.unwrap();
+                let mut methods = vec![ 0; greeting[1] as usize ];
+                client.read_exact( &mut methods ).await.unwrap();
+                if !methods.contains( &2 ) {
+                    return client.write_all( &[ 5, 0xff ] ).await.unwrap();
+                }
+                client.write_all( &[ 5, 2 ] ).await.unwrap();
+
+                let mut login = [ 0; 1 ];
+                client.read_exact( &mut login ).await.unwrap();
+                let username = read_field( &mut client ).await;
+                let password = read_field( &mut client ).await;
+                if ( username.as_str(), password.as_str() ) != ( "user", "pass" ) {
+                    return client.write_all( &[ 1, 1 ] ).await.unwrap();
+                }
+                client.write_all( &[ 1, 0 ] ).await.unwrap();
+
+                let mut request = [ 0; 4 ];
+                client.read_exact( &mut request ).await.unwrap();
+                assert_eq!( request[ ..3 ], [ 5, 1, 0 ] );
+                assert_eq!( request[3], 3, "the name should be resolved by the proxy" );
+                let host = read_field( &mut client ).await;
+                let port = client.read_u16().await.unwrap();
+                let _ = seen.send( format!( "{}:{}", host, port ) );
+
+                let mut server = TcpStream::connect( ( "127.0.0.1", port ) ).await.unwrap();
+                client.write_all( &[ 5, 0, 0, 1, 127, 0, 0, 1, 0, 0 ] ).await.unwrap();
+                let _ = tokio::io::copy_bidirectional( &mut client, &mut server ).await;
+            });
+        }
+    });
+    ( address, requests )
+}
+
+/// Reads a length-prefixed field of the SOCKS5 handshake.
+async fn read_field( stream: &mut TcpStream ) -> String {
+    let mut field = vec![ 0; stream.read_u8().await.unwrap() as usize ];
+    stream.read_exact( &mut field ).await.unwrap();
+    String::from_utf8( field ).unwrap()
+}
+
+#[tokio::test]
+async fn opens_websockets_to_the_server_through_a_socks5_proxy() {
+    let backend = serve( echo_backend ).await;
+    let ( socks, mut requests ) = socks5_proxy().await;
+    let target = format!( "localhost:{}", backend.port() );
+    let proxy = |credentials: Option<( &str, &str )>| {
+        let mut socks = Socks5Proxy::new( socks.clone() );
+        if let Some( ( username, password ) ) = credentials {
+            socks.credentials( username, password );
+        }
+        let config = ProxyConfig::new( target.clone() ).ws_insecure().ws_socks5_proxy( &socks ).finish();
+        async move { format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await ) }
+    };
+
+    let ( mut socket, _ ) = connect_async( proxy( Some( ( "user", "pass" ) ) ).await ).await.unwrap();
+    socket.send( tungstenite::Message::Text( "hello".into() ) ).await.unwrap();
+    assert_eq!( socket.next().await.unwrap().unwrap(), tungstenite::Message::Text( "hello".into() ) );
+    assert_eq!( requests.recv().await.unwrap(), target );
+
+    // Without the right credentials the server can't be reached
+    for credentials in [ None, Some( ( "user", "wrong" ) ) ] {
+        let ( mut socket, _ ) = connect_async( proxy( credentials ).await ).await.unwrap();
+        match socket.next().await {
+            Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => assert_eq!( u16::from( frame.code ), 1011 ),
+            other => panic!( "expected a close frame, got {:?}", other ),
+        }
+    }
+    assert!( requests.try_recv().is_err() );
+}
+
+/// A websocket backend that closes every websocket as going away once it is sent
+/// something.
+#[handler]
+fn going_away_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.on_upgrade( |mut socket| async move {
+        socket.next().await;
+        let _ = socket.send( Message::Close( Some( ( CloseCode::Away, "going away".into() ) ) ) ).await;
+        while socket.next().await.is_some() {}
+    })
+}
+
+#[tokio::test]
+async fn passes_on_the_close_code_and_reason_of_the_server() {
+    let backend = serve( going_away_backend ).await;
+    for half_close in [ false, true ] {
+        let mut config = ProxyConfig::new( backend.to_string() );
+        config.ws_insecure();
+        if half_close {
+            config.ws_half_close();
+        }
+        let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config.finish() ) ).await );
+        let ( mut socket, _ ) = connect_async( url ).await.unwrap();
+        socket.send( tungstenite::Message::Text( "bye".into() ) ).await.unwrap();
+
+        match socket.next().await {
+            Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => {
+                assert_eq!( u16::from( frame.code ), 1001 );
+                assert_eq!( frame.reason, "going away" );
+            },
+            other => panic!( "expected a close frame, got {:?}", other ),
+        }
+    }
+}
+
+#[tokio::test]
+async fn pings_clients_and_closes_those_that_stop_answering() {
+    let ( url, mut seen ) = closing_proxy( |config| config.ws_keepalive( Duration::from_millis( 50 ) ) ).await;
+
+    // Reading the pings answers them, which keeps the websocket open
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    for _ in 0..6 {
+        match tokio::time::timeout( Duration::from_secs( 1 ), socket.next() ).await {
+            Ok( Some( Ok( tungstenite::Message::Ping( _ ) ) ) ) => {},
+            other => panic!( "expected a ping, got {:?}", other ),
+        }
+    }
+    socket.send( tungstenite::Message::Text( "close".into() ) ).await.unwrap();
+    assert_eq!( next_event( &mut seen ).await, r#"close Some((1000, "done"))"# );
+    assert_eq!( next_event( &mut seen ).await, "end" );
+
+    // A client that doesn't answer is taken to be gone, on both sides
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+    tokio::time::sleep( Duration::from_millis( 300 ) ).await;
+    assert_eq!( next_event( &mut seen ).await, r#"close Some((1001, "The client stopped answering pings"))"# );
+    loop {
+        match socket.next().await {
+            Some( Ok( tungstenite::Message::Ping( _ ) ) ) => {},
+            Some( Ok( tungstenite::Message::Close( Some( frame ) ) ) ) => break assert_eq!( u16::from( frame.code ), 1001 ),
+            other => panic!( "expected a close frame, got {:?}", other ),
+        }
+    }
+}
+
+/// A websocket backend that speaks the `chat` and `graphql-ws` subprotocols.
+#[handler]
+fn subprotocol_backend( ws: WebSocket ) -> impl IntoResponse {
+    ws.protocols( [ "graphql-ws", "chat" ] ).on_upgrade( |mut socket| async move {
+        while socket.next().await.is_some() {}
+    })
+}
+
+#[tokio::test]
+async fn tells_clients_the_subprotocol_the_server_picked() {
+    let backend = serve( subprotocol_backend ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let offering = |protocols: &str| {
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert( "sec-websocket-protocol", protocols.parse().unwrap() );
+        request
+    };
+
+    let ( _socket, res ) = connect_async( offering( "superchat, chat" ) ).await.unwrap();
+    assert_eq!( res.headers()[ "sec-websocket-protocol" ], "chat" );
+
+    // Nothing is picked when nothing was offered
+    let ( _socket, res ) = connect_async( &url ).await.unwrap();
+    assert!( res.headers().get( "sec-websocket-protocol" ).is_none() );
+}
+
+#[tokio::test]
+async fn turns_away_clients_asking_for_subprotocols_not_allowed() {
+    let handshakes = Arc::new( AtomicUsize::new( 0 ) );
+    let counted = handshakes.clone();
+    let backend = serve( subprotocol_backend.before( move |req| {
+        counted.fetch_add( 1, Ordering::SeqCst );
+        async { Ok( req ) }
+    }) ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().ws_allowed_subprotocols( [ "chat", "graphql-ws" ] ).finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let offering = |protocols: &str| {
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert( "sec-websocket-protocol", protocols.parse().unwrap() );
+        request
+    };
+
+    let ( _socket, res ) = connect_async( offering( "chat" ) ).await.unwrap();
+    assert_eq!( res.headers()[ "sec-websocket-protocol" ], "chat" );
+    assert_eq!( handshakes.load( Ordering::SeqCst ), 1 );
+
+    // One protocol that isn't allowed is enough, and the server never hears of it
+    for protocols in [ "superchat", "chat, superchat", "Chat" ] {
+        match connect_async( offering( protocols ) ).await {
+            Err( tungstenite::Error::Http( res ) ) => assert_eq!( res.status(), 403, "{}", protocols ),
+            other => panic!( "{}: expected a 403, got {:?}", protocols, other.map( |( _, res )| res.status() ) ),
+        }
+    }
+    assert_eq!( handshakes.load( Ordering::SeqCst ), 1 );
+
+    // Not asking for a subprotocol is always allowed
+    connect_async( &url ).await.unwrap();
+    assert_eq!( handshakes.load( Ordering::SeqCst ), 2 );
+}
+
+#[tokio::test]
+async fn turns_away_handshakes_with_headers_over_the_size_limit() {
+    let handshakes = Arc::new( AtomicUsize::new( 0 ) );
+    let counted = handshakes.clone();
+    let backend = serve( echo_backend.before( move |req| {
+        counted.fetch_add( 1, Ordering::SeqCst );
+        async { Ok( req ) }
+    }) ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().ws_max_handshake_size( 2048 ).finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let with_cookie = |size: usize| {
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert( "cookie", format!( "session={}", "x".repeat( size ) ).parse().unwrap() );
+        request
+    };
+
+    let ( _socket, _ ) = connect_async( with_cookie( 100 ) ).await.unwrap();
+    assert_eq!( handshakes.load( Ordering::SeqCst ), 1 );
+
+    // The server never hears of a handshake that is too large
+    match connect_async( with_cookie( 4096 ) ).await {
+        Err( tungstenite::Error::Http( res ) ) => assert_eq!( res.status(), 431 ),
+        other => panic!( "expected a 431, got {:?}", other.map( |( _, res )| res.status() ) ),
+    }
+    assert_eq!( handshakes.load( Ordering::SeqCst ), 1 );
+}
+
+/// A frame sink that keeps every frame it is given.
+#[derive(Default)]
+struct KeptFrames( std::sync::Mutex<Vec<TappedFrame>> );
+
+#[poem_proxy::async_trait]
+impl FrameSink for KeptFrames {
+    async fn tap( &self, frame: TappedFrame ) {
+        self.0.lock().unwrap().push( frame );
+    }
+}
+
+#[tokio::test]
+async fn taps_a_sample_of_the_messages_without_changing_them() {
+    let backend = serve( echo_backend ).await;
+    let sink = Arc::new( KeptFrames::default() );
+    let config = ProxyConfig::new( backend.to_string() )
+        .ws_insecure()
+        .tap_websockets( WebSocketTap::new( sink.clone() ).sample_rate( 0.25 ) )
+        .finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+    let ( mut socket, _ ) = connect_async( &url ).await.unwrap();
+
+    for index in 0..400 {
+        let msg = tungstenite::Message::Text( format!( "message {}", index ) );
+        socket.send( msg.clone() ).await.unwrap();
+        assert_eq!( socket.next().await.unwrap().unwrap(), msg );
+    }
+    tokio::time::sleep( Duration::from_millis( 100 ) ).await;
+
+    // Around a quarter of the messages each way are copied, as they were relayed
+    let frames = sink.0.lock().unwrap();
+    for direction in [ FrameDirection::ClientToServer, FrameDirection::ServerToClient ] {
+        let tapped = frames.iter().filter( |frame| frame.direction == direction ).collect::<Vec<_>>();
+        assert!( ( 50..=150 ).contains( &tapped.len() ), "{} of 400 {:?} messages were tapped", tapped.len(), direction );
+        for frame in tapped {
+            assert_eq!( frame.message, Message::Text( format!( "message {}", frame.index ) ) );
+            assert!( frame.uri.starts_with( "ws://" ), "{}", frame.uri );
+        }
+    }
+}
+
+/// How many handshakes a backend is in the middle of, and the most there have been.
+#[derive(Default)]
+struct Handshakes {
+    current: AtomicUsize,
+    most: AtomicUsize,
+}
+
+/// A websocket backend that takes 300ms to answer each handshake, keeping track of how
+/// many it is answering at once.
+#[handler]
+async fn slow_handshake_backend( ws: WebSocket, handshakes: Data<&Arc<Handshakes>> ) -> impl IntoResponse {
+    let current = handshakes.current.fetch_add( 1, Ordering::SeqCst ) + 1;
+    handshakes.most.fetch_max( current, Ordering::SeqCst );
+    tokio::time::sleep( Duration::from_millis( 300 ) ).await;
+    handshakes.current.fetch_sub( 1, Ordering::SeqCst );
+    ws.on_upgrade( |mut socket| async move {
+        while socket.next().await.is_some() {}
+    })
+}
+
+#[tokio::test]
+async fn limits_the_handshakes_in_progress_with_the_server() {
+    let handshakes = Arc::new( Handshakes::default() );
+    let backend = serve( slow_handshake_backend.data( handshakes.clone() ) ).await;
+    let config = ProxyConfig::new( backend.to_string() ).ws_insecure().ws_max_concurrent_handshakes( 3 ).finish();
+    let url = format!( "ws://{}/", serve( poem_proxy::ProxyEndpoint::new( config ) ).await );
+
+    let attempts = futures_util::future::join_all( ( 0..10 ).map( |_| connect_async( &url ) ) ).await;
+    let mut opened = Vec::new();
+    for attempt in attempts {
+        match attempt {
+            Ok( ( socket, _ ) ) => opened.push( socket ),
+            Err( tungstenite::Error::Http( res ) ) => {
+                assert_eq!( res.status(), 503 );
+                assert!( res.headers().contains_key( "retry-after" ) );
+            },
+            Err( e ) => panic!( "expected the upgrade to be rejected, got {}", e ),
+        }
+    }
+    assert_eq!( opened.len(), 3 );
+    assert_eq!( handshakes.most.load( Ordering::SeqCst ), 3 );
+
+    // Open websockets don't count towards the limit
+    let ( _socket, _ ) = connect_async( &url ).await.unwrap();
+}